@@ -0,0 +1,534 @@
+//! Measurements and paint strokes as data: JSON with pixel coordinates, and SVG overlays
+
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs::{read, read_to_string, write},
+    io::Cursor,
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, ImageFormat};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    image_editing::{EditState, ImageOperation, ImgOpItem, MeasureShape},
+    paint::{PaintLayer, PaintStroke, PaintTool},
+};
+
+/// The current version of the annotation format
+pub const ANNOTATIONS_VERSION: u32 = 1;
+
+/// Marks the JSON copy of the annotations inside an exported SVG, so it can be imported again
+const SVG_METADATA_ID: &str = "oculante-annotations";
+
+/// All annotations of an image, in pixel coordinates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotations {
+    pub version: u32,
+    /// File name of the annotated image
+    #[serde(default)]
+    pub image: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub shapes: Vec<ShapeAnnotation>,
+    #[serde(default)]
+    pub strokes: Vec<StrokeAnnotation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShapeAnnotation {
+    pub shape: MeasureShape,
+    /// In pixels. Informational only, ignored on import.
+    #[serde(default)]
+    pub length: f64,
+    /// In square pixels. Informational only, ignored on import.
+    #[serde(default)]
+    pub area: f64,
+    /// In degrees, for angles. Informational only, ignored on import.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub angle: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StrokeAnnotation {
+    /// The stroke, with points in pixels instead of relative to the image size
+    pub stroke: PaintStroke,
+    /// Name of the layer the stroke is on
+    #[serde(default)]
+    pub layer_name: String,
+    /// Brush diameter in pixels. Informational only, ignored on import.
+    #[serde(default)]
+    pub brush_size: f32,
+    /// In pixels, along the drawn path. Informational only, ignored on import.
+    #[serde(default)]
+    pub length: f64,
+}
+
+/// How an SVG overlay refers to the annotated image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SvgImage {
+    None,
+    Linked,
+    Embedded,
+}
+
+impl Annotations {
+    /// Collect the measure shapes and paint strokes of an edit state
+    pub fn from_edit_state(edit_state: &EditState, size: (u32, u32), image: Option<&Path>) -> Self {
+        let shapes = measure_shapes(edit_state)
+            .map(|shape| ShapeAnnotation {
+                length: shape.length(),
+                area: shape.area(),
+                angle: shape.angle_with(&Default::default()),
+                shape: shape.clone(),
+            })
+            .collect();
+        let strokes = edit_state
+            .paint_strokes
+            .iter()
+            .filter(|stroke| !stroke.is_empty())
+            .map(|stroke| {
+                let length = stroke
+                    .outline(size)
+                    .iter()
+                    .flat_map(|path| path.windows(2).map(|p| p[0].distance(p[1]) as f64))
+                    .sum();
+                StrokeAnnotation {
+                    stroke: PaintStroke {
+                        points: stroke
+                            .points
+                            .iter()
+                            .map(|p| (p.0 * size.0 as f32, p.1 * size.1 as f32))
+                            .collect(),
+                        highlight: false,
+                        committed: false,
+                        ..stroke.clone()
+                    },
+                    layer_name: edit_state
+                        .paint_layers
+                        .get(stroke.layer)
+                        .map(|l| l.name.clone())
+                        .unwrap_or_default(),
+                    brush_size: stroke.brush_size(size),
+                    length,
+                }
+            })
+            .collect();
+        Self {
+            version: ANNOTATIONS_VERSION,
+            image: image
+                .and_then(|p| p.file_name())
+                .map(|n| n.to_string_lossy().to_string()),
+            width: size.0,
+            height: size.1,
+            shapes,
+            strokes,
+        }
+    }
+
+    /// Add the annotations to an edit state. Coordinates are scaled if the image
+    /// has a different size than the one the annotations were made on.
+    pub fn apply(&self, edit_state: &mut EditState, size: (u32, u32)) {
+        let scale = (
+            size.0 as f64 / self.width.max(1) as f64,
+            size.1 as f64 / self.height.max(1) as f64,
+        );
+        let mut shapes = self
+            .shapes
+            .iter()
+            .map(|annotation| {
+                let mut shape = annotation.shape.clone();
+                for p in shape.points_mut() {
+                    *p = (
+                        (p.0 as f64 * scale.0).round() as u32,
+                        (p.1 as f64 * scale.1).round() as u32,
+                    );
+                }
+                shape
+            })
+            .collect::<Vec<_>>();
+
+        if !shapes.is_empty() {
+            let existing =
+                edit_state
+                    .image_op_stack
+                    .iter_mut()
+                    .find_map(|op| match &mut op.operation {
+                        ImageOperation::Measure { shapes } => Some(shapes),
+                        _ => None,
+                    });
+            match existing {
+                Some(existing) => existing.append(&mut shapes),
+                None => {
+                    edit_state
+                        .image_op_stack
+                        .push(ImgOpItem::new(ImageOperation::Measure { shapes }));
+                    edit_state.sort_stacks();
+                }
+            }
+        }
+
+        // keep the empty stroke waiting for input at the end
+        let pending = match edit_state.paint_strokes.last() {
+            Some(stroke) if stroke.is_empty() => edit_state.paint_strokes.pop(),
+            _ => None,
+        };
+        for annotation in &self.strokes {
+            let layer = match edit_state
+                .paint_layers
+                .iter()
+                .position(|l| l.name == annotation.layer_name)
+            {
+                Some(layer) => layer,
+                None if annotation.layer_name.is_empty() => 0,
+                None => {
+                    edit_state
+                        .paint_layers
+                        .push(PaintLayer::new(&annotation.layer_name));
+                    edit_state.paint_layers.len() - 1
+                }
+            };
+            edit_state.paint_strokes.push(PaintStroke {
+                points: annotation
+                    .stroke
+                    .points
+                    .iter()
+                    .map(|p| {
+                        (
+                            p.0 / self.width.max(1) as f32,
+                            p.1 / self.height.max(1) as f32,
+                        )
+                    })
+                    .collect(),
+                layer,
+                highlight: false,
+                committed: false,
+                ..annotation.stroke.clone()
+            });
+        }
+        edit_state.paint_strokes.extend(pending);
+        // An empty result makes the edit panel process all operators again
+        edit_state.result_image_op = Default::default();
+    }
+
+    pub fn save_json(&self, path: &Path) -> Result<()> {
+        write(path, serde_json::to_string_pretty(self)?)?;
+        debug!("Saved annotations to {}", path.display());
+        Ok(())
+    }
+
+    /// Write an SVG overlay with the image size, optionally showing the image below
+    pub fn save_svg(&self, path: &Path, image: Option<&Path>, mode: SvgImage) -> Result<()> {
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            w = self.width,
+            h = self.height
+        )?;
+        let json = serde_json::to_string(self)?.replace("]]>", "]]]]><![CDATA[>");
+        writeln!(
+            svg,
+            r#"<metadata id="{SVG_METADATA_ID}"><![CDATA[{json}]]></metadata>"#
+        )?;
+
+        if let Some(image) = image {
+            let href = match mode {
+                SvgImage::None => None,
+                SvgImage::Linked => Some(linked_href(image, path)),
+                SvgImage::Embedded => Some(embedded_href(image)?),
+            };
+            if let Some(href) = href {
+                writeln!(
+                    svg,
+                    r#"<image x="0" y="0" width="{}" height="{}" xlink:href="{}"/>"#,
+                    self.width,
+                    self.height,
+                    escape(&href)
+                )?;
+            }
+        }
+
+        writeln!(svg, r#"<g id="measurements" fill="none">"#)?;
+        for annotation in &self.shapes {
+            let shape = &annotation.shape;
+            let [r, g, b, a] = shape.color();
+            let style = format!(
+                r#"stroke="rgb({r},{g},{b})" stroke-opacity="{:.3}" stroke-width="{}""#,
+                a as f32 / 255.,
+                shape.width()
+            );
+            match shape {
+                MeasureShape::Line { points, .. } => {
+                    for p in points.chunks_exact(2) {
+                        writeln!(
+                            svg,
+                            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" {style}/>"#,
+                            p[0].0, p[0].1, p[1].0, p[1].1
+                        )?;
+                    }
+                }
+                MeasureShape::Rect { points, .. } => {
+                    let [p0, p1, ..] = points.as_slice() else {
+                        continue;
+                    };
+                    writeln!(
+                        svg,
+                        r#"<rect x="{}" y="{}" width="{}" height="{}" {style}/>"#,
+                        p0.0.min(p1.0),
+                        p0.1.min(p1.1),
+                        p0.0.abs_diff(p1.0),
+                        p0.1.abs_diff(p1.1)
+                    )?;
+                    writeln!(
+                        svg,
+                        r#"<text x="{}" y="{}" fill="rgb({r},{g},{b})" font-family="sans-serif" font-size="14" text-anchor="middle">{}x{}</text>"#,
+                        (p0.0 + p1.0) / 2,
+                        p0.1.max(p1.1) + 16,
+                        p0.0.abs_diff(p1.0),
+                        p0.1.abs_diff(p1.1)
+                    )?;
+                }
+                MeasureShape::Angle { points, .. } | MeasureShape::Polyline { points, .. } => {
+                    writeln!(
+                        svg,
+                        r#"<polyline points="{}" {style}/>"#,
+                        svg_points(points)
+                    )?;
+                }
+                MeasureShape::Polygon { points, .. } => {
+                    writeln!(svg, r#"<polygon points="{}" {style}/>"#, svg_points(points))?;
+                }
+                MeasureShape::Ellipse { points, .. } => {
+                    let [p0, p1, ..] = points.as_slice() else {
+                        continue;
+                    };
+                    writeln!(
+                        svg,
+                        r#"<ellipse cx="{:.1}" cy="{:.1}" rx="{:.1}" ry="{:.1}" {style}/>"#,
+                        (p0.0 + p1.0) as f32 / 2.,
+                        (p0.1 + p1.1) as f32 / 2.,
+                        p0.0.abs_diff(p1.0) as f32 / 2.,
+                        p0.1.abs_diff(p1.1) as f32 / 2.
+                    )?;
+                }
+            }
+        }
+        writeln!(svg, "</g>")?;
+
+        // One group per layer, in the order layers first appear
+        let mut seen = HashSet::new();
+        let layers = self
+            .strokes
+            .iter()
+            .map(|s| s.layer_name.as_str())
+            .filter(|layer| seen.insert(*layer))
+            .collect::<Vec<_>>();
+        for layer in layers {
+            writeln!(
+                svg,
+                r#"<g class="paint-layer" data-name="{}" fill="none" stroke-linecap="round" stroke-linejoin="round">"#,
+                escape(layer)
+            )?;
+            for annotation in self.strokes.iter().filter(|s| s.layer_name == layer) {
+                let stroke = &annotation.stroke;
+                // Erasers only change pixels and have no vector equivalent
+                if stroke.tool == PaintTool::Eraser {
+                    continue;
+                }
+                let [r, g, b, a] = stroke.color.map(|c| (c.clamp(0., 1.) * 255.) as u8);
+                // points are already in pixels, so the outline is computed on a 1x1 "image"
+                for path in stroke.outline((1, 1)) {
+                    let points = path
+                        .iter()
+                        .map(|p| format!("{:.1},{:.1}", p.x, p.y))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    writeln!(
+                        svg,
+                        r#"<polyline points="{points}" stroke="rgb({r},{g},{b})" stroke-opacity="{:.3}" stroke-width="{:.1}"/>"#,
+                        a as f32 / 255. * stroke.opacity,
+                        annotation.brush_size
+                    )?;
+                }
+            }
+            writeln!(svg, "</g>")?;
+        }
+        writeln!(svg, "</svg>")?;
+
+        write(path, svg)?;
+        debug!("Saved annotation overlay to {}", path.display());
+        Ok(())
+    }
+
+    /// Load annotations from JSON, or from an SVG exported by oculante
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            read_to_string(path).with_context(|| format!("Can't open {}", path.display()))?;
+        let json = if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+        {
+            svg_metadata(&text)
+                .with_context(|| format!("{} was not exported with annotations", path.display()))?
+        } else {
+            text
+        };
+        let annotations = serde_json::from_str::<Self>(&json)
+            .with_context(|| format!("{} does not contain annotations", path.display()))?;
+        if annotations.version > ANNOTATIONS_VERSION {
+            bail!("These annotations were saved by a newer version of oculante.");
+        }
+        Ok(annotations)
+    }
+}
+
+/// The measure shapes of all active measure operators
+fn measure_shapes(edit_state: &EditState) -> impl Iterator<Item = &MeasureShape> {
+    edit_state
+        .image_op_stack
+        .iter()
+        .filter(|op| op.active)
+        .filter_map(|op| match &op.operation {
+            ImageOperation::Measure { shapes } => Some(shapes),
+            _ => None,
+        })
+        .flatten()
+        .filter(|shape| !shape.is_placeholder())
+}
+
+fn svg_points(points: &[(u32, u32)]) -> String {
+    points
+        .iter()
+        .map(|p| format!("{},{}", p.0, p.1))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The JSON stored in an SVG written by `save_svg`
+fn svg_metadata(svg: &str) -> Option<String> {
+    let start = svg.find(&format!(r#"<metadata id="{SVG_METADATA_ID}">"#))?;
+    let rest = &svg[start..];
+    let content = &rest[rest.find("<![CDATA[")? + 9..rest.find("</metadata>")?];
+    let content = content.strip_suffix("]]>")?;
+    Some(content.replace("]]]]><![CDATA[>", "]]>"))
+}
+
+/// The image relative to the SVG if they share a directory, otherwise as an absolute path
+fn linked_href(image: &Path, svg: &Path) -> String {
+    if image.parent() == svg.parent() {
+        if let Some(name) = image.file_name() {
+            return name.to_string_lossy().to_string();
+        }
+    }
+    image.to_string_lossy().to_string()
+}
+
+/// The image as a data URL. Formats browsers can't show are converted to PNG.
+fn embedded_href(image: &Path) -> Result<String> {
+    let extension = image
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mime = match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "bmp" => Some("image/bmp"),
+        "avif" => Some("image/avif"),
+        _ => None,
+    };
+    let (mime, bytes) = match mime {
+        Some(mime) => (mime, read(image)?),
+        None => {
+            let img: DynamicImage =
+                image::open(image).with_context(|| format!("Can't embed {}", image.display()))?;
+            let mut bytes = vec![];
+            img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+            ("image/png", bytes)
+        }
+    };
+    Ok(format!("data:{mime};base64,{}", base64(&bytes)))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | ((*b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotations_round_trip() {
+        let mut edit_state = EditState::default();
+        edit_state
+            .image_op_stack
+            .push(ImgOpItem::new(ImageOperation::Measure {
+                shapes: vec![MeasureShape::new_rect(vec![(10, 20), (40, 60)])],
+            }));
+        edit_state.paint_layers = vec![PaintLayer::new("Ink"), PaintLayer::new("Notes")];
+        // strokes on interleaved layers
+        for layer in [0, 1, 0] {
+            edit_state.paint_strokes.push(PaintStroke {
+                points: vec![(0.1, 0.1), (0.5, 0.5)],
+                layer,
+                ..PaintStroke::new()
+            });
+        }
+        let annotations = Annotations::from_edit_state(&edit_state, (100, 200), None);
+        assert_eq!(annotations.shapes[0].area, 1200.);
+        assert_eq!(annotations.shapes[0].length, 140.);
+        assert_eq!(annotations.strokes[0].stroke.points[1], (50., 100.));
+
+        let dir = std::env::temp_dir().join("oculante_annotations_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let svg = dir.join("overlay.svg");
+        annotations.save_svg(&svg, None, SvgImage::None).unwrap();
+        assert_eq!(Annotations::load(&svg).unwrap(), annotations);
+        let text = read_to_string(&svg).unwrap();
+        assert_eq!(text.matches(r#"class="paint-layer""#).count(), 2);
+        assert_eq!(text.matches("<polyline").count(), 3);
+
+        // Importing onto an image twice the size scales everything
+        let mut imported = EditState::default();
+        annotations.apply(&mut imported, (200, 400));
+        let ImageOperation::Measure { shapes } = &imported.image_op_stack[0].operation else {
+            panic!("No measure operator");
+        };
+        assert_eq!(shapes[0].points(), &[(20, 40), (80, 120)]);
+        assert_eq!(imported.paint_strokes[0].points[1], (0.5, 0.5));
+        // "Layer 1", "Ink" and "Notes"
+        assert_eq!(imported.paint_layers.len(), 3);
+        assert_eq!(imported.paint_strokes[2].layer, 1);
+
+        assert_eq!(base64(b"oculante"), "b2N1bGFudGU=");
+    }
+}
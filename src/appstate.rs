@@ -1,6 +1,14 @@
 use crate::{
+    brushes::BrushLibrary,
     comparelist::CompareList,
+    cull::CullLog,
+    duplicates::DuplicateFinder,
+    edit_history::EditHistory,
     image_editing::EditState,
+    presets::PresetLibrary,
+    rating::Ratings,
+    histogram::StatsWorker,
+    scopes::{Scopes, ScopesWorker},
     scrubber::Scrubber,
     settings::{PersistentSettings, VolatileSettings},
     texture_wrapper::TextureWrapperManager,
@@ -14,7 +22,10 @@ use nalgebra::Vector2;
 use notan::{prelude::Texture, AppState};
 use std::{
     path::PathBuf,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
 };
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -60,12 +71,19 @@ pub struct OculanteState {
     pub cursor: Vector2<f32>,
     pub cursor_relative: Vector2<f32>,
     pub sampled_color: [f32; 4],
+    /// The region selected in the info panel, as `[x, y, width, height]`
+    pub region: Option<[u32; 4]>,
     pub mouse_delta: Vector2<f32>,
     pub texture_channel: (Sender<Frame>, Receiver<Frame>),
     pub message_channel: (Sender<Message>, Receiver<Message>),
     /// Channel to load images from
     pub load_channel: (Sender<PathBuf>, Receiver<PathBuf>),
     pub extended_info_channel: (Sender<ExtendedImageInfo>, Receiver<ExtendedImageInfo>),
+    pub scopes_worker: ScopesWorker,
+    /// Statistics of the histogram panel, when they differ from the extended info
+    pub histogram_stats: StatsWorker,
+    /// Statistics of the selected region
+    pub region_stats: StatsWorker,
     /// The Player, responsible for loading and sending Frames
     pub player: Player,
     //pub current_texture: Option<TexWrap>,
@@ -74,10 +92,13 @@ pub struct OculanteState {
     pub current_image: Option<DynamicImage>,
     pub settings_enabled: bool,
     pub image_metadata: Option<ExtendedImageInfo>,
+    /// Scopes of the displayed image, including edits
+    pub scopes: Option<Arc<Scopes>>,
     pub tiling: usize,
     pub mouse_grab: bool,
     pub key_grab: bool,
     pub edit_state: EditState,
+    pub edit_history: EditHistory,
     pub pointer_over_ui: bool,
     /// Things that perisist between launches
     pub persistent_settings: PersistentSettings,
@@ -95,6 +116,13 @@ pub struct OculanteState {
     pub toasts: Toasts,
     pub filebrowser_id: Option<String>,
     pub thumbnails: Thumbnails,
+    pub presets: PresetLibrary,
+    pub brushes: BrushLibrary,
+    pub duplicates: DuplicateFinder,
+    /// Ratings, labels and tags read so far
+    pub ratings: Ratings,
+    /// Files moved and copied to bookmarked folders
+    pub culling: CullLog,
 }
 
 impl<'b> OculanteState {
@@ -135,6 +163,7 @@ impl<'b> Default for OculanteState {
             cursor: Default::default(),
             cursor_relative: Default::default(),
             sampled_color: [0., 0., 0., 0.],
+            region: None,
             player: Player::new(
                 tx_channel.0.clone(),
                 20,
@@ -145,16 +174,21 @@ impl<'b> Default for OculanteState {
             message_channel: msg_channel,
             load_channel: mpsc::channel(),
             extended_info_channel: meta_channel,
+            scopes_worker: Default::default(),
+            histogram_stats: Default::default(),
+            region_stats: Default::default(),
             mouse_delta: Default::default(),
             current_texture: Default::default(),
             current_image: Default::default(),
             current_path: Default::default(),
             settings_enabled: Default::default(),
             image_metadata: Default::default(),
+            scopes: Default::default(),
             tiling: 1,
             mouse_grab: Default::default(),
             key_grab: Default::default(),
             edit_state: Default::default(),
+            edit_history: Default::default(),
             pointer_over_ui: Default::default(),
             persistent_settings: PersistentSettings::load().unwrap_or_default(),
             volatile_settings: VolatileSettings::load().unwrap_or_default(),
@@ -169,6 +203,11 @@ impl<'b> Default for OculanteState {
             toasts: Toasts::default().with_anchor(egui_notify::Anchor::BottomLeft),
             filebrowser_id: None,
             thumbnails: Default::default(),
+            presets: Default::default(),
+            brushes: BrushLibrary::load(),
+            duplicates: Default::default(),
+            ratings: Default::default(),
+            culling: Default::default(),
         }
     }
 }
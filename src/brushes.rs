@@ -0,0 +1,292 @@
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, Rgba, RgbaImage};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{paint::PaintStroke, settings::get_config_dir};
+
+/// Stamps larger than this are scaled down on import, as strokes resize them anyway
+const MAX_STAMP_SIZE: u32 = 256;
+
+/// Stroke settings a brush starts with when it is picked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrushSettings {
+    /// Distance between dabs, relative to the brush size
+    pub spacing: f32,
+    /// Random rotation of each dab, from 0 to a full turn
+    pub rotation_jitter: f32,
+    /// Random offset of each dab, relative to the brush size
+    pub scatter: f32,
+}
+
+impl Default for BrushSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 0.25,
+            rotation_jitter: 0.,
+            scatter: 0.,
+        }
+    }
+}
+
+impl BrushSettings {
+    pub fn from_stroke(stroke: &PaintStroke) -> Self {
+        Self {
+            spacing: stroke.spacing,
+            rotation_jitter: stroke.rotation_jitter,
+            scatter: stroke.scatter,
+        }
+    }
+
+    pub fn apply(&self, stroke: &mut PaintStroke) {
+        stroke.spacing = self.spacing;
+        stroke.rotation_jitter = self.rotation_jitter;
+        stroke.scatter = self.scatter;
+    }
+}
+
+/// A stamp used to paint strokes. Only the alpha channel is used.
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub name: String,
+    pub image: RgbaImage,
+    pub settings: BrushSettings,
+    /// Shipped with oculante and not stored on disk
+    pub builtin: bool,
+}
+
+impl Brush {
+    /// Turn any image into a stamp. Images without transparency are read as
+    /// grayscale stamps, where dark areas paint.
+    pub fn from_image(name: &str, img: &DynamicImage) -> Self {
+        let mut image = img.to_rgba8();
+        let (width, height) = image.dimensions();
+        if width.max(height) > MAX_STAMP_SIZE {
+            // both sides by the same factor, so the stamp keeps its shape
+            let scale = MAX_STAMP_SIZE as f32 / width.max(height) as f32;
+            image = image::imageops::resize(
+                &image,
+                ((width as f32 * scale).round() as u32).max(1),
+                ((height as f32 * scale).round() as u32).max(1),
+                image::imageops::Triangle,
+            );
+        }
+        let transparent = image.pixels().any(|p| p[3] < 255);
+        for p in image.pixels_mut() {
+            let luma = (p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000;
+            let alpha = if transparent { p[3] } else { 255 - luma as u8 };
+            *p = Rgba([255, 255, 255, alpha]);
+        }
+        Self {
+            name: name.to_string(),
+            image,
+            settings: Default::default(),
+            builtin: false,
+        }
+    }
+
+    /// The file name in the brush directory, derived from the name
+    fn file_name(&self) -> PathBuf {
+        let stem = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        PathBuf::from(stem.trim()).with_extension("png")
+    }
+
+    /// Where the brush settings are stored. Built-in brushes only store their settings.
+    fn settings_file_name(&self) -> PathBuf {
+        self.file_name().with_extension("json")
+    }
+}
+
+/// The brush a stroke names, or the first one if it was removed from the library
+pub fn find_brush<'a>(brushes: &'a [Brush], name: &str) -> Option<&'a Brush> {
+    brushes.iter().find(|b| b.name == name).or(brushes.first())
+}
+
+/// All brushes: built-ins and the ones imported into the config dir
+#[derive(Debug, Default)]
+pub struct BrushLibrary {
+    brushes: Vec<Brush>,
+}
+
+impl BrushLibrary {
+    /// Read all brushes, once when the app starts
+    pub fn load() -> Self {
+        Self {
+            brushes: load_brushes(),
+        }
+    }
+
+    pub fn brushes(&self) -> &[Brush] {
+        &self.brushes
+    }
+
+    /// Read brushes from disk again
+    pub fn reload(&mut self) {
+        self.brushes = load_brushes();
+    }
+
+    /// Copy an image into the library as a new brush
+    pub fn import(&mut self, path: &Path) -> Result<()> {
+        let img = image::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let brush = Brush::from_image(&name, &img);
+        if self.brushes().iter().any(|b| b.name == brush.name) {
+            bail!("A brush named {} already exists.", brush.name);
+        }
+        let dir = ensure_brushes_dir()?;
+        brush.image.save(dir.join(brush.file_name()))?;
+        debug!("Imported brush {}", brush.name);
+        self.reload();
+        Ok(())
+    }
+
+    /// Store the settings a brush starts with
+    pub fn set_settings(&mut self, name: &str, settings: BrushSettings) -> Result<()> {
+        let Some(brush) = self.brushes.iter_mut().find(|b| b.name == name) else {
+            bail!("There is no brush {name}.");
+        };
+        brush.settings = settings;
+        let path = ensure_brushes_dir()?.join(brush.settings_file_name());
+        serde_json::to_writer_pretty(File::create(path)?, &settings)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        let Some(brush) = self.brushes.iter().find(|b| b.name == name).cloned() else {
+            bail!("There is no brush {name}.");
+        };
+        if brush.builtin {
+            bail!("Built-in brushes can't be deleted.");
+        }
+        let dir = brushes_dir()?;
+        remove_file(dir.join(brush.file_name()))?;
+        let settings = dir.join(brush.settings_file_name());
+        if settings.exists() {
+            remove_file(settings)?;
+        }
+        self.reload();
+        Ok(())
+    }
+}
+
+/// Where imported brushes are stored
+pub fn brushes_dir() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("brushes"))
+}
+
+fn ensure_brushes_dir() -> Result<PathBuf> {
+    let dir = brushes_dir()?;
+    if !dir.exists() {
+        info!("Created {}", dir.display());
+        create_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+/// Built-in brushes followed by imported ones, each with its stored settings
+pub fn load_brushes() -> Vec<Brush> {
+    let mut brushes = builtin_brushes();
+    let Ok(dir) = brushes_dir() else {
+        return brushes;
+    };
+    if let Ok(entries) = read_dir(&dir) {
+        let mut paths = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        brushes.extend(paths.iter().filter_map(|path| {
+            let img = image::open(path).map_err(|e| warn!("{e}")).ok()?;
+            Some(Brush {
+                name: path.file_stem()?.to_string_lossy().to_string(),
+                image: img.into_rgba8(),
+                settings: Default::default(),
+                builtin: false,
+            })
+        }));
+    }
+    for brush in &mut brushes {
+        let path = dir.join(brush.settings_file_name());
+        if let Ok(f) = File::open(&path) {
+            match serde_json::from_reader(f) {
+                Ok(settings) => brush.settings = settings,
+                Err(e) => warn!("Can't read {}: {e}", path.display()),
+            }
+        }
+    }
+    brushes
+}
+
+const BUILTIN_BRUSHES: [&[u8]; 6] = [
+    include_bytes!("../res/brushes/brush1.png"),
+    include_bytes!("../res/brushes/brush2.png"),
+    include_bytes!("../res/brushes/brush3.png"),
+    include_bytes!("../res/brushes/brush4.png"),
+    include_bytes!("../res/brushes/brush5.png"),
+    include_bytes!("../res/brushes/brush6.png"),
+];
+
+/// The name of a built-in brush by its position, e.g. to read strokes that stored it
+pub fn builtin_brush_name(index: usize) -> Option<String> {
+    (index < BUILTIN_BRUSHES.len()).then(|| format!("Brush {index}"))
+}
+
+/// Brushes shipped with oculante
+pub fn builtin_brushes() -> Vec<Brush> {
+    BUILTIN_BRUSHES
+        .iter()
+        .enumerate()
+        .map(|(i, bytes)| Brush {
+            name: builtin_brush_name(i).unwrap_or_default(),
+            image: image::load_from_memory(bytes)
+                .expect("Brushes must always load")
+                .into_rgba8(),
+            settings: Default::default(),
+            builtin: true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    #[test]
+    fn grayscale_stamp_import() {
+        let mut gray = GrayImage::from_pixel(4, 4, image::Luma([255]));
+        gray.put_pixel(1, 1, image::Luma([0]));
+        let brush = Brush::from_image("stamp", &DynamicImage::ImageLuma8(gray));
+        assert_eq!(brush.image.get_pixel(1, 1), &Rgba([255, 255, 255, 255]));
+        assert_eq!(brush.image.get_pixel(0, 0)[3], 0);
+        assert_eq!(brush.file_name(), PathBuf::from("stamp.png"));
+
+        // large stamps are scaled down without changing their shape
+        let wide = DynamicImage::ImageLuma8(GrayImage::new(1024, 256));
+        let brush = Brush::from_image("wide", &wide);
+        assert_eq!(
+            brush.image.dimensions(),
+            (MAX_STAMP_SIZE, MAX_STAMP_SIZE / 4)
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! Converting pixel measurements to real-world units
+
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+use strum::EnumIter;
+
+use crate::utils::DicomData;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumIter)]
+pub enum LengthUnit {
+    #[default]
+    Pixel,
+    Millimeter,
+    Centimeter,
+    Inch,
+}
+
+impl LengthUnit {
+    /// How many millimeters one unit is. Pixels have no physical size.
+    pub fn millimeters(&self) -> Option<f64> {
+        match self {
+            Self::Pixel => None,
+            Self::Millimeter => Some(1.),
+            Self::Centimeter => Some(10.),
+            Self::Inch => Some(25.4),
+        }
+    }
+}
+
+impl fmt::Display for LengthUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pixel => write!(f, "px"),
+            Self::Millimeter => write!(f, "mm"),
+            Self::Centimeter => write!(f, "cm"),
+            Self::Inch => write!(f, "in"),
+        }
+    }
+}
+
+/// Temporary egui data holding the calibration measurements are drawn with
+pub const CALIBRATION_ID: &str = "MEASURE_CALIBRATION";
+
+/// Where a calibration comes from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CalibrationSource {
+    /// Measured by the user along a line of known length
+    #[default]
+    Manual,
+    Exif,
+    Dicom,
+}
+
+impl fmt::Display for CalibrationSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Manual => write!(f, "Manual"),
+            Self::Exif => write!(f, "EXIF resolution"),
+            Self::Dicom => write!(f, "DICOM pixel spacing"),
+        }
+    }
+}
+
+/// The physical size of a pixel, and the unit measurements are shown in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Millimeters per pixel, horizontally and vertically
+    pub pixel_size: (f64, f64),
+    pub unit: LengthUnit,
+    pub source: CalibrationSource,
+}
+
+impl Calibration {
+    /// A calibration where a line from `a` to `b` has a known `length` in `unit`
+    pub fn from_line(a: (f64, f64), b: (f64, f64), length: f64, unit: LengthUnit) -> Option<Self> {
+        let pixels = (a.0 - b.0).hypot(a.1 - b.1);
+        let millimeters = length * unit.millimeters()?;
+        if pixels <= 0. || millimeters <= 0. {
+            return None;
+        }
+        let size = millimeters / pixels;
+        Some(Self {
+            pixel_size: (size, size),
+            unit,
+            source: CalibrationSource::Manual,
+        })
+    }
+
+    /// Read `XResolution`/`YResolution`. Most cameras write 72 dpi without it meaning
+    /// anything, so that value is ignored.
+    pub fn from_exif(exif: &HashMap<String, String>) -> Option<Self> {
+        let parse = |key: &str| {
+            let value = exif.get(key)?;
+            let (number, unit) = value.split_once(" pixels per ")?;
+            let per_unit = number.trim().parse::<f64>().ok()?;
+            let unit = match unit.trim() {
+                "inch" => LengthUnit::Inch,
+                "cm" => LengthUnit::Centimeter,
+                _ => return None,
+            };
+            (per_unit > 0. && per_unit != 72.)
+                .then(|| (unit.millimeters().unwrap_or(1.) / per_unit, unit))
+        };
+        let (x, unit) = parse("XResolution")?;
+        let (y, _) = parse("YResolution").unwrap_or((x, unit));
+        Some(Self {
+            pixel_size: (x, y),
+            unit,
+            source: CalibrationSource::Exif,
+        })
+    }
+
+    pub fn from_dicom(dicom: &DicomData) -> Option<Self> {
+        let (x, y) = dicom.physical_size;
+        (x > 0. && y > 0.).then_some(Self {
+            pixel_size: (x as f64, y as f64),
+            unit: LengthUnit::Millimeter,
+            source: CalibrationSource::Dicom,
+        })
+    }
+
+    /// `unit`s per pixel, horizontally and vertically, or `None` to keep pixels
+    fn scale(&self) -> Option<(f64, f64)> {
+        let mm = self.unit.millimeters()?;
+        Some((self.pixel_size.0 / mm, self.pixel_size.1 / mm))
+    }
+
+    /// A vector in image pixels, converted to the display unit
+    fn convert(&self, delta: (f64, f64)) -> (f64, f64) {
+        match self.scale() {
+            Some(scale) => (delta.0 * scale.0, delta.1 * scale.1),
+            None => delta,
+        }
+    }
+
+    /// Distance between two pixel positions, in the display unit
+    pub fn length(&self, a: (f64, f64), b: (f64, f64)) -> f64 {
+        let d = self.convert((b.0 - a.0, b.1 - a.1));
+        d.0.hypot(d.1)
+    }
+
+    /// Area of an axis-aligned rectangle given in pixels, in the display unit squared
+    pub fn area(&self, size: (f64, f64)) -> f64 {
+        let d = self.convert(size);
+        d.0 * d.1
+    }
+
+    /// Length along a series of points, optionally back to the first one
+    pub fn path_length(&self, points: &[(f64, f64)], closed: bool) -> f64 {
+        let open: f64 = points.windows(2).map(|p| self.length(p[0], p[1])).sum();
+        match (closed, points.first(), points.last()) {
+            (true, Some(first), Some(last)) if points.len() > 2 => {
+                open + self.length(*last, *first)
+            }
+            _ => open,
+        }
+    }
+
+    /// Area enclosed by a polygon given in pixels
+    pub fn polygon_area(&self, points: &[(f64, f64)]) -> f64 {
+        let twice_area: f64 = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+            .sum();
+        self.area((twice_area.abs() / 2., 1.))
+    }
+
+    /// Circumference of an ellipse with a bounding box given in pixels
+    pub fn ellipse_perimeter(&self, size: (f64, f64)) -> f64 {
+        let d = self.convert(size);
+        let (a, b) = (d.0.abs() / 2., d.1.abs() / 2.);
+        // Ramanujan's approximation
+        let h = ((a - b) / (a + b)).powi(2);
+        if !h.is_finite() {
+            return 0.;
+        }
+        std::f64::consts::PI * (a + b) * (1. + 3. * h / (10. + (4. - 3. * h).sqrt()))
+    }
+
+    /// Area of an ellipse with a bounding box given in pixels
+    pub fn ellipse_area(&self, size: (f64, f64)) -> f64 {
+        self.area(size).abs() * std::f64::consts::FRAC_PI_4
+    }
+
+    /// The angle at `vertex` between the lines to `a` and `b`, in degrees. Pixels that
+    /// are not square change angles, so they are measured in physical space.
+    pub fn angle(&self, a: (f64, f64), vertex: (f64, f64), b: (f64, f64)) -> f64 {
+        let u = self.convert((a.0 - vertex.0, a.1 - vertex.1));
+        let v = self.convert((b.0 - vertex.0, b.1 - vertex.1));
+        let angle = (u.0 * v.1 - u.1 * v.0).atan2(u.0 * v.0 + u.1 * v.1);
+        angle.abs().to_degrees()
+    }
+
+    pub fn format_length(&self, length: f64) -> String {
+        format!("{} {}", self.format_number(length), self.unit)
+    }
+
+    /// Width and height, e.g. of a rectangle given in pixels
+    pub fn format_size(&self, size: (f64, f64)) -> String {
+        let d = self.convert(size);
+        format!(
+            "{}x{} {}",
+            self.format_number(d.0),
+            self.format_number(d.1),
+            self.unit
+        )
+    }
+
+    pub fn format_area(&self, area: f64) -> String {
+        format!("{} {}²", self.format_number(area), self.unit)
+    }
+
+    fn format_number(&self, value: f64) -> String {
+        if self.unit == LengthUnit::Pixel {
+            format!("{value:.0}")
+        } else if value.abs() < 10. {
+            format!("{value:.2}")
+        } else {
+            format!("{value:.1}")
+        }
+    }
+}
+
+impl Default for Calibration {
+    /// Plain pixels
+    fn default() -> Self {
+        Self {
+            pixel_size: (1., 1.),
+            unit: LengthUnit::Pixel,
+            source: CalibrationSource::Manual,
+        }
+    }
+}
+
+/// Parse DICOM `PixelSpacing`, which is "row spacing\column spacing" in millimeters,
+/// into the horizontal and vertical size of a pixel.
+pub fn parse_pixel_spacing(value: &str) -> Option<(f32, f32)> {
+    let mut parts = value.split('\\').map(|v| v.trim().parse::<f32>().ok());
+    let row = parts.next()??;
+    let column = parts.next().flatten().unwrap_or(row);
+    Some((column, row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibrated_lengths() {
+        // a 100 pixel line that is 5 cm long
+        let calibration =
+            Calibration::from_line((0., 0.), (60., 80.), 5., LengthUnit::Centimeter).unwrap();
+        assert!((calibration.length((0., 0.), (0., 20.)) - 1.).abs() < 1e-9);
+        assert!((calibration.area((20., 40.)) - 2.).abs() < 1e-9);
+        let mm = Calibration {
+            unit: LengthUnit::Millimeter,
+            ..calibration
+        };
+        assert_eq!(mm.format_length(mm.length((0., 0.), (100., 0.))), "50.0 mm");
+
+        let exif = HashMap::from([("XResolution".to_string(), "300 pixels per inch".to_string())]);
+        let from_exif = Calibration::from_exif(&exif).unwrap();
+        assert!((from_exif.length((0., 0.), (300., 0.)) - 1.).abs() < 1e-9);
+
+        assert_eq!(parse_pixel_spacing("0.5\\0.25"), Some((0.25, 0.5)));
+        let anisotropic = Calibration {
+            pixel_size: (1., 2.),
+            unit: LengthUnit::Millimeter,
+            source: CalibrationSource::Dicom,
+        };
+        // a square in pixels is a 1:2 rectangle in millimeters
+        assert!((anisotropic.angle((1., 0.), (0., 0.), (1., 1.)) - 63.434948).abs() < 1e-5);
+
+        let square = [(0., 0.), (10., 0.), (10., 10.), (0., 10.)];
+        assert_eq!(anisotropic.polygon_area(&square), 200.);
+        assert_eq!(anisotropic.path_length(&square, true), 60.);
+        // a circle of 10 pixels
+        let plain = Calibration::default();
+        assert!((plain.ellipse_perimeter((10., 10.)) - 10. * std::f64::consts::PI).abs() < 1e-9);
+        assert!((plain.ellipse_area((10., 10.)) - 25. * std::f64::consts::PI).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,223 @@
+//! Sorting images into folders while culling a shoot
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use strum::Display;
+
+use crate::{rating, sidecar};
+
+/// How many bookmarked folders have hotkeys
+pub const CULL_TARGETS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum CullAction {
+    #[strum(to_string = "Moved")]
+    Move,
+    #[strum(to_string = "Copied")]
+    Copy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CullOperation {
+    pub action: CullAction,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Sidecars that went with the file, from where to where
+    pub companions: Vec<(PathBuf, PathBuf)>,
+    pub time: DateTime<Local>,
+}
+
+impl std::fmt::Display for CullOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} to {}",
+            self.action,
+            self.source
+                .file_name()
+                .map(|n| n.to_string_lossy())
+                .unwrap_or_default(),
+            self.destination
+                .parent()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// The files that go where an image goes: its XMP sidecar and its edits
+fn companions(image: &Path) -> [PathBuf; 2] {
+    [rating::sidecar_path(image), sidecar::edits_path(image)]
+}
+
+/// A free path for `file` in `folder`, with free paths for its sidecars. Existing files are
+/// never overwritten, a number is added to the name instead.
+pub fn destination_path(file: &Path, folder: &Path) -> Result<PathBuf> {
+    let name = file.file_name().context("File has no name")?;
+    let mut destination = folder.join(name);
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = file
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut i = 1;
+    while destination.exists() || companions(&destination).iter().any(|c| c.exists()) {
+        destination = folder.join(format!("{stem} ({i}){ext}"));
+        i += 1;
+    }
+    Ok(destination)
+}
+
+/// Rename a file, copying it if it has to go to another file system.
+/// A copy that can't be completed is removed again.
+fn move_file(source: &Path, destination: &Path) -> Result<()> {
+    match std::fs::rename(source, destination) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            if let Err(e) =
+                std::fs::copy(source, destination).and_then(|_| std::fs::remove_file(source))
+            {
+                if source.exists() {
+                    _ = std::fs::remove_file(destination);
+                }
+                return Err(e.into());
+            }
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Files moved and copied this session, newest last
+#[derive(Debug, Default)]
+pub struct CullLog {
+    pub operations: Vec<CullOperation>,
+}
+
+impl CullLog {
+    /// Move or copy `file` into `folder`
+    pub fn apply(
+        &mut self,
+        action: CullAction,
+        file: &Path,
+        folder: &Path,
+    ) -> Result<&CullOperation> {
+        if !folder.is_dir() {
+            bail!("{} is not a folder", folder.display());
+        }
+        if file.parent() == Some(folder) {
+            bail!("{} is already in this folder", file.display());
+        }
+        let destination = destination_path(file, folder)?;
+        let transfer = |from: &Path, to: &Path| match action {
+            CullAction::Move => move_file(from, to),
+            CullAction::Copy => Ok(std::fs::copy(from, to).map(|_| ())?),
+        };
+        transfer(file, &destination)?;
+        // Sidecars that can't follow stay behind, the image is already where it belongs
+        let mut moved_companions = vec![];
+        for (from, to) in companions(file).into_iter().zip(companions(&destination)) {
+            if !from.is_file() {
+                continue;
+            }
+            match transfer(&from, &to) {
+                Ok(()) => moved_companions.push((from, to)),
+                Err(e) => warn!("{} stays behind: {e}", from.display()),
+            }
+        }
+        let operation = CullOperation {
+            action,
+            source: file.to_path_buf(),
+            destination,
+            companions: moved_companions,
+            time: Local::now(),
+        };
+        info!("{operation}");
+        self.operations.push(operation);
+        Ok(self.operations.last().expect("just pushed"))
+    }
+
+    /// Revert the last operation. Moved files go back, copies are deleted.
+    pub fn undo(&mut self) -> Result<Option<CullOperation>> {
+        let Some(operation) = self.operations.last() else {
+            return Ok(None);
+        };
+        let files = std::iter::once((&operation.source, &operation.destination))
+            .chain(operation.companions.iter().map(|(from, to)| (from, to)));
+        match operation.action {
+            CullAction::Move => {
+                if let Some((source, _)) = files.clone().find(|(source, _)| source.exists()) {
+                    bail!("{} exists again", source.display());
+                }
+                for (source, destination) in files {
+                    move_file(destination, source)?;
+                }
+            }
+            CullAction::Copy => {
+                for (_, destination) in files {
+                    std::fs::remove_file(destination)?;
+                }
+            }
+        }
+        info!("Undid: {operation}");
+        Ok(self.operations.pop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_copy_and_undo() {
+        let dir = std::env::temp_dir().join("oculante_cull_test");
+        _ = std::fs::remove_dir_all(&dir);
+        let (shoot, keep) = (dir.join("shoot"), dir.join("keep"));
+        std::fs::create_dir_all(&shoot).unwrap();
+        std::fs::create_dir_all(&keep).unwrap();
+        let (a, b) = (shoot.join("a.jpg"), shoot.join("b.jpg"));
+        std::fs::write(&a, "a").unwrap();
+        std::fs::write(&b, "b").unwrap();
+        // sidecars of the ratings and edits of a
+        std::fs::write(shoot.join("a.jpg.xmp"), "rating").unwrap();
+        std::fs::write(shoot.join("a.oculante"), "edits").unwrap();
+        // a file of the same name is already there, and edits under the next free name
+        std::fs::write(keep.join("a.jpg"), "old").unwrap();
+        std::fs::write(keep.join("a (1).oculante"), "other").unwrap();
+
+        let mut log = CullLog::default();
+        let moved = log.apply(CullAction::Move, &a, &keep).unwrap().clone();
+        assert_eq!(moved.destination, keep.join("a (2).jpg"));
+        assert!(!a.exists());
+        assert_eq!(std::fs::read_to_string(keep.join("a.jpg")).unwrap(), "old");
+        assert_eq!(
+            std::fs::read_to_string(keep.join("a (2).jpg.xmp")).unwrap(),
+            "rating"
+        );
+        assert_eq!(
+            std::fs::read_to_string(keep.join("a (2).oculante")).unwrap(),
+            "edits"
+        );
+        log.apply(CullAction::Copy, &b, &keep).unwrap();
+        assert!(b.exists() && keep.join("b.jpg").exists());
+        assert!(log.apply(CullAction::Copy, &b, &shoot).is_err());
+
+        assert_eq!(log.undo().unwrap().unwrap().action, CullAction::Copy);
+        assert!(!keep.join("b.jpg").exists());
+        assert_eq!(log.undo().unwrap().unwrap(), moved);
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a");
+        assert!(!keep.join("a (2).jpg").exists());
+        assert!(shoot.join("a.jpg.xmp").exists() && shoot.join("a.oculante").exists());
+        assert!(!keep.join("a (2).oculante").exists());
+        assert!(log.undo().unwrap().is_none());
+        _ = std::fs::remove_dir_all(&dir);
+    }
+}
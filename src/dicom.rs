@@ -0,0 +1,367 @@
+//! Browsing DICOM tags, windowing, and navigating frames and series
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use dicom_core::DataDictionary;
+use dicom_object::{open_file, InMemDicomObject, OpenFileOptions, StandardDataDictionary, Tag};
+use dicom_pixeldata::{ConvertOptions, PixelDecoder, VoiLutOption, WindowLevel};
+use image::DynamicImage;
+
+const PIXEL_DATA: Tag = Tag(0x7FE0, 0x0010);
+/// Longer values are cut off in the tag browser
+const MAX_VALUE_LENGTH: usize = 256;
+
+/// A data element, with the items of a sequence as nested tags
+#[derive(Debug, Clone, PartialEq)]
+pub struct DicomTag {
+    /// The group and element, like `(0010,0010)`
+    pub tag: String,
+    /// The keyword from the standard dictionary, empty for private tags
+    pub name: String,
+    pub vr: String,
+    pub value: String,
+    pub items: Vec<Vec<DicomTag>>,
+}
+
+impl DicomTag {
+    /// Does this tag or any tag nested in it contain a lowercase search text
+    pub fn matches(&self, query: &str) -> bool {
+        query.is_empty()
+            || self.tag.to_lowercase().contains(query)
+            || self.name.to_lowercase().contains(query)
+            || self.value.to_lowercase().contains(query)
+            || self.items.iter().flatten().any(|tag| tag.matches(query))
+    }
+}
+
+/// All data elements of an object, in file order
+pub fn read_tags(obj: &InMemDicomObject) -> Vec<DicomTag> {
+    obj.iter()
+        .map(|element| {
+            let tag = element.header().tag;
+            let items: Vec<Vec<DicomTag>> = element
+                .items()
+                .map(|items| items.iter().map(read_tags).collect())
+                .unwrap_or_default();
+            let value = if tag == PIXEL_DATA || !items.is_empty() {
+                String::new()
+            } else {
+                let value = element.to_str().map(|s| s.to_string()).unwrap_or_default();
+                match value.char_indices().nth(MAX_VALUE_LENGTH) {
+                    Some((end, _)) => format!("{}...", &value[..end]),
+                    None => value,
+                }
+            };
+            DicomTag {
+                tag: tag.to_string(),
+                name: StandardDataDictionary
+                    .by_tag(tag)
+                    .map(|entry| entry.alias.to_string())
+                    .unwrap_or_default(),
+                vr: element.vr().to_string().to_owned(),
+                value,
+                items,
+            }
+        })
+        .collect()
+}
+
+/// A window center and width stored in the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowPreset {
+    /// The explanation from the file, like `BONE`, or a number
+    pub name: String,
+    pub center: f64,
+    pub width: f64,
+}
+
+/// The windows in `WindowCenter` and `WindowWidth`
+pub fn window_presets(obj: &InMemDicomObject) -> Vec<WindowPreset> {
+    let values = |name: &str| {
+        obj.element_by_name(name)
+            .ok()
+            .and_then(|e| e.to_multi_float64().ok())
+            .unwrap_or_default()
+    };
+    let names = obj
+        .element_by_name("WindowCenterWidthExplanation")
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|s| s.split('\\').map(|n| n.trim().to_string()).collect())
+        .unwrap_or_else(Vec::new);
+    values("WindowCenter")
+        .into_iter()
+        .zip(values("WindowWidth"))
+        .enumerate()
+        .map(|(i, (center, width))| WindowPreset {
+            name: names
+                .get(i)
+                .filter(|n| !n.is_empty())
+                .cloned()
+                .unwrap_or_else(|| format!("Window {}", i + 1)),
+            center,
+            width,
+        })
+        .collect()
+}
+
+/// How stored values are mapped to gray levels
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Windowing {
+    /// The first window or lookup table in the file
+    #[default]
+    File,
+    Custom {
+        center: f64,
+        width: f64,
+    },
+    /// Stretch the lowest to the highest value
+    MinMax,
+    /// Rescaled values without any window
+    Raw,
+}
+
+impl Windowing {
+    fn voi_lut(&self) -> VoiLutOption {
+        match *self {
+            Self::File => VoiLutOption::First,
+            Self::Custom { center, width } => VoiLutOption::Custom(WindowLevel {
+                center,
+                width: width.max(1.),
+            }),
+            Self::MinMax => VoiLutOption::Normalize,
+            Self::Raw => VoiLutOption::Identity,
+        }
+    }
+}
+
+/// The number of frames stored in a file
+pub fn frame_count(obj: &InMemDicomObject) -> u32 {
+    obj.element_by_name("NumberOfFrames")
+        .ok()
+        .and_then(|e| e.to_int::<u32>().ok())
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Decode one frame. 16-bit data stays 16-bit.
+pub fn decode_frame(path: &Path, frame: u32, windowing: Windowing) -> Result<DynamicImage> {
+    let obj = open_file(path)?;
+    let pixels = obj.decode_pixel_data_frame(frame)?;
+    let options = ConvertOptions::new().with_voi_lut(windowing.voi_lut());
+    Ok(pixels.to_dynamic_image_with_options(0, &options)?)
+}
+
+/// The series UID, instance number and path of every DICOM file in a folder
+#[derive(Debug, Clone, Default)]
+struct FolderIndex {
+    modified: Option<SystemTime>,
+    files: Vec<(Option<String>, i64, PathBuf)>,
+}
+
+impl FolderIndex {
+    fn read(dir: &Path, modified: Option<SystemTime>) -> Result<Self> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let member = entry?.path();
+            let is_dicom = member
+                .extension()
+                .map(|e| e.to_ascii_lowercase())
+                .is_some_and(|e| e == "dcm" || e == "ima");
+            if !is_dicom {
+                continue;
+            }
+            let Ok(obj) = OpenFileOptions::new()
+                .read_until(PIXEL_DATA)
+                .open_file(&member)
+            else {
+                continue;
+            };
+            let uid = obj
+                .element_by_name("SeriesInstanceUID")
+                .ok()
+                .and_then(|e| e.to_str().ok())
+                .map(|s| s.trim_end_matches('\0').trim().to_string());
+            let instance = obj
+                .element_by_name("InstanceNumber")
+                .ok()
+                .and_then(|e| e.to_int::<i64>().ok())
+                .unwrap_or_default();
+            files.push((uid, instance, member));
+        }
+        Ok(Self { modified, files })
+    }
+}
+
+/// The files in the same folder and series as `path`, ordered by instance number.
+/// Headers are read once per folder and again only when the folder changes, so stepping
+/// through a series doesn't read every file each time.
+pub fn series(path: &Path) -> Result<Vec<PathBuf>> {
+    static FOLDERS: OnceLock<Mutex<HashMap<PathBuf, FolderIndex>>> = OnceLock::new();
+    let folders = FOLDERS.get_or_init(Default::default);
+
+    let dir = path.parent().context("No parent directory")?;
+    let modified = std::fs::metadata(dir)?.modified().ok();
+    let cached = folders
+        .lock()
+        .ok()
+        .and_then(|f| f.get(dir).cloned())
+        .filter(|index| {
+            index.modified == modified && index.files.iter().any(|(_, _, p)| p == path)
+        });
+    let index = match cached {
+        Some(index) => index,
+        None => {
+            let index = FolderIndex::read(dir, modified)?;
+            if let Ok(mut folders) = folders.lock() {
+                folders.insert(dir.to_path_buf(), index.clone());
+            }
+            index
+        }
+    };
+
+    let series_uid = index
+        .files
+        .iter()
+        .find(|(_, _, p)| p == path)
+        .context("Can't read DICOM header")?
+        .0
+        .clone();
+    if series_uid.is_none() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut members = index
+        .files
+        .into_iter()
+        .filter(|(uid, _, _)| uid == &series_uid)
+        .map(|(_, instance, p)| (instance, p))
+        .collect::<Vec<_>>();
+    members.sort();
+    Ok(members.into_iter().map(|(_, p)| p).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom_core::value::{DataSetSequence, PrimitiveValue};
+    use dicom_core::{DataElement, VR};
+    use dicom_object::FileMetaTableBuilder;
+
+    /// A 2x1 image with two 16-bit frames
+    fn write_test_file(path: &Path, instance: &str) {
+        let text = |group, element, vr, value: &str| {
+            DataElement::new(Tag(group, element), vr, PrimitiveValue::from(value))
+        };
+        let short = |group, element, value: u16| {
+            DataElement::new(Tag(group, element), VR::US, PrimitiveValue::from(value))
+        };
+        let mut item = InMemDicomObject::new_empty();
+        item.put(text(0x0008, 0x1155, VR::UI, "1.2.3.9"));
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(text(0x0008, 0x0016, VR::UI, "1.2.840.10008.5.1.4.1.1.7"));
+        obj.put(text(0x0008, 0x0018, VR::UI, instance));
+        obj.put(DataElement::new(
+            Tag(0x0008, 0x1140),
+            VR::SQ,
+            DataSetSequence::from(vec![item]),
+        ));
+        obj.put(text(0x0010, 0x0010, VR::PN, "Doe^Jane"));
+        obj.put(text(0x0020, 0x000E, VR::UI, "1.2.3.4"));
+        obj.put(text(
+            0x0020,
+            0x0013,
+            VR::IS,
+            &instance[instance.len() - 1..],
+        ));
+        obj.put(short(0x0028, 0x0002, 1));
+        obj.put(text(0x0028, 0x0004, VR::CS, "MONOCHROME2"));
+        obj.put(text(0x0028, 0x0008, VR::IS, "2"));
+        obj.put(short(0x0028, 0x0010, 1));
+        obj.put(short(0x0028, 0x0011, 2));
+        obj.put(short(0x0028, 0x0100, 16));
+        obj.put(short(0x0028, 0x0101, 16));
+        obj.put(short(0x0028, 0x0102, 15));
+        obj.put(short(0x0028, 0x0103, 0));
+        obj.put(text(0x0028, 0x1050, VR::DS, "100\\2000"));
+        obj.put(text(0x0028, 0x1051, VR::DS, "200\\4000"));
+        obj.put(text(0x0028, 0x1055, VR::LO, "SOFT\\BONE"));
+        obj.put(DataElement::new(
+            PIXEL_DATA,
+            VR::OW,
+            PrimitiveValue::U16(vec![1000, 3000, 40000, 50000].into()),
+        ));
+        obj.with_meta(
+            FileMetaTableBuilder::new()
+                .transfer_syntax("1.2.840.10008.1.2.1")
+                .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+                .media_storage_sop_instance_uid(instance),
+        )
+        .unwrap()
+        .write_to_file(path)
+        .unwrap();
+    }
+
+    #[test]
+    fn tags_windows_frames_and_series() {
+        let dir = std::env::temp_dir().join("oculante_dicom_test");
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join("b.dcm"), dir.join("a.dcm"));
+        write_test_file(&first, "1.2.3.5.1");
+        write_test_file(&second, "1.2.3.5.2");
+
+        let obj = open_file(&first).unwrap();
+        let tags = read_tags(&obj);
+        let patient = tags.iter().find(|t| t.name == "PatientName").unwrap();
+        assert_eq!(patient.tag, "(0010,0010)");
+        assert_eq!(patient.value, "Doe^Jane");
+        let sequence = tags
+            .iter()
+            .find(|t| t.name == "ReferencedImageSequence")
+            .unwrap();
+        assert_eq!(sequence.items[0][0].value, "1.2.3.9");
+        assert!(sequence.matches("1.2.3.9"));
+        assert!(!patient.matches("bone"));
+
+        let presets = window_presets(&obj);
+        assert_eq!(presets.len(), 2);
+        assert_eq!(
+            presets[1],
+            WindowPreset {
+                name: "BONE".into(),
+                center: 2000.,
+                width: 4000.
+            }
+        );
+        assert_eq!(frame_count(&obj), 2);
+
+        let raw = decode_frame(&first, 1, Windowing::Raw).unwrap();
+        assert_eq!(raw.as_luma16().unwrap().as_raw(), &vec![40000, 50000]);
+        let windowed = decode_frame(
+            &first,
+            0,
+            Windowing::Custom {
+                center: 2000.,
+                width: 2000.,
+            },
+        )
+        .unwrap()
+        .into_luma16();
+        assert!(windowed.as_raw()[0] < windowed.as_raw()[1]);
+
+        // ordered by instance number, not file name
+        assert_eq!(
+            series(&second).unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+        // new files are picked up, although the folder was read before
+        let third = dir.join("c.dcm");
+        write_test_file(&third, "1.2.3.5.3");
+        assert_eq!(series(&first).unwrap(), vec![first, second, third]);
+        _ = std::fs::remove_dir_all(&dir);
+    }
+}
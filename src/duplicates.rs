@@ -0,0 +1,337 @@
+//! Finding near-duplicate images with perceptual hashes
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, GrayImage};
+use log::warn;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use strum::{Display, EnumIter};
+
+use crate::{
+    image_loader::open_image, scrubber::get_image_filenames_for_directory, thumbnails::THUMB_SIZE,
+};
+
+/// The largest distance the grouping threshold can be set to
+pub const MAX_DISTANCE: u32 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+pub enum HashKind {
+    /// Brighter or darker than the average
+    #[strum(to_string = "Average (aHash)")]
+    Average,
+    /// Brighter or darker than the right neighbour
+    #[default]
+    #[strum(to_string = "Difference (dHash)")]
+    Difference,
+    /// Low frequencies of the cosine transform. Slowest, but survives more edits.
+    #[strum(to_string = "Perceptual (pHash)")]
+    Perceptual,
+}
+
+impl HashKind {
+    /// A 64 bit hash. Similar images have hashes that differ in few bits.
+    pub fn hash(&self, img: &DynamicImage) -> u64 {
+        let gray = |w, h| img.resize_exact(w, h, FilterType::Triangle).to_luma8();
+        match self {
+            Self::Average => {
+                let small = gray(8, 8);
+                let mean = small.pixels().map(|p| p.0[0] as u32).sum::<u32>() / 64;
+                bits(small.pixels().map(|p| p.0[0] as u32 > mean))
+            }
+            Self::Difference => {
+                let small = gray(9, 8);
+                bits((0..8).flat_map(|y| {
+                    let small = &small;
+                    (0..8).map(move |x| small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0])
+                }))
+            }
+            Self::Perceptual => {
+                let coefficients = low_frequencies(&gray(32, 32));
+                // the first coefficient is the average brightness and would skew the median
+                let mut sorted = coefficients[1..].to_vec();
+                sorted.sort_by(f32::total_cmp);
+                let median = sorted[sorted.len() / 2];
+                bits(coefficients.iter().map(|c| *c > median))
+            }
+        }
+    }
+}
+
+fn bits(values: impl Iterator<Item = bool>) -> u64 {
+    values
+        .take(64)
+        .fold(0, |hash, bit| (hash << 1) | bit as u64)
+}
+
+/// The top left 8x8 coefficients of the DCT-II of a 32x32 image
+fn low_frequencies(img: &GrayImage) -> Vec<f32> {
+    let n = img.width() as usize;
+    let cos = |i: usize, k: usize| {
+        ((2 * i + 1) as f32 * k as f32 * std::f32::consts::PI / (2 * n) as f32).cos()
+    };
+    let rows: Vec<[f32; 8]> = (0..n)
+        .map(|y| {
+            std::array::from_fn(|u| {
+                (0..n)
+                    .map(|x| img.get_pixel(x as u32, y as u32).0[0] as f32 * cos(x, u))
+                    .sum()
+            })
+        })
+        .collect();
+    (0..8)
+        .flat_map(|v| {
+            let rows = &rows;
+            (0..8).map(move |u| rows.iter().enumerate().map(|(y, r)| r[u] * cos(y, v)).sum())
+        })
+        .collect()
+}
+
+/// The number of bits two hashes differ in
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageEntry {
+    pub path: PathBuf,
+    pub hash: u64,
+    pub dimensions: (u32, u32),
+    /// Size of the file in bytes
+    pub file_size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl ImageEntry {
+    pub fn from_file(path: &Path, kind: HashKind) -> Result<Self> {
+        let img = open_image(path, None, None)?
+            .recv()?
+            .get_image()
+            .context("Can't get buffer")?;
+        let meta = std::fs::metadata(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            hash: kind.hash(&img.thumbnail(THUMB_SIZE[0], THUMB_SIZE[0])),
+            dimensions: img.dimensions(),
+            file_size: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// All images in a folder, and optionally in the folders below it
+pub fn image_files(folder: &Path, recursive: bool) -> Vec<PathBuf> {
+    let mut files = get_image_filenames_for_directory(folder).unwrap_or_default();
+    if recursive {
+        let mut folders = std::fs::read_dir(folder)
+            .into_iter()
+            .flatten()
+            .flatten()
+            // symlinks are not followed, so links can't loop
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        folders.sort();
+        for sub in folders {
+            files.extend(image_files(&sub, true));
+        }
+    }
+    files
+}
+
+/// Indices of entries whose hashes are at most `threshold` bits apart, directly or through
+/// other entries. Only groups of two or more are returned, largest images first.
+pub fn group(entries: &[ImageEntry], threshold: u32) -> Vec<Vec<usize>> {
+    let mut parent = (0..entries.len()).collect::<Vec<_>>();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for a in 0..entries.len() {
+        for b in a + 1..entries.len() {
+            if distance(entries[a].hash, entries[b].hash) <= threshold {
+                let (ra, rb) = (root(&mut parent, a), root(&mut parent, b));
+                parent[rb] = ra;
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![vec![]; entries.len()];
+    for i in 0..entries.len() {
+        let r = root(&mut parent, i);
+        groups[r].push(i);
+    }
+    let mut groups = groups
+        .into_iter()
+        .filter(|g| g.len() > 1)
+        .collect::<Vec<_>>();
+    for g in &mut groups {
+        g.sort_by_key(|i| {
+            let e = &entries[*i];
+            std::cmp::Reverse((e.dimensions.0 as u64 * e.dimensions.1 as u64, e.file_size))
+        });
+    }
+    groups
+}
+
+/// A scan running in the background
+#[derive(Debug)]
+struct Scan {
+    /// Zero until all images in the folder are listed
+    total: Arc<AtomicUsize>,
+    done: Arc<AtomicUsize>,
+    receiver: Receiver<Vec<ImageEntry>>,
+}
+
+/// State of the duplicate finder window
+#[derive(Debug)]
+pub struct DuplicateFinder {
+    pub open: bool,
+    pub kind: HashKind,
+    /// Include images in subfolders
+    pub recursive: bool,
+    /// Largest hash distance of images in a group
+    pub threshold: u32,
+    /// The hash the entries were computed with
+    pub scanned_kind: HashKind,
+    pub entries: Vec<ImageEntry>,
+    pub groups: Vec<Vec<usize>>,
+    scan: Option<Scan>,
+}
+
+impl Default for DuplicateFinder {
+    fn default() -> Self {
+        Self {
+            open: false,
+            kind: Default::default(),
+            recursive: false,
+            threshold: 6,
+            scanned_kind: Default::default(),
+            entries: vec![],
+            groups: vec![],
+            scan: None,
+        }
+    }
+}
+
+impl DuplicateFinder {
+    /// List and hash all images in a folder in the background
+    pub fn start(&mut self, folder: &Path) {
+        let total = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = channel();
+        let kind = self.kind;
+        let recursive = self.recursive;
+        let folder = folder.to_path_buf();
+        let (listed, counter) = (total.clone(), done.clone());
+        self.scan = Some(Scan {
+            total,
+            done,
+            receiver,
+        });
+        self.scanned_kind = kind;
+        std::thread::spawn(move || {
+            let files = image_files(&folder, recursive);
+            listed.store(files.len(), Ordering::Relaxed);
+            let entries = files
+                .par_iter()
+                .filter_map(|p| {
+                    let entry = ImageEntry::from_file(p, kind)
+                        .map_err(|e| warn!("Can't hash {}: {e}", p.display()))
+                        .ok();
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    entry
+                })
+                .collect::<Vec<_>>();
+            _ = sender.send(entries);
+        });
+    }
+
+    /// Hashed and total images, while a scan is running. The total is zero while the
+    /// images are still being listed.
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        self.scan.as_ref().map(|s| {
+            (
+                s.done.load(Ordering::Relaxed),
+                s.total.load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Take the results of a finished scan
+    pub fn poll(&mut self) {
+        if let Some(entries) = self.scan.as_ref().and_then(|s| s.receiver.try_recv().ok()) {
+            self.entries = entries;
+            self.scan = None;
+            self.regroup();
+        }
+    }
+
+    pub fn regroup(&mut self) {
+        self.groups = group(&self.entries, self.threshold);
+    }
+
+    /// Forget a file after it was deleted
+    pub fn remove(&mut self, path: &Path) {
+        self.entries.retain(|e| e.path != path);
+        self.regroup();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Luma, RgbImage};
+    use strum::IntoEnumIterator;
+
+    fn entry(name: &str, img: &DynamicImage, kind: HashKind) -> ImageEntry {
+        ImageEntry {
+            path: name.into(),
+            hash: kind.hash(img),
+            dimensions: img.dimensions(),
+            file_size: 0,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn hashes_and_groups() {
+        let pattern = |w: u32, h: u32| {
+            DynamicImage::ImageLuma8(GrayImage::from_fn(w, h, |x, y| {
+                let (u, v) = (x as f32 / w as f32, y as f32 / h as f32);
+                let wave = (u * 9.).sin() * (v * 6.).cos();
+                Luma([(128. + 100. * wave) as u8])
+            }))
+        };
+        let original = pattern(200, 150);
+        let smaller = pattern(100, 75);
+        let other = DynamicImage::ImageRgb8(RgbImage::from_fn(200, 150, |x, y| {
+            let v = if (x / 25 + y / 25) % 2 == 0 { 255 } else { 0 };
+            image::Rgb([v, v, v])
+        }));
+
+        for kind in HashKind::iter() {
+            let entries = [
+                entry("original", &original, kind),
+                entry("other", &other, kind),
+                entry("smaller", &smaller, kind),
+            ];
+            assert!(distance(entries[0].hash, entries[2].hash) <= 4, "{kind}");
+            assert!(distance(entries[0].hash, entries[1].hash) > 12, "{kind}");
+            // the larger copy comes first
+            assert_eq!(group(&entries, 6), vec![vec![0, 2]], "{kind}");
+        }
+    }
+}
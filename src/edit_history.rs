@@ -0,0 +1,267 @@
+//! Undo and redo of edits
+
+use log::debug;
+
+use crate::{
+    image_editing::{EditState, ImgOpItem},
+    paint::{PaintLayer, PaintStroke},
+};
+
+/// How many states are kept before the oldest ones are dropped
+pub const MAX_HISTORY: usize = 100;
+
+/// The parts of an `EditState` that can be undone
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// Describes the change that led to this state
+    pub label: String,
+    pub pixel_op_stack: Vec<ImgOpItem>,
+    pub image_op_stack: Vec<ImgOpItem>,
+    pub paint_strokes: Vec<PaintStroke>,
+    pub paint_layers: Vec<PaintLayer>,
+}
+
+impl HistoryEntry {
+    fn new(label: String, edit_state: &EditState) -> Self {
+        Self {
+            label,
+            pixel_op_stack: edit_state.pixel_op_stack.clone(),
+            image_op_stack: edit_state.image_op_stack.clone(),
+            paint_strokes: strokes(edit_state)
+                .map(|stroke| PaintStroke {
+                    highlight: false,
+                    committed: false,
+                    ..stroke.clone()
+                })
+                .collect(),
+            paint_layers: edit_state.paint_layers.clone(),
+        }
+    }
+
+    fn ops(&self) -> impl Iterator<Item = &ImgOpItem> {
+        self.image_op_stack.iter().chain(&self.pixel_op_stack)
+    }
+
+    fn matches(&self, edit_state: &EditState) -> bool {
+        self.pixel_op_stack == edit_state.pixel_op_stack
+            && self.image_op_stack == edit_state.image_op_stack
+            && self.paint_layers == edit_state.paint_layers
+            && self.paint_strokes.len() == strokes(edit_state).count()
+            && self
+                .paint_strokes
+                .iter()
+                .zip(strokes(edit_state))
+                .all(|(a, b)| same_stroke(a, b))
+    }
+}
+
+/// A bounded list of edit states with a position that can be moved back and forth
+#[derive(Debug, Default)]
+pub struct EditHistory {
+    entries: Vec<HistoryEntry>,
+    position: usize,
+    /// The current entry is still being changed, e.g. by dragging a slider
+    open: bool,
+}
+
+impl EditHistory {
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.position > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.position + 1 < self.entries.len()
+    }
+
+    /// Compare the edit state to the current entry and add a new one if it changed.
+    /// Changes made while `dragging` are merged into one entry.
+    pub fn record(&mut self, edit_state: &EditState, dragging: bool) {
+        let Some(current) = self.entries.get(self.position) else {
+            self.entries
+                .push(HistoryEntry::new("Open image".into(), edit_state));
+            self.position = 0;
+            return;
+        };
+        if current.matches(edit_state) {
+            self.open &= dragging;
+            return;
+        }
+
+        if self.open && self.position > 0 {
+            let label = self.entries[self.position].label.clone();
+            self.entries[self.position] = HistoryEntry::new(label, edit_state);
+        } else {
+            let label = describe(current, edit_state);
+            debug!("Recorded edit: {label}");
+            self.entries.truncate(self.position + 1);
+            self.entries.push(HistoryEntry::new(label, edit_state));
+            if self.entries.len() > MAX_HISTORY {
+                self.entries.remove(0);
+            }
+            self.position = self.entries.len() - 1;
+        }
+        self.open = dragging;
+    }
+
+    pub fn undo(&mut self, edit_state: &mut EditState) -> bool {
+        if !self.can_undo() {
+            return false;
+        }
+        self.jump(self.position - 1, edit_state)
+    }
+
+    pub fn redo(&mut self, edit_state: &mut EditState) -> bool {
+        if !self.can_redo() {
+            return false;
+        }
+        self.jump(self.position + 1, edit_state)
+    }
+
+    /// Restore the state at `index`
+    pub fn jump(&mut self, index: usize, edit_state: &mut EditState) -> bool {
+        let Some(entry) = self.entries.get(index) else {
+            return false;
+        };
+        edit_state.pixel_op_stack = entry.pixel_op_stack.clone();
+        edit_state.image_op_stack = entry.image_op_stack.clone();
+        edit_state.paint_strokes = entry.paint_strokes.clone();
+        edit_state.paint_layers = entry.paint_layers.clone();
+        // An empty result makes the edit panel process all operators again
+        edit_state.result_image_op = Default::default();
+        self.position = index;
+        self.open = false;
+        true
+    }
+}
+
+/// Strokes that have been painted, without the empty one waiting for input
+fn strokes(edit_state: &EditState) -> impl Iterator<Item = &PaintStroke> {
+    edit_state
+        .paint_strokes
+        .iter()
+        .filter(|stroke| !stroke.is_empty())
+}
+
+fn same_stroke(a: &PaintStroke, b: &PaintStroke) -> bool {
+    a.points == b.points
+        && a.fade == b.fade
+        && a.color == b.color
+        && a.width == b.width
+        && a.brush == b.brush
+        && a.flip_random == b.flip_random
+        && a.tool == b.tool
+        && a.layer == b.layer
+        && a.spacing == b.spacing
+        && a.hardness == b.hardness
+        && a.opacity == b.opacity
+        && a.pressure == b.pressure
+        && a.rotation_jitter == b.rotation_jitter
+        && a.scatter == b.scatter
+}
+
+/// A short description of what changed between an entry and the new state
+fn describe(before: &HistoryEntry, after: &EditState) -> String {
+    let stroke_count = strokes(after).count();
+    if stroke_count > before.paint_strokes.len() {
+        return "Paint stroke".into();
+    }
+    if stroke_count < before.paint_strokes.len() {
+        return "Remove stroke".into();
+    }
+    if after.paint_layers != before.paint_layers {
+        return "Edit layers".into();
+    }
+
+    let old_ops = before.ops().collect::<Vec<_>>();
+    let new_ops = after
+        .image_op_stack
+        .iter()
+        .chain(&after.pixel_op_stack)
+        .collect::<Vec<_>>();
+    if new_ops.len() > old_ops.len() {
+        if let Some(added) = new_ops.iter().find(|op| !old_ops.contains(*op)) {
+            return format!("Add {added}");
+        }
+    }
+    if new_ops.len() < old_ops.len() {
+        if let Some(removed) = old_ops.iter().find(|op| !new_ops.contains(*op)) {
+            return format!("Remove {removed}");
+        }
+    }
+    if let Some((old, new)) = old_ops.iter().zip(&new_ops).find(|(old, new)| old != new) {
+        if old.operation == new.operation && old.active != new.active {
+            let verb = if new.active { "Enable" } else { "Disable" };
+            return format!("{verb} {new}");
+        }
+        let mut old_sorted = old_ops.clone();
+        let mut new_sorted = new_ops.clone();
+        old_sorted.sort();
+        new_sorted.sort();
+        if old_sorted == new_sorted {
+            return format!("Move {new}");
+        }
+        return format!("Change {new}");
+    }
+    "Edit stroke".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_editing::ImageOperation;
+
+    #[test]
+    fn history_undo_redo() {
+        let mut edit_state = EditState::default();
+        let mut history = EditHistory::default();
+        history.record(&edit_state, false);
+
+        edit_state
+            .pixel_op_stack
+            .push(ImgOpItem::new(ImageOperation::Brightness(0)));
+        history.record(&edit_state, false);
+
+        // A drag only creates one entry
+        for value in 1..10 {
+            edit_state.pixel_op_stack[0].operation = ImageOperation::Brightness(value);
+            history.record(&edit_state, true);
+        }
+        history.record(&edit_state, false);
+
+        let labels = history
+            .entries()
+            .iter()
+            .map(|entry| entry.label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            ["Open image", "Add Brightness", "Change Brightness"]
+        );
+
+        assert!(history.undo(&mut edit_state));
+        assert_eq!(
+            edit_state.pixel_op_stack[0].operation,
+            ImageOperation::Brightness(0)
+        );
+        assert!(history.undo(&mut edit_state));
+        assert!(edit_state.pixel_op_stack.is_empty());
+        assert!(!history.undo(&mut edit_state));
+
+        assert!(history.redo(&mut edit_state));
+        assert_eq!(edit_state.pixel_op_stack.len(), 1);
+
+        // A new change discards what could have been redone
+        edit_state.pixel_op_stack.clear();
+        history.record(&edit_state, false);
+        assert_eq!(history.entries().len(), 3);
+        assert!(!history.can_redo());
+    }
+}
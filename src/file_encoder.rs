@@ -6,7 +6,7 @@ use crate::ui::EguiExt;
 use anyhow::Result;
 use image::codecs::jpeg::JpegEncoder;
 use image::codecs::png::{CompressionType, PngEncoder};
-use image::{DynamicImage, ImageEncoder};
+use image::DynamicImage;
 use notan::egui::Ui;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -67,7 +67,8 @@ impl FileEncoder {
         match self {
             FileEncoder::Jpg { quality } => {
                 let w = File::create(path)?;
-                JpegEncoder::new_with_quality(w, *quality as u8).encode_image(image)?;
+                // JPEG is 8 bit only
+                JpegEncoder::new_with_quality(w, *quality as u8).encode_image(&image.to_rgb8())?;
             }
             FileEncoder::Png { compressionlevel } => {
                 let file = File::create(path)?;
@@ -81,12 +82,13 @@ impl FileEncoder {
                     },
                     image::codecs::png::FilterType::default(),
                 );
-                encoder.write_image(
-                    image.as_bytes(),
-                    image.width(),
-                    image.height(),
-                    image::ExtendedColorType::Rgba8,
-                )?;
+                match image {
+                    // PNG has no float support, store as 16 bit instead
+                    DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                        DynamicImage::ImageRgba16(image.to_rgba16()).write_with_encoder(encoder)?
+                    }
+                    _ => image.write_with_encoder(encoder)?,
+                }
             }
             FileEncoder::Bmp => {
                 image.save_with_format(path, image::ImageFormat::Bmp)?;
@@ -583,6 +583,9 @@ pub fn browse<F: FnMut(&PathBuf)>(
                         fe.ui(ui);
                     }
                 }
+                if FileEncoder::iter().any(|f| f.ext() == ext.to_lowercase()) {
+                    settings.privacy.ui(ui);
+                }
             }
         });
     });
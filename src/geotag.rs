@@ -0,0 +1,260 @@
+//! Reading image locations from EXIF and exporting them as GPX or GeoJSON
+
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use exif::{In, Tag, Value};
+use serde_json::{json, Value as JsonValue};
+
+use crate::metadata::{escape_xml, GpsPosition};
+
+/// Where and when an image was taken
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoTag {
+    pub position: GpsPosition,
+    /// The direction the camera pointed, in degrees clockwise from north
+    pub heading: Option<f64>,
+    /// Capture time in ISO 8601. UTC if the GPS time is known, otherwise local time,
+    /// with an offset if there is one.
+    pub time: Option<String>,
+}
+
+impl GeoTag {
+    pub fn from_exif(exif: &exif::Exif) -> Option<Self> {
+        let position = GpsPosition::from_exif(exif)?;
+        let text = |tag: Tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(v) => v
+                .first()
+                .map(|s| String::from_utf8_lossy(s).trim().to_string()),
+            _ => None,
+        };
+        let rationals = |tag: Tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(v) => Some(v.iter().map(|r| r.to_f64()).collect::<Vec<_>>()),
+            _ => None,
+        };
+
+        let heading = rationals(Tag::GPSImgDirection)
+            .and_then(|v| v.first().copied())
+            .filter(|h| h.is_finite());
+        let gps_time = text(Tag::GPSDateStamp)
+            .zip(rationals(Tag::GPSTimeStamp))
+            .and_then(|(date, time)| {
+                let [h, m, s]: [f64; 3] = time.get(..3)?.try_into().ok()?;
+                Some(format!(
+                    "{}T{h:02}:{m:02}:{:02}Z",
+                    date.replace(':', "-"),
+                    s.floor()
+                ))
+            });
+        let local_time = text(Tag::DateTimeOriginal).and_then(|dt| {
+            let (date, time) = dt.split_once(' ')?;
+            Some(format!(
+                "{}T{time}{}",
+                date.replace(':', "-"),
+                text(Tag::OffsetTimeOriginal).unwrap_or_default()
+            ))
+        });
+
+        Some(Self {
+            position,
+            heading,
+            time: gps_time.or(local_time),
+        })
+    }
+
+    /// Read the location of an image file, if it has one
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let exif = exif::Reader::new().read_from_container(&mut reader)?;
+        Ok(Self::from_exif(&exif))
+    }
+
+    /// Latitude and longitude in decimal degrees, as most map services accept them
+    pub fn coordinates(&self) -> String {
+        format!(
+            "{:.6}, {:.6}",
+            self.position.latitude, self.position.longitude
+        )
+    }
+
+    pub fn map_url(&self) -> String {
+        let (lat, lon) = (self.position.latitude, self.position.longitude);
+        format!(
+            "https://www.openstreetmap.org/?mlat={lat:.6}&mlon={lon:.6}#map=16/{lat:.6}/{lon:.6}"
+        )
+    }
+}
+
+/// The locations of all images in `paths` that have one
+pub fn read_all(paths: &[PathBuf]) -> Vec<(PathBuf, GeoTag)> {
+    paths
+        .iter()
+        .filter_map(|path| Some((path.clone(), GeoTag::read(path).ok()??)))
+        .collect()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Waypoints named after the images
+pub fn to_gpx(tags: &[(PathBuf, GeoTag)]) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"oculante\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    for (path, tag) in tags {
+        gpx += &format!(
+            "  <wpt lat=\"{:.7}\" lon=\"{:.7}\">\n",
+            tag.position.latitude, tag.position.longitude
+        );
+        if let Some(altitude) = tag.position.altitude {
+            gpx += &format!("    <ele>{altitude:.2}</ele>\n");
+        }
+        if let Some(time) = &tag.time {
+            gpx += &format!("    <time>{}</time>\n", escape_xml(time));
+        }
+        gpx += &format!("    <name>{}</name>\n", escape_xml(&file_name(path)));
+        gpx += &format!("    <desc>{}</desc>\n", escape_xml(&path.to_string_lossy()));
+        gpx += "  </wpt>\n";
+    }
+    gpx += "</gpx>\n";
+    gpx
+}
+
+/// A point feature per image
+pub fn to_geojson(tags: &[(PathBuf, GeoTag)]) -> JsonValue {
+    let features = tags
+        .iter()
+        .map(|(path, tag)| {
+            let mut coordinates = vec![tag.position.longitude, tag.position.latitude];
+            coordinates.extend(tag.position.altitude);
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": coordinates },
+                "properties": {
+                    "name": file_name(path),
+                    "path": path,
+                    "time": tag.time,
+                    "heading": tag.heading,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "type": "FeatureCollection", "features": features })
+}
+
+/// Save locations as GPX, or as GeoJSON for `.geojson` and `.json` files
+pub fn export(path: &Path, tags: &[(PathBuf, GeoTag)]) -> Result<()> {
+    let is_json = path
+        .extension()
+        .is_some_and(|ext| ext == "geojson" || ext == "json");
+    if is_json {
+        serde_json::to_writer_pretty(File::create(path)?, &to_geojson(tags))?;
+    } else {
+        fs::write(path, to_gpx(tags))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{experimental::Writer, Field, Rational};
+    use std::io::Cursor;
+
+    /// Encode the fields as TIFF and read them back, as they would come from a file
+    fn exif(fields: &[(Tag, Value)]) -> exif::Exif {
+        let fields = fields
+            .iter()
+            .map(|(tag, value)| Field {
+                tag: *tag,
+                ifd_num: In::PRIMARY,
+                value: value.clone(),
+            })
+            .collect::<Vec<_>>();
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+        exif::Reader::new().read_raw(tiff.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn heading_and_time_from_exif() {
+        let ascii = |text: &str| Value::Ascii(vec![text.as_bytes().to_vec()]);
+        let rationals = |values: &[(u32, u32)]| {
+            Value::Rational(values.iter().map(|r| Rational::from(*r)).collect())
+        };
+        let mut fields = vec![
+            (Tag::GPSLatitudeRef, ascii("S")),
+            (
+                Tag::GPSLatitude,
+                rationals(&[(33, 1), (51, 1), (3600, 100)]),
+            ),
+            (Tag::GPSLongitudeRef, ascii("E")),
+            (Tag::GPSLongitude, rationals(&[(151, 1), (12, 1), (0, 1)])),
+            (Tag::GPSImgDirection, rationals(&[(2475, 10)])),
+            (Tag::DateTimeOriginal, ascii("2024:05:01 18:30:45")),
+            (Tag::OffsetTimeOriginal, ascii("+10:00")),
+        ];
+
+        // without GPS time, the local time and its offset are used
+        let tag = GeoTag::from_exif(&exif(&fields)).unwrap();
+        assert_eq!(tag.coordinates(), "-33.860000, 151.200000");
+        assert_eq!(tag.heading, Some(247.5));
+        assert_eq!(tag.time.as_deref(), Some("2024-05-01T18:30:45+10:00"));
+
+        fields.extend([
+            (Tag::GPSDateStamp, ascii("2024:05:01")),
+            (
+                Tag::GPSTimeStamp,
+                rationals(&[(8, 1), (30, 1), (4575, 100)]),
+            ),
+        ]);
+        let tag = GeoTag::from_exif(&exif(&fields)).unwrap();
+        assert_eq!(tag.time.as_deref(), Some("2024-05-01T08:30:45Z"));
+
+        // a heading without denominator is no heading
+        fields[4].1 = rationals(&[(90, 0)]);
+        assert_eq!(GeoTag::from_exif(&exif(&fields)).unwrap().heading, None);
+        assert_eq!(GeoTag::from_exif(&exif(&fields[4..])), None);
+    }
+
+    #[test]
+    fn gpx_and_geojson() {
+        let tags = vec![(
+            PathBuf::from("/photos/a&b.jpg"),
+            GeoTag {
+                position: GpsPosition {
+                    latitude: 48.8584,
+                    longitude: 2.2945,
+                    altitude: Some(35.),
+                },
+                heading: Some(90.),
+                time: Some("2024-05-01T12:30:00Z".into()),
+            },
+        )];
+        let gpx = to_gpx(&tags);
+        assert!(gpx.contains("<wpt lat=\"48.8584000\" lon=\"2.2945000\">"));
+        assert!(gpx.contains("<name>a&amp;b.jpg</name>"));
+        assert!(gpx.contains("<ele>35.00</ele>"));
+
+        let geojson = to_geojson(&tags);
+        let feature = &geojson["features"][0];
+        assert_eq!(
+            feature["geometry"]["coordinates"],
+            json!([2.2945, 48.8584, 35.])
+        );
+        assert_eq!(feature["properties"]["heading"], json!(90.));
+        assert_eq!(tags[0].1.coordinates(), "48.858400, 2.294500");
+    }
+}
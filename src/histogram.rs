@@ -0,0 +1,438 @@
+//! Histograms, statistics and pixel values that keep the range of 16-bit and float images
+
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Condvar, Mutex,
+};
+
+use image::{DynamicImage, Primitive};
+use strum::{Display, EnumIter};
+
+pub const DEFAULT_BINS: usize = 256;
+/// Resolution float medians are found with
+const FINE_BINS: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display, EnumIter)]
+pub enum HistogramChannel {
+    Red,
+    Green,
+    Blue,
+    Luminance,
+    Alpha,
+}
+
+impl HistogramChannel {
+    fn value(&self, p: [f64; 4]) -> f64 {
+        match self {
+            Self::Red => p[0],
+            Self::Green => p[1],
+            Self::Blue => p[2],
+            // Rec. 709
+            Self::Luminance => 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2],
+            Self::Alpha => p[3],
+        }
+    }
+}
+
+const CHANNELS: [HistogramChannel; 5] = [
+    HistogramChannel::Red,
+    HistogramChannel::Green,
+    HistogramChannel::Blue,
+    HistogramChannel::Luminance,
+    HistogramChannel::Alpha,
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub std_dev: f64,
+    /// Pixel counts, evenly spread over `ImageStats::range`
+    pub histogram: Vec<u64>,
+}
+
+/// Statistics of an image or a rectangle of it, in the image's own scale:
+/// 0-255 for 8 bit, 0-65535 for 16 bit, and unbounded for float images.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageStats {
+    /// The values the histograms cover. Integer images cover all possible values,
+    /// float images at least 0-1 and anything outside it.
+    pub range: (f64, f64),
+    pub float: bool,
+    pub pixels: usize,
+    channels: Vec<ChannelStats>,
+}
+
+impl ImageStats {
+    /// Compute statistics of all pixels, or those in an `[x, y, width, height]` region
+    pub fn compute(img: &DynamicImage, region: Option<[u32; 4]>, bins: usize) -> Self {
+        let bins = bins.max(1);
+        match img {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                let buffer = img.to_rgba32f();
+                compute_buffer(&buffer, buffer.dimensions(), region, bins, None)
+            }
+            DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => {
+                let converted;
+                let buffer = match img.as_rgba16() {
+                    Some(buffer) => buffer,
+                    None => {
+                        converted = img.to_rgba16();
+                        &converted
+                    }
+                };
+                compute_buffer(buffer, buffer.dimensions(), region, bins, Some(u16::MAX))
+            }
+            _ => {
+                let converted;
+                let buffer = match img.as_rgba8() {
+                    Some(buffer) => buffer,
+                    None => {
+                        converted = img.to_rgba8();
+                        &converted
+                    }
+                };
+                compute_buffer(buffer, buffer.dimensions(), region, bins, Some(u8::MAX))
+            }
+        }
+    }
+
+    pub fn channel(&self, channel: HistogramChannel) -> Option<&ChannelStats> {
+        self.channels.get(channel as usize)
+    }
+
+    pub fn bins(&self) -> usize {
+        self.channels
+            .first()
+            .map(|c| c.histogram.len())
+            .unwrap_or_default()
+    }
+
+    /// The lowest value counted in a bin
+    pub fn bin_value(&self, bin: usize) -> f64 {
+        self.range.0 + (self.range.1 - self.range.0) * bin as f64 / self.bins().max(1) as f64
+    }
+}
+
+/// An image, identified by a key, and the region and bins to compute statistics for
+#[derive(Clone, PartialEq)]
+struct StatsRequest {
+    image_key: u64,
+    region: Option<[u32; 4]>,
+    bins: usize,
+}
+
+/// A request waiting for the worker, with the image it is for
+type PendingStats = Option<(StatsRequest, Arc<DynamicImage>)>;
+type ComputedStats = (StatsRequest, ImageStats);
+
+/// Computes statistics on a single background thread, like `ScopesWorker` does for scopes.
+/// Only the latest request waits to be computed, and the image is only copied when it
+/// changes, not when the region or bins do.
+pub struct StatsWorker {
+    pending: Arc<(Mutex<PendingStats>, Condvar)>,
+    results: (Sender<ComputedStats>, Receiver<ComputedStats>),
+    started: bool,
+    image: Option<(u64, Arc<DynamicImage>)>,
+    requested: Option<StatsRequest>,
+    latest: Option<ComputedStats>,
+}
+
+impl Default for StatsWorker {
+    fn default() -> Self {
+        Self {
+            pending: Default::default(),
+            results: mpsc::channel(),
+            started: false,
+            image: None,
+            requested: None,
+            latest: None,
+        }
+    }
+}
+
+impl StatsWorker {
+    /// Statistics of `img`, which `image_key` identifies, for a region and number of bins.
+    /// Returns the newest statistics computed so far, and whether they are the ones asked for.
+    pub fn stats(
+        &mut self,
+        img: &DynamicImage,
+        image_key: u64,
+        region: Option<[u32; 4]>,
+        bins: usize,
+    ) -> (Option<&ImageStats>, bool) {
+        let request = StatsRequest {
+            image_key,
+            region,
+            bins,
+        };
+        if self.requested.as_ref() != Some(&request) {
+            self.request(img, request);
+        }
+        if let Some(result) = self.results.1.try_iter().last() {
+            self.latest = Some(result);
+        }
+        match &self.latest {
+            Some((computed, stats)) => (Some(stats), self.requested.as_ref() == Some(computed)),
+            None => (None, false),
+        }
+    }
+
+    fn request(&mut self, img: &DynamicImage, request: StatsRequest) {
+        if !self.started {
+            self.started = true;
+            let pending = self.pending.clone();
+            let sender = self.results.0.clone();
+            std::thread::spawn(move || loop {
+                let Ok(mut next) = pending.0.lock() else {
+                    return;
+                };
+                let (request, img) = loop {
+                    if let Some(next) = next.take() {
+                        break next;
+                    }
+                    let Ok(waited) = pending.1.wait(next) else {
+                        return;
+                    };
+                    next = waited;
+                };
+                drop(next);
+                let stats = ImageStats::compute(&img, request.region, request.bins);
+                if sender.send((request, stats)).is_err() {
+                    return;
+                }
+            });
+        }
+        let img = match &self.image {
+            Some((key, img)) if *key == request.image_key => img.clone(),
+            _ => {
+                let img = Arc::new(img.clone());
+                self.image = Some((request.image_key, img.clone()));
+                img
+            }
+        };
+        if let Ok(mut next) = self.pending.0.lock() {
+            *next = Some((request.clone(), img));
+            self.pending.1.notify_one();
+        }
+        self.requested = Some(request);
+    }
+}
+
+/// The samples of a pixel as stored, without converting 16 bit or float images to 8 bit
+pub fn raw_pixel(img: &DynamicImage, x: u32, y: u32) -> Option<Vec<f64>> {
+    let channels = 0..img.color().channel_count();
+    if let Some(samples) = img.as_flat_samples_u8() {
+        channels
+            .map(|c| samples.get_sample(c, x, y).map(|v| *v as f64))
+            .collect()
+    } else if let Some(samples) = img.as_flat_samples_u16() {
+        channels
+            .map(|c| samples.get_sample(c, x, y).map(|v| *v as f64))
+            .collect()
+    } else {
+        let samples = img.as_flat_samples_f32()?;
+        channels
+            .map(|c| samples.get_sample(c, x, y).map(|v| *v as f64))
+            .collect()
+    }
+}
+
+/// `samples` are RGBA pixels of an image with the given dimensions
+fn compute_buffer<T: Primitive + Into<f64>>(
+    samples: &[T],
+    (image_width, image_height): (u32, u32),
+    region: Option<[u32; 4]>,
+    bins: usize,
+    max: Option<T>,
+) -> ImageStats {
+    let [x, y, width, height] = region.unwrap_or([0, 0, image_width, image_height]);
+    let (x1, y1) = (
+        x.saturating_add(width).min(image_width),
+        y.saturating_add(height).min(image_height),
+    );
+    let pixels = || {
+        (y.min(y1)..y1).flat_map(move |py| {
+            let row = py as usize * image_width as usize;
+            samples[(row + x.min(x1) as usize) * 4..(row + x1 as usize) * 4]
+                .chunks_exact(4)
+                .map(|p| [p[0].into(), p[1].into(), p[2].into(), p[3].into()])
+        })
+    };
+
+    let mut min = [f64::INFINITY; 5];
+    let mut max_value = [f64::NEG_INFINITY; 5];
+    let mut sum = [0f64; 5];
+    let mut sum_sq = [0f64; 5];
+    let mut count = [0usize; 5];
+    for p in pixels() {
+        for (i, channel) in CHANNELS.iter().enumerate() {
+            let v = channel.value(p);
+            if !v.is_finite() {
+                continue;
+            }
+            min[i] = min[i].min(v);
+            max_value[i] = max_value[i].max(v);
+            sum[i] += v;
+            sum_sq[i] += v * v;
+            count[i] += 1;
+        }
+    }
+    let total = (x1.saturating_sub(x) as usize) * (y1.saturating_sub(y) as usize);
+    if total == 0 {
+        return ImageStats::default();
+    }
+
+    // Integer images are binned by value, float images finely over their range
+    let (range, fine) = match max {
+        Some(max) => {
+            let max: f64 = max.into();
+            ((0., max + 1.), max as usize + 1)
+        }
+        None => {
+            let lo = min.iter().copied().fold(0f64, f64::min);
+            let hi = max_value.iter().copied().fold(1f64, f64::max);
+            ((lo, hi), FINE_BINS)
+        }
+    };
+    let fine_bin = |v: f64| {
+        let t = (v - range.0) / (range.1 - range.0);
+        ((t * fine as f64) as usize).min(fine - 1)
+    };
+    let mut fine_histograms = vec![vec![0u64; fine]; 5];
+    for p in pixels() {
+        for (i, channel) in CHANNELS.iter().enumerate() {
+            let v = channel.value(p);
+            if v.is_finite() {
+                fine_histograms[i][fine_bin(if max.is_some() { v.round() } else { v })] += 1;
+            }
+        }
+    }
+
+    let channels = fine_histograms
+        .iter()
+        .enumerate()
+        .map(|(i, fine_histogram)| {
+            if count[i] == 0 {
+                return ChannelStats {
+                    histogram: vec![0; bins],
+                    ..Default::default()
+                };
+            }
+            let n = count[i] as f64;
+            let mean = sum[i] / n;
+            let mut histogram = vec![0u64; bins];
+            let mut median = None;
+            let mut cumulative = 0;
+            for (bin, c) in fine_histogram.iter().enumerate() {
+                histogram[bin * bins / fine] += c;
+                cumulative += c;
+                if median.is_none() && cumulative * 2 >= count[i] as u64 {
+                    let width = (range.1 - range.0) / fine as f64;
+                    let offset = if max.is_some() { 0. } else { width / 2. };
+                    median = Some(range.0 + bin as f64 * width + offset);
+                }
+            }
+            ChannelStats {
+                min: min[i],
+                max: max_value[i],
+                mean,
+                median: median.unwrap_or(mean),
+                std_dev: (sum_sq[i] / n - mean * mean).max(0.).sqrt(),
+                histogram,
+            }
+        })
+        .collect();
+
+    ImageStats {
+        range,
+        float: max.is_none(),
+        pixels: total,
+        channels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, Rgba32FImage, RgbaImage};
+
+    #[test]
+    fn worker_computes_the_latest_request() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, _| {
+            Rgba([x as u8 * 30, 0, 0, 255])
+        }));
+        let region = Some([0, 0, 2, 8]);
+        let expected = ImageStats::compute(&img, region, 16);
+
+        let mut worker = StatsWorker::default();
+        worker.stats(&img, 1, None, 64);
+        worker.stats(&img, 1, Some([4, 4, 4, 4]), 32);
+        let start = std::time::Instant::now();
+        loop {
+            assert!(
+                start.elapsed().as_secs() < 10,
+                "statistics of the last request never arrived"
+            );
+            if let (Some(stats), true) = worker.stats(&img, 1, region, 16) {
+                assert_eq!(stats, &expected);
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn integer_and_float_stats() {
+        let mut img = RgbaImage::from_pixel(4, 1, Rgba([0, 0, 0, 255]));
+        for x in 0..4 {
+            img.put_pixel(x, 0, Rgba([x as u8 * 10, 100, 0, 255]));
+        }
+        let stats = ImageStats::compute(&DynamicImage::ImageRgba8(img), None, 256);
+        let red = stats.channel(HistogramChannel::Red).unwrap();
+        assert_eq!((red.min, red.max, red.mean), (0., 30., 15.));
+        assert_eq!(red.median, 10.);
+        assert_eq!(red.histogram[20], 1);
+        assert_eq!(stats.channel(HistogramChannel::Alpha).unwrap().std_dev, 0.);
+        // a region of the two rightmost pixels
+        let region = ImageStats::compute(
+            &DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| Rgba([x as u8, 0, 0, 0]))),
+            Some([2, 0, 2, 1]),
+            16,
+        );
+        assert_eq!(region.pixels, 2);
+        assert_eq!(region.channel(HistogramChannel::Red).unwrap().mean, 2.5);
+
+        // values above 1 widen the range instead of being clipped
+        let hdr = Rgba32FImage::from_fn(2, 1, |x, _| Rgba([x as f32 * 4., 0.5, 0.5, 1.]));
+        let stats = ImageStats::compute(&DynamicImage::ImageRgba32F(hdr), None, 8);
+        assert!(stats.float);
+        assert_eq!(stats.range, (0., 4.));
+        let red = stats.channel(HistogramChannel::Red).unwrap();
+        assert_eq!(red.histogram.len(), 8);
+        assert_eq!((red.histogram[0], red.histogram[7]), (1, 1));
+        assert_eq!(stats.bin_value(4), 2.);
+    }
+
+    #[test]
+    fn raw_pixel_values() {
+        let img = DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Luma([40000u16]),
+        ));
+        assert_eq!(raw_pixel(&img, 1, 1), Some(vec![40000.]));
+        assert_eq!(raw_pixel(&img, 2, 0), None);
+        let hdr = DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(
+            1,
+            1,
+            image::Rgb([1.5, 0.25, -0.5]),
+        ));
+        assert_eq!(raw_pixel(&hdr, 0, 0), Some(vec![1.5, 0.25, -0.5]));
+    }
+}
@@ -1,21 +1,33 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
 
+use crate::brushes::Brush;
+use crate::calibration::{Calibration, CALIBRATION_ID};
 use crate::icons::*;
-use crate::paint::PaintStroke;
+use crate::paint::{PaintLayer, PaintStroke};
 use crate::settings::VolatileSettings;
 use crate::ui::EguiExt;
 use crate::{appstate::ImageGeometry, utils::pos_from_coord};
 #[cfg(not(feature = "file_open"))]
 use crate::{filebrowser, utils::SUPPORTED_EXTENSIONS};
+use ab_glyph::FontArc;
 use anyhow::{bail, Result};
 use evalexpr::*;
 use fast_image_resize::{self as fr, ResizeOptions};
-use image::{imageops, ColorType, DynamicImage, Rgba, RgbaImage};
-use imageproc::geometric_transformations::Interpolation;
+use font_kit::source::SystemSource;
+use image::{
+    imageops, ColorType, DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, RgbImage, Rgba,
+    Rgba32FImage, RgbaImage,
+};
+use imageproc::definitions::{Clamp, Image};
+use imageproc::drawing::{draw_text_mut, text_size};
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
 use log::{debug, error, info};
-use nalgebra::{Vector2, Vector4};
+use nalgebra::{Vector2, Vector3, Vector4};
 use notan::egui::epaint::PathShape;
 use notan::egui::{
     self, lerp, vec2, Align2, Color32, DragValue, FontId, Id, Pos2, Rect, Sense, Stroke,
@@ -23,12 +35,17 @@ use notan::egui::{
 };
 use notan::egui::{Response, Ui};
 use palette::{rgb::Rgb, Hsl, IntoColor};
-use rand::{thread_rng, Rng};
-use rayon::{iter::ParallelIterator, slice::ParallelSliceMut};
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumIter, IntoEnumIterator};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct EditState {
     #[serde(skip)]
     /// The final result of image modifications
@@ -41,39 +58,29 @@ pub struct EditState {
     pub block_panning: bool,
     pub non_destructive_painting: bool,
     pub paint_strokes: Vec<PaintStroke>,
+    /// Annotation layers the strokes are painted on
+    pub paint_layers: Vec<PaintLayer>,
     pub paint_fade: bool,
-    #[serde(skip, default = "default_brushes")]
-    pub brushes: Vec<RgbaImage>,
     pub pixel_op_stack: Vec<ImgOpItem>,
     pub image_op_stack: Vec<ImgOpItem>,
     pub export_extension: String,
+    /// Real-world size of a pixel set by the user, for measurements
+    pub calibration: Option<Calibration>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct LegacyEditState {
-    pub painting: bool,
-    pub non_destructive_painting: bool,
-    pub paint_strokes: Vec<PaintStroke>,
-    pub paint_fade: bool,
-    pub pixel_op_stack: Vec<ImageOperation>,
-    pub image_op_stack: Vec<ImageOperation>,
-    pub export_extension: String,
-}
-
-impl LegacyEditState {
-    pub fn upgrade(&self) -> EditState {
-        let mut ne = EditState::default();
-        ne.image_op_stack = self
-            .image_op_stack
-            .iter()
-            .map(|op| ImgOpItem::new(op.clone()))
-            .collect();
-        ne.pixel_op_stack = self
+impl EditState {
+    /// Move operators to the stack they are processed in. Edits saved by older versions
+    /// can have operators in the pixel stack that are image operators now. The pixel
+    /// operators before them move along, so everything is processed in the same order.
+    pub fn sort_stacks(&mut self) {
+        if let Some(last) = self
             .pixel_op_stack
             .iter()
-            .map(|op| ImgOpItem::new(op.clone()))
-            .collect();
-        ne
+            .rposition(|op| !op.operation.is_per_pixel())
+        {
+            self.image_op_stack
+                .extend(self.pixel_op_stack.drain(..=last));
+        }
     }
 }
 
@@ -86,38 +93,16 @@ impl Default for EditState {
             block_panning: false,
             non_destructive_painting: Default::default(),
             paint_strokes: Default::default(),
+            paint_layers: vec![PaintLayer::new("Layer 1")],
             paint_fade: false,
-            brushes: default_brushes(),
             pixel_op_stack: vec![],
             image_op_stack: vec![],
             export_extension: "png".into(),
+            calibration: None,
         }
     }
 }
 
-fn default_brushes() -> Vec<RgbaImage> {
-    vec![
-        image::load_from_memory(include_bytes!("../res/brushes/brush1.png"))
-            .expect("Brushes must always load")
-            .into_rgba8(),
-        image::load_from_memory(include_bytes!("../res/brushes/brush2.png"))
-            .expect("Brushes must always load")
-            .into_rgba8(),
-        image::load_from_memory(include_bytes!("../res/brushes/brush3.png"))
-            .expect("Brushes must always load")
-            .into_rgba8(),
-        image::load_from_memory(include_bytes!("../res/brushes/brush4.png"))
-            .expect("Brushes must always load")
-            .into_rgba8(),
-        image::load_from_memory(include_bytes!("../res/brushes/brush5.png"))
-            .expect("Brushes must always load")
-            .into_rgba8(),
-        image::load_from_memory(include_bytes!("../res/brushes/brush6.png"))
-            .expect("Brushes must always load")
-            .into_rgba8(),
-    ]
-}
-
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
 pub enum Channel {
     Red,
@@ -136,11 +121,105 @@ pub enum ScaleFilter {
     Lanczos3,
 }
 
+/// Interpolation for free rotation
+#[derive(
+    Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize, EnumIter,
+)]
+pub enum RotateFilter {
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+}
+
+impl RotateFilter {
+    fn interpolation(&self) -> Interpolation {
+        match self {
+            Self::Nearest => Interpolation::Nearest,
+            Self::Bilinear => Interpolation::Bilinear,
+            Self::Bicubic => Interpolation::Bicubic,
+        }
+    }
+}
+
+/// Where to place something on the image
+#[derive(
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    EnumIter,
+    Display,
+)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    #[default]
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::TopLeft => "↖",
+            Self::Top => "↑",
+            Self::TopRight => "↗",
+            Self::Left => "←",
+            Self::Center => "•",
+            Self::Right => "→",
+            Self::BottomLeft => "↙",
+            Self::Bottom => "↓",
+            Self::BottomRight => "↘",
+        }
+    }
+
+    /// Top left position of an item of `size` on a `canvas`, `margin` pixels away from the anchored edges.
+    pub fn place(&self, canvas: (u32, u32), size: (u32, u32), margin: i64) -> (i64, i64) {
+        let free_x = canvas.0 as i64 - size.0 as i64;
+        let free_y = canvas.1 as i64 - size.1 as i64;
+        let x = match self {
+            Self::TopLeft | Self::Left | Self::BottomLeft => margin,
+            Self::Top | Self::Center | Self::Bottom => free_x / 2,
+            Self::TopRight | Self::Right | Self::BottomRight => free_x - margin,
+        };
+        let y = match self {
+            Self::TopLeft | Self::Top | Self::TopRight => margin,
+            Self::Left | Self::Center | Self::Right => free_y / 2,
+            Self::BottomLeft | Self::Bottom | Self::BottomRight => free_y - margin,
+        };
+        (x, y)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 
 pub struct ImgOpItem {
     pub active: bool,
     pub operation: ImageOperation,
+    /// Strength of the operator in percent
+    #[serde(default = "full_opacity")]
+    pub opacity: u8,
+    #[serde(default)]
+    pub blend_mode: BlendMode,
+    /// Limits the operator to a part of the image
+    #[serde(default)]
+    pub mask: Option<Mask>,
+}
+
+fn full_opacity() -> u8 {
+    100
 }
 
 impl ImgOpItem {
@@ -148,7 +227,141 @@ impl ImgOpItem {
         Self {
             active: true,
             operation: op,
+            opacity: full_opacity(),
+            blend_mode: Default::default(),
+            mask: None,
+        }
+    }
+
+    /// True if the operator applies at full strength to the whole image
+    pub fn is_plain(&self) -> bool {
+        self.opacity >= 100 && self.blend_mode == BlendMode::Normal && self.mask.is_none()
+    }
+
+    /// Process an image operator, respecting opacity, blend mode and mask.
+    /// Per pixel operators can be in the image stack as well, after `EditState::sort_stacks`.
+    pub fn process_image(&self, img: &mut DynamicImage, brushes: &[Brush]) -> Result<()> {
+        let process = |img: &mut DynamicImage| {
+            if self.operation.is_per_pixel() {
+                process_pixels(img, &vec![self.operation.clone()])
+            } else {
+                self.operation.process_image(img)
+            }
+        };
+        if self.is_plain() {
+            return process(img);
+        }
+        let original = img.clone();
+        process(img)?;
+        self.blend(&original, img, brushes);
+        Ok(())
+    }
+
+    /// Mix the `result` of the operator into the `original` image
+    pub fn blend(&self, original: &DynamicImage, result: &mut DynamicImage, brushes: &[Brush]) {
+        if (original.width(), original.height()) != (result.width(), result.height()) {
+            debug!(
+                "{} changed the image size and can't be blended",
+                self.operation
+            );
+            return;
         }
+        let color_type = ColorTypeExt::from_image(result.color());
+        let bottom = original.to_rgba32f();
+        let mut top = result.to_rgba32f();
+        let weights = self
+            .mask
+            .as_ref()
+            .map(|mask| mask.weights(&bottom, brushes));
+        let opacity = self.opacity as f32 / 100.;
+        top.par_chunks_mut(4)
+            .zip(bottom.par_chunks(4))
+            .enumerate()
+            .for_each(|(i, (t, b))| {
+                let weight = opacity * weights.as_ref().map(|w| w[i]).unwrap_or(1.);
+                for (t, b) in t.iter_mut().zip(b).take(3) {
+                    *t = b + (self.blend_mode.blend(*b, *t) - b) * weight;
+                }
+                t[3] = b[3] + (t[3] - b[3]) * weight;
+            });
+        *result = color_type.convert(&DynamicImage::ImageRgba32F(top));
+    }
+
+    /// Opacity, blend mode and mask of the operator
+    pub fn blend_ui(
+        &mut self,
+        ui: &mut Ui,
+        geo: &ImageGeometry,
+        block_panning: &mut bool,
+    ) -> Response {
+        let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+        egui::CollapsingHeader::new("Blending")
+            .id_salt("blending")
+            .show(ui, |ui| {
+                egui::Grid::new("blending").num_columns(2).show(ui, |ui| {
+                    ui.label("Opacity");
+                    if ui.styled_slider(&mut self.opacity, 0..=100).changed() {
+                        r.mark_changed();
+                    }
+                    ui.end_row();
+
+                    ui.label("Mode");
+                    egui::ComboBox::from_id_salt("blend_mode")
+                        .selected_text(self.blend_mode.to_string())
+                        .show_ui(ui, |ui| {
+                            for mode in BlendMode::iter() {
+                                if ui
+                                    .selectable_value(&mut self.blend_mode, mode, mode.to_string())
+                                    .clicked()
+                                {
+                                    r.mark_changed();
+                                }
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label("Mask");
+                    let selected = self
+                        .mask
+                        .as_ref()
+                        .map(|mask| mask.shape.to_string())
+                        .unwrap_or("None".into());
+                    egui::ComboBox::from_id_salt("mask")
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(self.mask.is_none(), "None").clicked() {
+                                self.mask = None;
+                                r.mark_changed();
+                            }
+                            for shape in MaskShape::defaults() {
+                                let current = self.mask.as_ref().is_some_and(|mask| {
+                                    std::mem::discriminant(&mask.shape)
+                                        == std::mem::discriminant(&shape)
+                                });
+                                if ui.selectable_label(current, shape.to_string()).clicked()
+                                    && !current
+                                {
+                                    self.mask = Some(Mask {
+                                        shape,
+                                        feather: 20,
+                                        invert: false,
+                                    });
+                                    r.mark_changed();
+                                }
+                            }
+                        });
+                    ui.end_row();
+                });
+
+                if let Some(mask) = &mut self.mask {
+                    if mask_ui(ui, mask, geo, block_panning) {
+                        r.mark_changed();
+                    }
+                }
+            })
+            .header_response
+            .on_hover_text("Operators that change the image size are not blended");
+        r
     }
 }
 
@@ -158,13 +371,220 @@ impl fmt::Display for ImgOpItem {
     }
 }
 
+/// How the result of an operator is combined with the image it was applied to
+#[derive(
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    EnumIter,
+    Display,
+)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    #[strum(to_string = "Soft light")]
+    SoftLight,
+    #[strum(to_string = "Hard light")]
+    HardLight,
+    Darken,
+    Lighten,
+    Difference,
+}
+
+impl BlendMode {
+    /// Combine a channel of the operator result `top` with the same channel of the original `bottom`
+    pub fn blend(&self, bottom: f32, top: f32) -> f32 {
+        match self {
+            Self::Normal => top,
+            Self::Multiply => bottom * top,
+            Self::Screen => 1. - (1. - bottom) * (1. - top),
+            Self::Overlay => Self::HardLight.blend(top, bottom),
+            Self::SoftLight => {
+                if top <= 0.5 {
+                    bottom - (1. - 2. * top) * bottom * (1. - bottom)
+                } else {
+                    let d = if bottom <= 0.25 {
+                        ((16. * bottom - 12.) * bottom + 4.) * bottom
+                    } else {
+                        bottom.max(0.).sqrt()
+                    };
+                    bottom + (2. * top - 1.) * (d - bottom)
+                }
+            }
+            Self::HardLight => {
+                if top <= 0.5 {
+                    2. * bottom * top
+                } else {
+                    1. - 2. * (1. - bottom) * (1. - top)
+                }
+            }
+            Self::Darken => bottom.min(top),
+            Self::Lighten => bottom.max(top),
+            Self::Difference => (bottom - top).abs(),
+        }
+    }
+}
+
+/// Limits an operator to a part of the image
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct Mask {
+    pub shape: MaskShape,
+    /// Softness of the mask edge in percent
+    pub feather: u8,
+    pub invert: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize, Display)]
+pub enum MaskShape {
+    /// x, y, width and height in 1/10000 of the image size
+    Rectangle([u16; 4]),
+    Ellipse([u16; 4]),
+    /// Lower and upper luminance of the pixels the operator applies to
+    Luminance((u8, u8)),
+    /// Painted with the brush engine
+    Painted(Vec<MaskStroke>),
+}
+
+impl MaskShape {
+    /// A starting point for each kind of mask
+    pub fn defaults() -> [Self; 4] {
+        [
+            Self::Rectangle([2500, 2500, 5000, 5000]),
+            Self::Ellipse([2500, 2500, 5000, 5000]),
+            Self::Luminance((128, 255)),
+            Self::Painted(vec![]),
+        ]
+    }
+}
+
+/// A brush stroke of a painted mask
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
+pub struct MaskStroke {
+    /// Positions in 1/10000 of the image size
+    pub points: Vec<(u16, u16)>,
+    /// Brush size in 1/10000 of the smallest image dimension
+    pub width: u16,
+    /// Name of the brush, the first one is used if it is empty or missing
+    #[serde(default)]
+    pub brush: String,
+    /// Remove from the mask instead of adding to it
+    pub erase: bool,
+}
+
+impl MaskStroke {
+    fn paint_stroke(&self) -> PaintStroke {
+        PaintStroke {
+            points: self
+                .points
+                .iter()
+                .map(|p| (p.0 as f32 / 10000., p.1 as f32 / 10000.))
+                .collect(),
+            width: self.width as f32 / 10000.,
+            brush: self.brush.clone(),
+            ..PaintStroke::new()
+        }
+    }
+}
+
+impl Mask {
+    /// How much the operator applies to each pixel of `img`, from 0 to 1
+    pub fn weights(&self, img: &Rgba32FImage, brushes: &[Brush]) -> Vec<f32> {
+        let (width, height) = img.dimensions();
+        // keep a tiny soft edge, so hard masks don't need a special case
+        let feather = (self.feather as f32 / 100.).max(1e-3);
+        let mut weights = match &self.shape {
+            MaskShape::Rectangle(rect) | MaskShape::Ellipse(rect) => {
+                let [x, y, w, h] = rect.map(|v| v as f32 / 10000.);
+                let (rx, ry) = (
+                    (w * width as f32 / 2.).max(0.5),
+                    (h * height as f32 / 2.).max(0.5),
+                );
+                let (cx, cy) = (x * width as f32 + rx, y * height as f32 + ry);
+                let ellipse = matches!(self.shape, MaskShape::Ellipse(_));
+                (0..height)
+                    .flat_map(|py| (0..width).map(move |px| (px, py)))
+                    .map(|(px, py)| {
+                        // 0 in the center, 1 on the edge of the shape
+                        let dx = ((px as f32 + 0.5 - cx) / rx).abs();
+                        let dy = ((py as f32 + 0.5 - cy) / ry).abs();
+                        let d = if ellipse {
+                            (dx * dx + dy * dy).sqrt()
+                        } else {
+                            dx.max(dy)
+                        };
+                        smoothstep(1., 1. - feather, d)
+                    })
+                    .collect()
+            }
+            MaskShape::Luminance((low, high)) => {
+                let (low, high) = (*low as f32 / 255., *high as f32 / 255.);
+                // at 100%, the edges fade over half of the luminance range
+                let soft = feather / 2.;
+                img.pixels()
+                    .map(|p| {
+                        let l = luminance(&p.0);
+                        smoothstep(low - soft, low, l) * smoothstep(high + soft, high, l)
+                    })
+                    .collect()
+            }
+            MaskShape::Painted(strokes) => {
+                let mut weights = vec![0.; (width * height) as usize];
+                let mut layer = RgbaImage::new(width, height);
+                for stroke in strokes {
+                    layer.fill(0);
+                    stroke.paint_stroke().render(&mut layer, brushes);
+                    for (w, p) in weights.iter_mut().zip(layer.pixels()) {
+                        let a = p[3] as f32 / 255.;
+                        *w = if stroke.erase {
+                            *w * (1. - a)
+                        } else {
+                            a + *w * (1. - a)
+                        };
+                    }
+                }
+                if self.feather > 0 {
+                    let sigma = feather * width.min(height) as f32 * 0.05;
+                    if let Some(buffer) =
+                        ImageBuffer::<Luma<f32>, _>::from_raw(width, height, weights.clone())
+                    {
+                        weights = imageops::fast_blur(&buffer, sigma).into_raw();
+                    }
+                }
+                weights
+            }
+        };
+        if self.invert {
+            for w in &mut weights {
+                *w = 1. - *w;
+            }
+        }
+        weights
+    }
+}
+
+/// 0 at `edge0`, 1 at `edge1`, smoothly interpolated in between
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
 pub enum ImageOperation {
     ColorConverter(ColorTypeExt),
     Brightness(i32),
     /// discard pixels around a threshold: position and range, bool for mode.
     Slice(u8, u8, bool),
-    Expression(String),
+    Expression(ExpressionCode),
     Desaturate(u8),
     Posterize(u8),
     Filter3x3([i32; 9]),
@@ -202,10 +622,76 @@ pub enum ImageOperation {
         points: [(u32, u32); 4],
         original_size: (u32, u32),
     },
+    /// Rotate by any angle, in 1/100 degrees clockwise. With `crop`, the result is cut
+    /// to the largest rectangle without empty corners.
+    RotateFree {
+        angle: i32,
+        filter: RotateFilter,
+        crop: bool,
+    },
     Measure {
         shapes: Vec<MeasureShape>,
     },
     LUT(String),
+    /// Render text onto the image. Size and margin are percent of the image height.
+    Text {
+        text: String,
+        /// System font family, empty for the default font
+        font: String,
+        size: u8,
+        color: [u8; 3],
+        opacity: u8,
+        anchor: Anchor,
+        margin: u8,
+        /// Degrees, clockwise
+        rotation: i16,
+    },
+    /// Darken or lighten towards the edges. Amount and roundness are -100 to 100,
+    /// midpoint and feather 0 to 100.
+    Vignette {
+        amount: i8,
+        midpoint: u8,
+        roundness: i8,
+        feather: u8,
+    },
+    /// Film grain, strongest in the midtones. Amount and size are 0 to 100.
+    /// The same seed gives the same grain on every image.
+    Grain {
+        amount: u8,
+        size: u8,
+        #[serde(default)]
+        seed: u32,
+    },
+    /// Tint shadows and highlights. Balance from -100 to 100 moves the split point.
+    SplitToning {
+        shadows: [u8; 3],
+        highlights: [u8; 3],
+        balance: i8,
+    },
+    /// Add a border around the image. Sides are left, top, right and bottom,
+    /// in pixels or in percent of the image width/height.
+    Pad {
+        sides: [u32; 4],
+        percent: bool,
+        color: [u8; 4],
+    },
+    /// Extend the canvas to an aspect ratio (width, height), placing the image by anchor.
+    ExtendAspect {
+        ratio: (u32, u32),
+        anchor: Anchor,
+        color: [u8; 4],
+    },
+    /// Place an image file onto the image. Size is percent of the image width,
+    /// margin percent of the image height.
+    Watermark {
+        path: String,
+        size: u8,
+        opacity: u8,
+        anchor: Anchor,
+        margin: u8,
+        /// Degrees, clockwise
+        rotation: i16,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize, Deserialize)]
@@ -220,6 +706,29 @@ pub enum MeasureShape {
         color: [u8; 4],
         width: u8,
     },
+    /// The end of the first arm, the vertex and the end of the second arm
+    Angle {
+        points: Vec<(u32, u32)>,
+        color: [u8; 4],
+        width: u8,
+    },
+    Polyline {
+        points: Vec<(u32, u32)>,
+        color: [u8; 4],
+        width: u8,
+    },
+    /// A closed outline
+    Polygon {
+        points: Vec<(u32, u32)>,
+        color: [u8; 4],
+        width: u8,
+    },
+    /// A circle or ellipse, given by two corners of its bounding box
+    Ellipse {
+        points: Vec<(u32, u32)>,
+        color: [u8; 4],
+        width: u8,
+    },
 }
 
 impl MeasureShape {
@@ -237,6 +746,168 @@ impl MeasureShape {
             width: 4,
         }
     }
+
+    pub fn points(&self) -> &[(u32, u32)] {
+        match self {
+            Self::Line { points, .. }
+            | Self::Rect { points, .. }
+            | Self::Angle { points, .. }
+            | Self::Polyline { points, .. }
+            | Self::Polygon { points, .. }
+            | Self::Ellipse { points, .. } => points,
+        }
+    }
+
+    pub fn points_mut(&mut self) -> &mut Vec<(u32, u32)> {
+        match self {
+            Self::Line { points, .. }
+            | Self::Rect { points, .. }
+            | Self::Angle { points, .. }
+            | Self::Polyline { points, .. }
+            | Self::Polygon { points, .. }
+            | Self::Ellipse { points, .. } => points,
+        }
+    }
+
+    pub fn color(&self) -> [u8; 4] {
+        match self {
+            Self::Line { color, .. }
+            | Self::Rect { color, .. }
+            | Self::Angle { color, .. }
+            | Self::Polyline { color, .. }
+            | Self::Polygon { color, .. }
+            | Self::Ellipse { color, .. } => *color,
+        }
+    }
+
+    pub fn width(&self) -> u8 {
+        match self {
+            Self::Line { width, .. }
+            | Self::Rect { width, .. }
+            | Self::Angle { width, .. }
+            | Self::Polyline { width, .. }
+            | Self::Polygon { width, .. }
+            | Self::Ellipse { width, .. } => *width,
+        }
+    }
+
+    /// The measure tool keeps an empty rectangle until something is measured
+    pub fn is_placeholder(&self) -> bool {
+        self.points().iter().all(|p| *p == (0, 0))
+    }
+
+    fn float_points(&self) -> Vec<(f64, f64)> {
+        self.points()
+            .iter()
+            .map(|p| (p.0 as f64, p.1 as f64))
+            .collect()
+    }
+
+    /// Size of the bounding box of the first two points
+    fn box_size(&self) -> (f64, f64) {
+        match self.float_points().as_slice() {
+            [a, b, ..] => ((a.0 - b.0).abs(), (a.1 - b.1).abs()),
+            _ => (0., 0.),
+        }
+    }
+
+    /// Length in pixels: the sum of all line segments, the path along a polyline or angle,
+    /// or the perimeter of a closed shape
+    pub fn length(&self) -> f64 {
+        self.length_with(&Calibration::default())
+    }
+
+    pub fn length_with(&self, calibration: &Calibration) -> f64 {
+        let points = self.float_points();
+        match self {
+            Self::Line { .. } => points
+                .chunks_exact(2)
+                .map(|p| calibration.length(p[0], p[1]))
+                .sum(),
+            Self::Rect { .. } => {
+                let (w, h) = self.box_size();
+                2. * (calibration.length((0., 0.), (w, 0.)) + calibration.length((0., 0.), (0., h)))
+            }
+            Self::Angle { .. } | Self::Polyline { .. } => calibration.path_length(&points, false),
+            Self::Polygon { .. } => calibration.path_length(&points, true),
+            Self::Ellipse { .. } => calibration.ellipse_perimeter(self.box_size()),
+        }
+    }
+
+    /// Area in square pixels. Open shapes have none.
+    pub fn area(&self) -> f64 {
+        self.area_with(&Calibration::default())
+    }
+
+    pub fn area_with(&self, calibration: &Calibration) -> f64 {
+        match self {
+            Self::Line { .. } | Self::Angle { .. } | Self::Polyline { .. } => 0.,
+            Self::Rect { .. } => calibration.area(self.box_size()),
+            Self::Polygon { .. } => calibration.polygon_area(&self.float_points()),
+            Self::Ellipse { .. } => calibration.ellipse_area(self.box_size()),
+        }
+    }
+
+    /// The angle at the vertex in degrees, for angle shapes with all three points
+    pub fn angle_with(&self, calibration: &Calibration) -> Option<f64> {
+        match (self, self.float_points().as_slice()) {
+            (Self::Angle { .. }, [a, vertex, b]) => Some(calibration.angle(*a, *vertex, *b)),
+            _ => None,
+        }
+    }
+}
+
+/// What the measure tool places on the image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumIter, Display)]
+pub enum MeasureTool {
+    #[default]
+    Rectangle,
+    Line,
+    Angle,
+    Polyline,
+    Polygon,
+    #[strum(to_string = "Circle / Ellipse")]
+    Ellipse,
+}
+
+impl MeasureTool {
+    pub fn shape(&self, points: Vec<(u32, u32)>) -> MeasureShape {
+        let color = [255, 255, 255, 255];
+        let width = 2;
+        match self {
+            Self::Rectangle => MeasureShape::new_rect(points),
+            Self::Line => MeasureShape::Line {
+                points,
+                color,
+                width,
+            },
+            Self::Angle => MeasureShape::Angle {
+                points,
+                color,
+                width,
+            },
+            Self::Polyline => MeasureShape::Polyline {
+                points,
+                color,
+                width,
+            },
+            Self::Polygon => MeasureShape::Polygon {
+                points,
+                color,
+                width,
+            },
+            Self::Ellipse => MeasureShape::Ellipse {
+                points,
+                color,
+                width,
+            },
+        }
+    }
+
+    /// Shapes placed by dragging, the others are placed by clicking their points
+    pub fn is_dragged(&self) -> bool {
+        matches!(self, Self::Rectangle | Self::Line | Self::Ellipse)
+    }
 }
 
 impl fmt::Display for ImageOperation {
@@ -260,6 +931,7 @@ impl fmt::Display for ImageOperation {
             Self::Measure { .. } => write!(f, "Measure"),
             Self::Flip(_) => write!(f, "Flip"),
             Self::Rotate(_) => write!(f, "Rotate"),
+            Self::RotateFree { .. } => write!(f, "Free rotate"),
             Self::Invert => write!(f, "Invert"),
             Self::ChannelSwap(_) => write!(f, "Channel Copy"),
             Self::HSV(_) => write!(f, "HSV"),
@@ -272,6 +944,13 @@ impl fmt::Display for ImageOperation {
             Self::MDiv => write!(f, "Divide by alpha"),
             Self::LUT(_) => write!(f, "Apply Color LUT"),
             Self::Filter3x3(_) => write!(f, "3x3 Filter"),
+            Self::Vignette { .. } => write!(f, "Vignette"),
+            Self::Grain { .. } => write!(f, "Film grain"),
+            Self::SplitToning { .. } => write!(f, "Split toning"),
+            Self::Pad { .. } => write!(f, "Padding"),
+            Self::ExtendAspect { .. } => write!(f, "Extend to aspect ratio"),
+            Self::Text { .. } => write!(f, "Text"),
+            Self::Watermark { .. } => write!(f, "Watermark"),
             // _ => write!(f, "Not implemented Display"),
         }
     }
@@ -285,12 +964,20 @@ impl ImageOperation {
             Self::Crop(_) => false,
             Self::CropPerspective { .. } => false,
             Self::Rotate(_) => false,
+            Self::RotateFree { .. } => false,
             Self::ColorConverter(_) => false,
             Self::Flip(_) => false,
             Self::ChromaticAberration(_) => false,
             Self::LUT(_) => false,
             Self::Filter3x3(_) => false,
             Self::ScaleImageMinMax => false,
+            Self::Vignette { .. } => false,
+            Self::Grain { .. } => false,
+            Self::Expression(_) => false,
+            Self::Pad { .. } => false,
+            Self::ExtendAspect { .. } => false,
+            Self::Text { .. } => false,
+            Self::Watermark { .. } => false,
             _ => true,
         }
     }
@@ -379,7 +1066,57 @@ impl ImageOperation {
                 x
             }
             Self::Posterize(val) => ui.styled_slider(val, 1..=255),
-            Self::Expression(expr) => ui.text_edit_singleline(expr),
+            Self::Expression(code) => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+                ui.vertical(|ui| {
+                    if ui
+                        .add(
+                            egui::TextEdit::multiline(&mut code.source)
+                                .code_editor()
+                                .desired_rows(3),
+                        )
+                        .on_hover_text(EXPRESSION_HELP)
+                        .changed()
+                    {
+                        code.parsed = None;
+                        r.mark_changed();
+                    }
+                    let parsed = code.parsed().clone();
+                    if let Some(e) = &parsed.error {
+                        ui.colored_label(Color32::LIGHT_RED, e);
+                    }
+
+                    // Declared parameters get a slider, which writes back to the declaration
+                    let mut lines = code.source.lines().map(String::from).collect::<Vec<_>>();
+                    let mut lines_changed = false;
+                    for (line, mut param) in parsed.source.params {
+                        ui.label(&param.name);
+                        if ui
+                            .styled_slider(&mut param.value, param.range.clone())
+                            .changed()
+                        {
+                            param.value = (param.value * 1000.).round() / 1000.;
+                            lines[line] = param.to_string();
+                            lines_changed = true;
+                        }
+                    }
+
+                    let mut compare = parsed.source.compare.unwrap_or_default();
+                    ui.label("Compare image");
+                    if pick_image_ui(ui, settings, "EXPRESSION_COMPARE", &mut compare) {
+                        lines.retain(|l| parse_compare(l).is_none());
+                        lines.insert(0, format!("compare {compare}"));
+                        lines_changed = true;
+                    }
+
+                    if lines_changed {
+                        code.source = lines.join("\n");
+                        code.parsed = None;
+                        r.mark_changed();
+                    }
+                });
+                r
+            }
             Self::LUT(lut_name) => {
                 ui.scope(|ui| {
                     let mut x = ui.allocate_response(vec2(0.0, 0.0), Sense::click_and_drag());
@@ -719,19 +1456,59 @@ impl ImageOperation {
                 }
                 r
             }
-            Self::Desaturate(val) => ui.styled_slider(val, 0..=100),
-            Self::Contrast(val) => ui.styled_slider(val, -128..=128),
-            Self::CropPerspective {
-                points,
-                original_size,
+            Self::RotateFree {
+                angle,
+                filter,
+                crop,
             } => {
-                let id = Id::new("crop");
-                let points_transformed = points
-                    .iter()
-                    .map(|p| {
-                        (
-                            geo.scale * p.0 as f32 + geo.offset.x,
-                            geo.scale * p.1 as f32 + geo.offset.y,
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::click_and_drag());
+                ui.vertical(|ui| {
+                    let mut degrees = *angle as f32 / 100.;
+                    if ui.styled_slider(&mut degrees, -180.0..=180.0).changed() {
+                        *angle = (degrees * 100.).round() as i32;
+                        r.mark_changed();
+                    }
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_id_salt("rotate_filter")
+                            .selected_text(format!("{filter:?}"))
+                            .show_ui(ui, |ui| {
+                                for f in RotateFilter::iter() {
+                                    if ui.selectable_value(filter, f, format!("{f:?}")).clicked() {
+                                        r.mark_changed();
+                                    }
+                                }
+                            });
+                        if ui
+                            .styled_checkbox(crop, "Crop")
+                            .on_hover_text("Crop to the largest area without empty corners")
+                            .changed()
+                        {
+                            r.mark_changed();
+                        }
+                    });
+
+                    if let Some(correction) = straighten_ui(ui, geo, block_panning) {
+                        // The line is drawn on the rotated result, so the correction adds up
+                        let total = *angle + (correction * 100.).round() as i32;
+                        *angle = (total + 18000).rem_euclid(36000) - 18000;
+                        r.mark_changed();
+                    }
+                });
+                r
+            }
+            Self::Desaturate(val) => ui.styled_slider(val, 0..=100),
+            Self::Contrast(val) => ui.styled_slider(val, -128..=128),
+            Self::CropPerspective {
+                points,
+                original_size,
+            } => {
+                let id = Id::new("crop");
+                let points_transformed = points
+                    .iter()
+                    .map(|p| {
+                        (
+                            geo.scale * p.0 as f32 + geo.offset.x,
+                            geo.scale * p.1 as f32 + geo.offset.y,
                         )
                     })
                     .collect::<Vec<_>>();
@@ -833,151 +1610,7 @@ impl ImageOperation {
 
                 r
             }
-            Self::Measure { shapes } => {
-                // create a fake response to alter
-                let r = ui.allocate_response(Vec2::ZERO, Sense::click_and_drag());
-                // enable this if this is used to draw
-                // let id = Id::new("shapes");
-
-                // let cursor_abs = ui.input(|i| i.pointer.hover_pos()).unwrap_or_default();
-
-                // let cursor_relative = pos_from_coord(
-                //     geo.offset,
-                //     Vector2::new(cursor_abs.x, cursor_abs.y),
-                //     Vector2::new(geo.dimensions.0 as f32, geo.dimensions.1 as f32),
-                //     geo.scale,
-                // );
-
-                // draw shapes
-                for shape in shapes {
-                    match shape {
-                        MeasureShape::Line {
-                            points,
-                            color,
-                            width,
-                        } => {
-                            let points_transformed = points
-                                .iter()
-                                .map(|p| {
-                                    (
-                                        geo.scale * p.0 as f32 + geo.offset.x,
-                                        geo.scale * p.1 as f32 + geo.offset.y,
-                                    )
-                                })
-                                .collect::<Vec<_>>();
-                            for p in points_transformed.chunks(2) {
-                                ui.painter().line_segment(
-                                    [
-                                        Pos2::new(p[0].0 as f32, p[0].1 as f32),
-                                        Pos2::new(p[1].0 as f32, p[1].1 as f32),
-                                    ],
-                                    Stroke::new(
-                                        *width as f32,
-                                        Color32::from_rgb(color[0], color[1], color[2]),
-                                    ),
-                                );
-                            }
-                        }
-                        MeasureShape::Rect {
-                            points,
-                            color,
-                            width,
-                        } => {
-                            let points_transformed = points
-                                .iter()
-                                .map(|p| {
-                                    (
-                                        geo.scale * p.0 as f32 + geo.offset.x,
-                                        geo.scale * p.1 as f32 + geo.offset.y,
-                                    )
-                                })
-                                .collect::<Vec<_>>();
-
-                            let rect = Rect {
-                                min: Pos2::new(points_transformed[0].0, points_transformed[0].1),
-                                max: Pos2::new(points_transformed[1].0, points_transformed[1].1),
-                            };
-
-                            let rect_orig = Rect {
-                                min: Pos2::new(points[0].0 as f32, points[0].1 as f32),
-                                max: Pos2::new(points[1].0 as f32, points[1].1 as f32),
-                            };
-
-                            ui.painter().rect_stroke(
-                                rect,
-                                0.0,
-                                Stroke::new(*width as f32, Color32::BLACK),
-                                StrokeKind::Inside,
-                            );
-
-                            ui.painter().rect_filled(
-                                rect,
-                                0.0,
-                                Color32::BLACK,
-                                // Stroke::new(*width as f32 / 2., Color32::WHITE),
-                                // StrokeKind::Inside,
-                            );
-
-                            ui.painter().text(
-                                rect.expand(14.).center_bottom(),
-                                Align2::CENTER_CENTER,
-                                format!(
-                                    "{}x{}",
-                                    rect_orig.width() as i32,
-                                    rect_orig.height() as i32
-                                ),
-                                FontId::proportional(14.),
-                                Color32::from_rgb(color[0], color[1], color[2]),
-                            );
-
-                            ui.painter().line_segment(
-                                [rect.left_center(), rect.right_center()],
-                                Stroke::new(1., Color32::from_rgba_unmultiplied(255, 255, 255, 10)),
-                            );
-
-                            ui.painter().line_segment(
-                                [rect.center_top(), rect.center_bottom()],
-                                Stroke::new(1., Color32::from_rgba_unmultiplied(255, 255, 255, 10)),
-                            );
-                        }
-                    }
-                }
-
-                // for (i, pt) in points_transformed.iter().enumerate() {
-                //     let maxdist = 20.;
-                //     let d = Pos2::new(pt.0, pt.1).distance(cursor_abs);
-
-                //     if d < maxdist {
-                //         if ui.input(|i| i.pointer.any_down()) {
-                //             *block_panning = true;
-                //             ui.ctx().data_mut(|w| w.insert_temp("pt".into(), i));
-                //         }
-                //         if ui.input(|r| r.pointer.any_released()) {
-                //             *block_panning = false;
-                //             ui.ctx().data_mut(|w| w.remove_temp::<usize>("pt".into()));
-                //         }
-                //     }
-
-                //     let col = if d < maxdist {
-                //         Color32::LIGHT_BLUE
-                //     } else {
-                //         Color32::GOLD
-                //     };
-
-                //     ui.painter().rect_filled(
-                //         Rect::from_center_size(Pos2::new(pt.0, pt.1), Vec2::splat(15.)),
-                //         2.,
-                //         col,
-                //     );
-                // }
-
-                // if let Some(pt) = ui.ctx().data(|r| r.get_temp::<usize>("pt".into())) {
-                //     points[pt].0 = cursor_relative.x as u32;
-                //     points[pt].1 = cursor_relative.y as u32;
-                // }
-
-                r
-            }
+            Self::Measure { shapes } => measure_shapes_ui(ui, shapes, geo, block_panning),
             Self::Crop(bounds) => {
                 let mut float_bounds = bounds.map(|b| b as f32 / 10000.);
                 // debug!("Float bounds {:?}", float_bounds);
@@ -1171,301 +1804,731 @@ impl ImageOperation {
                 })
                 .inner
             }
-            _ => ui.label("Filter has no options."),
-        }
-    }
-
-    /// Process all image operators (All things that modify the image and are not "per pixel")
-    pub fn process_image(&self, dyn_img: &mut DynamicImage) -> Result<()> {
-        match dyn_img {
-            DynamicImage::ImageRgba8(img) => {
-                match self {
-                    Self::Blur(amt) => {
-                        if *amt != 0 {
-                            let i = img.clone();
-                            let mut data = i.into_raw();
-                            libblur::stack_blur(
-                                data.as_mut_slice(),
-                                img.width() * 4,
-                                img.width(),
-                                img.height(),
-                                (*amt as u32).clamp(2, 254),
-                                libblur::FastBlurChannels::Channels4,
-                                libblur::ThreadingPolicy::Adaptive,
-                            );
-                            use anyhow::Context;
-                            *img = RgbaImage::from_raw(img.width(), img.height(), data)
-                                .context("Can't construct image from blur result")?;
+            Self::Pad {
+                sides,
+                percent,
+                color,
+            } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+                ui.vertical(|ui| {
+                    let max = if *percent { 100 } else { 10000 };
+                    egui::Grid::new("pad").show(ui, |ui| {
+                        for (i, (side, label)) in sides
+                            .iter_mut()
+                            .zip(["Left ", "Top ", "Right ", "Bottom "])
+                            .enumerate()
+                        {
+                            if ui
+                                .add(egui::DragValue::new(side).range(0..=max).prefix(label))
+                                .changed()
+                            {
+                                r.mark_changed();
+                            }
+                            if i % 2 == 1 {
+                                ui.end_row();
+                            }
+                        }
+                    });
+                    if ui.styled_checkbox(percent, "Percent").changed() {
+                        for side in sides.iter_mut() {
+                            *side = (*side).min(100);
                         }
+                        r.mark_changed();
                     }
-                    Self::Filter3x3(amt) => {
-                        let kernel = amt
-                            .into_iter()
-                            .map(|a| *a as f32 / 100.)
-                            .collect::<Vec<_>>();
-                        *img = imageops::filter3x3(img, &kernel);
+                    if canvas_color_ui(ui, color) {
+                        r.mark_changed();
                     }
-                    Self::LUT(lut_name) => {
-                        use lutgen::identity::correct_image;
-                        let mut external_image = DynamicImage::ImageRgba8(img.clone()).to_rgb8();
-                        if let Some(lut_data) = builtin_luts().get(lut_name) {
-                            let lut_img = image::load_from_memory(&lut_data).unwrap().to_rgb8();
-                            correct_image(&mut external_image, &lut_img);
-                        } else if let Ok(lut_img) = image::open(&lut_name) {
-                            correct_image(&mut external_image, &lut_img.to_rgb8());
+                });
+                r
+            }
+            Self::ExtendAspect {
+                ratio,
+                anchor,
+                color,
+            } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+                ui.vertical(|ui| {
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_id_salt("aspect_presets")
+                            .selected_text(format!("{}:{}", ratio.0, ratio.1))
+                            .show_ui(ui, |ui| {
+                                for preset in [(1, 1), (4, 5), (3, 2), (4, 3), (16, 9), (9, 16)] {
+                                    let label = format!("{}:{}", preset.0, preset.1);
+                                    if ui.selectable_value(ratio, preset, label).clicked() {
+                                        r.mark_changed();
+                                    }
+                                }
+                            });
+                        let r0 = ui.add(egui::DragValue::new(&mut ratio.0).range(1..=100));
+                        ui.label(":");
+                        let r1 = ui.add(egui::DragValue::new(&mut ratio.1).range(1..=100));
+                        if r0.changed() || r1.changed() {
+                            r.mark_changed();
                         }
-                        *img = DynamicImage::ImageRgb8(external_image).to_rgba8();
+                    });
+                    ui.label("Position");
+                    if anchor_ui(ui, anchor) {
+                        r.mark_changed();
                     }
-                    Self::Crop(dim) => {
-                        if *dim != [0, 0, 0, 0] {
-                            let window = cropped_range(dim, &(img.width(), img.height()));
-                            let sub_img = image::imageops::crop_imm(
-                                img, window[0], window[1], window[2], window[3],
-                            );
-                            *img = sub_img.to_image();
-                        }
+                    if canvas_color_ui(ui, color) {
+                        r.mark_changed();
                     }
-                    Self::CropPerspective { points, .. } => {
-                        let img_dim = img.dimensions();
-
-                        let max_width = points[1].0.max(points[3].0);
-                        let min_width = points[0].0.min(points[2].0);
-                        let max_height = points[2].1.max(points[3].1);
-                        let min_height = points[0].1.min(points[1].1);
-                        let x = max_width - min_width;
-                        let y = max_height - min_height;
-
-                        let from = [
-                            (points[0].0 as f32, points[0].1 as f32),
-                            (points[1].0 as f32, points[1].1 as f32),
-                            (points[2].0 as f32, points[2].1 as f32),
-                            (points[3].0 as f32, points[3].1 as f32),
-                        ];
-
-                        let to = [
-                            (0 as f32, 0 as f32),
-                            (img_dim.0 as f32, 0 as f32),
-                            (0 as f32, img_dim.1 as f32),
-                            (img_dim.0 as f32, img_dim.1 as f32),
-                        ];
-
-                        if let Some(proj) =
-                            imageproc::geometric_transformations::Projection::from_control_points(
-                                from, to,
-                            )
+                });
+                r
+            }
+            Self::Vignette {
+                amount,
+                midpoint,
+                roundness,
+                feather,
+            } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+                ui.vertical(|ui| {
+                    ui.label("Amount");
+                    if ui.styled_slider(amount, -100..=100).changed() {
+                        r.mark_changed();
+                    }
+                    ui.label("Midpoint");
+                    if ui.styled_slider(midpoint, 0..=100).changed() {
+                        r.mark_changed();
+                    }
+                    ui.label("Roundness");
+                    if ui.styled_slider(roundness, -100..=100).changed() {
+                        r.mark_changed();
+                    }
+                    ui.label("Feather");
+                    if ui.styled_slider(feather, 0..=100).changed() {
+                        r.mark_changed();
+                    }
+                });
+                r
+            }
+            Self::Grain { amount, size, seed } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+                ui.vertical(|ui| {
+                    ui.label("Amount");
+                    if ui.styled_slider(amount, 0..=100).changed() {
+                        r.mark_changed();
+                    }
+                    ui.label("Size");
+                    if ui.styled_slider(size, 0..=100).changed() {
+                        r.mark_changed();
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Seed");
+                        if ui.add(DragValue::new(seed)).changed() {
+                            r.mark_changed();
+                        }
+                        if ui
+                            .button("Shuffle")
+                            .on_hover_text("Use a new random seed for different grain")
+                            .clicked()
                         {
-                            let default_p: Rgba<u8> = [0, 0, 0, 0].into();
-
-                            *img = imageproc::geometric_transformations::warp(
-                                img,
-                                &proj,
-                                Interpolation::Bicubic,
-                                default_p,
-                            );
-
-                            *img = imageops::resize(img, x, y, imageops::FilterType::CatmullRom);
-                        } else {
-                            error!("Projection failed")
+                            *seed = thread_rng().gen();
+                            r.mark_changed();
                         }
+                    });
+                });
+                r
+            }
+            Self::SplitToning {
+                shadows,
+                highlights,
+                balance,
+            } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
+                ui.vertical(|ui| {
+                    for (label, color) in [("Shadows", shadows), ("Highlights", highlights)] {
+                        ui.horizontal(|ui| {
+                            let mut col = color.map(|c| c as f32 / 255.);
+                            if ui.color_edit_button_rgb(&mut col).changed() {
+                                *color = col.map(|c| (c * 255.) as u8);
+                                r.mark_changed();
+                            }
+                            ui.label(label);
+                        });
                     }
-                    Self::Resize {
-                        dimensions, filter, ..
-                    } => {
-                        if *dimensions != Default::default() {
-                            let filter = match filter {
-                                ScaleFilter::Box => fr::FilterType::Box,
-                                ScaleFilter::Bilinear => fr::FilterType::Bilinear,
-                                ScaleFilter::Hamming => fr::FilterType::Hamming,
-                                ScaleFilter::CatmullRom => fr::FilterType::CatmullRom,
-                                ScaleFilter::Mitchell => fr::FilterType::Mitchell,
-                                ScaleFilter::Lanczos3 => fr::FilterType::Lanczos3,
-                            };
-
-                            let src_image = fr::images::Image::from_vec_u8(
-                                img.width(),
-                                img.height(),
-                                img.clone().into_raw(),
-                                fr::PixelType::U8x4,
-                            )?;
-
-                            // Create container for data of destination image
-                            let mut dst_image = fr::images::Image::new(
-                                dimensions.0,
-                                dimensions.1,
-                                src_image.pixel_type(),
-                            );
-
-                            let mut resizer = fr::Resizer::new();
-
-                            resizer.resize(
-                                &src_image,
-                                &mut dst_image,
-                                Some(
-                                    &ResizeOptions::new().resize_alg(
-                                        fast_image_resize::ResizeAlg::Convolution(filter),
-                                    ),
-                                ),
-                            )?;
+                    ui.label("Balance");
+                    if ui.styled_slider(balance, -100..=100).changed() {
+                        r.mark_changed();
+                    }
+                });
+                r
+            }
+            Self::Text {
+                text,
+                font,
+                size,
+                color,
+                opacity,
+                anchor,
+                margin,
+                rotation,
+            } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
 
-                            *img = anyhow::Context::context(
-                                image::RgbaImage::from_raw(
-                                    dimensions.0,
-                                    dimensions.1,
-                                    dst_image.into_vec(),
-                                ),
-                                "Can't create RgbaImage",
-                            )?;
-                        }
+                ui.vertical(|ui| {
+                    if ui.text_edit_multiline(text).changed() {
+                        r.mark_changed();
                     }
-                    Self::Rotate(angle) => match angle {
-                        90 => *img = image::imageops::rotate90(img),
-                        -90 => *img = image::imageops::rotate270(img),
-                        270 => *img = image::imageops::rotate270(img),
-                        180 => *img = image::imageops::rotate180(img),
-                        _ => (),
-                    },
-                    Self::Flip(vert) => {
-                        if *vert {
-                            *img = image::imageops::flip_vertical(img);
+
+                    egui::ComboBox::from_id_salt("font")
+                        .selected_text(if font.is_empty() {
+                            "Default font"
                         } else {
-                            *img = image::imageops::flip_horizontal(img);
-                        }
-                    }
-                    Self::ScaleImageMinMax => {
-                        //Step 0: Get color channel min and max values
-                        let mut min = 255u8;
-                        let mut max = 0u8;
-
-                        img.chunks_mut(4).for_each(|px| {
-                            min = std::cmp::min(min, px[0]);
-                            min = std::cmp::min(min, px[1]);
-                            min = std::cmp::min(min, px[2]);
-
-                            max = std::cmp::max(max, px[0]);
-                            max = std::cmp::max(max, px[1]);
-                            max = std::cmp::max(max, px[2]);
+                            font.as_str()
+                        })
+                        .show_ui(ui, |ui| {
+                            if ui
+                                .selectable_value(font, String::new(), "Default font")
+                                .clicked()
+                            {
+                                r.mark_changed();
+                            }
+                            for family in system_font_families() {
+                                if ui
+                                    .selectable_value(font, family.clone(), family.as_str())
+                                    .clicked()
+                                {
+                                    r.mark_changed();
+                                }
+                            }
                         });
-                        let min_f = min as f64;
-                        let max_f = max as f64;
 
-                        //Step 1: Don't do zero division
-                        if min != max {
-                            //Step 2: Create 8-Bit LUT
-                            let mut lut: [u8; 256] = [0u8; 256];
+                    ui.horizontal(|ui| {
+                        let mut col = color.map(|c| c as f32 / 255.);
+                        if ui.color_edit_button_rgb(&mut col).changed() {
+                            *color = col.map(|c| (c * 255.) as u8);
+                            r.mark_changed();
+                        }
+                        ui.label("Color");
+                    });
 
-                            for n in min as usize..=max as usize {
-                                let g = n as f64;
-                                lut[n] = (255.0 * (g - min_f) / (max_f - min_f)) as u8;
-                            }
+                    if overlay_ui(ui, size, opacity, anchor, margin, rotation) {
+                        r.mark_changed();
+                    }
+                });
+                r
+            }
+            Self::Watermark {
+                path,
+                size,
+                opacity,
+                anchor,
+                margin,
+                rotation,
+            } => {
+                let mut r = ui.allocate_response(Vec2::ZERO, Sense::hover());
 
-                            //Step 3: Apply 8-Bit LUT
-                            img.par_chunks_mut(4).for_each(|px| {
-                                px[0] = lut[px[0] as usize];
-                                px[1] = lut[px[1] as usize];
-                                px[2] = lut[px[2] as usize];
-                            });
-                        }
+                ui.vertical(|ui| {
+                    if pick_image_ui(ui, settings, "WATERMARK", path) {
+                        r.mark_changed();
                     }
-                    Self::ChromaticAberration(amt) => {
-                        let center = (img.width() as i32 / 2, img.height() as i32 / 2);
-                        let img_c = img.clone();
-
-                        for (x, y, p) in img.enumerate_pixels_mut() {
-                            let dist_to_center = (x as i32 - center.0, y as i32 - center.1);
-                            let dist_to_center = (
-                                (dist_to_center.0 as f32 / center.0 as f32) * *amt as f32 / 10.,
-                                (dist_to_center.1 as f32 / center.1 as f32) * *amt as f32 / 10.,
-                            );
-                            if let Some(l) = img_c.get_pixel_checked(
-                                (x as i32 + dist_to_center.0 as i32).max(0) as u32,
-                                (y as i32 + dist_to_center.1 as i32).max(0) as u32,
-                            ) {
-                                p[0] = l[0];
-                            }
-                        }
+
+                    if overlay_ui(ui, size, opacity, anchor, margin, rotation) {
+                        r.mark_changed();
                     }
+                });
+                r
+            }
+            _ => ui.label("Filter has no options."),
+        }
+    }
 
-                    _ => (),
+    /// Process all image operators (All things that modify the image and are not "per pixel")
+    pub fn process_image(&self, dyn_img: &mut DynamicImage) -> Result<()> {
+        match self {
+            // These work on all color types directly
+            Self::ColorConverter(t) => {
+                *dyn_img = t.convert(dyn_img);
+            }
+            Self::Flip(vert) => {
+                if *vert {
+                    *dyn_img = dyn_img.flipv();
+                } else {
+                    *dyn_img = dyn_img.fliph();
                 }
             }
-            // These must work on all types
-            _ => {
-                info!("Proc with color type {:?}", dyn_img.color());
-
-                match self {
-                    Self::ColorConverter(t) => match t {
-                        ColorTypeExt::L8 => {
-                            *dyn_img = DynamicImage::ImageLuma8(dyn_img.to_luma8());
-                        }
-                        ColorTypeExt::La8 => {
-                            *dyn_img = DynamicImage::ImageLumaA8(dyn_img.to_luma_alpha8());
-                        }
-                        ColorTypeExt::Rgb8 => {
-                            *dyn_img = DynamicImage::ImageRgb8(dyn_img.to_rgb8());
-                        }
-                        ColorTypeExt::Rgba8 => {
-                            *dyn_img = DynamicImage::ImageRgba8(dyn_img.to_rgba8());
-                        }
-                        ColorTypeExt::L16 => {
-                            *dyn_img = DynamicImage::ImageLuma16(dyn_img.to_luma16());
-                        }
-                        ColorTypeExt::La16 => {
-                            *dyn_img = DynamicImage::ImageLumaA16(dyn_img.to_luma_alpha16());
-                        }
-                        ColorTypeExt::Rgb16 => {
-                            *dyn_img = DynamicImage::ImageRgb16(dyn_img.to_rgb16());
-                        }
-                        ColorTypeExt::Rgba16 => {
-                            *dyn_img = DynamicImage::ImageRgba16(dyn_img.to_rgba16());
-                        }
-                        ColorTypeExt::Rgb32F => {
-                            *dyn_img = DynamicImage::ImageRgb32F(dyn_img.to_rgb32f());
-                        }
-                        ColorTypeExt::Rgba32F => {
-                            *dyn_img = DynamicImage::ImageRgba32F(dyn_img.to_rgba32f());
-                        }
-                    },
-                    Self::Flip(vert) => {
-                        if *vert {
-                            *dyn_img = dyn_img.flipv();
+            Self::Rotate(angle) => match angle {
+                90 => *dyn_img = dyn_img.rotate90(),
+                -90 => *dyn_img = dyn_img.rotate270(),
+                270 => *dyn_img = dyn_img.rotate270(),
+                180 => *dyn_img = dyn_img.rotate180(),
+                _ => (),
+            },
+            Self::RotateFree {
+                angle,
+                filter,
+                crop,
+            } => {
+                if *angle % 36000 != 0 {
+                    let degrees = *angle as f32 / 100.;
+                    let size = (dyn_img.width(), dyn_img.height());
+                    let interpolation = filter.interpolation();
+                    if let Some(img) = dyn_img.as_rgba8() {
+                        *dyn_img = DynamicImage::ImageRgba8(rotate_image(
+                            img,
+                            degrees,
+                            interpolation,
+                            Rgba([0, 0, 0, 0]),
+                        ));
+                    } else {
+                        let color_type = ColorTypeExt::from_image(dyn_img.color());
+                        // keep the empty corners transparent, unless they are cropped away
+                        let color_type = if *crop {
+                            color_type
                         } else {
-                            *dyn_img = dyn_img.fliph();
-                        }
+                            color_type.with_alpha()
+                        };
+                        let rotated = rotate_image(
+                            &dyn_img.to_rgba32f(),
+                            degrees,
+                            interpolation,
+                            Rgba([0., 0., 0., 0.]),
+                        );
+                        *dyn_img = color_type.convert(&DynamicImage::ImageRgba32F(rotated));
                     }
-                    Self::Rotate(angle) => match angle {
-                        90 => *dyn_img = dyn_img.rotate90(),
-                        -90 => *dyn_img = dyn_img.rotate270(),
-                        270 => *dyn_img = dyn_img.rotate270(),
-                        180 => *dyn_img = dyn_img.rotate180(),
-                        _ => (),
-                    },
-                    Self::Blur(amt) => {
-                        if *amt != 0 {
-                            *dyn_img = dyn_img.fast_blur(*amt as f32 / 2.5);
-                        }
+                    if *crop {
+                        let inner = largest_inscribed_rect(size, degrees.to_radians());
+                        *dyn_img = dyn_img.crop_imm(
+                            dyn_img.width().saturating_sub(inner.0) / 2,
+                            dyn_img.height().saturating_sub(inner.1) / 2,
+                            inner.0,
+                            inner.1,
+                        );
                     }
-                    Self::Resize {
-                        dimensions, filter, ..
-                    } => {
-                        let filter = match filter {
-                            ScaleFilter::CatmullRom => imageops::FilterType::CatmullRom,
-                            ScaleFilter::Lanczos3 => imageops::FilterType::Lanczos3,
-                            _ => imageops::FilterType::Gaussian,
-                        };
-                        *dyn_img = dyn_img.resize(dimensions.0, dimensions.0, filter);
+                }
+            }
+            Self::Crop(dim) => {
+                if *dim != [0, 0, 0, 0] {
+                    let window = cropped_range(dim, &(dyn_img.width(), dyn_img.height()));
+                    *dyn_img = dyn_img.crop_imm(window[0], window[1], window[2], window[3]);
+                }
+            }
+            Self::Resize {
+                dimensions, filter, ..
+            } => {
+                if *dimensions != Default::default() {
+                    *dyn_img = resize_image(dyn_img, *dimensions, filter)?;
+                }
+            }
+            Self::Blur(amt) => {
+                if *amt != 0 {
+                    if let Some(img) = dyn_img.as_mut_rgba8() {
+                        self.process_rgba8(img)?;
+                    } else {
+                        *dyn_img = dyn_img.fast_blur(*amt as f32 / 2.5);
                     }
-                    _ => {
-                        bail!("This color type is unsupported: {:?}", dyn_img.color())
+                }
+            }
+            Self::Pad {
+                sides,
+                percent,
+                color,
+            } => {
+                if *sides != [0, 0, 0, 0] {
+                    // wide enough to never overflow, the canvas size is checked below
+                    let (width, height) = (dyn_img.width() as u64, dyn_img.height() as u64);
+                    let sides = sides.map(|s| s as u64);
+                    let [left, top, right, bottom] = if *percent {
+                        [
+                            sides[0] * width / 100,
+                            sides[1] * height / 100,
+                            sides[2] * width / 100,
+                            sides[3] * height / 100,
+                        ]
+                    } else {
+                        sides
+                    };
+                    let canvas = canvas_size(width + left + right, height + top + bottom)?;
+                    *dyn_img = extend_canvas(dyn_img, canvas, (left as i64, top as i64), *color);
+                }
+            }
+            Self::ExtendAspect {
+                ratio,
+                anchor,
+                color,
+            } => {
+                if ratio.0 != 0 && ratio.1 != 0 {
+                    let (width, height) = (dyn_img.width(), dyn_img.height());
+                    let target = ratio.0 as f64 / ratio.1 as f64;
+                    // Only ever grow one side, so the image is never cut
+                    let (w, h) = (width as f64, height as f64);
+                    let canvas = if w / h < target {
+                        ((h * target).round().max(w), h)
+                    } else {
+                        (w, (w / target).round().max(h))
+                    };
+                    let canvas = canvas_size(canvas.0 as u64, canvas.1 as u64)?;
+                    let pos = anchor.place(canvas, (width, height), 0);
+                    *dyn_img = extend_canvas(dyn_img, canvas, pos, *color);
+                }
+            }
+            Self::Text {
+                text,
+                font,
+                size,
+                color,
+                opacity,
+                anchor,
+                margin,
+                rotation,
+            } => {
+                if !text.is_empty() {
+                    let font = load_font(font)?;
+                    let line_height = (dyn_img.height() as f32 * *size as f32 / 100.).max(1.);
+                    let lines = text.lines().collect::<Vec<_>>();
+                    let width = lines
+                        .iter()
+                        .map(|line| text_size(line_height, &font, line).0)
+                        .max()
+                        .unwrap_or_default();
+
+                    // Render the text coverage first and color it after rotating, so edges stay clean
+                    let mut coverage = GrayImage::new(
+                        width.max(1),
+                        (line_height * lines.len() as f32).ceil() as u32,
+                    );
+                    for (i, line) in lines.iter().enumerate() {
+                        // align lines within the block like the block is aligned on the image
+                        let line_width = text_size(line_height, &font, line).0;
+                        let x = anchor.place((width, 0), (line_width, 0), 0).0;
+                        let y = i as f32 * line_height;
+                        draw_text_mut(
+                            &mut coverage,
+                            Luma([255]),
+                            x as i32,
+                            y as i32,
+                            line_height,
+                            &font,
+                            line,
+                        );
+                    }
+                    let coverage = rotate_image(
+                        &coverage,
+                        *rotation as f32,
+                        Interpolation::Bilinear,
+                        Luma([0]),
+                    );
+
+                    let layer = RgbaImage::from_fn(coverage.width(), coverage.height(), |x, y| {
+                        let alpha = coverage.get_pixel(x, y)[0] as u32 * *opacity as u32 / 255;
+                        Rgba([color[0], color[1], color[2], alpha as u8])
+                    });
+                    let pos = anchor.place(
+                        (dyn_img.width(), dyn_img.height()),
+                        layer.dimensions(),
+                        (dyn_img.height() as f32 * *margin as f32 / 100.) as i64,
+                    );
+                    blend_layer(dyn_img, &layer, pos);
+                }
+            }
+            Self::Watermark {
+                path,
+                size,
+                opacity,
+                anchor,
+                margin,
+                rotation,
+            } => {
+                if !path.is_empty() {
+                    let mark = load_watermark(path)?;
+                    let width = (dyn_img.width() as f32 * *size as f32 / 100.).max(1.) as u32;
+                    let height =
+                        ((mark.height() as f32 * width as f32 / mark.width() as f32) as u32).max(1);
+                    let mut mark = imageops::resize(
+                        mark.as_ref(),
+                        width,
+                        height,
+                        imageops::FilterType::CatmullRom,
+                    );
+                    for p in mark.pixels_mut() {
+                        p[3] = (p[3] as u32 * *opacity as u32 / 255) as u8;
                     }
+                    let mark = rotate_image(
+                        &mark,
+                        *rotation as f32,
+                        Interpolation::Bilinear,
+                        Rgba([0, 0, 0, 0]),
+                    );
+
+                    let pos = anchor.place(
+                        (dyn_img.width(), dyn_img.height()),
+                        mark.dimensions(),
+                        (dyn_img.height() as f32 * *margin as f32 / 100.) as i64,
+                    );
+                    blend_layer(dyn_img, &mark, pos);
+                }
+            }
+            Self::Filter3x3(_)
+            | Self::LUT(_)
+            | Self::CropPerspective { .. }
+            | Self::ScaleImageMinMax
+            | Self::ChromaticAberration(_) => {
+                if let Some(img) = dyn_img.as_mut_rgba8() {
+                    self.process_rgba8(img)?;
+                } else {
+                    self.process_as_rgba32f(dyn_img)?;
                 }
             }
+            // Float only, which is lossless for 8 bit images as well
+            Self::Vignette { .. } | Self::Grain { .. } | Self::Expression(_) => {
+                self.process_as_rgba32f(dyn_img)?
+            }
+            _ => (),
         }
 
         Ok(())
     }
 
-    /// Process a single pixel.
-    pub fn process_pixel(&self, p: &mut Vector4<f32>) -> Result<()> {
+    /// Process as 32 bit float and convert back, so no precision is lost
+    fn process_as_rgba32f(&self, dyn_img: &mut DynamicImage) -> Result<()> {
+        debug!("Proc with color type {:?}", dyn_img.color());
+        let color_type = ColorTypeExt::from_image(dyn_img.color());
+        let mut img = dyn_img.to_rgba32f();
+        self.process_rgba32f(&mut img)?;
+        *dyn_img = color_type.convert(&DynamicImage::ImageRgba32F(img));
+        Ok(())
+    }
+
+    /// Image operators specialized for 8 bit RGBA
+    fn process_rgba8(&self, img: &mut RgbaImage) -> Result<()> {
+        match self {
+            Self::Blur(amt) => {
+                let i = img.clone();
+                let mut data = i.into_raw();
+                libblur::stack_blur(
+                    data.as_mut_slice(),
+                    img.width() * 4,
+                    img.width(),
+                    img.height(),
+                    (*amt as u32).clamp(2, 254),
+                    libblur::FastBlurChannels::Channels4,
+                    libblur::ThreadingPolicy::Adaptive,
+                );
+                use anyhow::Context;
+                *img = RgbaImage::from_raw(img.width(), img.height(), data)
+                    .context("Can't construct image from blur result")?;
+            }
+            Self::Filter3x3(amt) => {
+                let kernel = amt
+                    .into_iter()
+                    .map(|a| *a as f32 / 100.)
+                    .collect::<Vec<_>>();
+                *img = imageops::filter3x3(img, &kernel);
+            }
+            Self::LUT(lut_name) => {
+                use lutgen::identity::correct_image;
+                let mut external_image = DynamicImage::ImageRgba8(img.clone()).to_rgb8();
+                if let Some(lut_img) = load_lut(lut_name) {
+                    correct_image(&mut external_image, &lut_img);
+                }
+                *img = DynamicImage::ImageRgb8(external_image).to_rgba8();
+            }
+            Self::CropPerspective { points, .. } => {
+                let default_p: Rgba<u8> = [0, 0, 0, 0].into();
+                if let Some((warped, size)) = warp_perspective(img, points, default_p) {
+                    *img =
+                        imageops::resize(&warped, size.0, size.1, imageops::FilterType::CatmullRom);
+                }
+            }
+            Self::ScaleImageMinMax => {
+                //Step 0: Get color channel min and max values
+                let mut min = 255u8;
+                let mut max = 0u8;
+
+                img.chunks_mut(4).for_each(|px| {
+                    min = std::cmp::min(min, px[0]);
+                    min = std::cmp::min(min, px[1]);
+                    min = std::cmp::min(min, px[2]);
+
+                    max = std::cmp::max(max, px[0]);
+                    max = std::cmp::max(max, px[1]);
+                    max = std::cmp::max(max, px[2]);
+                });
+                let min_f = min as f64;
+                let max_f = max as f64;
+
+                //Step 1: Don't do zero division
+                if min != max {
+                    //Step 2: Create 8-Bit LUT
+                    let mut lut: [u8; 256] = [0u8; 256];
+
+                    for n in min as usize..=max as usize {
+                        let g = n as f64;
+                        lut[n] = (255.0 * (g - min_f) / (max_f - min_f)) as u8;
+                    }
+
+                    //Step 3: Apply 8-Bit LUT
+                    img.par_chunks_mut(4).for_each(|px| {
+                        px[0] = lut[px[0] as usize];
+                        px[1] = lut[px[1] as usize];
+                        px[2] = lut[px[2] as usize];
+                    });
+                }
+            }
+            Self::ChromaticAberration(amt) => chromatic_aberration(img, *amt),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Image operators for all other color types. These are processed as 32 bit float
+    /// and values outside of 0-1 are kept, so HDR images don't clip.
+    fn process_rgba32f(&self, img: &mut Rgba32FImage) -> Result<()> {
+        match self {
+            Self::Filter3x3(amt) => {
+                let kernel = amt.map(|a| a as f32 / 100.);
+                // normalize like imageops::filter3x3 does
+                let sum = kernel.iter().sum::<f32>();
+                let sum = if sum == 0.0 { 1.0 } else { sum };
+                let src = img.clone();
+                let (width, height) = (src.width() as i64, src.height() as i64);
+                img.par_chunks_mut(width as usize * 4)
+                    .enumerate()
+                    .for_each(|(y, row)| {
+                        for x in 0..width {
+                            let mut acc = [0f32; 4];
+                            for (i, k) in kernel.iter().enumerate() {
+                                // repeat the border pixels
+                                let sx = (x + i as i64 % 3 - 1).clamp(0, width - 1);
+                                let sy = (y as i64 + i as i64 / 3 - 1).clamp(0, height - 1);
+                                let p = src.get_pixel(sx as u32, sy as u32);
+                                for (a, c) in acc.iter_mut().zip(p.0) {
+                                    *a += c * k;
+                                }
+                            }
+                            let x = x as usize * 4;
+                            for (c, a) in row[x..x + 4].iter_mut().zip(acc) {
+                                *c = a / sum;
+                            }
+                        }
+                    });
+            }
+            Self::LUT(lut_name) => {
+                if let Some(lut_img) = load_lut(lut_name) {
+                    img.par_chunks_mut(4)
+                        .for_each(|px| apply_hald_clut(px, &lut_img));
+                }
+            }
+            Self::CropPerspective { points, .. } => {
+                let default_p: Rgba<f32> = [0., 0., 0., 0.].into();
+                if let Some((warped, size)) = warp_perspective(img, points, default_p) {
+                    *img = resize_image(
+                        &DynamicImage::ImageRgba32F(warped),
+                        size,
+                        &ScaleFilter::CatmullRom,
+                    )?
+                    .into_rgba32f();
+                }
+            }
+            Self::ScaleImageMinMax => {
+                let (min, max) = img
+                    .pixels()
+                    .flat_map(|px| [px[0], px[1], px[2]])
+                    .fold((f32::MAX, f32::MIN), |(min, max), c| {
+                        (min.min(c), max.max(c))
+                    });
+
+                // Don't do zero division
+                if min < max {
+                    img.par_chunks_mut(4).for_each(|px| {
+                        for c in px.iter_mut().take(3) {
+                            *c = (*c - min) / (max - min);
+                        }
+                    });
+                }
+            }
+            Self::ChromaticAberration(amt) => chromatic_aberration(img, *amt),
+            Self::Expression(expr) => {
+                let program = ExpressionProgram::compile(&expr.source, img)?;
+                let width = img.width();
+                img.par_chunks_mut(width as usize * 4)
+                    .enumerate()
+                    .for_each_init(
+                        || program.context.clone(),
+                        |context, (y, row)| {
+                            for (x, px) in row.chunks_mut(4).enumerate() {
+                                program.eval(context, x as u32, y as u32, px);
+                            }
+                        },
+                    );
+            }
+            Self::Vignette {
+                amount,
+                midpoint,
+                roundness,
+                feather,
+            } => {
+                let amount = *amount as f32 / 100.;
+                let roundness = *roundness as f32 / 100.;
+                let start = *midpoint as f32 / 100.;
+                let end = start + (*feather as f32 / 100.).max(0.01);
+                let (width, height) = img.dimensions();
+                // stretch of each axis for a circle that fits the image width or height
+                let short_side = width.min(height) as f32;
+                let aspect = (width as f32 / short_side, height as f32 / short_side);
+
+                img.par_chunks_mut(width as usize * 4)
+                    .enumerate()
+                    .for_each(|(y, row)| {
+                        let v = (y as f32 + 0.5) / height as f32 * 2. - 1.;
+                        for (x, px) in row.chunks_mut(4).enumerate() {
+                            let u = (x as f32 + 0.5) / width as f32 * 2. - 1.;
+                            let dist = if roundness >= 0. {
+                                let ellipse = u.hypot(v);
+                                let circle =
+                                    (u * aspect.0).hypot(v * aspect.1) / aspect.0.max(aspect.1);
+                                lerp(ellipse..=circle, roundness)
+                            } else {
+                                // superellipse, gets more rectangular with lower roundness
+                                let exp = 2. - roundness * 8.;
+                                (u.abs().powf(exp) + v.abs().powf(exp)).powf(1. / exp)
+                            };
+                            let t = ((dist - start) / (end - start)).clamp(0., 1.);
+                            let weight = t * t * (3. - 2. * t);
+                            for c in px.iter_mut().take(3) {
+                                if amount < 0. {
+                                    *c *= 1. + amount * weight;
+                                } else {
+                                    *c = lerp(*c..=1., amount * weight);
+                                }
+                            }
+                        }
+                    });
+            }
+            Self::Grain { amount, size, seed } => {
+                let (width, height) = img.dimensions();
+                // larger grain comes from a coarser noise grid that gets interpolated
+                let cell = 1. + *size as f32 / 100. * 4.;
+                let grid_width = (width as f32 / cell).ceil().max(1.) as u32;
+                let grid_height = (height as f32 / cell).ceil().max(1.) as u32;
+                // seeded, so the grain does not change each time the stack is processed
+                let mut rng = ChaCha8Rng::seed_from_u64(*seed as u64);
+                let noise: Image<Luma<f32>> = Image::from_fn(grid_width, grid_height, |_, _| {
+                    // the average of two samples is closer to the distribution of real grain
+                    Luma([(rng.gen::<f32>() + rng.gen::<f32>()) / 2.])
+                });
+                let noise = imageops::resize(&noise, width, height, imageops::FilterType::Triangle);
+                let amount = *amount as f32 / 100. * 0.5;
+
+                img.par_chunks_mut(4).enumerate().for_each(|(i, px)| {
+                    let n = (noise.as_raw()[i] - 0.5) * 2.;
+                    // grain shows most in the midtones
+                    let l = luminance(px).clamp(0., 1.);
+                    let weight = 4. * l * (1. - l);
+                    for c in px.iter_mut().take(3) {
+                        *c += n * amount * weight;
+                    }
+                });
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Process a single pixel.
+    pub fn process_pixel(&self, p: &mut Vector4<f32>) -> Result<()> {
         match self {
             Self::Brightness(amt) => {
                 let amt = *amt as f32 / 255.;
@@ -1506,42 +2569,10 @@ impl ImageOperation {
             Self::GradientMap(col) => {
                 let brightness = 0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2];
                 // let res = interpolate_spline(col, brightness);
-                // let res = interpolate(col, brightness);
-                let res = interpolate_u8(col, (brightness * 255.) as u8);
-                p[0] = res[0] as f32 / 255.;
-                p[1] = res[1] as f32 / 255.;
-                p[2] = res[2] as f32 / 255.;
-            }
-            Self::Expression(expr) => {
-                let mut context: HashMapContext<DefaultNumericTypes> = context_map! {
-                    "r" => float p[0],
-                    "g" => float p[1],
-                    "b" => float p[2],
-                    "a" => float p[3],
-                }?;
-
-                if eval_empty_with_context_mut(expr, &mut context).is_ok() {
-                    if let Some(r) = context.get_value("r") {
-                        if let Ok(r) = r.as_float() {
-                            p[0] = r as f32
-                        }
-                    }
-                    if let Some(g) = context.get_value("g") {
-                        if let Ok(g) = g.as_float() {
-                            p[1] = g as f32
-                        }
-                    }
-                    if let Some(b) = context.get_value("b") {
-                        if let Ok(b) = b.as_float() {
-                            p[2] = b as f32
-                        }
-                    }
-                    if let Some(a) = context.get_value("a") {
-                        if let Ok(a) = a.as_float() {
-                            p[3] = a as f32
-                        }
-                    }
-                }
+                let res = interpolate(col, brightness);
+                p[0] = res[0];
+                p[1] = res[1];
+                p[2] = res[2];
             }
             Self::Posterize(levels) => {
                 p[0] = (p[0] * *levels as f32).round() / *levels as f32;
@@ -1618,6 +2649,25 @@ impl ImageOperation {
                 p[1] = (factor * p[1] - 0.5) + 0.5;
                 p[2] = (factor * p[2] - 0.5) + 0.5;
             }
+            Self::SplitToning {
+                shadows,
+                highlights,
+                balance,
+            } => {
+                // Only add the hue of the tints, so their brightness does not shift the image
+                let tint = |c: &[u8; 3]| {
+                    let c = [c[0] as f32 / 255., c[1] as f32 / 255., c[2] as f32 / 255.];
+                    let l = luminance(&c);
+                    Vector3::new(c[0] - l, c[1] - l, c[2] - l)
+                };
+                let pivot = 0.5 + *balance as f32 / 200.;
+                let t = (luminance(p.as_slice()) - pivot + 0.5).clamp(0., 1.);
+                let highlight_weight = t * t * (3. - 2. * t);
+                let offset = tint(shadows).lerp(&tint(highlights), highlight_weight);
+                p[0] += offset[0];
+                p[1] += offset[1];
+                p[2] += offset[2];
+            }
             _ => (),
         }
         Ok(())
@@ -1652,100 +2702,1476 @@ pub fn builtin_luts() -> HashMap<String, Vec<u8>> {
     luts
 }
 
-pub fn process_pixels(dynimage: &mut DynamicImage, operators: &Vec<ImageOperation>) -> Result<()> {
-    match dynimage {
-        DynamicImage::ImageRgb8(buffer) => {
-            buffer.par_chunks_mut(3).for_each(|px| {
-                let mut float_pixel =
-                    Vector4::new(px[0] as f32, px[1] as f32, px[2] as f32, 1.0 as f32) / 255.;
-                // run pixel operations
-                for operation in operators {
-                    if let Err(e) = operation.process_pixel(&mut float_pixel) {
-                        error!("{e}")
+/// Sliders shared by the overlay operators. Returns true if anything changed.
+fn overlay_ui(
+    ui: &mut Ui,
+    size: &mut u8,
+    opacity: &mut u8,
+    anchor: &mut Anchor,
+    margin: &mut u8,
+    rotation: &mut i16,
+) -> bool {
+    let mut changed = false;
+    ui.label("Size");
+    changed |= ui.styled_slider(size, 1..=100).changed();
+    ui.label("Opacity");
+    changed |= ui.styled_slider(opacity, 0..=255).changed();
+    ui.label("Margin");
+    changed |= ui.styled_slider(margin, 0..=50).changed();
+    ui.label("Rotation");
+    changed |= ui.styled_slider(rotation, -180..=180).changed();
+    ui.label("Position");
+    changed |= anchor_ui(ui, anchor);
+    changed
+}
+
+/// Pick an image file, returns true if `path` changed
+fn pick_image_ui(
+    ui: &mut Ui,
+    settings: &mut VolatileSettings,
+    id: &str,
+    path: &mut String,
+) -> bool {
+    let mut changed = false;
+    ui.label(
+        Path::new(path)
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or("No image selected".into()),
+    );
+
+    #[cfg(not(feature = "file_open"))]
+    {
+        if ui.button("Load image").clicked() {
+            ui.ctx().memory_mut(|w| w.open_popup(Id::new(id)));
+        }
+
+        if ui.ctx().memory(|w| w.is_popup_open(Id::new(id))) {
+            filebrowser::browse_modal(
+                false,
+                SUPPORTED_EXTENSIONS,
+                settings,
+                |p| {
+                    *path = p.to_string_lossy().to_string();
+                    changed = true;
+                },
+                ui.ctx(),
+            );
+        }
+    }
+
+    #[cfg(feature = "file_open")]
+    {
+        _ = id;
+        if ui.button("Load image").clicked() {
+            if let Some(file) = rfd::FileDialog::new()
+                .set_directory(settings.last_open_directory.clone())
+                .pick_file()
+            {
+                *path = file.to_string_lossy().to_string();
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Let the user draw a line along something that should be level, like a horizon.
+/// Returns the clockwise correction in degrees once the line is finished.
+fn straighten_ui(ui: &mut Ui, geo: &ImageGeometry, block_panning: &mut bool) -> Option<f32> {
+    // per operator, so each rotation has its own line
+    let id = ui.id().with("straighten");
+    let start_id = ui.id().with("straighten_start");
+    let mut active = ui.data(|r| r.get_temp::<bool>(id)).unwrap_or_default();
+
+    if ui
+        .selectable_label(active, format!("{PENCIL_SIMPLE_LINE} Straighten"))
+        .on_hover_text("Draw a line along the horizon or a vertical edge")
+        .clicked()
+    {
+        active = !active;
+        ui.data_mut(|w| w.insert_temp(id, active));
+    }
+    if !active {
+        return None;
+    }
+
+    let cursor_abs = ui.input(|i| i.pointer.hover_pos())?;
+    let start = ui.data(|r| r.get_temp::<Pos2>(start_id));
+
+    // only start on the image, not on the ui
+    if start.is_none()
+        && ui.input(|i| i.pointer.primary_pressed())
+        && !ui.ctx().is_pointer_over_area()
+    {
+        *block_panning = true;
+        ui.data_mut(|w| w.insert_temp(start_id, cursor_abs));
+        return None;
+    }
+
+    let start = start?;
+    let delta = cursor_abs - start;
+    // angle of the line, folded to -90..90
+    let mut line_angle = delta.y.atan2(delta.x).to_degrees();
+    if line_angle > 90. {
+        line_angle -= 180.;
+    } else if line_angle < -90. {
+        line_angle += 180.;
+    }
+    // steep lines are meant to become vertical
+    let correction = if line_angle > 45. {
+        90. - line_angle
+    } else if line_angle < -45. {
+        -90. - line_angle
+    } else {
+        -line_angle
+    };
+
+    ui.painter()
+        .line_segment([start, cursor_abs], Stroke::new(2., Color32::GOLD));
+    ui.painter().text(
+        cursor_abs + vec2(0., -20.),
+        Align2::CENTER_CENTER,
+        format!("{correction:.2}°"),
+        FontId::proportional(14.),
+        Color32::GOLD,
+    );
+
+    if ui.input(|i| i.pointer.primary_released()) {
+        *block_panning = false;
+        ui.data_mut(|w| {
+            w.remove_temp::<Pos2>(start_id);
+            w.insert_temp(id, false);
+        });
+        // ignore clicks without a line
+        if delta.length() / geo.scale.max(f32::EPSILON) > 2. {
+            return Some(correction);
+        }
+    }
+    None
+}
+
+/// Settings of a mask, and placing or painting it on the image
+fn mask_ui(ui: &mut Ui, mask: &mut Mask, geo: &ImageGeometry, block_panning: &mut bool) -> bool {
+    let mut changed = false;
+    let ellipse = matches!(mask.shape, MaskShape::Ellipse(_));
+    egui::Grid::new("mask").num_columns(2).show(ui, |ui| {
+        match &mut mask.shape {
+            MaskShape::Rectangle(rect) | MaskShape::Ellipse(rect) => {
+                for (label, value) in ["X", "Y", "Width", "Height"].into_iter().zip(rect) {
+                    ui.label(label);
+                    if ui.add(relative_drag_value(value)).changed() {
+                        changed = true;
                     }
+                    ui.end_row();
                 }
-                float_pixel *= 255.;
-                px[0] = (float_pixel[0]) as u8;
-                px[1] = (float_pixel[1]) as u8;
-                px[2] = (float_pixel[2]) as u8;
-            });
+            }
+            MaskShape::Luminance((low, high)) => {
+                ui.label("From");
+                if ui.styled_slider(low, 0..=255).changed() {
+                    changed = true;
+                }
+                ui.end_row();
+                ui.label("To");
+                if ui.styled_slider(high, 0..=255).changed() {
+                    changed = true;
+                }
+                ui.end_row();
+            }
+            MaskShape::Painted(strokes) => {
+                ui.label(format!("{} strokes", strokes.len()));
+                if ui.button("Clear").clicked() {
+                    strokes.clear();
+                    changed = true;
+                }
+                ui.end_row();
+            }
         }
-        DynamicImage::ImageRgba8(buffer) => {
-            buffer.par_chunks_mut(4).for_each(|px| {
-                let mut float_pixel =
-                    Vector4::new(px[0] as f32, px[1] as f32, px[2] as f32, px[3] as f32) / 255.;
-                // run pixel operations
-                for operation in operators {
-                    if let Err(e) = operation.process_pixel(&mut float_pixel) {
-                        error!("{e}")
-                    }
+        ui.label("Feather");
+        if ui.styled_slider(&mut mask.feather, 0..=100).changed() {
+            changed = true;
+        }
+        ui.end_row();
+        ui.label("Invert");
+        if ui.styled_checkbox(&mut mask.invert, "").changed() {
+            changed = true;
+        }
+        ui.end_row();
+    });
+
+    match &mut mask.shape {
+        MaskShape::Rectangle(rect) | MaskShape::Ellipse(rect) => {
+            if let Some(drawn) = mask_draw_ui(ui, *rect, ellipse, geo, block_panning) {
+                *rect = drawn;
+                changed = true;
+            }
+        }
+        MaskShape::Painted(strokes) => {
+            if mask_paint_ui(ui, strokes, geo, block_panning) {
+                changed = true;
+            }
+        }
+        MaskShape::Luminance(_) => {}
+    }
+    changed
+}
+
+/// Edit a value in 1/10000 of the image size as percentage
+fn relative_drag_value(value: &mut u16) -> DragValue<'_> {
+    DragValue::new(value)
+        .range(0..=10000)
+        .speed(10.)
+        .custom_formatter(|v, _| format!("{:.1}%", v / 100.))
+        .custom_parser(|s| {
+            s.trim_end_matches('%')
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .map(|v| v * 100.)
+        })
+}
+
+/// The pointer position in 1/10000 of the image size
+fn pointer_on_image(ui: &Ui, geo: &ImageGeometry) -> Option<(u16, u16)> {
+    let pos = ui.input(|i| i.pointer.hover_pos())?;
+    let size = Vector2::new(
+        geo.dimensions.0.max(1) as f32,
+        geo.dimensions.1.max(1) as f32,
+    );
+    let p = pos_from_coord(geo.offset, Vector2::new(pos.x, pos.y), size, geo.scale);
+    Some((
+        (p.x / size.x * 10000.) as u16,
+        (p.y / size.y * 10000.) as u16,
+    ))
+}
+
+/// Draw measure shapes with live readouts. Their points can be moved by dragging the handles.
+fn measure_shapes_ui(
+    ui: &mut Ui,
+    shapes: &mut [MeasureShape],
+    geo: &ImageGeometry,
+    block_panning: &mut bool,
+) -> Response {
+    // create a fake response to alter
+    let mut r = ui.allocate_response(Vec2::ZERO, Sense::click_and_drag());
+    let calibration = ui
+        .ctx()
+        .data(|r| r.get_temp::<Calibration>(Id::new(CALIBRATION_ID)))
+        .unwrap_or_default();
+    let to_screen = |p: &(u32, u32)| {
+        Pos2::new(
+            geo.scale * p.0 as f32 + geo.offset.x,
+            geo.scale * p.1 as f32 + geo.offset.y,
+        )
+    };
+
+    // draw shapes
+    for shape in shapes.iter() {
+        let color = shape.color();
+        let text_color = Color32::from_rgb(color[0], color[1], color[2]);
+        let stroke = Stroke::new(shape.width() as f32, text_color);
+        let screen = shape.points().iter().map(to_screen).collect::<Vec<_>>();
+        let label = |pos: Pos2, text: String| {
+            ui.painter().text(
+                pos,
+                Align2::CENTER_CENTER,
+                text,
+                FontId::proportional(14.),
+                text_color,
+            );
+        };
+
+        match shape {
+            MeasureShape::Line { points, .. } => {
+                for (p, orig) in screen.chunks_exact(2).zip(points.chunks_exact(2)) {
+                    ui.painter().line_segment([p[0], p[1]], stroke);
+                    let a = (orig[0].0 as f64, orig[0].1 as f64);
+                    let b = (orig[1].0 as f64, orig[1].1 as f64);
+                    label(
+                        p[0].lerp(p[1], 0.5) + Vec2::new(0., -14.),
+                        format!(
+                            "{} {:.1}°",
+                            calibration.format_length(calibration.length(a, b)),
+                            calibration.angle((a.0 + 1., a.1), a, b)
+                        ),
+                    );
                 }
-                float_pixel *= 255.;
-                px[0] = (float_pixel[0]) as u8;
-                px[1] = (float_pixel[1]) as u8;
-                px[2] = (float_pixel[2]) as u8;
-                px[3] = (float_pixel[3]) as u8;
-            });
+            }
+            MeasureShape::Rect { width, .. } => {
+                let [min, max, ..] = screen.as_slice() else {
+                    continue;
+                };
+                let rect = Rect::from_two_pos(*min, *max);
+
+                ui.painter().rect_stroke(
+                    rect,
+                    0.0,
+                    Stroke::new(*width as f32, Color32::BLACK),
+                    StrokeKind::Inside,
+                );
+
+                ui.painter().rect_filled(rect, 0.0, Color32::BLACK);
+
+                label(
+                    rect.expand(14.).center_bottom(),
+                    calibration.format_size(shape.box_size()),
+                );
+
+                ui.painter().line_segment(
+                    [rect.left_center(), rect.right_center()],
+                    Stroke::new(1., Color32::from_rgba_unmultiplied(255, 255, 255, 10)),
+                );
+
+                ui.painter().line_segment(
+                    [rect.center_top(), rect.center_bottom()],
+                    Stroke::new(1., Color32::from_rgba_unmultiplied(255, 255, 255, 10)),
+                );
+            }
+            MeasureShape::Angle { .. } => {
+                ui.painter().line(screen.clone(), stroke);
+                if let (Some(angle), Some(vertex)) = (shape.angle_with(&calibration), screen.get(1))
+                {
+                    label(*vertex + Vec2::new(0., -18.), format!("{angle:.1}°"));
+                }
+            }
+            MeasureShape::Polyline { .. } => {
+                ui.painter().line(screen.clone(), stroke);
+                if let Some(last) = screen.last() {
+                    label(
+                        *last + Vec2::new(0., -18.),
+                        calibration.format_length(shape.length_with(&calibration)),
+                    );
+                }
+            }
+            MeasureShape::Polygon { .. } => {
+                if screen.is_empty() {
+                    continue;
+                }
+                let center = screen.iter().fold(Vec2::ZERO, |sum, p| sum + p.to_vec2())
+                    / screen.len() as f32;
+                ui.painter()
+                    .add(egui::Shape::closed_line(screen.clone(), stroke));
+                label(
+                    center.to_pos2(),
+                    format!(
+                        "{}\n{}",
+                        calibration.format_area(shape.area_with(&calibration)),
+                        calibration.format_length(shape.length_with(&calibration))
+                    ),
+                );
+            }
+            MeasureShape::Ellipse { .. } => {
+                let [a, b, ..] = screen.as_slice() else {
+                    continue;
+                };
+                let rect = Rect::from_two_pos(*a, *b);
+                ui.painter().add(egui::Shape::ellipse_stroke(
+                    rect.center(),
+                    rect.size() / 2.,
+                    stroke,
+                ));
+                let size = shape.box_size();
+                // within a pixel, show it as a circle
+                let dimensions = if (size.0 - size.1).abs() <= 1. {
+                    format!(
+                        "⌀ {}",
+                        calibration.format_length(calibration.length((0., 0.), (size.0, 0.)))
+                    )
+                } else {
+                    calibration.format_size(size)
+                };
+                label(
+                    rect.expand(14.).center_bottom(),
+                    format!(
+                        "{dimensions}, {}",
+                        calibration.format_area(shape.area_with(&calibration))
+                    ),
+                );
+            }
         }
-        DynamicImage::ImageLuma8(buffer) => {
-            buffer.par_chunks_mut(1).for_each(|px| {
-                let mut float_pixel = Vector4::new(px[0] as f32, 0.0, 0.0, 0.0) / 255.;
-                // run pixel operations
-                for operation in operators {
-                    if let Err(e) = operation.process_pixel(&mut float_pixel) {
-                        error!("{e}")
-                    }
+    }
+
+    // move points by their handles
+    let handle_id = Id::new("measure_handle");
+    let dragged = ui.data(|r| r.get_temp::<(usize, usize)>(handle_id));
+    if let Some(pointer) = ui.input(|i| i.pointer.hover_pos()) {
+        let on_image = (
+            ((pointer.x - geo.offset.x) / geo.scale).clamp(0., geo.dimensions.0 as f32) as u32,
+            ((pointer.y - geo.offset.y) / geo.scale).clamp(0., geo.dimensions.1 as f32) as u32,
+        );
+        if let Some((shape_index, point_index)) = dragged {
+            if ui.input(|i| i.pointer.primary_down()) {
+                if let Some(point) = shapes
+                    .get_mut(shape_index)
+                    .and_then(|shape| shape.points_mut().get_mut(point_index))
+                {
+                    *point = on_image;
+                    r.mark_changed();
                 }
-                float_pixel *= 255.;
-                px[0] = (float_pixel[0]) as u8;
-            });
+            } else {
+                *block_panning = false;
+                ui.data_mut(|w| w.remove_temp::<(usize, usize)>(handle_id));
+            }
+        } else if !ui.ctx().is_pointer_over_area() {
+            let hovered = shapes
+                .iter()
+                .enumerate()
+                .filter(|(_, shape)| !shape.is_placeholder())
+                .flat_map(|(s, shape)| {
+                    shape
+                        .points()
+                        .iter()
+                        .enumerate()
+                        .map(move |(p, point)| ((s, p), *point))
+                })
+                .find(|(_, point)| to_screen(point).distance(pointer) < 8.);
+            if let Some((handle, _)) = hovered {
+                ui.ctx().set_cursor_icon(egui::CursorIcon::Grab);
+                if ui.input(|i| i.pointer.primary_pressed()) {
+                    *block_panning = true;
+                    ui.data_mut(|w| w.insert_temp(handle_id, handle));
+                }
+            }
         }
-        DynamicImage::ImageLumaA8(buffer) => {
-            buffer.par_chunks_mut(2).for_each(|px| {
-                let mut float_pixel = Vector4::new(px[0] as f32, 0.0, 0.0, px[1] as f32) / 255.;
-                // run pixel operations
-                for operation in operators {
-                    if let Err(e) = operation.process_pixel(&mut float_pixel) {
-                        error!("{e}")
-                    }
+    }
+
+    for shape in shapes.iter().filter(|shape| !shape.is_placeholder()) {
+        for point in shape.points() {
+            ui.painter().rect_filled(
+                Rect::from_center_size(to_screen(point), Vec2::splat(7.)),
+                2.,
+                Color32::GOLD,
+            );
+        }
+    }
+
+    r
+}
+
+/// The screen position of a point in 1/10000 of the image size
+fn image_to_screen(p: (u16, u16), geo: &ImageGeometry) -> Pos2 {
+    Pos2::new(
+        geo.offset.x + p.0 as f32 / 10000. * geo.dimensions.0 as f32 * geo.scale,
+        geo.offset.y + p.1 as f32 / 10000. * geo.dimensions.1 as f32 * geo.scale,
+    )
+}
+
+/// Drag a rectangle on the image. Returns it once the drag is finished.
+fn mask_draw_ui(
+    ui: &mut Ui,
+    rect: [u16; 4],
+    ellipse: bool,
+    geo: &ImageGeometry,
+    block_panning: &mut bool,
+) -> Option<[u16; 4]> {
+    let id = ui.id().with("mask_draw");
+    let start_id = ui.id().with("mask_draw_start");
+    let mut active = ui.data(|r| r.get_temp::<bool>(id)).unwrap_or_default();
+
+    if ui
+        .selectable_label(active, format!("{PENCIL_SIMPLE_LINE} Draw"))
+        .on_hover_text("Drag on the image to place the mask")
+        .clicked()
+    {
+        active = !active;
+        ui.data_mut(|w| w.insert_temp(id, active));
+    }
+    if !active {
+        return None;
+    }
+
+    let current = pointer_on_image(ui, geo)?;
+    let start = ui.data(|r| r.get_temp::<(u16, u16)>(start_id));
+
+    // only start on the image, not on the ui
+    if start.is_none()
+        && ui.input(|i| i.pointer.primary_pressed())
+        && !ui.ctx().is_pointer_over_area()
+    {
+        *block_panning = true;
+        ui.data_mut(|w| w.insert_temp(start_id, current));
+        return None;
+    }
+
+    let preview = start
+        .map(|start| rect_between(start, current))
+        .unwrap_or(rect);
+    let screen_rect = Rect::from_min_max(
+        image_to_screen((preview[0], preview[1]), geo),
+        image_to_screen(
+            (
+                preview[0].saturating_add(preview[2]),
+                preview[1].saturating_add(preview[3]),
+            ),
+            geo,
+        ),
+    );
+    let stroke = Stroke::new(2., Color32::GOLD);
+    if ellipse {
+        ui.painter().add(egui::Shape::ellipse_stroke(
+            screen_rect.center(),
+            screen_rect.size() / 2.,
+            stroke,
+        ));
+    } else {
+        ui.painter()
+            .rect_stroke(screen_rect, 0., stroke, StrokeKind::Middle);
+    }
+
+    let start = start?;
+    if ui.input(|i| i.pointer.primary_released()) {
+        *block_panning = false;
+        ui.data_mut(|w| w.remove_temp::<(u16, u16)>(start_id));
+        let drawn = rect_between(start, current);
+        // ignore clicks without a rectangle
+        if drawn[2] > 0 && drawn[3] > 0 {
+            return Some(drawn);
+        }
+    }
+    None
+}
+
+fn rect_between(a: (u16, u16), b: (u16, u16)) -> [u16; 4] {
+    [
+        a.0.min(b.0),
+        a.1.min(b.1),
+        a.0.abs_diff(b.0),
+        a.1.abs_diff(b.1),
+    ]
+}
+
+/// Paint on the mask with the brush engine. Returns true if a stroke changed.
+fn mask_paint_ui(
+    ui: &mut Ui,
+    strokes: &mut Vec<MaskStroke>,
+    geo: &ImageGeometry,
+    block_panning: &mut bool,
+) -> bool {
+    let id = ui.id().with("mask_paint");
+    let stroke_id = ui.id().with("mask_paint_stroke");
+    // painting enabled, brush size and erasing
+    let (mut active, mut width, mut erase) = ui
+        .data(|r| r.get_temp::<(bool, u16, bool)>(id))
+        .unwrap_or((false, 500, false));
+
+    ui.horizontal(|ui| {
+        if ui
+            .selectable_label(active, format!("{PENCIL_SIMPLE_LINE} Paint"))
+            .on_hover_text("Paint the mask on the image")
+            .clicked()
+        {
+            active = !active;
+            *block_panning = active;
+        }
+        ui.styled_checkbox(&mut erase, "Erase");
+        ui.add(
+            DragValue::new(&mut width)
+                .range(10..=5000)
+                .speed(5.)
+                .custom_formatter(|v, _| format!("{:.1}%", v / 100.)),
+        )
+        .on_hover_text("Brush size");
+    });
+    ui.data_mut(|w| w.insert_temp(id, (active, width, erase)));
+    if !active {
+        return false;
+    }
+
+    let Some(pos) = pointer_on_image(ui, geo) else {
+        return false;
+    };
+    let radius =
+        width as f32 / 10000. * geo.dimensions.0.min(geo.dimensions.1) as f32 * geo.scale / 2.;
+    ui.painter().circle_stroke(
+        image_to_screen(pos, geo),
+        radius,
+        Stroke::new(1., Color32::GOLD),
+    );
+
+    let mut changed = false;
+    if ui.input(|i| i.pointer.primary_pressed()) && !ui.ctx().is_pointer_over_area() {
+        strokes.push(MaskStroke {
+            points: vec![pos],
+            width,
+            brush: String::new(),
+            erase,
+        });
+        ui.data_mut(|w| w.insert_temp(stroke_id, true));
+        changed = true;
+    } else if ui.input(|i| i.pointer.primary_down())
+        && ui
+            .data(|r| r.get_temp::<bool>(stroke_id))
+            .unwrap_or_default()
+    {
+        if let Some(stroke) = strokes.last_mut() {
+            if stroke.points.last() != Some(&pos) {
+                stroke.points.push(pos);
+                changed = true;
+            }
+        }
+    }
+    if ui.input(|i| i.pointer.primary_released()) {
+        ui.data_mut(|w| w.remove_temp::<bool>(stroke_id));
+    }
+    changed
+}
+
+/// A 3x3 grid to pick an anchor
+fn anchor_ui(ui: &mut Ui, anchor: &mut Anchor) -> bool {
+    let mut changed = false;
+    egui::Grid::new("anchor").show(ui, |ui| {
+        for (i, a) in Anchor::iter().enumerate() {
+            if ui
+                .selectable_value(anchor, a, a.symbol())
+                .on_hover_text(a.to_string())
+                .clicked()
+            {
+                changed = true;
+            }
+            if i % 3 == 2 {
+                ui.end_row();
+            }
+        }
+    });
+    changed
+}
+
+/// Fill color for new canvas areas, with a shortcut to make them transparent
+fn canvas_color_ui(ui: &mut Ui, color: &mut [u8; 4]) -> bool {
+    ui.horizontal(|ui| {
+        let mut changed = ui.color_edit_button_srgba_unmultiplied(color).changed();
+        ui.label("Fill");
+        if ui.button("Transparent").clicked() {
+            color[3] = 0;
+            changed = true;
+        }
+        changed
+    })
+    .inner
+}
+
+/// All font families installed on the system
+fn system_font_families() -> &'static [String] {
+    static FAMILIES: OnceLock<Vec<String>> = OnceLock::new();
+    FAMILIES.get_or_init(|| {
+        let mut families = SystemSource::new().all_families().unwrap_or_default();
+        families.sort();
+        families.dedup();
+        families
+    })
+}
+
+/// Load a system font by family name, or the default font if `family` is empty.
+/// Fonts are kept around, as operators get processed often.
+fn load_font(family: &str) -> Result<FontArc> {
+    static FONTS: OnceLock<Mutex<HashMap<String, FontArc>>> = OnceLock::new();
+    let fonts = FONTS.get_or_init(Default::default);
+
+    if let Some(font) = fonts.lock().ok().and_then(|f| f.get(family).cloned()) {
+        return Ok(font);
+    }
+
+    let font = if family.is_empty() {
+        FontArc::try_from_slice(crate::FONT)?
+    } else {
+        match crate::ui::load_font_family(&[family]) {
+            Some(data) => FontArc::try_from_vec(data)?,
+            None => bail!("Font {family} could not be loaded"),
+        }
+    };
+
+    if let Ok(mut fonts) = fonts.lock() {
+        fonts.insert(family.to_string(), font.clone());
+    }
+    Ok(font)
+}
+
+/// Load a watermark image. Decoded images are kept until their file changes,
+/// as operators get processed often.
+fn load_watermark(path: &str) -> Result<Arc<RgbaImage>> {
+    use anyhow::Context;
+    type Marks = HashMap<String, (Option<SystemTime>, Arc<RgbaImage>)>;
+    static MARKS: OnceLock<Mutex<Marks>> = OnceLock::new();
+    let marks = MARKS.get_or_init(Default::default);
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    if let Some(mark) = marks.lock().ok().and_then(|m| {
+        m.get(path)
+            .filter(|(time, _)| *time == modified)
+            .map(|(_, mark)| mark.clone())
+    }) {
+        return Ok(mark);
+    }
+
+    let mark = Arc::new(
+        image::open(path)
+            .with_context(|| format!("Can't open watermark {path}"))?
+            .into_rgba8(),
+    );
+    if let Ok(mut marks) = marks.lock() {
+        marks.insert(path.to_string(), (modified, mark.clone()));
+    }
+    Ok(mark)
+}
+
+/// Rotate an image clockwise, growing it so the corners are not cut off
+fn rotate_image<P>(
+    img: &Image<P>,
+    degrees: f32,
+    interpolation: Interpolation,
+    background: P,
+) -> Image<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync + Into<f32> + Clamp<f32>,
+{
+    if degrees % 360. == 0. {
+        return img.clone();
+    }
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    // bounding box of the rotated image
+    let rotated_width = ((width * cos.abs() + height * sin.abs()).round() as u32).max(1);
+    let rotated_height = ((width * sin.abs() + height * cos.abs()).round() as u32).max(1);
+    // the canvas to rotate on must hold both the image and the result
+    let canvas_width = rotated_width.max(img.width());
+    let canvas_height = rotated_height.max(img.height());
+    let mut padded = Image::from_pixel(canvas_width, canvas_height, background);
+    imageops::replace(
+        &mut padded,
+        img,
+        (canvas_width - img.width()) as i64 / 2,
+        (canvas_height - img.height()) as i64 / 2,
+    );
+    let rotated = rotate_about_center(&padded, degrees.to_radians(), interpolation, background);
+    if (canvas_width, canvas_height) == (rotated_width, rotated_height) {
+        return rotated;
+    }
+    imageops::crop_imm(
+        &rotated,
+        (canvas_width - rotated_width) / 2,
+        (canvas_height - rotated_height) / 2,
+        rotated_width,
+        rotated_height,
+    )
+    .to_image()
+}
+
+/// Size of the largest axis aligned rectangle within a rectangle of `size` rotated by `angle` radians
+fn largest_inscribed_rect(size: (u32, u32), angle: f32) -> (u32, u32) {
+    let (width, height) = (size.0 as f32, size.1 as f32);
+    let (sin, cos) = (angle.sin().abs(), angle.cos().abs());
+    let (long, short) = if width >= height {
+        (width, height)
+    } else {
+        (height, width)
+    };
+    let (w, h) = if short <= 2. * sin * cos * long || (sin - cos).abs() < 1e-6 {
+        // two corners of the rectangle touch the longer sides
+        let half = 0.5 * short;
+        if width >= height {
+            (half / sin, half / cos)
+        } else {
+            (half / cos, half / sin)
+        }
+    } else {
+        // all four corners touch the sides
+        let cos_2a = cos * cos - sin * sin;
+        (
+            (width * cos - height * sin) / cos_2a,
+            (height * cos - width * sin) / cos_2a,
+        )
+    };
+    // tolerate float error, so right angles keep their exact size
+    (
+        ((w + 1e-3).floor() as u32).max(1),
+        ((h + 1e-3).floor() as u32).max(1),
+    )
+}
+
+/// Blend an 8 bit RGBA layer over an image of any color type, with its top left corner at `pos`
+pub fn blend_layer(img: &mut DynamicImage, layer: &RgbaImage, pos: (i64, i64)) {
+    if let Some(buffer) = img.as_mut_rgba8() {
+        imageops::overlay(buffer, layer, pos.0, pos.1);
+        return;
+    }
+
+    let color_type = ColorTypeExt::from_image(img.color());
+    let mut buffer = img.to_rgba32f();
+    for (x, y, top) in layer.enumerate_pixels() {
+        let (x, y) = (x as i64 + pos.0, y as i64 + pos.1);
+        if top[3] == 0 || x < 0 || y < 0 {
+            continue;
+        }
+        if let Some(p) = buffer.get_pixel_mut_checked(x as u32, y as u32) {
+            let alpha = top[3] as f32 / 255.;
+            let bottom_alpha = p[3];
+            let out_alpha = alpha + bottom_alpha * (1. - alpha);
+            for (c, top_c) in p.0.iter_mut().zip(top.0).take(3) {
+                *c = (top_c as f32 / 255. * alpha + *c * bottom_alpha * (1. - alpha))
+                    / out_alpha.max(f32::EPSILON);
+            }
+            p[3] = out_alpha;
+        }
+    }
+    *img = color_type.convert(&DynamicImage::ImageRgba32F(buffer));
+}
+
+/// The largest side of a canvas that padding or extending creates
+const MAX_CANVAS_SIDE: u32 = 65535;
+
+/// The size of a new canvas, if neither side is larger than `MAX_CANVAS_SIDE`.
+/// Sizes from sidecars or presets can be anything and must not exhaust memory.
+fn canvas_size(width: u64, height: u64) -> Result<(u32, u32)> {
+    if width > MAX_CANVAS_SIDE as u64 || height > MAX_CANVAS_SIDE as u64 {
+        bail!("A canvas of {width}x{height} pixels is too large");
+    }
+    Ok((width as u32, height as u32))
+}
+
+/// Place an image of any color type onto a new canvas filled with `color`.
+/// Images without alpha channel get one if the fill is not opaque.
+fn extend_canvas(
+    img: &DynamicImage,
+    size: (u32, u32),
+    pos: (i64, i64),
+    color: [u8; 4],
+) -> DynamicImage {
+    if let Some(buffer) = img.as_rgba8() {
+        let mut canvas = RgbaImage::from_pixel(size.0, size.1, Rgba(color));
+        imageops::replace(&mut canvas, buffer, pos.0, pos.1);
+        return DynamicImage::ImageRgba8(canvas);
+    }
+
+    let color_type = ColorTypeExt::from_image(img.color());
+    let color_type = if color[3] == 255 {
+        color_type
+    } else {
+        color_type.with_alpha()
+    };
+    let mut canvas = Rgba32FImage::from_pixel(size.0, size.1, Rgba(color.map(|c| c as f32 / 255.)));
+    imageops::replace(&mut canvas, &img.to_rgba32f(), pos.0, pos.1);
+    color_type.convert(&DynamicImage::ImageRgba32F(canvas))
+}
+
+/// Resize an image of any color type
+fn resize_image(
+    img: &DynamicImage,
+    dimensions: (u32, u32),
+    filter: &ScaleFilter,
+) -> Result<DynamicImage> {
+    let filter = match filter {
+        ScaleFilter::Box => fr::FilterType::Box,
+        ScaleFilter::Bilinear => fr::FilterType::Bilinear,
+        ScaleFilter::Hamming => fr::FilterType::Hamming,
+        ScaleFilter::CatmullRom => fr::FilterType::CatmullRom,
+        ScaleFilter::Mitchell => fr::FilterType::Mitchell,
+        ScaleFilter::Lanczos3 => fr::FilterType::Lanczos3,
+    };
+
+    // Create container for data of destination image
+    let mut dst_image = DynamicImage::new(dimensions.0, dimensions.1, img.color());
+
+    let mut resizer = fr::Resizer::new();
+    resizer.resize(
+        img,
+        &mut dst_image,
+        Some(&ResizeOptions::new().resize_alg(fr::ResizeAlg::Convolution(filter))),
+    )?;
+    Ok(dst_image)
+}
+
+/// Warp the quad spanned by `points` so it fills the image.
+/// Returns the warped image and the size it should be scaled to.
+fn warp_perspective<P>(
+    img: &Image<P>,
+    points: &[(u32, u32); 4],
+    default: P,
+) -> Option<(Image<P>, (u32, u32))>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync + Into<f32> + Clamp<f32>,
+{
+    let img_dim = img.dimensions();
+
+    let max_width = points[1].0.max(points[3].0);
+    let min_width = points[0].0.min(points[2].0);
+    let max_height = points[2].1.max(points[3].1);
+    let min_height = points[0].1.min(points[1].1);
+    let x = max_width - min_width;
+    let y = max_height - min_height;
+
+    let from = [
+        (points[0].0 as f32, points[0].1 as f32),
+        (points[1].0 as f32, points[1].1 as f32),
+        (points[2].0 as f32, points[2].1 as f32),
+        (points[3].0 as f32, points[3].1 as f32),
+    ];
+
+    let to = [
+        (0 as f32, 0 as f32),
+        (img_dim.0 as f32, 0 as f32),
+        (0 as f32, img_dim.1 as f32),
+        (img_dim.0 as f32, img_dim.1 as f32),
+    ];
+
+    if let Some(proj) =
+        imageproc::geometric_transformations::Projection::from_control_points(from, to)
+    {
+        let warped =
+            imageproc::geometric_transformations::warp(img, &proj, Interpolation::Bicubic, default);
+        Some((warped, (x, y)))
+    } else {
+        error!("Projection failed");
+        None
+    }
+}
+
+fn chromatic_aberration<P: Pixel>(img: &mut Image<P>, amt: u8) {
+    let center = (img.width() as i32 / 2, img.height() as i32 / 2);
+    let img_c = img.clone();
+
+    for (x, y, p) in img.enumerate_pixels_mut() {
+        let dist_to_center = (x as i32 - center.0, y as i32 - center.1);
+        let dist_to_center = (
+            (dist_to_center.0 as f32 / center.0 as f32) * amt as f32 / 10.,
+            (dist_to_center.1 as f32 / center.1 as f32) * amt as f32 / 10.,
+        );
+        if let Some(l) = img_c.get_pixel_checked(
+            (x as i32 + dist_to_center.0 as i32).max(0) as u32,
+            (y as i32 + dist_to_center.1 as i32).max(0) as u32,
+        ) {
+            p.channels_mut()[0] = l.channels()[0];
+        }
+    }
+}
+
+/// Load a builtin LUT by name, or a LUT image from disk
+fn load_lut(lut_name: &str) -> Option<RgbImage> {
+    if let Some(lut_data) = builtin_luts().get(lut_name) {
+        image::load_from_memory(lut_data).ok().map(|i| i.to_rgb8())
+    } else {
+        image::open(lut_name).ok().map(|i| i.to_rgb8())
+    }
+}
+
+/// Look up a float pixel in a Hald CLUT, interpolating trilinearly between the entries
+fn apply_hald_clut(px: &mut [f32], lut: &RgbImage) {
+    let level = (lut.width() as f32).cbrt().round() as u32;
+    let cube_size = level * level;
+    if cube_size < 2 {
+        return;
+    }
+
+    let entry = |r: u32, g: u32, b: u32| {
+        let i = r + g * cube_size + b * cube_size * cube_size;
+        let c = lut.get_pixel(i % lut.width(), i / lut.width());
+        Vector3::new(c[0] as f32, c[1] as f32, c[2] as f32) / 255.
+    };
+
+    let pos = [px[0], px[1], px[2]].map(|c| c.clamp(0.0, 1.0) * (cube_size - 1) as f32);
+    let lo = pos.map(|c| c.floor() as u32);
+    let hi = lo.map(|c| (c + 1).min(cube_size - 1));
+    let t = [
+        pos[0] - lo[0] as f32,
+        pos[1] - lo[1] as f32,
+        pos[2] - lo[2] as f32,
+    ];
+
+    let c00 = entry(lo[0], lo[1], lo[2]).lerp(&entry(hi[0], lo[1], lo[2]), t[0]);
+    let c10 = entry(lo[0], hi[1], lo[2]).lerp(&entry(hi[0], hi[1], lo[2]), t[0]);
+    let c01 = entry(lo[0], lo[1], hi[2]).lerp(&entry(hi[0], lo[1], hi[2]), t[0]);
+    let c11 = entry(lo[0], hi[1], hi[2]).lerp(&entry(hi[0], hi[1], hi[2]), t[0]);
+    let res = c00.lerp(&c10, t[1]).lerp(&c01.lerp(&c11, t[1]), t[2]);
+
+    px[0] = res[0];
+    px[1] = res[1];
+    px[2] = res[2];
+}
+
+const EXPRESSION_HELP: &str = "Set r, g, b and a (0-1) per pixel, e.g. r = g * 0.5;
+Inputs: x, y, width, height, u and v (0-1 across the image)
+Neighbors: px(x + 1, y).r, same for g, b and a
+Compare image: cmp(x, y).r, after a line 'compare <path>'
+Sliders: a line 'param name = 0.5 [0, 1]' declares a variable";
+
+/// A `param name = value [min, max]` declaration in an expression, shown as a slider
+#[derive(Debug, Clone, PartialEq)]
+struct ExpressionParam {
+    name: String,
+    value: f64,
+    range: RangeInclusive<f64>,
+}
+
+impl ExpressionParam {
+    fn parse(line: &str) -> Option<Self> {
+        let (name, rest) = line.trim().strip_prefix("param ")?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        let (value, range) = match rest.split_once('[') {
+            Some((value, range)) => {
+                let (min, max) = range.trim().strip_suffix(']')?.split_once(',')?;
+                (value, min.trim().parse().ok()?..=max.trim().parse().ok()?)
+            }
+            None => (rest, 0.0..=1.0),
+        };
+        Some(Self {
+            name: name.to_string(),
+            value: value.trim().parse().ok()?,
+            range,
+        })
+    }
+}
+
+impl fmt::Display for ExpressionParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "param {} = {} [{}, {}]",
+            self.name,
+            self.value,
+            self.range.start(),
+            self.range.end()
+        )
+    }
+}
+
+/// The image path of a `compare <path>` line in an expression
+fn parse_compare(line: &str) -> Option<&str> {
+    let path = line.trim().strip_prefix("compare ")?.trim();
+    Some(path.trim_matches('"'))
+}
+
+/// The code of an `Expression` operator. Stored as a plain string, the parsed form is
+/// kept until the code changes, so the ui does not parse it every frame.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct ExpressionCode {
+    pub source: String,
+    /// Reset this when changing `source`
+    parsed: Option<ParsedExpression>,
+}
+
+#[derive(Debug, Clone)]
+struct ParsedExpression {
+    source: ExpressionSource,
+    /// Why the program does not compile
+    error: Option<String>,
+}
+
+impl ExpressionCode {
+    fn parsed(&mut self) -> &ParsedExpression {
+        self.parsed.get_or_insert_with(|| {
+            let source = ExpressionSource::parse(&self.source);
+            let error = build_operator_tree::<DefaultNumericTypes>(&source.program)
+                .err()
+                .map(|e| e.to_string());
+            ParsedExpression { source, error }
+        })
+    }
+}
+
+impl From<String> for ExpressionCode {
+    fn from(source: String) -> Self {
+        Self {
+            source,
+            parsed: None,
+        }
+    }
+}
+
+impl From<&str> for ExpressionCode {
+    fn from(source: &str) -> Self {
+        source.to_string().into()
+    }
+}
+
+impl From<ExpressionCode> for String {
+    fn from(code: ExpressionCode) -> Self {
+        code.source
+    }
+}
+
+impl PartialEq for ExpressionCode {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for ExpressionCode {}
+
+impl PartialOrd for ExpressionCode {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ExpressionCode {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.source.cmp(&other.source)
+    }
+}
+
+/// An expression split into the evalexpr program and its declarations
+#[derive(Debug, Clone)]
+struct ExpressionSource {
+    program: String,
+    /// Declared parameters and the lines they are declared on
+    params: Vec<(usize, ExpressionParam)>,
+    compare: Option<String>,
+}
+
+impl ExpressionSource {
+    fn parse(expr: &str) -> Self {
+        let mut params = vec![];
+        let mut compare = None;
+        let program = expr
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                if let Some(param) = ExpressionParam::parse(line) {
+                    params.push((i, param));
+                } else if let Some(path) = parse_compare(line) {
+                    compare = Some(path.to_string());
+                } else {
+                    return line;
                 }
-                float_pixel *= 255.;
-                px[0] = (float_pixel[0]) as u8;
-                px[1] = (float_pixel[1]) as u8;
-            });
+                // keep the line, so errors point to the right place
+                ""
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self {
+            program: rewrite_samplers(&program),
+            params,
+            compare,
         }
-        DynamicImage::ImageRgb32F(buffer) => {
-            buffer.par_chunks_mut(3).for_each(|px| {
-                let mut float_pixel = Vector4::new(px[0], px[1], px[2], 0.0);
-                for operation in operators {
-                    if let Err(e) = operation.process_pixel(&mut float_pixel) {
-                        error!("{e}")
-                    }
+    }
+}
+
+/// Turn `px(x, y).r` into `px_r(x, y)`, as evalexpr has no member access
+fn rewrite_samplers(program: &str) -> String {
+    let mut out = String::with_capacity(program.len());
+    let mut i = 0;
+    'chars: while let Some(c) = program[i..].chars().next() {
+        let at_word_start = !program[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        for name in ["px", "cmp"] {
+            if !at_word_start || !program[i..].starts_with(&format!("{name}(")) {
+                continue;
+            }
+            if let Some((call, end)) = rewrite_sampler_call(program, i, name) {
+                out.push_str(&call);
+                i = end;
+                continue 'chars;
+            }
+        }
+        out.push(c);
+        i += c.len_utf8();
+    }
+    out
+}
+
+/// Rewrite the `name(..).channel` call at `start`, returning it and the index after it
+fn rewrite_sampler_call(program: &str, start: usize, name: &str) -> Option<(String, usize)> {
+    let open = start + name.len();
+    let mut depth = 0;
+    let close = program[open..].char_indices().find_map(|(j, c)| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        (depth == 0).then_some(open + j)
+    })?;
+    let after = &program[close + 1..];
+    let channel = ["r", "g", "b", "a"].into_iter().find(|ch| {
+        after.starts_with(&format!(".{ch}"))
+            && !after[2..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    })?;
+    // arguments may sample as well
+    let args = rewrite_samplers(&program[open..=close]);
+    Some((format!("{name}_{channel}{args}"), close + 3))
+}
+
+/// Variables that change per pixel
+const PIXEL_INPUTS: [&str; 8] = ["x", "y", "u", "v", "r", "g", "b", "a"];
+
+/// An `Expression` operator, compiled once per run
+struct ExpressionProgram {
+    tree: Node<DefaultNumericTypes>,
+    /// Holds the sampling functions and the variables that are the same for all pixels
+    context: HashMapContext<DefaultNumericTypes>,
+    constants: Vec<(String, f64)>,
+    /// Indices and names of the `PIXEL_INPUTS` the program uses, named once here
+    /// instead of for every pixel
+    inputs: Vec<(usize, String)>,
+    size: (u32, u32),
+}
+
+impl ExpressionProgram {
+    fn compile(expr: &str, img: &Rgba32FImage) -> Result<Self> {
+        let source = ExpressionSource::parse(expr);
+        let tree = build_operator_tree::<DefaultNumericTypes>(&source.program)?;
+        let size = img.dimensions();
+        let mut context = HashMapContext::<DefaultNumericTypes>::new();
+
+        if source.program.contains("px_") {
+            // sample the unmodified image, as pixels are written while evaluating
+            let img = Arc::new(img.clone());
+            for (channel, name) in ["px_r", "px_g", "px_b", "px_a"].into_iter().enumerate() {
+                context.set_function(name.into(), sampler(img.clone(), channel, size))?;
+            }
+        }
+        if source.program.contains("cmp_") {
+            use anyhow::Context;
+            let Some(path) = &source.compare else {
+                bail!("Add a line 'compare <path>' or load a compare image to use cmp()");
+            };
+            let compare = Arc::new(
+                image::open(path)
+                    .with_context(|| format!("Can't open compare image {path}"))?
+                    .into_rgba32f(),
+            );
+            for (channel, name) in ["cmp_r", "cmp_g", "cmp_b", "cmp_a"].into_iter().enumerate() {
+                context.set_function(name.into(), sampler(compare.clone(), channel, size))?;
+            }
+        }
+
+        let constants = [("width", size.0 as f64), ("height", size.1 as f64)]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .chain(source.params.into_iter().map(|(_, p)| (p.name, p.value)))
+            .collect::<Vec<_>>();
+        for (name, value) in &constants {
+            context.set_value(name.clone(), Value::Float(*value))?;
+        }
+        // Channels the program writes are bound as well, so pixels it does not assign to
+        // keep their value instead of getting the one of the previous pixel
+        let reads = tree.iter_read_variable_identifiers().collect::<Vec<_>>();
+        let writes = tree.iter_write_variable_identifiers().collect::<Vec<_>>();
+        let inputs = PIXEL_INPUTS
+            .iter()
+            .enumerate()
+            .filter(|(i, name)| reads.contains(name) || (*i >= 4 && writes.contains(name)))
+            .map(|(i, name)| (i, name.to_string()))
+            .collect();
+
+        Ok(Self {
+            tree,
+            context,
+            constants,
+            inputs,
+            size,
+        })
+    }
+
+    /// Evaluate for one pixel. Pixels the expression fails on stay as they are.
+    fn eval(
+        &self,
+        context: &mut HashMapContext<DefaultNumericTypes>,
+        x: u32,
+        y: u32,
+        px: &mut [f32],
+    ) {
+        let (width, height) = (self.size.0 as f64, self.size.1 as f64);
+        let values = [
+            x as f64,
+            y as f64,
+            (x as f64 + 0.5) / width,
+            (y as f64 + 0.5) / height,
+            px[0] as f64,
+            px[1] as f64,
+            px[2] as f64,
+            px[3] as f64,
+        ];
+        for (i, name) in &self.inputs {
+            if context
+                .set_value(name.clone(), Value::Float(values[*i]))
+                .is_err()
+            {
+                // the previous pixel assigned a value of another type, so bind everything again
+                context.clear_variables();
+                for (name, value) in &self.constants {
+                    _ = context.set_value(name.clone(), Value::Float(*value));
                 }
-                px[0] = float_pixel[0];
-                px[1] = float_pixel[1];
-                px[2] = float_pixel[2];
-            });
+                for (i, name) in &self.inputs {
+                    _ = context.set_value(name.clone(), Value::Float(values[*i]));
+                }
+                break;
+            }
         }
-        DynamicImage::ImageRgba32F(buffer) => {
-            buffer.par_chunks_mut(3).for_each(|px| {
-                let mut float_pixel = Vector4::new(px[0], px[1], px[2], px[3]);
-                for operation in operators {
-                    if let Err(e) = operation.process_pixel(&mut float_pixel) {
-                        error!("{e}")
-                    }
+
+        if self.tree.eval_empty_with_context_mut(context).is_ok() {
+            for (name, c) in ["r", "g", "b", "a"].into_iter().zip(px.iter_mut()) {
+                if let Some(Ok(value)) = context.get_value(name).map(|v| v.as_number()) {
+                    *c = value as f32;
                 }
-                px[0] = float_pixel[0];
-                px[1] = float_pixel[1];
-                px[2] = float_pixel[2];
-                px[3] = float_pixel[3];
-            });
+            }
+        }
+    }
+}
+
+/// An expression function returning one channel of `img` at pixel coordinates of an image
+/// with `size`. Coordinates are scaled to the size of `img` and clamped to its edges.
+fn sampler(
+    img: Arc<Rgba32FImage>,
+    channel: usize,
+    size: (u32, u32),
+) -> Function<DefaultNumericTypes> {
+    Function::new(move |argument| {
+        let args = argument.as_fixed_len_tuple(2)?;
+        let coord = |arg: &Value<DefaultNumericTypes>, img_size: u32, size: u32| {
+            arg.as_number().map(|v| {
+                ((v * img_size as f64 / size as f64).floor() as i64).clamp(0, img_size as i64 - 1)
+                    as u32
+            })
+        };
+        let x = coord(&args[0], img.width(), size.0)?;
+        let y = coord(&args[1], img.height(), size.1)?;
+        Ok(Value::Float(img.get_pixel(x, y)[channel] as f64))
+    })
+}
+
+pub fn process_pixels(dynimage: &mut DynamicImage, operators: &Vec<ImageOperation>) -> Result<()> {
+    match dynimage {
+        DynamicImage::ImageLuma8(buffer) => {
+            process_samples(buffer, 1, operators, u8_to_float, float_to_u8)
+        }
+        DynamicImage::ImageLumaA8(buffer) => {
+            process_samples(buffer, 2, operators, u8_to_float, float_to_u8)
+        }
+        DynamicImage::ImageRgb8(buffer) => {
+            process_samples(buffer, 3, operators, u8_to_float, float_to_u8)
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            process_samples(buffer, 4, operators, u8_to_float, float_to_u8)
+        }
+        DynamicImage::ImageLuma16(buffer) => {
+            process_samples(buffer, 1, operators, u16_to_float, float_to_u16)
+        }
+        DynamicImage::ImageLumaA16(buffer) => {
+            process_samples(buffer, 2, operators, u16_to_float, float_to_u16)
+        }
+        DynamicImage::ImageRgb16(buffer) => {
+            process_samples(buffer, 3, operators, u16_to_float, float_to_u16)
         }
+        DynamicImage::ImageRgba16(buffer) => {
+            process_samples(buffer, 4, operators, u16_to_float, float_to_u16)
+        }
+        // Float images are not clamped, so HDR values survive
+        DynamicImage::ImageRgb32F(buffer) => process_samples(buffer, 3, operators, |v| v, |v| v),
+        DynamicImage::ImageRgba32F(buffer) => process_samples(buffer, 4, operators, |v| v, |v| v),
         _ => {
-            bail!("Pixel operators are not yet supported for this image type.");
+            bail!(
+                "Pixel operators are not supported for {:?} images.",
+                dynimage.color()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Process the active operators of a pixel stack. Operators that are blended or masked
+/// are processed on their own, all others in as few passes as possible.
+pub fn process_pixel_stack(
+    dynimage: &mut DynamicImage,
+    stack: &[ImgOpItem],
+    brushes: &[Brush],
+) -> Result<()> {
+    let mut plain_ops = vec![];
+    for item in stack.iter().filter(|op| op.active) {
+        if item.is_plain() {
+            plain_ops.push(item.operation.clone());
+            continue;
+        }
+        if !plain_ops.is_empty() {
+            process_pixels(dynimage, &plain_ops)?;
+            plain_ops.clear();
         }
+        let original = dynimage.clone();
+        process_pixels(dynimage, &vec![item.operation.clone()])?;
+        item.blend(&original, dynimage, brushes);
     }
-    return Ok(());
+    if !plain_ops.is_empty() {
+        process_pixels(dynimage, &plain_ops)?;
+    }
+    Ok(())
+}
+
+/// Rec. 709 luminance of an rgb(a) pixel, same as the image crate uses for luma conversion
+pub fn luminance(p: &[f32]) -> f32 {
+    0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]
+}
+
+fn u8_to_float(v: u8) -> f32 {
+    v as f32 / u8::MAX as f32
+}
+
+fn float_to_u8(v: f32) -> u8 {
+    (v * u8::MAX as f32).round() as u8
+}
+
+fn u16_to_float(v: u16) -> f32 {
+    v as f32 / u16::MAX as f32
+}
+
+fn float_to_u16(v: f32) -> u16 {
+    (v * u16::MAX as f32).round() as u16
+}
+
+/// Run pixel operators over interleaved samples with 1 (luma), 2 (luma + alpha),
+/// 3 (rgb) or 4 (rgba) channels. Luma is expanded to rgb for the operators
+/// and written back as the luminance of the result.
+fn process_samples<T: Copy + Send + Sync>(
+    buffer: &mut [T],
+    channels: usize,
+    operators: &[ImageOperation],
+    to_float: impl Fn(T) -> f32 + Sync,
+    from_float: impl Fn(f32) -> T + Sync,
+) {
+    buffer.par_chunks_mut(channels).for_each(|px| {
+        let mut float_pixel = match channels {
+            1 => {
+                let l = to_float(px[0]);
+                Vector4::new(l, l, l, 1.0)
+            }
+            2 => {
+                let l = to_float(px[0]);
+                Vector4::new(l, l, l, to_float(px[1]))
+            }
+            3 => Vector4::new(to_float(px[0]), to_float(px[1]), to_float(px[2]), 1.0),
+            _ => Vector4::new(
+                to_float(px[0]),
+                to_float(px[1]),
+                to_float(px[2]),
+                to_float(px[3]),
+            ),
+        };
+        // run pixel operations
+        for operation in operators {
+            if let Err(e) = operation.process_pixel(&mut float_pixel) {
+                error!("{e}")
+            }
+        }
+        match channels {
+            1 | 2 => {
+                px[0] = from_float(luminance(float_pixel.as_slice()));
+                if channels == 2 {
+                    px[1] = from_float(float_pixel[3]);
+                }
+            }
+            _ => {
+                for (sample, value) in px.iter_mut().zip(float_pixel.iter()) {
+                    *sample = from_float(*value);
+                }
+            }
+        }
+    });
 }
 
 /// Crop a left,top (x,y) plus x/y window safely into absolute pixel units.
@@ -1812,41 +4238,42 @@ pub fn lossless_tx(p: &std::path::Path, transform: turbojpeg::Transform) -> anyh
     Ok(())
 }
 
-fn interpolate_u8(data: &Vec<GradientStop>, pt: u8) -> [u8; 3] {
-    // debug!("Pt is {pt}");
+/// Interpolate the gradient at `pt` (0-1). Returns the color as 0-1 floats.
+fn interpolate(data: &[GradientStop], pt: f32) -> [f32; 3] {
+    // stop positions are 8 bit
+    let pt = pt * 255.;
 
     for i in 0..data.len() {
         let current = data[i];
 
         // return direct hit
-        if current.pos == pt {
-            return current.col;
+        if current.pos as f32 == pt {
+            return current.col.map(|c| c as f32 / 255.);
         }
 
         // pt is below first stop
-        if i == 0 && current.pos > pt {
-            return current.col;
+        if i == 0 && current.pos as f32 > pt {
+            return current.col.map(|c| c as f32 / 255.);
         }
 
         if let Some(next) = data.get(i + 1) {
-            if current.pos < pt && next.pos > pt {
-                let range = next.pos - current.pos;
-                let pos_in_range = pt - current.pos;
-                let rel = pos_in_range as f32 / range as f32;
+            if (current.pos as f32) < pt && next.pos as f32 > pt {
+                let range = (next.pos - current.pos) as f32;
+                let rel = (pt - current.pos as f32) / range;
 
-                let r = lerp(current.r() as f32..=next.r() as f32, rel) as u8;
-                let g = lerp(current.g() as f32..=next.g() as f32, rel) as u8;
-                let b = lerp(current.b() as f32..=next.b() as f32, rel) as u8;
+                let r = lerp(current.r() as f32..=next.r() as f32, rel);
+                let g = lerp(current.g() as f32..=next.g() as f32, rel);
+                let b = lerp(current.b() as f32..=next.b() as f32, rel);
 
-                return [r, g, b];
+                return [r / 255., g / 255., b / 255.];
             }
         } else {
-            return current.col;
+            return current.col.map(|c| c as f32 / 255.);
             //this was the last point
         }
     }
 
-    [0, 255, 0]
+    [0., 1., 0.]
 }
 
 fn closest_pt(data: &Vec<GradientStop>, value: u8) -> usize {
@@ -1922,7 +4349,7 @@ fn range_test() {
     ];
     std::env::set_var("RUST_LOG", "debug");
     let _ = env_logger::try_init();
-    let res = interpolate_u8(&map, 5);
+    let res = interpolate(&map, 5. / 255.);
 
     debug!("result: {:?}", res);
 }
@@ -1953,7 +4380,7 @@ pub enum ColorTypeExt {
 }
 
 impl ColorTypeExt {
-    pub fn _from_image(ct: ColorType) -> Self {
+    pub fn from_image(ct: ColorType) -> Self {
         match ct {
             ColorType::L8 => ColorTypeExt::L8,
             ColorType::La8 => ColorTypeExt::La8,
@@ -1968,4 +4395,516 @@ impl ColorTypeExt {
             _ => ColorTypeExt::Rgba8,
         }
     }
+
+    /// The same color type with an alpha channel
+    pub fn with_alpha(&self) -> Self {
+        match self {
+            ColorTypeExt::L8 => ColorTypeExt::La8,
+            ColorTypeExt::L16 => ColorTypeExt::La16,
+            ColorTypeExt::Rgb8 => ColorTypeExt::Rgba8,
+            ColorTypeExt::Rgb16 => ColorTypeExt::Rgba16,
+            ColorTypeExt::Rgb32F => ColorTypeExt::Rgba32F,
+            _ => self.clone(),
+        }
+    }
+
+    /// Convert an image to this color type
+    pub fn convert(&self, img: &DynamicImage) -> DynamicImage {
+        match self {
+            ColorTypeExt::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+            ColorTypeExt::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+            ColorTypeExt::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+            ColorTypeExt::Rgba8 => DynamicImage::ImageRgba8(img.to_rgba8()),
+            ColorTypeExt::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+            ColorTypeExt::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+            ColorTypeExt::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+            ColorTypeExt::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+            ColorTypeExt::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+            ColorTypeExt::Rgba32F => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    /// A small gradient with some transparency, converted to the given color type
+    fn test_image(color_type: &ColorTypeExt) -> DynamicImage {
+        let buffer = Rgba32FImage::from_fn(32, 16, |x, y| {
+            Rgba([x as f32 / 31., y as f32 / 15., 0.5, 1.0 - x as f32 / 62.])
+        });
+        color_type.convert(&DynamicImage::ImageRgba32F(buffer))
+    }
+
+    /// The error one quantization step of this color type can introduce
+    fn tolerance(color_type: &ColorTypeExt) -> f32 {
+        match color_type {
+            ColorTypeExt::L8 | ColorTypeExt::La8 | ColorTypeExt::Rgb8 | ColorTypeExt::Rgba8 => {
+                1. / 255.
+            }
+            ColorTypeExt::L16 | ColorTypeExt::La16 | ColorTypeExt::Rgb16 | ColorTypeExt::Rgba16 => {
+                1. / 65535.
+            }
+            ColorTypeExt::Rgb32F | ColorTypeExt::Rgba32F => 1e-6,
+        }
+    }
+
+    #[test]
+    fn pixel_ops_all_color_types() {
+        let ops = vec![
+            ImageOperation::Invert,
+            ImageOperation::Brightness(20),
+            ImageOperation::Desaturate(50),
+        ];
+
+        for color_type in ColorTypeExt::iter() {
+            let original = test_image(&color_type);
+            let mut result = original.clone();
+            process_pixels(&mut result, &ops).unwrap();
+            assert_eq!(result.color(), original.color());
+
+            // Run the same operators on a float copy and quantize it the same way
+            let mut expected = original.to_rgba32f();
+            for p in expected.pixels_mut() {
+                let mut float_pixel = Vector4::from(p.0);
+                for op in &ops {
+                    op.process_pixel(&mut float_pixel).unwrap();
+                }
+                p.0 = [
+                    float_pixel[0],
+                    float_pixel[1],
+                    float_pixel[2],
+                    float_pixel[3],
+                ];
+            }
+            let expected = color_type.convert(&DynamicImage::ImageRgba32F(expected));
+
+            for (r, e) in result
+                .to_rgba32f()
+                .pixels()
+                .zip(expected.to_rgba32f().pixels())
+            {
+                assert!(
+                    r.0.iter()
+                        .zip(e.0)
+                        .all(|(r, e)| (r - e).abs() <= tolerance(&color_type)),
+                    "{color_type}: {:?} != {:?}",
+                    r,
+                    e
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_ops_keep_precision() {
+        // every 16 bit value must survive a no-op
+        let mut img = DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(256, 256, |x, y| {
+            image::Luma([(y * 256 + x) as u16])
+        }));
+        let original = img.clone();
+        process_pixels(&mut img, &vec![ImageOperation::Brightness(0)]).unwrap();
+        assert_eq!(img, original);
+
+        // float values outside of 0-1 must not be clamped
+        let mut img =
+            DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(4, 4, Rgba([4.0, -1.0, 0.5, 1.0])));
+        process_pixels(&mut img, &vec![ImageOperation::Brightness(0)]).unwrap();
+        assert_eq!(
+            img.as_rgba32f().unwrap().get_pixel(0, 0).0,
+            [4.0, -1.0, 0.5, 1.0]
+        );
+    }
+
+    #[test]
+    fn image_ops_all_color_types() {
+        let ops = [
+            (ImageOperation::Blur(10), (32, 16)),
+            (
+                ImageOperation::Filter3x3([0, -100, 0, -100, 500, -100, 0, -100, 0]),
+                (32, 16),
+            ),
+            (ImageOperation::LUT("Polaroid Polachrome".into()), (32, 16)),
+            (
+                ImageOperation::CropPerspective {
+                    points: [(2, 2), (30, 1), (1, 14), (31, 15)],
+                    original_size: (32, 16),
+                },
+                (30, 14),
+            ),
+            (ImageOperation::ScaleImageMinMax, (32, 16)),
+            (ImageOperation::ChromaticAberration(10), (32, 16)),
+            (
+                ImageOperation::Vignette {
+                    amount: -50,
+                    midpoint: 50,
+                    roundness: -50,
+                    feather: 50,
+                },
+                (32, 16),
+            ),
+            (
+                ImageOperation::Grain {
+                    amount: 50,
+                    size: 50,
+                    seed: 0,
+                },
+                (32, 16),
+            ),
+            (
+                ImageOperation::RotateFree {
+                    angle: 4500,
+                    filter: RotateFilter::Bilinear,
+                    crop: true,
+                },
+                (11, 11),
+            ),
+            (
+                ImageOperation::RotateFree {
+                    angle: -9000,
+                    filter: RotateFilter::Bicubic,
+                    crop: true,
+                },
+                (16, 32),
+            ),
+            (
+                ImageOperation::Expression("r = u; g = px(x - 1, y).g;".into()),
+                (32, 16),
+            ),
+            (
+                ImageOperation::Pad {
+                    sides: [1, 2, 3, 4],
+                    percent: false,
+                    color: [255, 0, 0, 255],
+                },
+                (36, 22),
+            ),
+            (
+                ImageOperation::ExtendAspect {
+                    ratio: (1, 1),
+                    anchor: Anchor::Top,
+                    color: [0, 0, 0, 255],
+                },
+                (32, 32),
+            ),
+            (ImageOperation::Crop([1000, 1000, 1000, 1000]), (25, 12)),
+            (
+                ImageOperation::Resize {
+                    dimensions: (64, 8),
+                    aspect: false,
+                    filter: ScaleFilter::Lanczos3,
+                },
+                (64, 8),
+            ),
+            (ImageOperation::Rotate(90), (16, 32)),
+            (ImageOperation::Flip(true), (32, 16)),
+            (
+                ImageOperation::Text {
+                    text: "DRAFT\nshot 01".into(),
+                    font: String::new(),
+                    size: 30,
+                    color: [255, 0, 0],
+                    opacity: 200,
+                    anchor: Anchor::BottomRight,
+                    margin: 5,
+                    rotation: -30,
+                },
+                (32, 16),
+            ),
+            (
+                ImageOperation::Watermark {
+                    path: "tests/rust.png".into(),
+                    size: 50,
+                    opacity: 128,
+                    anchor: Anchor::TopLeft,
+                    margin: 0,
+                    rotation: 45,
+                },
+                (32, 16),
+            ),
+        ];
+
+        for color_type in ColorTypeExt::iter() {
+            for (op, dimensions) in &ops {
+                let mut img = test_image(&color_type);
+                op.process_image(&mut img).unwrap();
+                assert_eq!(
+                    img.color(),
+                    test_image(&color_type).color(),
+                    "{op} on {color_type}"
+                );
+                assert_eq!(img.dimensions(), *dimensions, "{op} on {color_type}");
+            }
+
+            let mut img = test_image(&ColorTypeExt::Rgba8);
+            ImageOperation::ColorConverter(color_type.clone())
+                .process_image(&mut img)
+                .unwrap();
+            assert_eq!(ColorTypeExt::from_image(img.color()), color_type);
+        }
+    }
+
+    #[test]
+    fn expression_inputs() {
+        assert_eq!(
+            rewrite_samplers("r = px(x + 1, y).g * px(px(x, y).r, y).b; a = cmp(0, 0).a;"),
+            "r = px_g(x + 1, y) * px_b(px_r(x, y), y); a = cmp_a(0, 0);"
+        );
+
+        let original = test_image(&ColorTypeExt::Rgba32F);
+        let mut img = original.clone();
+        ImageOperation::Expression("param k = 0.5 [0, 2]\nr = u * k;\ng = px(x + 1, y).r;".into())
+            .process_image(&mut img)
+            .unwrap();
+        let (img, original) = (img.as_rgba32f().unwrap(), original.as_rgba32f().unwrap());
+        assert!((img.get_pixel(3, 2)[0] - 3.5 / 32. * 0.5).abs() < 1e-6);
+        assert_eq!(img.get_pixel(3, 2)[1], original.get_pixel(4, 2)[0]);
+        // sampling is clamped to the edges
+        assert_eq!(img.get_pixel(31, 2)[1], original.get_pixel(31, 2)[0]);
+        // channels the expression does not assign stay as they are
+        assert_eq!(img.get_pixel(3, 2)[2], original.get_pixel(3, 2)[2]);
+
+        // assigning an integer changes the variable type for the next pixel
+        let mut img = DynamicImage::ImageRgba32F(original.clone());
+        ImageOperation::Expression("b = 1; r = r * 0.5".into())
+            .process_image(&mut img)
+            .unwrap();
+        for (p, o) in img.as_rgba32f().unwrap().pixels().zip(original.pixels()) {
+            assert_eq!(p[2], 1.0);
+            assert_eq!(p[0], o[0] * 0.5);
+        }
+    }
+
+    #[test]
+    fn sorting_stacks_keeps_the_order() {
+        let mut edit_state = EditState::default();
+        edit_state.image_op_stack = vec![ImgOpItem::new(ImageOperation::Blur(2))];
+        edit_state.pixel_op_stack = [
+            ImageOperation::Invert,
+            ImageOperation::Expression("r = px(x + 1, y).r".into()),
+            ImageOperation::Brightness(10),
+        ]
+        .into_iter()
+        .map(ImgOpItem::new)
+        .collect();
+        edit_state.sort_stacks();
+        assert_eq!(
+            edit_state.image_op_stack,
+            [
+                ImageOperation::Blur(2),
+                ImageOperation::Invert,
+                ImageOperation::Expression("r = px(x + 1, y).r".into()),
+            ]
+            .map(ImgOpItem::new)
+        );
+        assert_eq!(
+            edit_state.pixel_op_stack,
+            vec![ImgOpItem::new(ImageOperation::Brightness(10))]
+        );
+
+        // the moved pixel operator still works in the image stack
+        let mut img =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([10, 20, 30, 255])));
+        edit_state.image_op_stack[1]
+            .process_image(&mut img, &[])
+            .unwrap();
+        assert_eq!(
+            img.as_rgba8().unwrap().get_pixel(0, 0),
+            &Rgba([245, 235, 225, 255])
+        );
+    }
+
+    #[test]
+    fn blended_and_masked_operators() {
+        let img = DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(
+            20,
+            10,
+            Rgba([0.25, 0.5, 1.0, 1.0]),
+        ));
+        let brushes = crate::brushes::builtin_brushes();
+
+        let mut item = ImgOpItem::new(ImageOperation::Invert);
+        item.opacity = 50;
+        let mut result = img.clone();
+        process_pixel_stack(&mut result, &[item.clone()], &brushes).unwrap();
+        assert_eq!(
+            result.as_rgba32f().unwrap().get_pixel(0, 0).0,
+            [0.5, 0.5, 0.5, 1.0]
+        );
+
+        // only the left half is inverted
+        item.opacity = 100;
+        item.mask = Some(Mask {
+            shape: MaskShape::Rectangle([0, 0, 5000, 10000]),
+            feather: 0,
+            invert: false,
+        });
+        let mut result = img.clone();
+        process_pixel_stack(&mut result, &[item], &brushes).unwrap();
+        let result = result.as_rgba32f().unwrap();
+        assert_eq!(result.get_pixel(2, 5).0, [0.75, 0.5, 0.0, 1.0]);
+        assert_eq!(result.get_pixel(17, 5).0, [0.25, 0.5, 1.0, 1.0]);
+
+        assert_eq!(BlendMode::Multiply.blend(0.5, 0.5), 0.25);
+        assert_eq!(BlendMode::Screen.blend(0.5, 0.5), 0.75);
+    }
+
+    #[test]
+    fn image_ops_keep_precision() {
+        let identity = ImageOperation::Filter3x3([0, 0, 0, 0, 100, 0, 0, 0, 0]);
+
+        let img = DynamicImage::ImageRgba16(image::ImageBuffer::from_fn(256, 64, |x, y| {
+            let v = (y * 256 + x) as u16 * 4;
+            image::Rgba([v, v + 1, v + 2, u16::MAX - v])
+        }));
+        let mut result = img.clone();
+        identity.process_image(&mut result).unwrap();
+        assert_eq!(result, img);
+
+        // HDR values must not clip
+        let mut img =
+            DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(8, 8, Rgba([3.0, 0.5, 0.25, 1.0])));
+        identity.process_image(&mut img).unwrap();
+        ImageOperation::Resize {
+            dimensions: (4, 4),
+            aspect: false,
+            filter: ScaleFilter::Bilinear,
+        }
+        .process_image(&mut img)
+        .unwrap();
+        let p = img.as_rgba32f().unwrap().get_pixel(2, 2);
+        assert!((p[0] - 3.0).abs() < 1e-4, "{:?}", p);
+    }
+
+    #[test]
+    fn watermark_placement() {
+        let background = Rgba([40, 40, 40, 255]);
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 100, background));
+        ImageOperation::Watermark {
+            path: "tests/rust.png".into(),
+            size: 20,
+            opacity: 255,
+            anchor: Anchor::BottomRight,
+            margin: 0,
+            rotation: 0,
+        }
+        .process_image(&mut img)
+        .unwrap();
+        // the 20x20 mark sits in the bottom right corner and nowhere else
+        let (mark, rest): (Vec<_>, Vec<_>) = img
+            .as_rgba8()
+            .unwrap()
+            .enumerate_pixels()
+            .partition(|(x, y, _)| *x >= 80 && *y >= 80);
+        assert!(mark.iter().any(|(_, _, p)| **p != background));
+        assert!(rest.iter().all(|(_, _, p)| **p == background));
+    }
+
+    #[test]
+    fn vignette_split_toning_and_grain() {
+        let gray = DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(
+            32,
+            32,
+            Rgba([0.5, 0.5, 0.5, 1.0]),
+        ));
+
+        // darkening leaves the center as it is and darkens the corners
+        let mut img = gray.clone();
+        ImageOperation::Vignette {
+            amount: -50,
+            midpoint: 50,
+            roundness: 0,
+            feather: 50,
+        }
+        .process_image(&mut img)
+        .unwrap();
+        let img = img.into_rgba32f();
+        let (center, corner) = (img.get_pixel(16, 16)[0], img.get_pixel(0, 0)[0]);
+        assert_eq!(center, 0.5);
+        assert!(corner < center, "corner {corner}, center {center}");
+
+        // blue shadows and orange highlights
+        let toning = ImageOperation::SplitToning {
+            shadows: [0, 0, 255],
+            highlights: [255, 128, 0],
+            balance: 0,
+        };
+        let mut shadow = Vector4::new(0.1, 0.1, 0.1, 1.0);
+        let mut highlight = Vector4::new(0.9, 0.9, 0.9, 1.0);
+        toning.process_pixel(&mut shadow).unwrap();
+        toning.process_pixel(&mut highlight).unwrap();
+        assert!(shadow[2] > shadow[0], "{shadow:?}");
+        assert!(highlight[0] > highlight[2], "{highlight:?}");
+
+        // the seed decides the grain
+        let grain = |seed| {
+            let mut img = gray.clone();
+            ImageOperation::Grain {
+                amount: 50,
+                size: 0,
+                seed,
+            }
+            .process_image(&mut img)
+            .unwrap();
+            img
+        };
+        assert_ne!(grain(1), gray);
+        assert_eq!(grain(1), grain(1));
+        assert_ne!(grain(1), grain(2));
+    }
+
+    #[test]
+    fn canvas_placement() {
+        let red = Rgba([255, 0, 0, 255]);
+        let blue = [0, 0, 255, 255];
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, red));
+        ImageOperation::Pad {
+            sides: [1, 2, 3, 4],
+            percent: false,
+            color: blue,
+        }
+        .process_image(&mut img)
+        .unwrap();
+        let padded = img.as_rgba8().unwrap();
+        assert_eq!(padded.dimensions(), (8, 8));
+        for (x, y, p) in padded.enumerate_pixels() {
+            let inside = (1..5).contains(&x) && (2..4).contains(&y);
+            assert_eq!(*p, if inside { red } else { Rgba(blue) }, "{x}, {y}");
+        }
+
+        // extending to a square places the image at the anchor
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, red));
+        ImageOperation::ExtendAspect {
+            ratio: (1, 1),
+            anchor: Anchor::Bottom,
+            color: blue,
+        }
+        .process_image(&mut img)
+        .unwrap();
+        let extended = img.as_rgba8().unwrap();
+        assert_eq!(extended.dimensions(), (4, 4));
+        assert_eq!(extended.get_pixel(0, 1), &Rgba(blue));
+        assert_eq!(extended.get_pixel(0, 2), &red);
+        assert_eq!(extended.get_pixel(3, 3), &red);
+
+        // sides from a broken sidecar are refused
+        let mut img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 2, red));
+        assert!(ImageOperation::Pad {
+            sides: [u32::MAX, 0, u32::MAX, 0],
+            percent: true,
+            color: blue,
+        }
+        .process_image(&mut img)
+        .is_err());
+        assert!(ImageOperation::ExtendAspect {
+            ratio: (u32::MAX, 1),
+            anchor: Anchor::Center,
+            color: blue,
+        }
+        .process_image(&mut img)
+        .is_err());
+        assert_eq!(img.dimensions(), (4, 2));
+    }
 }
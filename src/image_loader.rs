@@ -96,10 +96,8 @@ pub fn open_image(
             }
         }
         "dcm" | "ima" => {
-            use dicom_pixeldata::PixelDecoder;
-            let obj = dicom_object::open_file(img_location)?;
-            let image = obj.decode_pixel_data()?;
-            let dynamic_image = image.to_dynamic_image(0)?;
+            let dynamic_image =
+                crate::dicom::decode_frame(&img_location, 0, crate::dicom::Windowing::File)?;
             _ = sender.send(Frame::new_still(dynamic_image));
         }
         "ktx2" => {
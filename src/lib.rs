@@ -1,9 +1,17 @@
+pub mod annotations;
 pub mod appstate;
+pub mod brushes;
 pub mod cache;
+pub mod calibration;
 pub mod comparelist;
+pub mod cull;
+pub mod dicom;
+pub mod duplicates;
+pub mod edit_history;
 pub mod image_editing;
 pub mod image_loader;
 pub mod ktx2_loader;
+pub mod metadata;
 pub mod settings;
 pub mod shortcuts;
 pub mod utils;
@@ -11,10 +19,16 @@ pub const FONT: &[u8; 309828] = include_bytes!("../res/fonts/Inter-Regular.ttf")
 pub const BOLD_FONT: &[u8; 344152] = include_bytes!("../res/fonts/Inter-Bold.ttf");
 pub mod file_encoder;
 pub mod filebrowser;
+pub mod geotag;
+pub mod histogram;
 pub mod icons;
 pub mod net;
 pub mod paint;
+pub mod presets;
+pub mod rating;
+pub mod scopes;
 pub mod scrubber;
+pub mod sidecar;
 pub mod texture_wrapper;
 pub mod thumbnails;
 pub mod ui;
@@ -3,7 +3,6 @@
 use clap::Arg;
 use clap::Command;
 use image::GenericImageView;
-use image_editing::LegacyEditState;
 use log::debug;
 use log::error;
 use log::info;
@@ -23,6 +22,8 @@ use notan::egui::FontTweak;
 use notan::egui::Id;
 use notan::prelude::*;
 use oculante::comparelist::CompareItem;
+use oculante::cull::CullAction;
+use oculante::rating::{ColorLabel, Flag};
 use std::io::{stdin, IsTerminal, Read};
 use std::path::PathBuf;
 use std::sync::mpsc;
@@ -40,9 +41,9 @@ use ui::*;
 
 #[cfg(feature = "turbo")]
 use image_editing::lossless_tx;
-use image_editing::EditState;
 use scrubber::find_first_image_in_directory;
 use shortcuts::InputEvent::*;
+use shortcuts::{COPY_TO, MOVE_TO};
 
 #[notan_main]
 fn main() -> Result<(), String> {
@@ -198,6 +199,11 @@ fn init(_app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins) -> OculanteSt
         ..Default::default()
     };
 
+    state.ratings.import_favourites(
+        &mut state.volatile_settings.favourite_images,
+        state.persistent_settings.rating_storage,
+    );
+
     state.player = Player::new(
         state.texture_channel.0.clone(),
         state.persistent_settings.max_cache,
@@ -471,6 +477,14 @@ fn process_events(app: &mut App, state: &mut OculanteState, evt: Event) {
                     Err(e) => state.send_message_err(&e.to_string()),
                 }
             }
+            if state.persistent_settings.edit_enabled {
+                if key_pressed(app, state, Undo) && state.edit_history.undo(&mut state.edit_state) {
+                    state.send_message_info("Undo");
+                }
+                if key_pressed(app, state, Redo) && state.edit_history.redo(&mut state.edit_state) {
+                    state.send_message_info("Redo");
+                }
+            }
             if key_pressed(app, state, Quit) {
                 _ = state.persistent_settings.save_blocking();
                 _ = state.volatile_settings.save_blocking();
@@ -546,6 +560,50 @@ fn process_events(app: &mut App, state: &mut OculanteState, evt: Event) {
             if key_pressed(app, state, ClearImage) {
                 clear_image(state);
             }
+            for (event, stars) in [
+                (Rate0, 0),
+                (Rate1, 1),
+                (Rate2, 2),
+                (Rate3, 3),
+                (Rate4, 4),
+                (Rate5, 5),
+            ] {
+                if key_pressed(app, state, event) {
+                    update_rating(state, |r| r.stars = stars);
+                }
+            }
+            for (event, label) in [
+                (LabelRed, ColorLabel::Red),
+                (LabelYellow, ColorLabel::Yellow),
+                (LabelGreen, ColorLabel::Green),
+                (LabelBlue, ColorLabel::Blue),
+                (LabelPurple, ColorLabel::Purple),
+            ] {
+                if key_pressed(app, state, event) {
+                    // setting the same label again removes it
+                    update_rating(state, |r| {
+                        r.label = (r.label != Some(label)).then_some(label)
+                    });
+                }
+            }
+            for (event, flag) in [(Pick, Flag::Pick), (Reject, Flag::Reject)] {
+                if key_pressed(app, state, event) {
+                    update_rating(state, |r| r.flag = (r.flag != Some(flag)).then_some(flag));
+                }
+            }
+            for (target, event) in MOVE_TO.into_iter().enumerate() {
+                if key_pressed(app, state, event) {
+                    cull_file(state, CullAction::Move, target);
+                }
+            }
+            for (target, event) in COPY_TO.into_iter().enumerate() {
+                if key_pressed(app, state, event) {
+                    cull_file(state, CullAction::Copy, target);
+                }
+            }
+            if key_pressed(app, state, UndoCull) {
+                undo_cull(state);
+            }
             if key_pressed(app, state, ZoomIn) {
                 let delta = zoomratio(3.5, state.image_geometry.scale);
                 let new_scale = state.image_geometry.scale + delta;
@@ -755,6 +813,11 @@ fn update(app: &mut App, state: &mut OculanteState) {
         app.window().request_frame();
     }
 
+    if let Some(scopes) = state.scopes_worker.latest() {
+        state.scopes = Some(Arc::new(scopes));
+        app.window().request_frame();
+    }
+
     // check if a new message has been sent
     if let Ok(msg) = state.message_channel.1.try_recv() {
         debug!("Received message: {:?}", msg);
@@ -807,17 +870,20 @@ fn drawe(app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins, state: &mut O
             Frame::AnimationStart(_) | Frame::Still(_) | Frame::ImageCollectionMember(_)
         ) {
             // Something new came in, update scrubber (index slider) and path
-            if let Some(path) = &state.current_path {
-                if state.scrubber.has_folder_changed(path) && !state.scrubber.fixed_paths {
+            if let Some(path) = state.current_path.clone() {
+                if state.scrubber.has_folder_changed(&path) && !state.scrubber.fixed_paths {
                     debug!("Folder has changed, creating new scrubber");
-                    state.scrubber = scrubber::Scrubber::new(path);
+                    let filter = state.scrubber.filter.clone();
+                    state.scrubber = scrubber::Scrubber::new(&path);
                     state.scrubber.wrap = state.persistent_settings.wrap_folder;
+                    state.scrubber.filter = filter;
+                    apply_rating_filter(state);
                 } else {
                     let index = state
                         .scrubber
                         .entries
                         .iter()
-                        .position(|p| p == path)
+                        .position(|p| *p == path)
                         .unwrap_or_default();
                     if index < state.scrubber.entries.len() {
                         state.scrubber.index = index;
@@ -857,70 +923,54 @@ fn drawe(app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins, state: &mut O
 
                 if !state.persistent_settings.keep_edits {
                     state.edit_state = Default::default();
-                    state.edit_state = Default::default();
+                    state.edit_history = Default::default();
                 }
 
                 // Load edit information if any
                 if let Some(p) = &state.current_path {
-                    if p.with_extension("oculante").is_file() {
-                        if let Ok(f) = std::fs::File::open(p.with_extension("oculante")) {
-                            match serde_json::from_reader::<_, EditState>(f) {
-                                Ok(edit_state) => {
-                                    state.send_message_info(
-                                        "Edits have been loaded for this image.",
-                                    );
-                                    state.edit_state = edit_state;
-                                    state.persistent_settings.edit_enabled = true;
-                                    state.reset_image = true;
-                                }
-                                Err(e) => {
-                                    // state.send_message_info("Edits have been loaded for this image.");
-                                    warn!("{e}");
-
-                                    if let Ok(f) = std::fs::File::open(p.with_extension("oculante"))
-                                    {
-                                        if let Ok(legacy_edit_state) =
-                                            serde_json::from_reader::<_, LegacyEditState>(f)
-                                        {
-                                            warn!("Legacy edits found");
-                                            state.send_message_info(
-                                                "Edits have been loaded for this image.",
-                                            );
-                                            state.edit_state = legacy_edit_state.upgrade();
-                                            state.persistent_settings.edit_enabled = true;
-                                            state.reset_image = true;
-                                            // Migrate config
-                                            if let Ok(f) =
-                                                std::fs::File::create(p.with_extension("oculante"))
-                                            {
-                                                _ = serde_json::to_writer_pretty(
-                                                    &f,
-                                                    &state.edit_state,
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        state.send_message_err("Edits could not be loaded.");
+                    let sidecar_path = sidecar::edits_path(p);
+                    let dir_sidecar_path = p.parent().map(|parent| parent.join(".oculante"));
+                    let loaded = if sidecar_path.is_file() {
+                        Some((sidecar::load(&sidecar_path, Some(p.as_path())), false))
+                    } else if let Some(dir_sidecar_path) = &dir_sidecar_path {
+                        debug!("Looking for {}", dir_sidecar_path.display());
+                        dir_sidecar_path
+                            .is_file()
+                            .then(|| (sidecar::load(dir_sidecar_path, None), true))
+                    } else {
+                        None
+                    };
+                    match loaded {
+                        Some((Ok(loaded), directory)) => {
+                            if directory {
+                                state.send_message_info(
+                                    "Directory edits have been loaded for this image.",
+                                );
+                            } else {
+                                state.send_message_info("Edits have been loaded for this image.");
+                                // Migrate config, unless that would drop unknown operators
+                                if loaded.migrated && loaded.warnings.is_empty() {
+                                    if let Err(e) = sidecar::save(
+                                        &sidecar_path,
+                                        &loaded.edit_state,
+                                        Some(p.as_path()),
+                                    ) {
+                                        warn!("Could not migrate edits: {e}");
                                     }
                                 }
                             }
-                        }
-                    } else if let Some(parent) = p.parent() {
-                        debug!("Looking for {}", parent.join(".oculante").display());
-                        if parent.join(".oculante").is_file() {
-                            debug!("is file {}", parent.join(".oculante").display());
-
-                            if let Ok(f) = std::fs::File::open(parent.join(".oculante")) {
-                                if let Ok(edit_state) = serde_json::from_reader::<_, EditState>(f) {
-                                    state.send_message_info(
-                                        "Directory edits have been loaded for this image.",
-                                    );
-                                    state.edit_state = edit_state;
-                                    state.persistent_settings.edit_enabled = true;
-                                    state.reset_image = true;
-                                }
+                            for warning in &loaded.warnings {
+                                state.send_message_warn(warning);
                             }
+                            state.edit_state = loaded.edit_state;
+                            state.persistent_settings.edit_enabled = true;
+                            state.reset_image = true;
+                        }
+                        Some((Err(e), _)) => {
+                            warn!("{e}");
+                            state.send_message_err("Edits could not be loaded.");
                         }
+                        None => (),
                     }
                 }
                 state.redraw = false;
@@ -949,9 +999,21 @@ fn drawe(app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins, state: &mut O
             Frame::UpdateTexture => {}
         }
 
-        if !matches!(frame, Frame::Animation(_, _)) {
+        // DICOM frames and windowing only change the pixels of the same file, so they keep the tags
+        let same_file = matches!(frame, Frame::EditResult(_));
+        if !matches!(frame, Frame::Animation(_, _)) && !same_file {
             state.image_metadata = None;
         }
+        // A selected region belongs to the image it was drawn on
+        if matches!(
+            frame,
+            Frame::Still(_)
+                | Frame::AnimationStart(_)
+                | Frame::ImageCollectionMember(_)
+                | Frame::CompareResult(_, _)
+        ) {
+            state.region = None;
+        }
 
         // Deal with everything that sends an image
         match frame {
@@ -1008,11 +1070,27 @@ fn drawe(app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins, state: &mut O
         // In those cases, we want the image to stay as it is.
         // TODO: PERF: This copies the image buffer. This should also maybe not run for animation frames
         // although it looks cool.
-        send_extended_info(
-            &state.current_image,
-            &state.current_path,
-            &state.extended_info_channel,
-        );
+        match (&state.image_metadata, same_file) {
+            (Some(info), true) => {
+                send_pixel_info(&state.current_image, info, &state.extended_info_channel)
+            }
+            _ => send_extended_info(
+                &state.current_image,
+                &state.current_path,
+                &state.extended_info_channel,
+            ),
+        }
+
+        // Scopes follow the edit result, so they update live while editing
+        if state.persistent_settings.info_enabled {
+            if state.edit_state.result_pixel_op.width() > 0 {
+                state.scopes_worker.request(&state.edit_state.result_pixel_op);
+            } else if let Some(img) = &state.current_image {
+                state.scopes_worker.request(img);
+            }
+        } else {
+            state.scopes = None;
+        }
     }
 
     if state.redraw {
@@ -1154,6 +1232,8 @@ fn drawe(app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins, state: &mut O
             }
         }
 
+        duplicates_ui(ctx, state);
+
         // Settings come last, as they block keyboard grab (for hotkey assigment)
         settings_ui(app, ctx, state, gfx);
     });
@@ -0,0 +1,1221 @@
+//! Editing EXIF, XMP and IPTC metadata in place, without re-encoding pixels
+
+use std::{
+    fmt, fs,
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use exif::{experimental::Writer, Field, In, Rational, Tag, Value};
+use img_parts::{
+    jpeg::{markers, Jpeg, JpegSegment},
+    png::{Png, PngChunk},
+    riff::{RiffChunk, RiffContent},
+    webp::{WebP, CHUNK_ALPH, CHUNK_EXIF, CHUNK_ICCP, CHUNK_VP8L, CHUNK_VP8X, CHUNK_XMP},
+    Bytes, DynImage, ImageEXIF,
+};
+use log::debug;
+use notan::egui::{self, TextEdit, Ui};
+use serde::{Deserialize, Serialize};
+use strum::{EnumIter, IntoEnumIterator};
+
+/// JPEG APP1 segments holding XMP start with this
+const XMP_JPEG_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// PNG iTXt chunks holding XMP use this keyword
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+/// JPEG APP13 segments holding Photoshop resources, including IPTC, start with this
+const PHOTOSHOP_PREFIX: &[u8] = b"Photoshop 3.0\0";
+const CHUNK_ITXT: [u8; 4] = *b"iTXt";
+/// Photoshop resource holding IPTC-IIM records
+const RESOURCE_IPTC: u16 = 0x0404;
+/// Photoshop resource holding an MD5 of the IPTC records, stale once they change
+const RESOURCE_IPTC_DIGEST: u16 = 0x0425;
+
+const IPTC_CHARSET: (u8, u8) = (1, 90);
+const IPTC_RECORD_VERSION: (u8, u8) = (2, 0);
+const IPTC_TITLE: (u8, u8) = (2, 5);
+const IPTC_KEYWORDS: (u8, u8) = (2, 25);
+const IPTC_COPYRIGHT: (u8, u8) = (2, 116);
+const IPTC_CAPTION: (u8, u8) = (2, 120);
+/// Longer values would need extended datasets, which are not used for text
+const IPTC_MAX_LEN: usize = 0x7fff;
+/// ESC % G, marking IPTC text as UTF-8
+const IPTC_UTF8: &[u8] = b"\x1b%G";
+
+/// An otherwise empty XMP packet new properties are added to
+pub(crate) const XMP_TEMPLATE: &str = "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>
+<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">
+ <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end=\"w\"?>";
+/// The description oculante writes Dublin Core properties into
+const XMP_DC_DESCRIPTION: &str =
+    "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">";
+
+/// A position in decimal degrees, and meters above sea level
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+impl GpsPosition {
+    /// Read the GPS IFD of EXIF data
+    pub fn from_exif(exif: &exif::Exif) -> Option<Self> {
+        let degrees = |tag: Tag, reference: Tag, negative: u8| {
+            let Value::Rational(dms) = &exif.get_field(tag, In::PRIMARY)?.value else {
+                return None;
+            };
+            let value = dms
+                .iter()
+                .zip([1., 60., 3600.])
+                .map(|(r, div)| r.to_f64() / div)
+                .sum::<f64>();
+            let sign = match &exif.get_field(reference, In::PRIMARY)?.value {
+                Value::Ascii(v) if v.first().and_then(|s| s.first()) == Some(&negative) => -1.,
+                _ => 1.,
+            };
+            value.is_finite().then_some(value * sign)
+        };
+        let altitude = match exif
+            .get_field(Tag::GPSAltitude, In::PRIMARY)
+            .map(|f| &f.value)
+        {
+            Some(Value::Rational(v)) => v.first().map(|r| r.to_f64()).filter(|a| a.is_finite()),
+            _ => None,
+        }
+        .map(|altitude| {
+            let below = exif
+                .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                .and_then(|f| f.value.get_uint(0))
+                == Some(1);
+            if below {
+                -altitude
+            } else {
+                altitude
+            }
+        });
+        Some(Self {
+            latitude: degrees(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?,
+            longitude: degrees(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?,
+            altitude,
+        })
+    }
+
+    /// The fields of a GPS IFD describing this position
+    fn exif_fields(&self) -> Vec<Field> {
+        let field = |tag, value| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        };
+        let dms = |degrees: f64| {
+            let degrees = degrees.abs();
+            let minutes = degrees.fract() * 60.;
+            let seconds = minutes.fract() * 60.;
+            Value::Rational(vec![
+                Rational::from((degrees as u32, 1)),
+                Rational::from((minutes as u32, 1)),
+                Rational::from(((seconds * 10000.).round() as u32, 10000)),
+            ])
+        };
+        let reference = |positive: bool, p: &str, n: &str| {
+            Value::Ascii(vec![if positive { p } else { n }.as_bytes().to_vec()])
+        };
+        let mut fields = vec![
+            field(Tag::GPSVersionID, Value::Byte(vec![2, 3, 0, 0])),
+            field(
+                Tag::GPSLatitudeRef,
+                reference(self.latitude >= 0., "N", "S"),
+            ),
+            field(Tag::GPSLatitude, dms(self.latitude)),
+            field(
+                Tag::GPSLongitudeRef,
+                reference(self.longitude >= 0., "E", "W"),
+            ),
+            field(Tag::GPSLongitude, dms(self.longitude)),
+        ];
+        if let Some(altitude) = self.altitude {
+            fields.push(field(
+                Tag::GPSAltitudeRef,
+                Value::Byte(vec![(altitude < 0.) as u8]),
+            ));
+            fields.push(field(
+                Tag::GPSAltitude,
+                Value::Rational(vec![Rational::from((
+                    (altitude.abs() * 100.).round() as u32,
+                    100,
+                ))]),
+            ));
+        }
+        fields
+    }
+}
+
+/// The editable subset of an image's metadata, merged from EXIF, XMP and IPTC.
+/// Empty strings and `None` mean the field is not set.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    pub title: String,
+    pub description: String,
+    pub copyright: String,
+    pub keywords: Vec<String>,
+    /// EXIF orientation, 1 to 8
+    pub orientation: Option<u16>,
+    /// EXIF `DateTimeOriginal`, formatted as `YYYY:MM:DD HH:MM:SS`
+    pub date_time_original: String,
+    pub gps: Option<GpsPosition>,
+}
+
+/// The metadata packets stored in an image file
+#[derive(Debug, Default)]
+struct Packets {
+    /// TIFF-structured EXIF, without any container prefix
+    exif: Option<Bytes>,
+    xmp: Option<String>,
+    /// Photoshop image resources, which may hold IPTC
+    photoshop: Option<Bytes>,
+    /// The VP8X header of a WebP, which replacing the EXIF may drop
+    webp_header: Option<Bytes>,
+}
+
+impl Packets {
+    fn read(image: &DynImage) -> Self {
+        let exif = image.exif();
+        match image {
+            DynImage::Jpeg(jpeg) => Self {
+                exif,
+                xmp: jpeg_segment(jpeg, markers::APP1, XMP_JPEG_PREFIX)
+                    .map(|xmp| String::from_utf8_lossy(&xmp).to_string()),
+                photoshop: jpeg_segment(jpeg, markers::APP13, PHOTOSHOP_PREFIX),
+                webp_header: None,
+            },
+            DynImage::Png(png) => Self {
+                exif,
+                xmp: png_xmp(png),
+                photoshop: None,
+                webp_header: None,
+            },
+            DynImage::WebP(webp) => Self {
+                exif,
+                xmp: webp
+                    .chunk_by_id(CHUNK_XMP)
+                    .and_then(|chunk| chunk.content().data())
+                    .map(|xmp| String::from_utf8_lossy(xmp).to_string()),
+                photoshop: None,
+                webp_header: webp
+                    .chunk_by_id(CHUNK_VP8X)
+                    .and_then(|chunk| chunk.content().data())
+                    .cloned(),
+            },
+        }
+    }
+}
+
+impl Metadata {
+    /// Read the metadata of a JPEG, PNG or WebP file
+    pub fn from_bytes(bytes: Bytes) -> Result<Self> {
+        let image = DynImage::from_bytes(bytes)?.context("Unsupported metadata format")?;
+        let packets = Packets::read(&image);
+        Ok(Self::from_packets(
+            packets.exif.as_deref(),
+            packets.xmp.as_deref(),
+            packets.photoshop.as_deref(),
+        ))
+    }
+
+    /// Merge the fields found in each packet. XMP is preferred, then EXIF, then IPTC.
+    fn from_packets(exif: Option<&[u8]>, xmp: Option<&str>, photoshop: Option<&[u8]>) -> Self {
+        let exif = exif.and_then(|e| exif::Reader::new().read_raw(e.to_vec()).ok());
+        let exif_text = |tag: Tag| match exif.as_ref()?.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(ref v) => v.first().map(|s| {
+                String::from_utf8_lossy(s)
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string()
+            }),
+            _ => None,
+        };
+        let xmp_text = |tag: &str| xmp.and_then(|x| xmp_values(x, tag).into_iter().next());
+        let iptc = photoshop.map(iptc_datasets).unwrap_or_default();
+        let iptc_values = |key: (u8, u8)| {
+            iptc.iter()
+                .filter(|(k, _)| *k == key)
+                .map(|(_, v)| String::from_utf8_lossy(v).trim().to_string())
+                .collect::<Vec<_>>()
+        };
+        let first = |values: [Option<String>; 3]| {
+            values
+                .into_iter()
+                .flatten()
+                .find(|v| !v.is_empty())
+                .unwrap_or_default()
+        };
+        let mut keywords = xmp.map(|x| xmp_values(x, "dc:subject")).unwrap_or_default();
+        if keywords.is_empty() {
+            keywords = iptc_values(IPTC_KEYWORDS);
+        }
+
+        Self {
+            title: first([
+                xmp_text("dc:title"),
+                None,
+                iptc_values(IPTC_TITLE).into_iter().next(),
+            ]),
+            description: first([
+                xmp_text("dc:description"),
+                exif_text(Tag::ImageDescription),
+                iptc_values(IPTC_CAPTION).into_iter().next(),
+            ]),
+            copyright: first([
+                xmp_text("dc:rights"),
+                exif_text(Tag::Copyright),
+                iptc_values(IPTC_COPYRIGHT).into_iter().next(),
+            ]),
+            keywords,
+            orientation: exif
+                .as_ref()
+                .and_then(|e| e.get_field(Tag::Orientation, In::PRIMARY))
+                .and_then(|f| f.value.get_uint(0))
+                .map(|o| o as u16),
+            date_time_original: exif_text(Tag::DateTimeOriginal).unwrap_or_default(),
+            gps: exif.as_ref().and_then(GpsPosition::from_exif),
+        }
+    }
+
+    /// Check the fields can be written
+    pub fn validate(&self) -> Result<()> {
+        if let Some(orientation) = self.orientation {
+            if !(1..=8).contains(&orientation) {
+                bail!("Orientation must be between 1 and 8.");
+            }
+        }
+        if !self.date_time_original.is_empty() {
+            exif::DateTime::from_ascii(self.date_time_original.as_bytes())
+                .map_err(|_| anyhow::anyhow!("Dates must look like YYYY:MM:DD HH:MM:SS."))?;
+        }
+        if let Some(gps) = self.gps {
+            if gps.latitude.abs() > 90. || gps.longitude.abs() > 180. {
+                bail!("GPS coordinates are out of range.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the metadata into a JPEG, PNG or WebP file. Only the metadata is replaced,
+    /// image data is copied as it is. Fields oculante does not edit are kept.
+    /// IPTC only exists in JPEG, other formats get EXIF and XMP.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        self.validate()?;
+        let input = fs::read(path)?;
+        let mut image = DynImage::from_bytes(input.into())?
+            .context("Metadata can only be written to JPEG, PNG and WebP files")?;
+        let packets = Packets::read(&image);
+
+        image.set_exif(self.apply_exif(packets.exif.as_deref())?.map(Bytes::from));
+        let xmp = self.apply_xmp(packets.xmp.as_deref());
+        match &mut image {
+            DynImage::Jpeg(jpeg) => {
+                let photoshop = self.apply_iptc(packets.photoshop.as_deref());
+                set_jpeg_segment(jpeg, markers::APP1, XMP_JPEG_PREFIX, xmp.map(Vec::from));
+                set_jpeg_segment(jpeg, markers::APP13, PHOTOSHOP_PREFIX, photoshop);
+            }
+            DynImage::Png(png) => set_png_xmp(png, xmp),
+            DynImage::WebP(webp) => set_webp_xmp(webp, xmp, packets.webp_header)?,
+        }
+
+        let mut output = vec![];
+        image.encoder().write_to(&mut output)?;
+        fs::write(path, output)?;
+        debug!("Wrote metadata to {}", path.display());
+        Ok(())
+    }
+
+    /// Replace the edited fields of existing EXIF data. `None` if nothing is left.
+    fn apply_exif(&self, exif: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+        let existing = exif.and_then(|e| exif::Reader::new().read_raw(e.to_vec()).ok());
+        let edited = [
+            Tag::ImageDescription,
+            Tag::Copyright,
+            Tag::Orientation,
+            Tag::DateTimeOriginal,
+        ];
+        let mut fields = existing
+            .iter()
+            .flat_map(|e| e.fields())
+            .filter(|f| {
+                !(f.ifd_num == In::PRIMARY
+                    && (edited.contains(&f.tag) || f.tag.context() == exif::Context::Gps))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let ascii = |tag, text: &str| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![text.as_bytes().to_vec()]),
+        };
+        if !self.description.is_empty() {
+            fields.push(ascii(Tag::ImageDescription, &self.description));
+        }
+        if !self.copyright.is_empty() {
+            fields.push(ascii(Tag::Copyright, &self.copyright));
+        }
+        if !self.date_time_original.is_empty() {
+            fields.push(ascii(Tag::DateTimeOriginal, &self.date_time_original));
+        }
+        if let Some(orientation) = self.orientation {
+            fields.push(Field {
+                tag: Tag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![orientation]),
+            });
+        }
+        if let Some(gps) = self.gps {
+            fields.extend(gps.exif_fields());
+        }
+        write_exif(existing.as_ref(), &fields, true)
+    }
+
+    /// Replace the Dublin Core properties of an XMP packet, keeping everything else.
+    /// `None` if there was no packet and there is nothing to add.
+    fn apply_xmp(&self, xmp: Option<&str>) -> Option<String> {
+        let properties = [
+            ("dc:title", "rdf:Alt", vec![self.title.clone()]),
+            ("dc:description", "rdf:Alt", vec![self.description.clone()]),
+            ("dc:rights", "rdf:Alt", vec![self.copyright.clone()]),
+            ("dc:subject", "rdf:Bag", self.keywords.clone()),
+        ];
+        let mut xmp = xmp.unwrap_or(XMP_TEMPLATE).to_string();
+        for (tag, _, _) in &properties {
+            remove_xmp_property(&mut xmp, tag);
+        }
+
+        let mut added = String::new();
+        for (tag, container, values) in &properties {
+            let values = values.iter().filter(|v| !v.is_empty()).collect::<Vec<_>>();
+            if values.is_empty() {
+                continue;
+            }
+            let lang = if *container == "rdf:Alt" {
+                " xml:lang=\"x-default\""
+            } else {
+                ""
+            };
+            added += &format!("\n   <{tag}>\n    <{container}>");
+            for value in values {
+                added += &format!("\n     <rdf:li{lang}>{}</rdf:li>", escape_xml(value));
+            }
+            added += &format!("\n    </{container}>\n   </{tag}>");
+        }
+
+        if let Some(start) = xmp.find(XMP_DC_DESCRIPTION) {
+            xmp.insert_str(start + XMP_DC_DESCRIPTION.len(), &added);
+        } else if !added.is_empty() {
+            let end = xmp.find("</rdf:RDF>")?;
+            xmp.insert_str(
+                end,
+                &format!("  {XMP_DC_DESCRIPTION}{added}\n  </rdf:Description>\n "),
+            );
+        } else if xmp == XMP_TEMPLATE {
+            return None;
+        }
+        Some(xmp)
+    }
+
+    /// Replace the edited IPTC datasets of Photoshop resources, keeping everything else.
+    /// `None` if no resources are left.
+    fn apply_iptc(&self, photoshop: Option<&[u8]>) -> Option<Vec<u8>> {
+        let resources = photoshop.map(photoshop_resources).unwrap_or_default();
+        let edited = [
+            IPTC_CHARSET,
+            IPTC_TITLE,
+            IPTC_KEYWORDS,
+            IPTC_COPYRIGHT,
+            IPTC_CAPTION,
+        ];
+        let mut datasets = resources
+            .iter()
+            .find(|(id, _, _)| *id == RESOURCE_IPTC)
+            .map(|(_, _, data)| iptc_records(data))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(key, _)| !edited.contains(key))
+            .collect::<Vec<_>>();
+
+        // cut on a character boundary, so the text stays valid UTF-8
+        let text = |key, value: &str| {
+            let len = value
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([value.len()])
+                .take_while(|i| *i <= IPTC_MAX_LEN)
+                .last()
+                .unwrap_or_default();
+            (key, value.as_bytes()[..len].to_vec())
+        };
+        for (key, value) in [
+            (IPTC_TITLE, &self.title),
+            (IPTC_COPYRIGHT, &self.copyright),
+            (IPTC_CAPTION, &self.description),
+        ] {
+            if !value.is_empty() {
+                datasets.push(text(key, value));
+            }
+        }
+        datasets.extend(
+            self.keywords
+                .iter()
+                .filter(|k| !k.is_empty())
+                .map(|k| text(IPTC_KEYWORDS, k)),
+        );
+        if datasets.iter().any(|(key, _)| key.0 == 2) {
+            if !datasets.iter().any(|(key, _)| *key == IPTC_RECORD_VERSION) {
+                datasets.push((IPTC_RECORD_VERSION, vec![0, 4]));
+            }
+            datasets.push((IPTC_CHARSET, IPTC_UTF8.to_vec()));
+        }
+        // stable, so repeated datasets like keywords keep their order
+        datasets.sort_by_key(|(key, _)| *key);
+
+        let mut records = vec![];
+        for ((record, dataset), value) in &datasets {
+            let len = value.len().min(IPTC_MAX_LEN);
+            records.extend([0x1c, *record, *dataset]);
+            records.extend((len as u16).to_be_bytes());
+            records.extend(&value[..len]);
+        }
+
+        let mut output = vec![];
+        let mut empty = true;
+        for (id, name, data) in resources
+            .iter()
+            .filter(|(id, _, _)| *id != RESOURCE_IPTC && *id != RESOURCE_IPTC_DIGEST)
+            .map(|(id, name, data)| (*id, name.as_slice(), data.as_slice()))
+            .chain((!records.is_empty()).then_some((RESOURCE_IPTC, &[][..], &records[..])))
+        {
+            empty = false;
+            output.extend(b"8BIM");
+            output.extend(id.to_be_bytes());
+            output.push(name.len() as u8);
+            output.extend(name);
+            if name.len() % 2 == 0 {
+                output.push(0);
+            }
+            output.extend((data.len() as u32).to_be_bytes());
+            output.extend(data);
+            if data.len() % 2 == 1 {
+                output.push(0);
+            }
+        }
+        (!empty).then_some(output)
+    }
+}
+
+/// How much metadata survives saving, or stripping files before publishing them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumIter)]
+pub enum PrivacyProfile {
+    #[default]
+    KeepAll,
+    RemoveGps,
+    /// Only the orientation, so images still display upright, and the ICC profile
+    OrientationAndIcc,
+    /// EXIF fields from a whitelist
+    Custom,
+}
+
+impl fmt::Display for PrivacyProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::KeepAll => write!(f, "Keep all"),
+            Self::RemoveGps => write!(f, "Remove GPS"),
+            Self::OrientationAndIcc => write!(f, "Only orientation and ICC"),
+            Self::Custom => write!(f, "Custom"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrivacySettings {
+    pub profile: PrivacyProfile,
+    /// EXIF field names kept by the custom profile. `XMP` and `IPTC` keep those
+    /// packets as a whole.
+    pub whitelist: Vec<String>,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            profile: Default::default(),
+            whitelist: [
+                "Orientation",
+                "DateTimeOriginal",
+                "Make",
+                "Model",
+                "ExposureTime",
+                "FNumber",
+                "PhotographicSensitivity",
+                "FocalLength",
+                "Artist",
+                "Copyright",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl PrivacySettings {
+    fn whitelisted(&self, name: &str) -> bool {
+        self.whitelist
+            .iter()
+            .any(|w| w.trim().eq_ignore_ascii_case(name))
+    }
+
+    fn keeps_field(&self, field: &Field) -> bool {
+        match self.profile {
+            PrivacyProfile::KeepAll => true,
+            PrivacyProfile::RemoveGps => field.tag.context() != exif::Context::Gps,
+            PrivacyProfile::OrientationAndIcc => {
+                field.ifd_num == In::PRIMARY && field.tag == Tag::Orientation
+            }
+            PrivacyProfile::Custom => {
+                field.ifd_num == In::PRIMARY && self.whitelisted(&field.tag.to_string())
+            }
+        }
+    }
+
+    /// Whether XMP and IPTC packets, or free text like comments, are kept
+    fn keeps_packet(&self, name: &str) -> bool {
+        match self.profile {
+            PrivacyProfile::KeepAll | PrivacyProfile::RemoveGps => true,
+            PrivacyProfile::OrientationAndIcc => false,
+            PrivacyProfile::Custom => self.whitelisted(name),
+        }
+    }
+
+    /// Remove what the profile does not keep from EXIF data. Thumbnails are only kept
+    /// by profiles that keep the camera data they may contain. `None` if nothing is left.
+    pub fn filter_exif(&self, exif: Option<Bytes>) -> Result<Option<Bytes>> {
+        let Some(exif) = exif else {
+            return Ok(None);
+        };
+        if self.profile == PrivacyProfile::KeepAll {
+            return Ok(Some(exif));
+        }
+        let existing = exif::Reader::new().read_raw(exif.to_vec())?;
+        let fields = existing
+            .fields()
+            .filter(|f| self.keeps_field(f))
+            .cloned()
+            .collect::<Vec<_>>();
+        let keep_thumbnail = self.profile == PrivacyProfile::RemoveGps;
+        Ok(write_exif(Some(&existing), &fields, keep_thumbnail)?.map(Bytes::from))
+    }
+
+    /// Strip metadata from a JPEG, PNG or WebP file in place, without re-encoding pixels.
+    /// ICC profiles are always kept.
+    pub fn strip_file(&self, path: &Path) -> Result<()> {
+        if self.profile == PrivacyProfile::KeepAll {
+            return Ok(());
+        }
+        let input = fs::read(path)?;
+        let mut image = DynImage::from_bytes(input.into())?
+            .context("Metadata can only be stripped from JPEG, PNG and WebP files")?;
+        let packets = Packets::read(&image);
+
+        image.set_exif(self.filter_exif(packets.exif)?);
+        let xmp = packets
+            .xmp
+            .filter(|_| self.keeps_packet("XMP"))
+            .map(|mut xmp| {
+                if self.profile == PrivacyProfile::RemoveGps {
+                    remove_xmp_gps(&mut xmp);
+                }
+                xmp
+            });
+        let keep_text = self.keeps_packet("XMP");
+        match &mut image {
+            DynImage::Jpeg(jpeg) => {
+                set_jpeg_segment(jpeg, markers::APP1, XMP_JPEG_PREFIX, xmp.map(Vec::from));
+                if !self.keeps_packet("IPTC") {
+                    set_jpeg_segment(jpeg, markers::APP13, PHOTOSHOP_PREFIX, None);
+                }
+                if !keep_text {
+                    jpeg.remove_segments_by_marker(markers::COM);
+                }
+            }
+            DynImage::Png(png) => {
+                set_png_xmp(png, xmp);
+                if !keep_text {
+                    png.chunks_mut()
+                        .retain(|c| ![*b"tEXt", *b"zTXt", CHUNK_ITXT].contains(&c.kind()));
+                }
+            }
+            DynImage::WebP(webp) => set_webp_xmp(webp, xmp, packets.webp_header)?,
+        }
+
+        let mut output = vec![];
+        image.encoder().write_to(&mut output)?;
+        fs::write(path, output)?;
+        debug!("Stripped metadata from {}", path.display());
+        Ok(())
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Metadata");
+            egui::ComboBox::from_id_salt("privacy_profile")
+                .selected_text(self.profile.to_string())
+                .show_ui(ui, |ui| {
+                    for profile in PrivacyProfile::iter() {
+                        ui.selectable_value(&mut self.profile, profile, profile.to_string());
+                    }
+                });
+        });
+        if self.profile == PrivacyProfile::Custom {
+            let id = ui.id().with("privacy_whitelist");
+            let mut text = ui
+                .data(|r| r.get_temp::<String>(id))
+                .unwrap_or_else(|| self.whitelist.join(", "));
+            if ui
+                .add(TextEdit::multiline(&mut text).hint_text("EXIF fields to keep, XMP, IPTC"))
+                .on_hover_text("Comma separated names of EXIF fields to keep, as shown in the EXIF panel. Add XMP or IPTC to keep those as a whole.")
+                .changed()
+            {
+                self.whitelist = text
+                    .split(',')
+                    .map(|w| w.trim().to_string())
+                    .filter(|w| !w.is_empty())
+                    .collect();
+            }
+            ui.data_mut(|w| w.insert_temp(id, text));
+        }
+    }
+}
+
+/// Remove the EXIF GPS properties XMP may carry along
+fn remove_xmp_gps(xmp: &mut String) {
+    let mut names = vec![];
+    for (start, _) in xmp.match_indices("exif:GPS") {
+        let len = xmp[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == ':'))
+            .unwrap_or(xmp.len() - start);
+        names.push(xmp[start..start + len].to_string());
+    }
+    names.sort();
+    names.dedup();
+    for name in names {
+        remove_xmp_property(xmp, &name);
+    }
+}
+
+/// Encode EXIF fields with the byte order of `existing`, optionally keeping its
+/// thumbnail. `None` if there are no fields for the main image.
+fn write_exif(
+    existing: Option<&exif::Exif>,
+    fields: &[Field],
+    keep_thumbnail: bool,
+) -> Result<Option<Vec<u8>>> {
+    // the writer can't encode values it does not know the type of
+    let fields = fields
+        .iter()
+        .filter(|f| !matches!(f.value, Value::Unknown(..)))
+        .collect::<Vec<_>>();
+    if !fields.iter().any(|f| f.ifd_num == In::PRIMARY) {
+        return Ok(None);
+    }
+
+    // The writer only knows the embedded thumbnail if it is passed separately
+    let thumbnail = existing.filter(|_| keep_thumbnail).and_then(|e| {
+        let offset = e
+            .get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?
+            .value
+            .get_uint(0)? as usize;
+        let len = e
+            .get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?
+            .value
+            .get_uint(0)? as usize;
+        e.buf().get(offset..offset.checked_add(len)?)
+    });
+
+    let mut writer = Writer::new();
+    for field in fields
+        .into_iter()
+        .filter(|f| keep_thumbnail || f.ifd_num == In::PRIMARY)
+    {
+        writer.push_field(field);
+    }
+    if let Some(thumbnail) = thumbnail {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+    let mut output = Cursor::new(vec![]);
+    writer.write(
+        &mut output,
+        existing.map(|e| e.little_endian()).unwrap_or(false),
+    )?;
+    Ok(Some(output.into_inner()))
+}
+
+/// The contents of the first JPEG segment with a marker and prefix, without the prefix
+fn jpeg_segment(jpeg: &Jpeg, marker: u8, prefix: &[u8]) -> Option<Bytes> {
+    jpeg.segments_by_marker(marker)
+        .find(|s| s.contents().starts_with(prefix))
+        .map(|s| s.contents().slice(prefix.len()..))
+}
+
+/// Replace all JPEG segments with a marker and prefix. New segments go after the
+/// other application segments at the start of the file.
+fn set_jpeg_segment(jpeg: &mut Jpeg, marker: u8, prefix: &[u8], contents: Option<Vec<u8>>) {
+    jpeg.segments_mut()
+        .retain(|s| !(s.marker() == marker && s.contents().starts_with(prefix)));
+    if let Some(contents) = contents {
+        let position = jpeg
+            .segments()
+            .iter()
+            .position(|s| !(markers::APP0..=markers::APP15).contains(&s.marker()))
+            .unwrap_or(jpeg.segments().len());
+        let segment = JpegSegment::new_with_contents(marker, [prefix, &contents].concat().into());
+        jpeg.segments_mut().insert(position, segment);
+    }
+}
+
+/// The text of an XMP iTXt chunk, which may be compressed
+fn png_xmp(png: &Png) -> Option<String> {
+    let chunk = png
+        .chunks_by_type(CHUNK_ITXT)
+        .find(|c| c.contents().starts_with(XMP_PNG_KEYWORD))?;
+    let data = &chunk.contents()[XMP_PNG_KEYWORD.len()..];
+    let (compressed, rest) = (*data.first()? == 1, data.get(2..)?);
+    // skip the language tag and translated keyword
+    let mut text = rest;
+    for _ in 0..2 {
+        let end = text.iter().position(|b| *b == 0)?;
+        text = &text[end + 1..];
+    }
+    if compressed {
+        let mut decompressed = String::new();
+        flate2::read::ZlibDecoder::new(text)
+            .read_to_string(&mut decompressed)
+            .ok()?;
+        Some(decompressed)
+    } else {
+        Some(String::from_utf8_lossy(text).to_string())
+    }
+}
+
+fn set_png_xmp(png: &mut Png, xmp: Option<String>) {
+    png.chunks_mut()
+        .retain(|c| !(c.kind() == CHUNK_ITXT && c.contents().starts_with(XMP_PNG_KEYWORD)));
+    if let Some(xmp) = xmp {
+        // uncompressed, without language tag or translated keyword
+        let contents = [XMP_PNG_KEYWORD, &[0, 0, 0, 0], xmp.as_bytes()].concat();
+        let end = png.chunks().len().saturating_sub(1);
+        png.chunks_mut()
+            .insert(end, PngChunk::new(CHUNK_ITXT, contents.into()));
+    }
+}
+
+/// Replace the XMP chunk of a WebP and rebuild the VP8X header the metadata chunks need.
+/// `header` is the one the file had before its EXIF was replaced: img-parts drops the
+/// header when there is no EXIF or ICC left, even from animations, and adds it without
+/// the alpha flag.
+fn set_webp_xmp(webp: &mut WebP, xmp: Option<String>, header: Option<Bytes>) -> Result<()> {
+    const ANIMATION_FLAG: u8 = 0b0000_0010;
+    const ALPHA_FLAG: u8 = 0b0001_0000;
+    webp.remove_chunks_by_id(CHUNK_XMP);
+    if let Some(xmp) = xmp {
+        webp.chunks_mut().push(RiffChunk::new(
+            CHUNK_XMP,
+            RiffContent::Data(xmp.into_bytes().into()),
+        ));
+    }
+
+    // without the header, the size is read from the bitstream
+    webp.remove_chunks_by_id(CHUNK_VP8X);
+    let header = header.filter(|h| h.len() >= 10);
+    let extended = [CHUNK_ICCP, CHUNK_EXIF, CHUNK_XMP]
+        .iter()
+        .any(|id| webp.has_chunk(*id));
+    if header.is_none() && !extended {
+        return Ok(());
+    }
+
+    let mut flags = [
+        (CHUNK_ICCP, 0b0010_0000),
+        (CHUNK_EXIF, 0b0000_1000),
+        (CHUNK_XMP, 0b0000_0100),
+    ]
+    .into_iter()
+    .filter(|(id, _)| webp.has_chunk(*id))
+    .fold(0, |flags, (_, flag)| flags | flag);
+    let lossless_alpha = webp
+        .chunk_by_id(CHUNK_VP8L)
+        .and_then(|c| c.content().data())
+        .and_then(|d| d.get(1..5))
+        .is_some_and(|h| u32::from_le_bytes([h[0], h[1], h[2], h[3]]) & (1 << 28) != 0);
+    if lossless_alpha || webp.has_chunk(CHUNK_ALPH) {
+        flags |= ALPHA_FLAG;
+    }
+
+    let contents = match header {
+        // only the old header knows about animation and the alpha of its frames
+        Some(header) => {
+            let mut contents = header.to_vec();
+            contents[0] = contents[0] & (ANIMATION_FLAG | ALPHA_FLAG) | flags;
+            contents
+        }
+        None => {
+            let (width, height) = webp.dimensions().context("Can't read WebP dimensions")?;
+            let mut contents = vec![flags, 0, 0, 0];
+            contents.extend(&(width - 1).to_le_bytes()[..3]);
+            contents.extend(&(height - 1).to_le_bytes()[..3]);
+            contents
+        }
+    };
+    webp.chunks_mut().insert(
+        0,
+        RiffChunk::new(CHUNK_VP8X, RiffContent::Data(contents.into())),
+    );
+    Ok(())
+}
+
+/// The byte range of the first element `tag`, including its start and end tags
+fn xmp_element(xmp: &str, tag: &str) -> Option<(usize, usize)> {
+    let open = format!("<{tag}");
+    let mut search = 0;
+    loop {
+        let start = search + xmp[search..].find(&open)?;
+        let after = start + open.len();
+        search = after;
+        match xmp[after..].chars().next()? {
+            '>' | ' ' | '\t' | '\r' | '\n' => {}
+            '/' => return Some((start, after + xmp[after..].find('>')? + 1)),
+            _ => continue,
+        }
+        let close = format!("</{tag}>");
+        let end = after + xmp[after..].find(&close)? + close.len();
+        return Some((start, end));
+    }
+}
+
+/// The values of a property, either the items of its `rdf:Alt`/`rdf:Bag`/`rdf:Seq`,
+/// its text, or an attribute of the same name
+pub(crate) fn xmp_values(xmp: &str, tag: &str) -> Vec<String> {
+    if let Some((start, end)) = xmp_element(xmp, tag) {
+        let element = &xmp[start..end];
+        let mut values = vec![];
+        let mut rest = element;
+        while let Some((li_start, li_end)) = xmp_element(rest, "rdf:li") {
+            let li = &rest[li_start..li_end];
+            if let (Some(open_end), Some(close)) = (li.find('>'), li.rfind("</rdf:li>")) {
+                values.push(unescape_xml(li[open_end + 1..close].trim()));
+            }
+            rest = &rest[li_end..];
+        }
+        if values.is_empty() {
+            if let (Some(open_end), Some(close)) = (element.find('>'), element.rfind("</")) {
+                if open_end < close {
+                    values.push(unescape_xml(element[open_end + 1..close].trim()));
+                }
+            }
+        }
+        return values.into_iter().filter(|v| !v.is_empty()).collect();
+    }
+    let attribute = format!(" {tag}=\"");
+    xmp.find(&attribute)
+        .and_then(|start| {
+            let value = &xmp[start + attribute.len()..];
+            Some(unescape_xml(&value[..value.find('"')?]))
+        })
+        .into_iter()
+        .collect()
+}
+
+/// Remove every element and attribute named `tag`
+pub(crate) fn remove_xmp_property(xmp: &mut String, tag: &str) {
+    while let Some((start, end)) = xmp_element(xmp, tag) {
+        // take the indentation along
+        let start = xmp[..start].trim_end_matches([' ', '\t']).len();
+        let start = xmp[..start].strip_suffix('\n').map_or(start, |s| s.len());
+        xmp.replace_range(start..end, "");
+    }
+    let attribute = format!(" {tag}=\"");
+    while let Some(start) = xmp.find(&attribute) {
+        let Some(len) = xmp[start + attribute.len()..].find('"') else {
+            break;
+        };
+        xmp.replace_range(start..start + attribute.len() + len + 1, "");
+    }
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#xA;", "\n")
+        .replace("&#10;", "\n")
+        .replace("&amp;", "&")
+}
+
+/// Photoshop image resources as id, raw name and data
+fn photoshop_resources(data: &[u8]) -> Vec<(u16, Vec<u8>, Vec<u8>)> {
+    let mut resources = vec![];
+    let mut i = 0;
+    while data.get(i..i + 4) == Some(b"8BIM") {
+        let Some(id) = data.get(i + 4..i + 6) else {
+            break;
+        };
+        let id = u16::from_be_bytes([id[0], id[1]]);
+        let Some(&name_len) = data.get(i + 6) else {
+            break;
+        };
+        let name_start = i + 7;
+        let name_end = name_start + name_len as usize;
+        // the name and its length byte are padded to an even size
+        let size_start = name_end + (name_len as usize + 1) % 2;
+        let (Some(name), Some(size)) = (
+            data.get(name_start..name_end),
+            data.get(size_start..size_start + 4),
+        ) else {
+            break;
+        };
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let data_start = size_start + 4;
+        let Some(contents) = data.get(data_start..data_start + size) else {
+            break;
+        };
+        resources.push((id, name.to_vec(), contents.to_vec()));
+        i = data_start + size + size % 2;
+    }
+    resources
+}
+
+/// IPTC-IIM datasets as (record, dataset) and value
+fn iptc_records(data: &[u8]) -> Vec<((u8, u8), Vec<u8>)> {
+    let mut datasets = vec![];
+    let mut i = 0;
+    while let Some(&[0x1c, record, dataset, len_hi, len_lo]) = data.get(i..i + 5) {
+        // extended datasets, longer than 32k, are not used for text
+        if len_hi & 0x80 != 0 {
+            break;
+        }
+        let len = u16::from_be_bytes([len_hi, len_lo]) as usize;
+        let Some(value) = data.get(i + 5..i + 5 + len) else {
+            break;
+        };
+        datasets.push(((record, dataset), value.to_vec()));
+        i += 5 + len;
+    }
+    datasets
+}
+
+/// The IPTC datasets stored in Photoshop image resources
+fn iptc_datasets(photoshop: &[u8]) -> Vec<((u8, u8), Vec<u8>)> {
+    photoshop_resources(photoshop)
+        .into_iter()
+        .filter(|(id, _, _)| *id == RESOURCE_IPTC)
+        .flat_map(|(_, _, data)| iptc_records(&data))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_round_trip() {
+        let metadata = Metadata {
+            title: "Harbour".into(),
+            description: "Boats & <nets>".into(),
+            copyright: "CC-BY".into(),
+            keywords: vec!["sea".into(), "boats".into()],
+            orientation: Some(6),
+            date_time_original: "2024:05:01 12:30:00".into(),
+            gps: Some(GpsPosition {
+                latitude: 52.5,
+                longitude: -13.25,
+                altitude: Some(-4.),
+            }),
+        };
+        let exif = metadata.apply_exif(None).unwrap().unwrap();
+        let xmp = metadata.apply_xmp(None).unwrap();
+        let photoshop = metadata.apply_iptc(None).unwrap();
+        let read = Metadata::from_packets(Some(&exif), Some(&xmp), Some(&photoshop));
+        assert_eq!(read, metadata);
+
+        // editing again replaces values instead of adding more
+        let cleared = Metadata {
+            keywords: vec!["boats".into()],
+            ..Default::default()
+        };
+        let xmp = cleared.apply_xmp(Some(&xmp)).unwrap();
+        assert_eq!(xmp_values(&xmp, "dc:subject"), vec!["boats"]);
+        assert!(xmp_values(&xmp, "dc:title").is_empty());
+        assert_eq!(xmp.matches(XMP_DC_DESCRIPTION).count(), 1);
+        assert_eq!(cleared.apply_exif(Some(&exif)).unwrap(), None);
+
+        // IPTC alone is read as well
+        let read = Metadata::from_packets(None, None, Some(&photoshop));
+        assert_eq!(read.title, "Harbour");
+        assert_eq!(read.keywords, vec!["sea", "boats"]);
+    }
+
+    #[test]
+    fn iptc_text_is_cut_between_characters() {
+        let description = "é".repeat(IPTC_MAX_LEN);
+        let metadata = Metadata {
+            description: description.clone(),
+            ..Default::default()
+        };
+        let photoshop = metadata.apply_iptc(None).unwrap();
+        let read = Metadata::from_packets(None, None, Some(&photoshop));
+        assert_eq!(read.description.len(), IPTC_MAX_LEN - 1);
+        assert!(description.starts_with(&read.description));
+    }
+
+    #[test]
+    fn write_to_containers() {
+        let dir = std::env::temp_dir().join("oculante_metadata_test");
+        fs::create_dir_all(&dir).unwrap();
+        let metadata = Metadata {
+            title: "Harbour".into(),
+            description: "Boats at dawn".into(),
+            copyright: "CC-BY".into(),
+            keywords: vec!["sea".into(), "boats".into()],
+            orientation: Some(8),
+            date_time_original: "2024:05:01 06:10:00".into(),
+            gps: Some(GpsPosition {
+                latitude: -33.5,
+                longitude: 151.25,
+                altitude: None,
+            }),
+        };
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(17, 11, |x, y| {
+            image::Rgba([x as u8 * 15, y as u8 * 20, 90, 255 - x as u8])
+        }));
+
+        for extension in ["jpg", "png", "webp"] {
+            let path = dir.join(format!("image.{extension}"));
+            if extension == "jpg" {
+                img.to_rgb8().save(&path).unwrap();
+            } else {
+                img.save(&path).unwrap();
+            }
+            let pixels = image::open(&path).unwrap();
+
+            metadata.write(&path).unwrap();
+            let bytes = Bytes::from(fs::read(&path).unwrap());
+            assert_eq!(Metadata::from_bytes(bytes.clone()).unwrap(), metadata);
+            // only the metadata changes
+            assert_eq!(image::open(&path).unwrap(), pixels, "{extension}");
+            if extension == "jpg" {
+                let jpeg = Jpeg::from_bytes(bytes).unwrap();
+                assert!(jpeg_segment(&jpeg, markers::APP13, PHOTOSHOP_PREFIX).is_some());
+            }
+
+            // clearing removes what was written
+            Metadata::default().write(&path).unwrap();
+            let bytes = Bytes::from(fs::read(&path).unwrap());
+            assert_eq!(Metadata::from_bytes(bytes).unwrap(), Metadata::default());
+            assert_eq!(image::open(&path).unwrap(), pixels, "{extension}");
+        }
+    }
+
+    #[test]
+    fn webp_xmp_adds_extended_header() {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            300,
+            2,
+            image::Rgba([10, 20, 30, 128]),
+        ));
+        let mut encoded = Cursor::new(vec![]);
+        img.write_to(&mut encoded, image::ImageFormat::WebP)
+            .unwrap();
+        let mut webp = WebP::from_bytes(encoded.into_inner().into()).unwrap();
+        assert!(!webp.has_chunk(CHUNK_VP8X));
+
+        set_webp_xmp(&mut webp, Some(XMP_TEMPLATE.into()), None).unwrap();
+        let vp8x = webp.chunks()[0].content().data().unwrap().to_vec();
+        assert_eq!(webp.chunks()[0].id(), CHUNK_VP8X);
+        // XMP and alpha flags, then the canvas size minus one
+        assert_eq!(vp8x, [0b0001_0100, 0, 0, 0, 43, 1, 0, 1, 0, 0]);
+        assert_eq!(
+            webp.chunk_by_id(CHUNK_XMP)
+                .and_then(|c| c.content().data())
+                .map(|d| d.to_vec()),
+            Some(XMP_TEMPLATE.as_bytes().to_vec())
+        );
+
+        // a previous header is kept without XMP, along with its animation flag
+        let mut header = vp8x.clone();
+        header[0] |= 0b0000_0010;
+        set_webp_xmp(&mut webp, None, Some(header.into())).unwrap();
+        assert!(!webp.has_chunk(CHUNK_XMP));
+        assert_eq!(
+            webp.chunk_by_id(CHUNK_VP8X)
+                .and_then(|c| c.content().data())
+                .map(|d| d.to_vec()),
+            Some(vec![0b0001_0010, 0, 0, 0, 43, 1, 0, 1, 0, 0])
+        );
+    }
+
+    #[test]
+    fn privacy_profiles() {
+        let metadata = Metadata {
+            description: "Garden".into(),
+            copyright: "Me".into(),
+            orientation: Some(3),
+            gps: Some(GpsPosition::default()),
+            ..Default::default()
+        };
+        let exif = Some(Bytes::from(metadata.apply_exif(None).unwrap().unwrap()));
+        let filtered = |profile, whitelist: &[&str]| {
+            let privacy = PrivacySettings {
+                profile,
+                whitelist: whitelist.iter().map(|w| w.to_string()).collect(),
+            };
+            let exif = privacy.filter_exif(exif.clone()).unwrap();
+            Metadata::from_packets(exif.as_deref(), None, None)
+        };
+
+        let no_gps = filtered(PrivacyProfile::RemoveGps, &[]);
+        assert_eq!(no_gps.gps, None);
+        assert_eq!(no_gps.description, "Garden");
+        assert_eq!(
+            filtered(PrivacyProfile::OrientationAndIcc, &[]),
+            Metadata {
+                orientation: Some(3),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            filtered(PrivacyProfile::Custom, &["copyright"]).copyright,
+            "Me"
+        );
+        assert_eq!(
+            filtered(PrivacyProfile::Custom, &["copyright"]).description,
+            ""
+        );
+
+        let mut xmp = "<rdf:Description exif:GPSLatitude=\"1,2N\" exif:FNumber=\"4\">
+ <exif:GPSAltitude>10</exif:GPSAltitude>
+</rdf:Description>"
+            .to_string();
+        remove_xmp_gps(&mut xmp);
+        assert!(!xmp.contains("GPS"));
+        assert!(xmp.contains("exif:FNumber"));
+    }
+}
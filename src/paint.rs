@@ -1,21 +1,98 @@
-use image::{Pixel, Rgba, RgbaImage};
-use notan::egui::{Color32, Pos2};
+use std::collections::HashMap;
+
+use image::{DynamicImage, GrayImage, Pixel, Rgba, RgbaImage};
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use notan::egui::{Pos2, Vec2};
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+
+use crate::{
+    brushes::{find_brush, Brush},
+    image_editing::blend_layer,
+};
+
+/// What a stroke draws
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumIter, Display)]
+pub enum PaintTool {
+    #[default]
+    Brush,
+    /// Removes paint of earlier strokes on the same layer
+    Eraser,
+    Line,
+    Rectangle,
+    Ellipse,
+    Arrow,
+}
+
+impl PaintTool {
+    /// Shapes are defined by their first and last point only
+    pub fn is_shape(&self) -> bool {
+        !matches!(self, Self::Brush | Self::Eraser)
+    }
+}
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// A group of strokes that can be hidden and exported on its own
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaintLayer {
+    pub name: String,
+    pub visible: bool,
+}
+
+impl PaintLayer {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            visible: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct PaintStroke {
     pub points: Vec<(f32, f32)>,
     pub fade: bool,
     pub color: [f32; 4],
     /// brush width from 0-1. 1 is equal to 1/10th of the smallest image dimension.
     pub width: f32,
-    pub brush_index: usize,
+    /// Name of the brush in the brush library. The first brush is used if it is not there.
+    #[serde(default)]
+    pub brush: String,
     /// For ui preview: if highlit, paint brush stroke differently
     pub highlight: bool,
     pub committed: bool,
     pub flip_random: bool,
+    #[serde(default)]
+    pub tool: PaintTool,
+    /// Index into the paint layers
+    #[serde(default)]
+    pub layer: usize,
+    /// Distance between brush dabs, relative to the brush size
+    #[serde(default = "default_spacing")]
+    pub spacing: f32,
+    /// From 0 (brush as is) to 1 (hard edges)
+    #[serde(default)]
+    pub hardness: f32,
+    #[serde(default = "default_opacity")]
+    pub opacity: f32,
+    /// How much faster pointer movement thins the stroke, like a pen with less pressure
+    #[serde(default)]
+    pub pressure: f32,
+    /// Random rotation of each dab, from 0 to a full turn
+    #[serde(default)]
+    pub rotation_jitter: f32,
+    /// Random offset of each dab, relative to the brush size
+    #[serde(default)]
+    pub scatter: f32,
+}
+
+fn default_spacing() -> f32 {
+    0.25
+}
+
+fn default_opacity() -> f32 {
+    1.0
 }
 
 impl PaintStroke {
@@ -30,6 +107,8 @@ impl PaintStroke {
         Self {
             color: [1., 1., 1., 1.],
             width: 0.05,
+            spacing: default_spacing(),
+            opacity: default_opacity(),
             ..Default::default()
         }
     }
@@ -38,90 +117,352 @@ impl PaintStroke {
         self.points.is_empty()
     }
 
-    // render brush stroke
-    pub fn render(&self, img: &mut RgbaImage, brushes: &[RgbaImage]) {
-        // Calculate the brush: use a fraction of the smallest image size
-        let max_brush_size = img.width().min(img.height());
+    /// Add a point while drawing. Shapes only keep their start and end point.
+    pub fn add_point(&mut self, point: (f32, f32)) {
+        if self.tool.is_shape() {
+            self.points.truncate(1);
+        }
+        self.points.push(point);
+    }
 
-        let mut brush = image::imageops::resize(
-            &brushes[self.brush_index],
-            (self.width * max_brush_size as f32) as u32,
-            (self.width * max_brush_size as f32) as u32,
-            image::imageops::Triangle,
-        );
+    /// Brush size in pixels on an image of this size
+    pub fn brush_size(&self, size: (u32, u32)) -> f32 {
+        (self.width * size.0.min(size.1) as f32).max(1.)
+    }
 
-        // transform points from UV into image space
+    /// The lines this stroke follows in pixel coordinates, e.g. to export it as vectors
+    pub fn outline(&self, size: (u32, u32)) -> Vec<Vec<Pos2>> {
+        self.paths(size, self.brush_size(size))
+            .into_iter()
+            .map(|path| path.into_iter().map(|(p, _)| p).collect())
+            .collect()
+    }
+
+    /// The lines to draw in image space, with a size factor for each point
+    fn paths(&self, size: (u32, u32), brush_size: f32) -> Vec<Vec<(Pos2, f32)>> {
         let abs_points = self
             .points
             .iter()
-            .map(|p| Pos2::new(img.width() as f32 * p.0, img.height() as f32 * p.1))
+            .map(|p| Pos2::new(size.0 as f32 * p.0, size.1 as f32 * p.1))
             .collect::<Vec<_>>();
+        let (Some(&a), Some(&b)) = (abs_points.first(), abs_points.last()) else {
+            return vec![];
+        };
+        let full = |points: &[Pos2]| points.iter().map(|p| (*p, 1.)).collect::<Vec<_>>();
 
-        let points = notan::egui::Shape::dotted_line(
-            &abs_points,
-            Color32::DARK_RED,
-            (brush.width() as f32 / 4.0).max(1.5), // .min(60.)
-            0.,
+        match self.tool {
+            PaintTool::Brush | PaintTool::Eraser => {
+                // fast movement leaves more space between points and makes the stroke thinner
+                let mut previous = a;
+                let path = abs_points
+                    .iter()
+                    .map(|p| {
+                        let speed = p.distance(previous) / brush_size.max(1.);
+                        previous = *p;
+                        (*p, (1. / (1. + self.pressure * speed)).max(0.2))
+                    })
+                    .collect();
+                vec![path]
+            }
+            PaintTool::Line => vec![full(&[a, b])],
+            PaintTool::Rectangle => {
+                vec![full(&[a, Pos2::new(b.x, a.y), b, Pos2::new(a.x, b.y), a])]
+            }
+            PaintTool::Ellipse => {
+                let center = a.lerp(b, 0.5);
+                let radius = (b - a).abs() / 2.;
+                let segments = 72;
+                let points = (0..=segments)
+                    .map(|i| {
+                        let angle = i as f32 / segments as f32 * std::f32::consts::TAU;
+                        center + Vec2::new(angle.cos() * radius.x, angle.sin() * radius.y)
+                    })
+                    .collect::<Vec<_>>();
+                vec![full(&points)]
+            }
+            PaintTool::Arrow => {
+                let direction = (b - a).normalized();
+                let head = (a.distance(b) * 0.2).max(brush_size * 2.);
+                let barb = |angle: f32| {
+                    let (sin, cos) = angle.to_radians().sin_cos();
+                    let back = Vec2::new(
+                        -direction.x * cos + direction.y * sin,
+                        -direction.x * sin - direction.y * cos,
+                    );
+                    full(&[b + back * head, b])
+                };
+                vec![full(&[a, b]), barb(30.), barb(-30.)]
+            }
+        }
+    }
+
+    // render brush stroke
+    pub fn render(&self, img: &mut RgbaImage, brushes: &[Brush]) {
+        // Calculate the brush: use a fraction of the smallest image size
+        let brush_size = self.brush_size(img.dimensions());
+
+        let Some(mut brush) = find_brush(brushes, &self.brush).map(|b| b.image.clone()) else {
+            return;
+        };
+        if self.hardness > 0. {
+            let scale = 1. / (1. - self.hardness.min(1.) * 0.95);
+            for p in brush.pixels_mut() {
+                p[3] = (p[3] as f32 * scale).min(255.) as u8;
+            }
+        }
+        // resized brushes, by size in pixels
+        let mut sized_brushes: HashMap<u32, RgbaImage> = HashMap::new();
+
+        let spacing = (brush_size * self.spacing).max(1.);
+        let dabs = self
+            .paths(img.dimensions(), brush_size)
+            .iter()
+            .flat_map(|path| dabs(path, spacing))
+            .collect::<Vec<_>>();
+        if dabs.is_empty() {
+            return;
+        }
+
+        // Dabs are collected as coverage first, so overlapping ones don't add up
+        let margin = brush_size * (1. + self.scatter.max(0.));
+        let min = dabs
+            .iter()
+            .fold(Pos2::new(f32::MAX, f32::MAX), |m, (p, _)| m.min(*p))
+            - Vec2::splat(margin);
+        let max = dabs
+            .iter()
+            .fold(Pos2::new(f32::MIN, f32::MIN), |m, (p, _)| m.max(*p))
+            + Vec2::splat(margin);
+        let origin = (min.x.max(0.) as u32, min.y.max(0.) as u32);
+        let end = (
+            (max.x.max(0.) as u32).min(img.width()),
+            (max.y.max(0.) as u32).min(img.height()),
         );
+        if end.0 <= origin.0 || end.1 <= origin.1 {
+            return;
+        }
+        let mut coverage = GrayImage::new(end.0 - origin.0, end.1 - origin.1);
 
-        for (i, p) in points.iter().enumerate() {
-            let pos_on_line = p.visual_bounding_rect().center();
+        for (i, &(pos, factor)) in dabs.iter().enumerate() {
+            let size = (brush_size * factor).round().max(1.) as u32;
+            let mut dab_brush = sized_brushes
+                .entry(size)
+                .or_insert_with(|| {
+                    // the longer side gets the brush size, so stamps keep their shape
+                    let scale = size as f32 / brush.width().max(brush.height()) as f32;
+                    image::imageops::resize(
+                        &brush,
+                        ((brush.width() as f32 * scale).round() as u32).max(1),
+                        ((brush.height() as f32 * scale).round() as u32).max(1),
+                        image::imageops::Triangle,
+                    )
+                })
+                .clone();
 
+            // seed by brush position so randomness only changes per brush instance
+            let mut rng = ChaCha8Rng::seed_from_u64(pos.x as u64 + pos.y as u64);
             if self.flip_random {
-                // seed by brush position so randomness only changes per brush instance
-                let mut rng =
-                    ChaCha8Rng::seed_from_u64(pos_on_line.x as u64 + pos_on_line.y as u64);
-
                 let flip_x: bool = rng.gen();
                 let flip_y: bool = rng.gen();
 
                 if flip_x {
-                    image::imageops::flip_horizontal_in_place(&mut brush);
+                    image::imageops::flip_horizontal_in_place(&mut dab_brush);
                 }
                 if flip_y {
-                    image::imageops::flip_vertical_in_place(&mut brush);
+                    image::imageops::flip_vertical_in_place(&mut dab_brush);
                 }
             }
 
-            let mut stroke_color = self.color;
+            if self.rotation_jitter > 0. {
+                let turn: f32 = rng.gen_range(-0.5..0.5);
+                let angle = turn * self.rotation_jitter * std::f32::consts::TAU;
+                dab_brush = rotate_about_center(
+                    &dab_brush,
+                    angle,
+                    Interpolation::Bilinear,
+                    Rgba([0, 0, 0, 0]),
+                );
+            }
+            let mut pos = pos;
+            if self.scatter > 0. {
+                let offset = Vec2::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                pos += offset * self.scatter * brush_size * factor;
+            }
+
+            let strength = if self.fade {
+                1.0 - i as f32 / dabs.len() as f32
+            } else {
+                1.0
+            };
+            let local = pos - Vec2::new(origin.0 as f32, origin.1 as f32);
+            stamp(&mut coverage, &dab_brush, local, strength);
+        }
+
+        let mut stroke_color = self.color;
+        if self.highlight {
+            stroke_color = stroke_color.map(|c| c * 2.5);
+        }
+        let opacity = self.opacity.clamp(0., 1.);
 
-            if self.fade {
-                let fraction = 1.0 - i as f32 / points.len() as f32;
-                stroke_color[3] *= fraction;
+        for (x, y, c) in coverage.enumerate_pixels() {
+            if c[0] == 0 {
+                continue;
             }
+            let p = img.get_pixel_mut(x + origin.0, y + origin.1);
+            let amount = c[0] as f32 / 255. * opacity;
+            if self.tool == PaintTool::Eraser {
+                p[3] = (p[3] as f32 * (1. - amount)) as u8;
+            } else {
+                let colored_pixel = Rgba([
+                    (stroke_color[0] * 255.).min(255.) as u8,
+                    (stroke_color[1] * 255.).min(255.) as u8,
+                    (stroke_color[2] * 255.).min(255.) as u8,
+                    (stroke_color[3] * amount * 255.).min(255.) as u8,
+                ]);
+                p.blend(&colored_pixel);
+            }
+        }
+    }
 
-            if self.highlight {
-                stroke_color[0] *= 2.5;
-                stroke_color[1] *= 2.5;
-                stroke_color[2] *= 2.5;
-                stroke_color[3] *= 2.5;
+    /// Render brush stroke onto an image of any color type. Images that are not 8 bit RGBA
+    /// get the stroke composited on top, so unpainted pixels keep their precision.
+    pub fn render_dynamic(&self, img: &mut DynamicImage, brushes: &[Brush]) {
+        if let Some(buffer) = img.as_mut_rgba8() {
+            self.render(buffer, brushes);
+            return;
+        }
+
+        let mut overlay = RgbaImage::new(img.width(), img.height());
+        self.render(&mut overlay, brushes);
+        blend_layer(img, &overlay, (0, 0));
+    }
+}
+
+/// Render strokes onto an image, skipping those on hidden layers. Layers with erasers
+/// are rendered on their own first, so erasing only removes paint.
+pub fn render_strokes(
+    img: &mut DynamicImage,
+    strokes: &[&PaintStroke],
+    layers: &[PaintLayer],
+    brushes: &[Brush],
+) {
+    let visible = |layer: usize| layers.get(layer).map(|l| l.visible).unwrap_or(true);
+    let mut layer_indices = strokes.iter().map(|s| s.layer).collect::<Vec<_>>();
+    layer_indices.sort();
+    layer_indices.dedup();
+
+    for layer in layer_indices.into_iter().filter(|l| visible(*l)) {
+        let layer_strokes = strokes.iter().filter(|s| s.layer == layer && !s.is_empty());
+        if layer_strokes.clone().any(|s| s.tool == PaintTool::Eraser) {
+            let canvas = render_layer((img.width(), img.height()), layer_strokes, brushes);
+            blend_layer(img, &canvas, (0, 0));
+        } else {
+            for stroke in layer_strokes {
+                stroke.render_dynamic(img, brushes);
             }
-            paint_at(img, &brush, &pos_on_line, stroke_color);
         }
     }
 }
 
-pub fn paint_at(img: &mut RgbaImage, brush: &RgbaImage, pos: &Pos2, color: [f32; 4]) {
-    // To test
-    // img.put_pixel(pos.x as u32, pos.y as u32, color_to_pixel(color));
-    // return;
+/// Render strokes onto a transparent image
+pub fn render_layer<'a>(
+    size: (u32, u32),
+    strokes: impl IntoIterator<Item = &'a &'a PaintStroke>,
+    brushes: &[Brush],
+) -> RgbaImage {
+    let mut canvas = RgbaImage::new(size.0, size.1);
+    for stroke in strokes {
+        stroke.render(&mut canvas, brushes);
+    }
+    canvas
+}
 
-    let brush_offset = Pos2::new(brush.width() as f32 / 2., brush.height() as f32 / 2.);
+/// Evenly spaced positions along a path, with interpolated size factors
+fn dabs(path: &[(Pos2, f32)], spacing: f32) -> Vec<(Pos2, f32)> {
+    let Some(first) = path.first() else {
+        return vec![];
+    };
+    let mut dabs = vec![*first];
+    // distance travelled since the last dab
+    let mut since = 0.;
+    for pair in path.windows(2) {
+        let ((a, fa), (b, fb)) = (pair[0], pair[1]);
+        let length = a.distance(b);
+        let mut t = spacing - since;
+        while t <= length {
+            let f = t / length;
+            dabs.push((a.lerp(b, f), fa + (fb - fa) * f));
+            t += spacing;
+        }
+        since = length - (t - spacing);
+    }
+    dabs
+}
 
+/// Add a brush to the coverage, keeping the maximum of both
+fn stamp(coverage: &mut GrayImage, brush: &RgbaImage, pos: Pos2, strength: f32) {
+    let offset = (
+        (pos.x - brush.width() as f32 / 2.) as i64,
+        (pos.y - brush.height() as f32 / 2.) as i64,
+    );
     for (b_x, b_y, b_pixel) in brush.enumerate_pixels() {
-        if let Some(p) = img.get_pixel_mut_checked(
-            (*pos - brush_offset).x as u32 + b_x,
-            (*pos - brush_offset).y as u32 + b_y,
-        ) {
-            // multiply brush with user color os it's tinted
-            let colored_pixel = Rgba([
-                (color[0] * b_pixel[0] as f32) as u8,
-                (color[1] * b_pixel[1] as f32) as u8,
-                (color[2] * b_pixel[2] as f32) as u8,
-                (color[3] * b_pixel[3] as f32) as u8,
-            ]);
-            // colored_pixel.blend(&color_to_pixel(color));
-            p.blend(&colored_pixel);
+        let (x, y) = (offset.0 + b_x as i64, offset.1 + b_y as i64);
+        if x < 0 || y < 0 {
+            continue;
+        }
+        if let Some(c) = coverage.get_pixel_mut_checked(x as u32, y as u32) {
+            c[0] = c[0].max((b_pixel[3] as f32 * strength) as u8);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strokes_render_on_visible_layers() {
+        let brushes = [Brush {
+            name: "Square".into(),
+            image: RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255])),
+            settings: Default::default(),
+            builtin: false,
+        }];
+        let background =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 100, Rgba([0, 0, 255, 255])));
+        let dab = PaintStroke {
+            points: vec![(0.5, 0.5)],
+            color: [1., 0., 0., 1.],
+            width: 0.1,
+            ..PaintStroke::new()
+        };
+        let eraser = PaintStroke {
+            tool: PaintTool::Eraser,
+            width: 0.2,
+            ..dab.clone()
+        };
+        let layers = vec![PaintLayer::new("Layer 1")];
+
+        // A dab only paints the pixels under it
+        let mut img = background.clone();
+        render_strokes(&mut img, &[&dab], &layers, &brushes);
+        let img = img.to_rgba8();
+        assert_eq!(img.get_pixel(50, 50), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(10, 10), &Rgba([0, 0, 255, 255]));
+        assert_eq!(img.get_pixel(60, 50), &Rgba([0, 0, 255, 255]));
+
+        // Erasing on the same layer removes the paint, not the image
+        let mut img = background.clone();
+        render_strokes(&mut img, &[&dab, &eraser], &layers, &brushes);
+        assert_eq!(img, background);
+
+        // Hidden layers are not rendered
+        let hidden = vec![PaintLayer {
+            visible: false,
+            ..PaintLayer::new("Hidden")
+        }];
+        let mut img = background.clone();
+        render_strokes(&mut img, &[&dab], &hidden, &brushes);
+        assert_eq!(img, background);
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    image_editing::{Anchor, EditState, ImageOperation, ImgOpItem},
+    settings::get_config_dir,
+};
+
+/// A named edit stack that can be applied to any image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditPreset {
+    pub name: String,
+    pub pixel_op_stack: Vec<ImgOpItem>,
+    pub image_op_stack: Vec<ImgOpItem>,
+    /// Shipped with oculante and not stored on disk
+    #[serde(skip)]
+    pub builtin: bool,
+}
+
+impl EditPreset {
+    /// Create a preset from a list of operators, sorted into the stacks they belong to
+    pub fn new(name: &str, operations: Vec<ImageOperation>) -> Self {
+        let (pixel_ops, image_ops): (Vec<_>, Vec<_>) = operations
+            .into_iter()
+            .map(ImgOpItem::new)
+            .partition(|op| op.operation.is_per_pixel());
+        Self {
+            name: name.to_string(),
+            pixel_op_stack: pixel_ops,
+            image_op_stack: image_ops,
+            builtin: false,
+        }
+    }
+
+    pub fn from_edit_state(name: &str, edit_state: &EditState) -> Self {
+        Self {
+            name: name.to_string(),
+            pixel_op_stack: edit_state.pixel_op_stack.clone(),
+            image_op_stack: edit_state.image_op_stack.clone(),
+            builtin: false,
+        }
+    }
+
+    /// Apply the preset, either replacing all operators or appending to them
+    pub fn apply(&self, edit_state: &mut EditState, append: bool) {
+        if !append {
+            edit_state.pixel_op_stack.clear();
+            edit_state.image_op_stack.clear();
+        }
+        edit_state
+            .pixel_op_stack
+            .extend(self.pixel_op_stack.iter().cloned());
+        edit_state
+            .image_op_stack
+            .extend(self.image_op_stack.iter().cloned());
+        edit_state.sort_stacks();
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let f = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+        let preset = serde_json::from_reader::<_, Self>(f)
+            .with_context(|| format!("{} is not a valid preset", path.display()))?;
+        Ok(preset)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let f = File::create(path)?;
+        serde_json::to_writer_pretty(&f, self)?;
+        debug!("Saved preset to {}", path.display());
+        Ok(())
+    }
+
+    /// The file name in the preset directory, derived from the name
+    fn file_name(&self) -> PathBuf {
+        let stem = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        PathBuf::from(stem.trim()).with_extension("json")
+    }
+}
+
+/// All presets: built-ins and the ones saved in the config dir
+#[derive(Debug, Default)]
+pub struct PresetLibrary {
+    /// `None` until first used, so the config dir is only read when needed
+    presets: Option<Vec<EditPreset>>,
+    /// Where user presets are stored, if not in the config dir
+    dir: Option<PathBuf>,
+}
+
+impl PresetLibrary {
+    pub fn presets(&mut self) -> &[EditPreset] {
+        let dir = self.dir();
+        self.presets.get_or_insert_with(|| {
+            let mut presets = builtin_presets();
+            if let Ok(dir) = dir {
+                presets.extend(load_user_presets(&dir));
+            }
+            presets
+        })
+    }
+
+    fn dir(&self) -> Result<PathBuf> {
+        match &self.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => presets_dir(),
+        }
+    }
+
+    /// Read user presets from disk again
+    pub fn reload(&mut self) {
+        self.presets = None;
+    }
+
+    /// Store a preset in the config dir, replacing one with the same name
+    pub fn add(&mut self, preset: EditPreset) -> Result<()> {
+        if preset.name.trim().is_empty() {
+            bail!("A preset needs a name.");
+        }
+        if self
+            .presets()
+            .iter()
+            .any(|p| p.builtin && p.name == preset.name)
+        {
+            bail!("{} is a built-in preset.", preset.name);
+        }
+        // different names can be sanitized to the same file
+        if let Some(other) = self
+            .presets()
+            .iter()
+            .find(|p| !p.builtin && p.name != preset.name && p.file_name() == preset.file_name())
+        {
+            bail!(
+                "{} would overwrite the preset {}. Please choose another name.",
+                preset.name,
+                other.name
+            );
+        }
+        let dir = self.dir()?;
+        if !dir.exists() {
+            info!("Created {}", dir.display());
+            create_dir_all(&dir)?;
+        }
+        preset.save(&dir.join(preset.file_name()))?;
+        self.reload();
+        Ok(())
+    }
+
+    /// Copy a preset file into the library
+    pub fn import(&mut self, path: &Path) -> Result<()> {
+        let preset = EditPreset::load(path)?;
+        self.add(preset)
+    }
+
+    pub fn remove(&mut self, preset: &EditPreset) -> Result<()> {
+        if preset.builtin {
+            bail!("Built-in presets can't be deleted.");
+        }
+        remove_file(self.dir()?.join(preset.file_name()))?;
+        self.reload();
+        Ok(())
+    }
+}
+
+/// Where user presets are stored
+pub fn presets_dir() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("presets"))
+}
+
+fn load_user_presets(dir: &Path) -> Vec<EditPreset> {
+    let Ok(entries) = read_dir(dir) else {
+        return vec![];
+    };
+    let mut presets = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| EditPreset::load(&path).map_err(|e| warn!("{e}")).ok())
+        .collect::<Vec<_>>();
+    presets.sort_by_key(|p| p.name.to_lowercase());
+    presets
+}
+
+/// Presets shipped with oculante
+pub fn builtin_presets() -> Vec<EditPreset> {
+    [
+        EditPreset::new(
+            "Black and white",
+            vec![
+                ImageOperation::Desaturate(100),
+                ImageOperation::Contrast(20),
+            ],
+        ),
+        EditPreset::new(
+            "Faded film",
+            vec![
+                ImageOperation::Contrast(-20),
+                ImageOperation::SplitToning {
+                    shadows: [40, 90, 160],
+                    highlights: [230, 170, 80],
+                    balance: 0,
+                },
+                ImageOperation::Grain {
+                    amount: 30,
+                    size: 30,
+                    seed: 0,
+                },
+                ImageOperation::Vignette {
+                    amount: -40,
+                    midpoint: 50,
+                    roundness: 0,
+                    feather: 60,
+                },
+            ],
+        ),
+        EditPreset::new(
+            "Square with white border",
+            vec![
+                ImageOperation::ExtendAspect {
+                    ratio: (1, 1),
+                    anchor: Anchor::Center,
+                    color: [255, 255, 255, 255],
+                },
+                ImageOperation::Pad {
+                    sides: [5, 5, 5, 5],
+                    percent: true,
+                    color: [255, 255, 255, 255],
+                },
+            ],
+        ),
+        EditPreset::new(
+            "Sharpen",
+            vec![ImageOperation::Filter3x3([
+                0, -100, 0, -100, 500, -100, 0, -100, 0,
+            ])],
+        ),
+    ]
+    .into_iter()
+    .map(|preset| EditPreset {
+        builtin: true,
+        ..preset
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_library() {
+        let dir = std::env::temp_dir().join("oculante_preset_test");
+        _ = std::fs::remove_dir_all(&dir);
+        let mut library = PresetLibrary {
+            presets: None,
+            dir: Some(dir.clone()),
+        };
+        let builtins = builtin_presets().len();
+        assert_eq!(library.presets().len(), builtins);
+
+        let preset = EditPreset::new("a/b", vec![ImageOperation::Invert, ImageOperation::Blur(3)]);
+        library.add(preset.clone()).unwrap();
+        let saved = EditPreset::load(&dir.join("a_b.json")).unwrap();
+        assert_eq!(saved.name, "a/b");
+        assert_eq!(saved.pixel_op_stack, preset.pixel_op_stack);
+        assert_eq!(saved.image_op_stack, preset.image_op_stack);
+        assert_eq!(library.presets().len(), builtins + 1);
+
+        // saving under the same name replaces, a name mapping to the same file is refused
+        library.add(preset.clone()).unwrap();
+        assert!(library
+            .add(EditPreset::new("a_b", vec![ImageOperation::Invert]))
+            .is_err());
+        assert!(library
+            .add(EditPreset::new("Sharpen", vec![ImageOperation::Invert]))
+            .is_err());
+        assert_eq!(library.presets().len(), builtins + 1);
+
+        let mut edit_state = EditState::default();
+        edit_state
+            .image_op_stack
+            .push(ImgOpItem::new(ImageOperation::Blur(8)));
+        preset.apply(&mut edit_state, true);
+        assert_eq!(edit_state.pixel_op_stack, preset.pixel_op_stack);
+        assert_eq!(edit_state.image_op_stack.len(), 2);
+        preset.apply(&mut edit_state, false);
+        assert_eq!(edit_state.image_op_stack, preset.image_op_stack);
+
+        let user = library.presets()[builtins].clone();
+        library.remove(&user).unwrap();
+        assert!(!dir.join("a_b.json").exists());
+        assert_eq!(library.presets().len(), builtins);
+        _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,471 @@
+//! Star ratings, color labels, pick and reject flags and tags for culling.
+//! They are kept in XMP sidecars other photo tools can read, or in a local index.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter, IntoEnumIterator};
+
+use crate::{
+    metadata::{escape_xml, remove_xmp_property, xmp_values, XMP_TEMPLATE},
+    settings::get_config_dir,
+};
+
+/// The description oculante writes ratings into
+const XMP_RATING_DESCRIPTION: &str = "<rdf:Description rdf:about=\"\" \
+    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" \
+    xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+    xmlns:oculante=\"https://github.com/woelper/oculante/ns/1.0/\">";
+const XMP_RATING: &str = "xmp:Rating";
+const XMP_LABEL: &str = "xmp:Label";
+const XMP_SUBJECT: &str = "dc:subject";
+/// Picks have no standard property. Rejects are also read from a rating of -1.
+const XMP_FLAG: &str = "oculante:Flag";
+
+/// Color labels, named like Lightroom and Bridge name them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Display, EnumIter)]
+pub enum ColorLabel {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+}
+
+impl ColorLabel {
+    fn parse(text: &str) -> Option<Self> {
+        Self::iter().find(|l| l.to_string().eq_ignore_ascii_case(text.trim()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Display, EnumIter)]
+pub enum Flag {
+    Pick,
+    Reject,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rating {
+    /// 0 to 5
+    pub stars: u8,
+    pub label: Option<ColorLabel>,
+    pub flag: Option<Flag>,
+    pub tags: BTreeSet<String>,
+}
+
+impl fmt::Display for Rating {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if self.stars > 0 {
+            parts.push(format!(
+                "{} star{}",
+                self.stars,
+                if self.stars == 1 { "" } else { "s" }
+            ));
+        }
+        parts.extend(self.label.map(|l| l.to_string()));
+        parts.extend(self.flag.map(|l| l.to_string()));
+        parts.extend(self.tags.iter().map(|t| format!("#{t}")));
+        if parts.is_empty() {
+            write!(f, "Not rated")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+impl Rating {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn from_xmp(xmp: &str) -> Self {
+        let first = |tag| xmp_values(xmp, tag).into_iter().next();
+        let rating = first(XMP_RATING)
+            .and_then(|r| r.trim().parse::<i32>().ok())
+            .unwrap_or_default();
+        let flag = match first(XMP_FLAG).as_deref().map(str::trim) {
+            Some("pick") => Some(Flag::Pick),
+            Some("reject") => Some(Flag::Reject),
+            _ if rating < 0 => Some(Flag::Reject),
+            _ => None,
+        };
+        Self {
+            stars: rating.clamp(0, 5) as u8,
+            label: first(XMP_LABEL).as_deref().and_then(ColorLabel::parse),
+            flag,
+            tags: xmp_values(xmp, XMP_SUBJECT).into_iter().collect(),
+        }
+    }
+
+    /// Replace the rating properties of an XMP packet, keeping everything else
+    pub fn apply_xmp(&self, xmp: Option<&str>) -> String {
+        let mut xmp = xmp.unwrap_or(XMP_TEMPLATE).to_string();
+        for tag in [XMP_RATING, XMP_LABEL, XMP_SUBJECT, XMP_FLAG] {
+            remove_xmp_property(&mut xmp, tag);
+        }
+
+        let mut added = format!("\n   <{XMP_RATING}>{}</{XMP_RATING}>", self.stars);
+        if let Some(label) = self.label {
+            added += &format!("\n   <{XMP_LABEL}>{label}</{XMP_LABEL}>");
+        }
+        if let Some(flag) = self.flag {
+            let flag = flag.to_string().to_lowercase();
+            added += &format!("\n   <{XMP_FLAG}>{flag}</{XMP_FLAG}>");
+        }
+        if !self.tags.is_empty() {
+            added += &format!("\n   <{XMP_SUBJECT}>\n    <rdf:Bag>");
+            for tag in &self.tags {
+                added += &format!("\n     <rdf:li>{}</rdf:li>", escape_xml(tag));
+            }
+            added += &format!("\n    </rdf:Bag>\n   </{XMP_SUBJECT}>");
+        }
+
+        if let Some(start) = xmp.find(XMP_RATING_DESCRIPTION) {
+            xmp.insert_str(start + XMP_RATING_DESCRIPTION.len(), &added);
+        } else if let Some(end) = xmp.find("</rdf:RDF>") {
+            xmp.insert_str(
+                end,
+                &format!("  {XMP_RATING_DESCRIPTION}{added}\n  </rdf:Description>\n "),
+            );
+        }
+        xmp
+    }
+}
+
+/// Where ratings are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Display, EnumIter)]
+pub enum RatingStorage {
+    /// An `.xmp` file next to each image
+    #[default]
+    #[strum(to_string = "XMP sidecars")]
+    Sidecar,
+    /// One file in the config directory, for folders that can't be written to
+    #[strum(to_string = "Local index")]
+    Index,
+}
+
+/// The sidecar of an image. An `image.xmp` that Lightroom or Bridge wrote is used if there
+/// is one, otherwise `image.ext.xmp`, so images that only differ in extension keep their own.
+pub fn sidecar_path(image: &Path) -> PathBuf {
+    let mut long = image.as_os_str().to_owned();
+    long.push(".xmp");
+    let long = PathBuf::from(long);
+    let short = image.with_extension("xmp");
+    if !long.is_file() && short.is_file() {
+        short
+    } else {
+        long
+    }
+}
+
+/// Ratings read so far, so filtering a folder does not read every sidecar again
+#[derive(Debug, Default)]
+pub struct Ratings {
+    cache: HashMap<PathBuf, Rating>,
+    index: Option<BTreeMap<PathBuf, Rating>>,
+    /// The index file, if not the one in the config directory
+    index_file: Option<PathBuf>,
+}
+
+impl Ratings {
+    fn index_path(&self) -> Result<PathBuf> {
+        match &self.index_file {
+            Some(file) => Ok(file.clone()),
+            None => Ok(get_config_dir()?.join("ratings.json")),
+        }
+    }
+
+    /// The local index. An index that can't be read is an error, so it is never overwritten.
+    fn index(&mut self) -> Result<&mut BTreeMap<PathBuf, Rating>> {
+        if self.index.is_none() {
+            let path = self.index_path()?;
+            let index = match fs::File::open(&path) {
+                Ok(file) => serde_json::from_reader(file).with_context(|| {
+                    format!("The ratings index {} can't be read", path.display())
+                })?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Default::default(),
+                Err(e) => return Err(e).context(format!("Can't open {}", path.display())),
+            };
+            self.index = Some(index);
+        }
+        Ok(self.index.get_or_insert_with(Default::default))
+    }
+
+    pub fn get(&mut self, path: &Path, storage: RatingStorage) -> &Rating {
+        if !self.cache.contains_key(path) {
+            let rating = match storage {
+                RatingStorage::Sidecar => fs::read_to_string(sidecar_path(path))
+                    .map(|xmp| Rating::from_xmp(&xmp))
+                    .unwrap_or_default(),
+                RatingStorage::Index => match self.index() {
+                    Ok(index) => index.get(path).cloned().unwrap_or_default(),
+                    Err(e) => {
+                        warn!("{e:#}");
+                        Default::default()
+                    }
+                },
+            };
+            self.cache.insert(path.to_path_buf(), rating);
+        }
+        &self.cache[path]
+    }
+
+    pub fn set(&mut self, path: &Path, rating: Rating, storage: RatingStorage) -> Result<()> {
+        match storage {
+            RatingStorage::Sidecar => {
+                let sidecar = sidecar_path(path);
+                let existing = fs::read_to_string(&sidecar).ok();
+                if existing.is_some() || !rating.is_empty() {
+                    fs::write(&sidecar, rating.apply_xmp(existing.as_deref()))?;
+                    debug!("Saved rating to {}", sidecar.display());
+                }
+            }
+            RatingStorage::Index => {
+                let index = self.index()?;
+                if rating.is_empty() {
+                    index.remove(path);
+                } else {
+                    index.insert(path.to_path_buf(), rating.clone());
+                }
+                self.save_index()?;
+            }
+        }
+        self.cache.insert(path.to_path_buf(), rating);
+        Ok(())
+    }
+
+    fn save_index(&self) -> Result<()> {
+        let index_path = self.index_path()?;
+        if let Some(dir) = index_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        serde_json::to_writer_pretty(fs::File::create(index_path)?, &self.index)?;
+        Ok(())
+    }
+
+    /// Keep the rating of a file that was moved. Sidecars travel with the image,
+    /// index entries are keyed by path and move here.
+    pub fn rename(&mut self, from: &Path, to: &Path, storage: RatingStorage) -> Result<()> {
+        if let Some(rating) = self.cache.remove(from) {
+            self.cache.insert(to.to_path_buf(), rating);
+        }
+        if storage == RatingStorage::Index {
+            let index = self.index()?;
+            if let Some(rating) = index.remove(from) {
+                index.insert(to.to_path_buf(), rating);
+                self.save_index()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Change a rating and save it
+    pub fn update(
+        &mut self,
+        path: &Path,
+        storage: RatingStorage,
+        edit: impl FnOnce(&mut Rating),
+    ) -> Result<Rating> {
+        let mut rating = self.get(path, storage).clone();
+        edit(&mut rating);
+        self.set(path, rating.clone(), storage)?;
+        Ok(rating)
+    }
+
+    /// Turn the favourites of older versions into picks. Favourites that can't be saved are
+    /// kept to try again, those of images that are gone are dropped.
+    pub fn import_favourites(&mut self, favourites: &mut HashSet<PathBuf>, storage: RatingStorage) {
+        favourites.retain(|path| {
+            if !path.is_file() {
+                return false;
+            }
+            match self.update(path, storage, |r| r.flag = Some(Flag::Pick)) {
+                Ok(_) => false,
+                Err(e) => {
+                    warn!("Can't turn favourite {} into a pick: {e:#}", path.display());
+                    true
+                }
+            }
+        });
+    }
+
+    /// Forget what was read, after the storage changed
+    pub fn clear(&mut self) {
+        if !self.cache.is_empty() {
+            warn!("Ratings storage changed, reading ratings again");
+        }
+        self.cache.clear();
+        self.index = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+pub enum FlagFilter {
+    #[default]
+    Any,
+    Picked,
+    #[strum(to_string = "Not rejected")]
+    NotRejected,
+    Rejected,
+}
+
+/// Which images the scrubber pages through
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RatingFilter {
+    pub min_stars: u8,
+    pub label: Option<ColorLabel>,
+    pub flag: FlagFilter,
+    /// Only images with this tag, if not empty
+    pub tag: String,
+}
+
+impl RatingFilter {
+    pub fn is_active(&self) -> bool {
+        *self != Self::default()
+    }
+
+    pub fn matches(&self, rating: &Rating) -> bool {
+        let flag = match self.flag {
+            FlagFilter::Any => true,
+            FlagFilter::Picked => rating.flag == Some(Flag::Pick),
+            FlagFilter::NotRejected => rating.flag != Some(Flag::Reject),
+            FlagFilter::Rejected => rating.flag == Some(Flag::Reject),
+        };
+        let tag = self.tag.trim();
+        rating.stars >= self.min_stars
+            && self.label.is_none_or(|l| rating.label == Some(l))
+            && flag
+            && (tag.is_empty() || rating.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xmp_ratings_and_filters() {
+        // written by another tool, with a rating attribute and a property to keep
+        let foreign = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">
+ <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">
+  <rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" \
+xmlns:crs=\"http://ns.adobe.com/camera-raw-settings/1.0/\" xmp:Rating=\"-1\" crs:Exposure2012=\"+0.50\"/>
+ </rdf:RDF>
+</x:xmpmeta>";
+        let rejected = Rating::from_xmp(foreign);
+        assert_eq!(rejected.flag, Some(Flag::Reject));
+        assert_eq!(rejected.stars, 0);
+
+        let rating = Rating {
+            stars: 4,
+            label: Some(ColorLabel::Green),
+            flag: Some(Flag::Pick),
+            tags: ["beach".to_string(), "R&D".to_string()].into(),
+        };
+        let xmp = rating.apply_xmp(Some(foreign));
+        assert!(xmp.contains("crs:Exposure2012=\"+0.50\""));
+        assert!(!xmp.contains("xmp:Rating=\"-1\""));
+        assert_eq!(Rating::from_xmp(&xmp), rating);
+        // writing again updates the same description
+        let xmp = Rating::default().apply_xmp(Some(&xmp));
+        assert_eq!(xmp.matches("<rdf:Description").count(), 2);
+        assert!(Rating::from_xmp(&xmp).is_empty());
+        assert_eq!(Rating::from_xmp(&rating.apply_xmp(None)), rating);
+
+        let filter = RatingFilter {
+            min_stars: 4,
+            tag: "BEACH".into(),
+            ..Default::default()
+        };
+        assert!(filter.is_active());
+        assert!(filter.matches(&rating));
+        assert!(!filter.matches(&Rating {
+            stars: 3,
+            ..rating.clone()
+        }));
+        let not_rejected = RatingFilter {
+            flag: FlagFilter::NotRejected,
+            ..Default::default()
+        };
+        assert!(!not_rejected.matches(&rejected));
+    }
+
+    #[test]
+    fn sidecars_index_and_favourites() {
+        let dir = std::env::temp_dir().join("oculante_rating_test");
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (jpg, png) = (dir.join("a.jpg"), dir.join("a.png"));
+        fs::write(&jpg, b"").unwrap();
+        fs::write(&png, b"").unwrap();
+
+        // images that only differ in extension have their own sidecars
+        assert_eq!(sidecar_path(&jpg), dir.join("a.jpg.xmp"));
+        assert_eq!(sidecar_path(&png), dir.join("a.png.xmp"));
+        let mut ratings = Ratings::default();
+        ratings
+            .update(&jpg, RatingStorage::Sidecar, |r| r.stars = 3)
+            .unwrap();
+        ratings.clear();
+        assert!(ratings.get(&png, RatingStorage::Sidecar).is_empty());
+        assert_eq!(ratings.get(&jpg, RatingStorage::Sidecar).stars, 3);
+        // one written by another tool is used
+        fs::write(dir.join("a.xmp"), Rating::default().apply_xmp(None)).unwrap();
+        assert_eq!(sidecar_path(&png), dir.join("a.xmp"));
+
+        // favourites become picks in the index
+        let index_file = dir.join("ratings.json");
+        let mut ratings = Ratings {
+            index_file: Some(index_file.clone()),
+            ..Default::default()
+        };
+        let mut favourites = HashSet::from([png.clone(), dir.join("gone.jpg")]);
+        ratings.import_favourites(&mut favourites, RatingStorage::Index);
+        assert!(favourites.is_empty());
+        ratings.clear();
+        assert_eq!(
+            ratings.get(&png, RatingStorage::Index).flag,
+            Some(Flag::Pick)
+        );
+
+        // moved files take their rating along, and back
+        let moved = dir.join("sorted").join("a.png");
+        ratings.rename(&png, &moved, RatingStorage::Index).unwrap();
+        ratings.clear();
+        assert!(ratings.get(&png, RatingStorage::Index).is_empty());
+        assert_eq!(
+            ratings.get(&moved, RatingStorage::Index).flag,
+            Some(Flag::Pick)
+        );
+        ratings.rename(&moved, &png, RatingStorage::Index).unwrap();
+        assert_eq!(
+            ratings.get(&png, RatingStorage::Index).flag,
+            Some(Flag::Pick)
+        );
+        ratings.clear();
+        assert!(ratings.get(&moved, RatingStorage::Index).is_empty());
+        assert_eq!(
+            ratings.get(&png, RatingStorage::Index).flag,
+            Some(Flag::Pick)
+        );
+
+        // a broken index is not overwritten
+        fs::write(&index_file, "{ broken").unwrap();
+        ratings.clear();
+        assert!(ratings.get(&png, RatingStorage::Index).is_empty());
+        assert!(ratings
+            .update(&jpg, RatingStorage::Index, |r| r.stars = 5)
+            .is_err());
+        assert_eq!(fs::read_to_string(&index_file).unwrap(), "{ broken");
+        _ = fs::remove_dir_all(&dir);
+    }
+}
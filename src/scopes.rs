@@ -0,0 +1,206 @@
+//! Video-style scopes: luma waveform, RGB parade and vectorscope
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+use image::{DynamicImage, GenericImageView};
+use strum::{Display, EnumIter};
+
+/// Width and height of every scope
+pub const SCOPE_SIZE: usize = 256;
+/// Larger images are sampled evenly down to about this many pixels
+const MAX_SAMPLES: u64 = 512 * 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumIter)]
+pub enum ScopeKind {
+    #[default]
+    Waveform,
+    Parade,
+    Vectorscope,
+}
+
+/// How many pixels fell into each cell of a grid. Row 0 is the top.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Density {
+    pub width: usize,
+    pub height: usize,
+    pub counts: Vec<u32>,
+}
+
+impl Density {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            counts: vec![0; width * height],
+        }
+    }
+
+    /// Count a pixel at a position from 0 to 1, from the bottom left
+    fn add(&mut self, x: f32, y: f32) {
+        let column = ((x * self.width as f32) as usize).min(self.width - 1);
+        let row = (((1. - y) * self.height as f32) as usize).min(self.height - 1);
+        self.counts[row * self.width + column] += 1;
+    }
+
+    pub fn max(&self) -> u32 {
+        self.counts.iter().copied().max().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scopes {
+    /// Luma of every image column, from black at the bottom to white at the top
+    pub waveform: Density,
+    /// Waveforms of the red, green and blue channels
+    pub parade: [Density; 3],
+    /// Chroma, with blue difference to the right and red difference up
+    pub vectorscope: Density,
+}
+
+impl Scopes {
+    pub fn compute(img: &DynamicImage) -> Self {
+        let mut scopes = Self {
+            waveform: Density::new(SCOPE_SIZE, SCOPE_SIZE),
+            parade: std::array::from_fn(|_| Density::new(SCOPE_SIZE, SCOPE_SIZE)),
+            vectorscope: Density::new(SCOPE_SIZE, SCOPE_SIZE),
+        };
+        let (width, height) = img.dimensions();
+        if width == 0 || height == 0 {
+            return scopes;
+        }
+        let pixels = width as u64 * height as u64;
+        let step = ((pixels as f64 / MAX_SAMPLES as f64).sqrt().ceil() as usize).max(1);
+
+        for y in (0..height).step_by(step) {
+            for x in (0..width).step_by(step) {
+                let [r, g, b, _] = img.get_pixel(x, y).0.map(|v| v as f32 / 255.);
+                let column = x as f32 / width as f32;
+                // Rec. 709 on the encoded values, as video scopes do
+                let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+                scopes.waveform.add(column, luma);
+                for (density, value) in scopes.parade.iter_mut().zip([r, g, b]) {
+                    density.add(column, value);
+                }
+                let cb = (b - luma) / 1.8556;
+                let cr = (r - luma) / 1.5748;
+                scopes.vectorscope.add(cb + 0.5, cr + 0.5);
+            }
+        }
+        scopes
+    }
+}
+
+/// Computes scopes on a single background thread. Only the latest image waits to be computed,
+/// so a burst of frames costs one computation and results arrive in the order they were asked for.
+pub struct ScopesWorker {
+    pending: Arc<(Mutex<Option<DynamicImage>>, Condvar)>,
+    results: (Sender<Scopes>, Receiver<Scopes>),
+    started: bool,
+}
+
+impl Default for ScopesWorker {
+    fn default() -> Self {
+        Self {
+            pending: Default::default(),
+            results: mpsc::channel(),
+            started: false,
+        }
+    }
+}
+
+impl ScopesWorker {
+    /// Ask for the scopes of an image, replacing an image that is still waiting
+    pub fn request(&mut self, img: &DynamicImage) {
+        if !self.started {
+            self.started = true;
+            let pending = self.pending.clone();
+            let sender = self.results.0.clone();
+            std::thread::spawn(move || loop {
+                let Ok(mut next) = pending.0.lock() else {
+                    return;
+                };
+                let img = loop {
+                    if let Some(img) = next.take() {
+                        break img;
+                    }
+                    let Ok(waited) = pending.1.wait(next) else {
+                        return;
+                    };
+                    next = waited;
+                };
+                drop(next);
+                if sender.send(Scopes::compute(&img)).is_err() {
+                    return;
+                }
+            });
+        }
+        if let Ok(mut next) = self.pending.0.lock() {
+            *next = Some(img.clone());
+            self.pending.1.notify_one();
+        }
+    }
+
+    /// The most recent scopes that were computed since the last call
+    pub fn latest(&self) -> Option<Scopes> {
+        self.results.1.try_iter().last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn scopes_of_gray_and_red() {
+        // left half mid gray, right half pure red
+        let img = RgbImage::from_fn(4, 2, |x, _| {
+            if x < 2 {
+                Rgb([128, 128, 128])
+            } else {
+                Rgb([255, 0, 0])
+            }
+        });
+        let scopes = Scopes::compute(&DynamicImage::ImageRgb8(img));
+        let total = |d: &Density| d.counts.iter().sum::<u32>();
+        assert_eq!(total(&scopes.waveform), 8);
+        assert_eq!(total(&scopes.vectorscope), 8);
+
+        // gray has no chroma and sits in the center of the vectorscope
+        let center = SCOPE_SIZE / 2;
+        assert_eq!(scopes.vectorscope.counts[center * SCOPE_SIZE + center], 4);
+        // the red columns are at the top of the red parade and the bottom of the green one
+        let column = SCOPE_SIZE * 3 / 4;
+        assert_eq!(scopes.parade[0].counts[column], 2);
+        assert_eq!(
+            scopes.parade[1].counts[(SCOPE_SIZE - 1) * SCOPE_SIZE + column],
+            2
+        );
+    }
+
+    #[test]
+    fn worker_keeps_the_latest_image() {
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([128, 128, 128])));
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 0, 0])));
+        let expected = Scopes::compute(&red);
+
+        let mut worker = ScopesWorker::default();
+        worker.request(&gray);
+        worker.request(&red);
+        let start = std::time::Instant::now();
+        let mut latest = None;
+        while latest.as_ref() != Some(&expected) {
+            assert!(
+                start.elapsed().as_secs() < 10,
+                "scopes of the last image never arrived"
+            );
+            if let Some(scopes) = worker.latest() {
+                latest = Some(scopes);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(worker.latest(), None);
+    }
+}
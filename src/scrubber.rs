@@ -1,3 +1,4 @@
+use crate::rating::RatingFilter;
 use crate::utils::is_ext_compatible;
 use anyhow::{bail, Context, Result};
 use log::{debug, warn};
@@ -17,6 +18,10 @@ pub struct Scrubber {
     pub wrap: bool,
     pub direction: Direction,
     pub fixed_paths: bool,
+    /// Only images matching this are paged through
+    pub filter: RatingFilter,
+    /// All entries, while the filter hides some of them
+    unfiltered: Option<Vec<PathBuf>>,
 }
 
 impl Scrubber {
@@ -29,6 +34,8 @@ impl Scrubber {
             wrap: true,
             direction: Direction::Forward,
             fixed_paths: false,
+            filter: Default::default(),
+            unfiltered: None,
         }
     }
 
@@ -69,8 +76,11 @@ impl Scrubber {
 
     pub fn remove_current(&mut self) -> PathBuf {
         debug!("Removing index {}", self.index);
-        if self.entries.get(self.index).is_some() {
+        if let Some(current) = self.entries.get(self.index).cloned() {
             self.entries.remove(self.index);
+            if let Some(unfiltered) = &mut self.unfiltered {
+                unfiltered.retain(|p| *p != current);
+            }
             match self.direction {
                 Direction::Forward => {
                     self.index = self.index.saturating_sub(1);
@@ -84,6 +94,65 @@ impl Scrubber {
         }
     }
 
+    /// Remove an entry other than the current one, keeping the current one selected
+    pub fn remove(&mut self, path: &Path) {
+        if let Some(unfiltered) = &mut self.unfiltered {
+            unfiltered.retain(|p| p != path);
+        }
+        if let Some(index) = self.entries.iter().position(|p| p == path) {
+            self.entries.remove(index);
+            if index < self.index {
+                self.index -= 1;
+            }
+        }
+    }
+
+    /// Put a file back in its place in the folder order and select it
+    pub fn restore(&mut self, path: &Path) {
+        let insert = |entries: &mut Vec<PathBuf>| {
+            if !entries.iter().any(|p| p == path) {
+                let index = entries.partition_point(|p| file_name_cmp(p, path).is_lt());
+                entries.insert(index, path.to_path_buf());
+            }
+        };
+        if let Some(unfiltered) = &mut self.unfiltered {
+            insert(unfiltered);
+        }
+        insert(&mut self.entries);
+        if let Some(index) = self.entries.iter().position(|p| p == path) {
+            self.index = index;
+        }
+    }
+
+    /// Hide the entries `keep` returns false for. The current image stays, so it can be
+    /// paged away from.
+    pub fn apply_filter(&mut self, current: Option<&Path>, mut keep: impl FnMut(&Path) -> bool) {
+        let all = self
+            .unfiltered
+            .take()
+            .unwrap_or_else(|| self.entries.clone());
+        if self.filter.is_active() {
+            self.entries = all
+                .iter()
+                .filter(|p| Some(p.as_path()) == current || keep(p))
+                .cloned()
+                .collect();
+            self.unfiltered = Some(all);
+        } else {
+            self.entries = all;
+        }
+        if let Some(index) = current.and_then(|c| self.entries.iter().position(|p| p == c)) {
+            self.index = index;
+        }
+    }
+
+    /// The number of entries, including those hidden by the filter
+    pub fn unfiltered_len(&self) -> usize {
+        self.unfiltered
+            .as_ref()
+            .map_or(self.entries.len(), |all| all.len())
+    }
+
     pub fn set(&mut self, index: usize) -> PathBuf {
         if index < self.entries.len() {
             self.index = index;
@@ -124,20 +193,23 @@ pub fn get_image_filenames_for_directory(folder_path: &Path) -> Result<Vec<PathB
         .filter(|x| is_ext_compatible(x))
         .collect::<Vec<PathBuf>>();
 
-    dir_files.sort_unstable_by(|a, b| {
-        lexical_sort::natural_lexical_cmp(
-            &a.file_name()
-                .map(|f| f.to_string_lossy())
-                .unwrap_or_default(),
-            &b.file_name()
-                .map(|f| f.to_string_lossy())
-                .unwrap_or_default(),
-        )
-    });
+    dir_files.sort_unstable_by(|a, b| file_name_cmp(a, b));
 
     Ok(dir_files)
 }
 
+/// Natural order of file names, like the folder listing
+fn file_name_cmp(a: &Path, b: &Path) -> std::cmp::Ordering {
+    lexical_sort::natural_lexical_cmp(
+        &a.file_name()
+            .map(|f| f.to_string_lossy())
+            .unwrap_or_default(),
+        &b.file_name()
+            .map(|f| f.to_string_lossy())
+            .unwrap_or_default(),
+    )
+}
+
 /// Find first valid image from the directory
 /// Assumes the given path is a directory and not a file
 pub fn find_first_image_in_directory(folder_path: &Path) -> Result<PathBuf> {
@@ -1,4 +1,7 @@
-use crate::{file_encoder::FileEncoder, shortcuts::*, utils::ColorChannel};
+use crate::{
+    cull::CULL_TARGETS, file_encoder::FileEncoder, metadata::PrivacySettings,
+    rating::RatingStorage, shortcuts::*, utils::ColorChannel,
+};
 use anyhow::{anyhow, Result};
 use log::{debug, info, trace};
 use notan::egui::{Context, Visuals};
@@ -17,7 +20,7 @@ use std::{
     path::PathBuf,
 };
 
-fn get_config_dir() -> Result<PathBuf> {
+pub fn get_config_dir() -> Result<PathBuf> {
     Ok(dirs::data_local_dir()
         .ok_or(anyhow!("Can't get local dir"))?
         .join("oculante"))
@@ -77,6 +80,8 @@ pub struct PersistentSettings {
     pub experimental_features: bool,
     /// Tunables for decoders, such as max memory usage
     pub decoders: DecoderSettings,
+    /// Where star ratings, labels and tags are kept
+    pub rating_storage: RatingStorage,
 }
 
 impl Default for PersistentSettings {
@@ -112,6 +117,7 @@ impl Default for PersistentSettings {
             min_window_size: (100, 100),
             experimental_features: false,
             decoders: Default::default(),
+            rating_storage: Default::default(),
         }
     }
 }
@@ -151,22 +157,28 @@ impl PersistentSettings {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct VolatileSettings {
-    pub favourite_images: HashSet<PathBuf>,
     pub recent_images: Vec<PathBuf>,
     pub window_geometry: ((u32, u32), (u32, u32)),
     pub last_open_directory: PathBuf,
     pub folder_bookmarks: BTreeSet<PathBuf>,
+    /// The folders the move and copy hotkeys sort into, by hotkey number
+    pub cull_targets: [Option<PathBuf>; CULL_TARGETS],
     pub encoding_options: Vec<FileEncoder>,
+    /// What metadata is kept when saving
+    pub privacy: PrivacySettings,
+    /// Favourites of older versions, turned into picks on start
+    #[serde(skip_serializing_if = "HashSet::is_empty")]
+    pub favourite_images: HashSet<PathBuf>,
 }
 
 impl Default for VolatileSettings {
     fn default() -> Self {
         Self {
-            favourite_images: Default::default(),
             recent_images: Default::default(),
             window_geometry: Default::default(),
             last_open_directory: Default::default(),
             folder_bookmarks: Default::default(),
+            cull_targets: Default::default(),
             encoding_options: [
                 // ("jpg".to_string(), FileEncoder::Jpg { quality: 75 }),
                 // ("png".to_string(), FileEncoder::WebP),
@@ -179,6 +191,8 @@ impl Default for VolatileSettings {
             ]
             .into_iter()
             .collect(),
+            privacy: Default::default(),
+            favourite_images: Default::default(),
         }
     }
 }
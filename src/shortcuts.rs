@@ -2,6 +2,7 @@ use log::{debug, error, info, trace, warn};
 use std::collections::{BTreeMap, BTreeSet};
 
 use crate::appstate::OculanteState;
+use crate::cull::CULL_TARGETS;
 use notan::prelude::App;
 use serde::{Deserialize, Serialize};
 
@@ -40,11 +41,55 @@ pub enum InputEvent {
     LosslessRotateLeft,
     Copy,
     Paste,
+    Undo,
+    Redo,
     Browse,
     Quit,
     ZenMode,
+    Rate0,
+    Rate1,
+    Rate2,
+    Rate3,
+    Rate4,
+    Rate5,
+    LabelRed,
+    LabelYellow,
+    LabelGreen,
+    LabelBlue,
+    LabelPurple,
+    Pick,
+    Reject,
+    MoveTo1,
+    MoveTo2,
+    MoveTo3,
+    MoveTo4,
+    MoveTo5,
+    CopyTo1,
+    CopyTo2,
+    CopyTo3,
+    CopyTo4,
+    CopyTo5,
+    UndoCull,
 }
 
+/// Hotkeys moving the current file to the culling folders, by slot
+pub const MOVE_TO: [InputEvent; CULL_TARGETS] = [
+    InputEvent::MoveTo1,
+    InputEvent::MoveTo2,
+    InputEvent::MoveTo3,
+    InputEvent::MoveTo4,
+    InputEvent::MoveTo5,
+];
+
+/// Hotkeys copying the current file to the culling folders, by slot
+pub const COPY_TO: [InputEvent; CULL_TARGETS] = [
+    InputEvent::CopyTo1,
+    InputEvent::CopyTo2,
+    InputEvent::CopyTo3,
+    InputEvent::CopyTo4,
+    InputEvent::CopyTo5,
+];
+
 pub type Shortcuts = BTreeMap<InputEvent, SimultaneousKeypresses>;
 
 pub type SimultaneousKeypresses = BTreeSet<String>;
@@ -138,7 +183,33 @@ impl ShortcutExt for Shortcuts {
             .add_keys(InputEvent::PanDown, &["LShift", "Down"])
             .add_keys(InputEvent::PanUp, &["LShift", "Up"])
             .add_keys(InputEvent::Paste, &["LControl", "V"])
-            .add_keys(InputEvent::Copy, &["LControl", "C"]);
+            .add_keys(InputEvent::Copy, &["LControl", "C"])
+            .add_keys(InputEvent::Undo, &["LControl", "Z"])
+            .add_keys(InputEvent::Redo, &["LControl", "LShift", "Z"])
+            .add_keys(InputEvent::Rate0, &["LControl", "Key0"])
+            .add_keys(InputEvent::Rate1, &["LControl", "Key1"])
+            .add_keys(InputEvent::Rate2, &["LControl", "Key2"])
+            .add_keys(InputEvent::Rate3, &["LControl", "Key3"])
+            .add_keys(InputEvent::Rate4, &["LControl", "Key4"])
+            .add_keys(InputEvent::Rate5, &["LControl", "Key5"])
+            .add_keys(InputEvent::LabelRed, &["LAlt", "Key1"])
+            .add_keys(InputEvent::LabelYellow, &["LAlt", "Key2"])
+            .add_keys(InputEvent::LabelGreen, &["LAlt", "Key3"])
+            .add_keys(InputEvent::LabelBlue, &["LAlt", "Key4"])
+            .add_keys(InputEvent::LabelPurple, &["LAlt", "Key5"])
+            .add_key(InputEvent::Pick, "P")
+            .add_key(InputEvent::Reject, "X")
+            .add_keys(InputEvent::MoveTo1, &["LShift", "Key1"])
+            .add_keys(InputEvent::MoveTo2, &["LShift", "Key2"])
+            .add_keys(InputEvent::MoveTo3, &["LShift", "Key3"])
+            .add_keys(InputEvent::MoveTo4, &["LShift", "Key4"])
+            .add_keys(InputEvent::MoveTo5, &["LShift", "Key5"])
+            .add_keys(InputEvent::CopyTo1, &["LControl", "LShift", "Key1"])
+            .add_keys(InputEvent::CopyTo2, &["LControl", "LShift", "Key2"])
+            .add_keys(InputEvent::CopyTo3, &["LControl", "LShift", "Key3"])
+            .add_keys(InputEvent::CopyTo4, &["LControl", "LShift", "Key4"])
+            .add_keys(InputEvent::CopyTo5, &["LControl", "LShift", "Key5"])
+            .add_keys(InputEvent::UndoCull, &["LShift", "U"]);
         #[cfg(target_os = "macos")]
         {
             for (_, keys) in s.iter_mut() {
@@ -0,0 +1,291 @@
+//! Reading and writing `.oculante` files, which store the edits of an image next to it
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    brushes::builtin_brush_name,
+    image_editing::{EditState, ImgOpItem},
+};
+
+/// The current version of the file format.
+/// 0: operator stacks without `active` flag, 1: unversioned, 2: version and checksum,
+/// 3: strokes name their brush
+pub const SIDECAR_VERSION: u64 = 3;
+
+/// Migrations from each version to the next, starting at version 0
+const MIGRATIONS: [fn(&mut Value); 3] = [migrate_v0, migrate_v1, migrate_v2];
+
+const STACKS: [&str; 2] = ["pixel_op_stack", "image_op_stack"];
+
+#[derive(Serialize)]
+struct SidecarRef<'a> {
+    version: u64,
+    /// Checksum of the image file the edits were made for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_checksum: Option<u32>,
+    #[serde(flatten)]
+    edit_state: &'a EditState,
+}
+
+/// The result of loading a `.oculante` file
+#[derive(Debug)]
+pub struct Sidecar {
+    pub edit_state: EditState,
+    /// Everything that did not load cleanly, ready to be shown to the user
+    pub warnings: Vec<String>,
+    /// The file was written in an older format
+    pub migrated: bool,
+}
+
+/// The file the edits of an image are saved to
+pub fn edits_path(image: &Path) -> PathBuf {
+    image.with_extension("oculante")
+}
+
+/// Save edits. With a `source` image, its checksum is stored to detect when the image changes.
+pub fn save(path: &Path, edit_state: &EditState, source: Option<&Path>) -> Result<()> {
+    let source_checksum = source.map(checksum).transpose()?;
+    let f = File::create(path)?;
+    serde_json::to_writer_pretty(
+        &f,
+        &SidecarRef {
+            version: SIDECAR_VERSION,
+            source_checksum,
+            edit_state,
+        },
+    )?;
+    debug!("Saved edits to {}", path.display());
+    Ok(())
+}
+
+/// Load edits of any version. Operators that can't be read are skipped with a warning.
+pub fn load(path: &Path, source: Option<&Path>) -> Result<Sidecar> {
+    let f = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+    let mut value = serde_json::from_reader::<_, Value>(BufReader::new(f))
+        .with_context(|| format!("{} is not valid JSON", path.display()))?;
+    let mut warnings = vec![];
+
+    let version = detect_version(&value);
+    if version > SIDECAR_VERSION {
+        warnings.push("These edits were saved by a newer version of oculante.".to_string());
+    }
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        migration(&mut value);
+    }
+    if version < SIDECAR_VERSION {
+        info!("Migrated edits from version {version} to {SIDECAR_VERSION}");
+    }
+
+    if let (Some(source), Some(stored)) =
+        (source, value.get("source_checksum").and_then(Value::as_u64))
+    {
+        // An image that can't be read is treated as changed, the edits still load
+        if !checksum(source).is_ok_and(|c| c as u64 == stored) {
+            warnings.push("The image has changed since these edits were saved.".to_string());
+        }
+    }
+
+    // Read operators one by one, so unknown ones don't make the whole file unreadable
+    let mut stacks = STACKS.map(|key| {
+        let Some(Value::Array(items)) = value.as_object_mut().and_then(|o| o.remove(key)) else {
+            return vec![];
+        };
+        items
+            .into_iter()
+            .filter_map(|item| {
+                serde_json::from_value::<ImgOpItem>(item.clone())
+                    .map_err(|e| {
+                        warn!("Skipping operator: {e}");
+                        warnings.push(format!("Skipped unknown operator {}", operator_name(&item)));
+                    })
+                    .ok()
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut edit_state = serde_json::from_value::<EditState>(value)
+        .with_context(|| format!("{} does not contain edits", path.display()))?;
+    edit_state.pixel_op_stack = std::mem::take(&mut stacks[0]);
+    edit_state.image_op_stack = std::mem::take(&mut stacks[1]);
+    edit_state.sort_stacks();
+
+    Ok(Sidecar {
+        edit_state,
+        warnings,
+        migrated: version < SIDECAR_VERSION,
+    })
+}
+
+/// CRC32 of a file
+pub fn checksum(path: &Path) -> Result<u32> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut crc = flate2::Crc::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        crc.update(&buffer[..read]);
+    }
+    Ok(crc.sum())
+}
+
+fn detect_version(value: &Value) -> u64 {
+    if let Some(version) = value.get("version").and_then(Value::as_u64) {
+        return version;
+    }
+    // Version 0 stored operators directly, later versions wrap them in an item
+    let bare_operators = STACKS.iter().any(|key| {
+        value
+            .get(key)
+            .and_then(Value::as_array)
+            .is_some_and(|ops| ops.iter().any(|op| op.get("operation").is_none()))
+    });
+    if bare_operators {
+        0
+    } else {
+        1
+    }
+}
+
+/// Wrap bare operators in an active item
+fn migrate_v0(value: &mut Value) {
+    for key in STACKS {
+        if let Some(Value::Array(ops)) = value.get_mut(key) {
+            for op in ops.iter_mut() {
+                *op = serde_json::json!({
+                    "active": true,
+                    "operation": op.take(),
+                });
+            }
+        }
+    }
+}
+
+/// Version 2 only adds the version and the optional checksum
+fn migrate_v1(value: &mut Value) {
+    value["version"] = 2.into();
+}
+
+/// Strokes referred to brushes by their position in the library. Only built-in brushes
+/// can be found again, others fall back to the first brush.
+fn migrate_v2(value: &mut Value) {
+    if let Some(Value::Array(strokes)) = value.get_mut("paint_strokes") {
+        for stroke in strokes.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(index) = stroke.remove("brush_index").and_then(|i| i.as_u64()) {
+                if let Some(name) = builtin_brush_name(index as usize) {
+                    stroke.insert("brush".into(), name.into());
+                }
+            }
+        }
+    }
+    value["version"] = 3.into();
+}
+
+/// The operator name of a serialized item, which is a string for unit variants
+/// and the only key of an object otherwise
+fn operator_name(item: &Value) -> String {
+    match item.get("operation") {
+        Some(Value::String(name)) => name.clone(),
+        Some(Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+        _ => "without name".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_editing::ImageOperation;
+
+    #[test]
+    fn sidecar_migration_and_unknown_operators() {
+        let dir = std::env::temp_dir().join("oculante_sidecar_test");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A version 0 file, with an operator this version does not know
+        let legacy = dir.join("legacy.oculante");
+        std::fs::write(
+            &legacy,
+            r#"{
+                "painting": false,
+                "non_destructive_painting": false,
+                "paint_strokes": [],
+                "paint_fade": false,
+                "pixel_op_stack": ["Invert", {"Sparkle": 3}],
+                "image_op_stack": [{"Blur": 4}],
+                "export_extension": "png"
+            }"#,
+        )
+        .unwrap();
+        let sidecar = load(&legacy, None).unwrap();
+        assert!(sidecar.migrated);
+        assert_eq!(sidecar.warnings.len(), 1);
+        assert!(sidecar.warnings[0].contains("Sparkle"));
+        assert_eq!(
+            sidecar.edit_state.pixel_op_stack,
+            vec![ImgOpItem::new(ImageOperation::Invert)]
+        );
+        assert_eq!(
+            sidecar.edit_state.image_op_stack,
+            vec![ImgOpItem::new(ImageOperation::Blur(4))]
+        );
+
+        // Round trip with a checksum, which detects a changed image
+        let source = dir.join("source.txt");
+        std::fs::write(&source, "image").unwrap();
+        let current = dir.join("current.oculante");
+        save(&current, &sidecar.edit_state, Some(&source)).unwrap();
+        let reloaded = load(&current, Some(&source)).unwrap();
+        assert!(!reloaded.migrated);
+        assert!(reloaded.warnings.is_empty());
+        assert_eq!(
+            reloaded.edit_state.pixel_op_stack,
+            sidecar.edit_state.pixel_op_stack
+        );
+
+        // Strokes of version 2 point to brushes by index
+        let strokes = dir.join("strokes.oculante");
+        std::fs::write(
+            &strokes,
+            r#"{
+                "version": 2,
+                "paint_strokes": [{
+                    "points": [[0.1, 0.1]],
+                    "fade": false,
+                    "color": [1, 1, 1, 1],
+                    "width": 0.05,
+                    "brush_index": 2,
+                    "highlight": false,
+                    "committed": false,
+                    "flip_random": false
+                }]
+            }"#,
+        )
+        .unwrap();
+        let migrated = load(&strokes, None).unwrap();
+        assert!(migrated.migrated);
+        assert_eq!(migrated.edit_state.paint_strokes[0].brush, "Brush 2");
+
+        std::fs::write(&source, "changed image").unwrap();
+        assert_eq!(load(&current, Some(&source)).unwrap().warnings.len(), 1);
+
+        // A missing image still loads the edits
+        std::fs::remove_file(&source).unwrap();
+        let orphaned = load(&current, Some(&source)).unwrap();
+        assert_eq!(orphaned.warnings.len(), 1);
+        assert_eq!(
+            orphaned.edit_state.image_op_stack,
+            sidecar.edit_state.image_op_stack
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use super::*;
+use crate::appstate::OculanteState;
+use crate::cull::{CullAction, CULL_TARGETS};
+use crate::shortcuts::{lookup, InputEvent, COPY_TO, MOVE_TO};
+use crate::utils::*;
+#[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
+use notan::egui::*;
+
+pub fn cull_ui(ui: &mut Ui, state: &mut OculanteState) {
+    if state.current_path.is_none() && state.culling.operations.is_empty() {
+        return;
+    }
+
+    ui.styled_collapsing("Culling", |ui| {
+        ui.vertical_centered_justified(|ui| {
+            dark_panel(ui, |ui| {
+                let bookmarks = state
+                    .volatile_settings
+                    .folder_bookmarks
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if bookmarks.is_empty() {
+                    ui.label("Bookmark folders in the file browser to sort images into them.");
+                }
+                let folder_name = |folder: &Path| {
+                    folder
+                        .file_name()
+                        .map(|f| f.to_string_lossy().to_string())
+                        .unwrap_or_else(|| folder.display().to_string())
+                };
+                let shortcuts = &state.persistent_settings.shortcuts;
+                let mut action = None;
+                for (i, target) in state
+                    .volatile_settings
+                    .cull_targets
+                    .iter_mut()
+                    .enumerate()
+                    .take(CULL_TARGETS)
+                {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}", i + 1)).on_hover_text(format!(
+                            "Move: {}\nCopy: {}",
+                            lookup(shortcuts, &MOVE_TO[i]),
+                            lookup(shortcuts, &COPY_TO[i])
+                        ));
+                        if ui
+                            .add_enabled(target.is_some(), egui::Button::new("Move"))
+                            .on_hover_text(lookup(shortcuts, &MOVE_TO[i]))
+                            .clicked()
+                        {
+                            action = Some((CullAction::Move, i));
+                        }
+                        if ui
+                            .add_enabled(target.is_some(), egui::Button::new("Copy"))
+                            .on_hover_text(lookup(shortcuts, &COPY_TO[i]))
+                            .clicked()
+                        {
+                            action = Some((CullAction::Copy, i));
+                        }
+                        egui::ComboBox::from_id_salt(("cull_target", i))
+                            .selected_text(
+                                target
+                                    .as_deref()
+                                    .map(folder_name)
+                                    .unwrap_or_else(|| "None".into()),
+                            )
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(target, None, "None");
+                                for folder in &bookmarks {
+                                    ui.selectable_value(
+                                        target,
+                                        Some(folder.clone()),
+                                        folder_name(folder),
+                                    )
+                                    .on_hover_text(folder.display().to_string());
+                                }
+                            })
+                            .response
+                            .on_hover_text(
+                                target
+                                    .as_ref()
+                                    .map(|f| f.display().to_string())
+                                    .unwrap_or_else(|| "Choose a bookmarked folder".into()),
+                            );
+                    });
+                }
+                if let Some((action, target)) = action {
+                    cull_file(state, action, target);
+                }
+
+                if state.culling.operations.is_empty() {
+                    return;
+                }
+                ui.separator();
+                if ui
+                    .button("↩ Undo last")
+                    .on_hover_text(lookup(
+                        &state.persistent_settings.shortcuts,
+                        &InputEvent::UndoCull,
+                    ))
+                    .clicked()
+                {
+                    undo_cull(state);
+                }
+                egui::ScrollArea::vertical()
+                    .id_salt("cull_log")
+                    .max_height(150.)
+                    .show(ui, |ui| {
+                        for operation in state.culling.operations.iter().rev() {
+                            ui.label(format!("{} {operation}", operation.time.format("%H:%M:%S")))
+                                .on_hover_text(operation.destination.display().to_string());
+                        }
+                    });
+            });
+        });
+    });
+}
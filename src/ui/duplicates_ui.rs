@@ -0,0 +1,180 @@
+use super::*;
+use crate::appstate::OculanteState;
+use crate::duplicates::{distance, HashKind, MAX_DISTANCE};
+use crate::utils::*;
+#[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
+use notan::egui::*;
+use std::time::Duration;
+
+/// File sizes in KB or MB
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024. * 1024.))
+    }
+}
+
+pub fn duplicates_ui(ctx: &Context, state: &mut OculanteState) {
+    if !state.duplicates.open {
+        return;
+    }
+    state.duplicates.poll();
+
+    let folder = state
+        .current_path
+        .as_ref()
+        .and_then(|p| p.parent())
+        .map(|p| p.to_path_buf());
+    let mut open = true;
+    let mut trash = None;
+    let mut confirm_trash = None;
+    let mut load = None;
+    let pending_id = Id::new("duplicate_trash");
+    let modal = Modal::new("trash_duplicate", ctx);
+
+    egui::Window::new("Duplicates")
+        .collapsible(false)
+        .open(&mut open)
+        .resizable(true)
+        .default_width(600.)
+        .show(ctx, |ui| {
+            let finder = &mut state.duplicates;
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_salt("duplicate_hash")
+                    .selected_text(finder.kind.to_string())
+                    .show_ui(ui, |ui| {
+                        for kind in HashKind::iter() {
+                            ui.selectable_value(&mut finder.kind, kind, kind.to_string());
+                        }
+                    });
+                ui.styled_checkbox(&mut finder.recursive, "Include subfolders");
+                if let Some(folder) = &folder {
+                    if ui
+                        .add_enabled(
+                            finder.progress().is_none(),
+                            egui::Button::new(format!("{SEARCH} Scan folder")),
+                        )
+                        .on_hover_text(folder.display().to_string())
+                        .clicked()
+                    {
+                        finder.start(folder);
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Threshold")
+                    .on_hover_text("How many of the 64 hash bits may differ within a group");
+                if ui
+                    .styled_slider(&mut finder.threshold, 0..=MAX_DISTANCE)
+                    .changed()
+                {
+                    finder.regroup();
+                }
+            });
+
+            if let Some((done, total)) = finder.progress() {
+                let text = if total == 0 {
+                    "Looking for images...".to_string()
+                } else {
+                    format!("{done} / {total}")
+                };
+                ui.add(egui::ProgressBar::new(done as f32 / total.max(1) as f32).text(text));
+                ui.ctx().request_repaint_after(Duration::from_millis(100));
+                return;
+            }
+            if finder.entries.is_empty() {
+                ui.label("Scan a folder to find similar images.");
+                return;
+            }
+            ui.label(format!(
+                "{} groups in {} images",
+                finder.groups.len(),
+                finder.entries.len()
+            ));
+            if finder.kind != finder.scanned_kind {
+                ui.weak(format!(
+                    "These results use {}. Scan again to use {}.",
+                    finder.scanned_kind, finder.kind
+                ));
+            }
+            ui.separator();
+
+            egui::ScrollArea::vertical()
+                .auto_shrink([false, true])
+                .show(ui, |ui| {
+                    for group in &finder.groups {
+                        let first = &finder.entries[group[0]];
+                        ui.horizontal_wrapped(|ui| {
+                            for i in group {
+                                let entry = &finder.entries[*i];
+                                ui.vertical(|ui| {
+                                    ui.set_width(THUMB_SIZE[0] as f32);
+                                    if render_file_icon(&entry.path, ui, &mut state.thumbnails)
+                                        .on_hover_text(entry.path.display().to_string())
+                                        .clicked()
+                                    {
+                                        load = Some(entry.path.clone());
+                                    }
+                                    ui.label(format!(
+                                        "{}x{}",
+                                        entry.dimensions.0, entry.dimensions.1
+                                    ));
+                                    ui.label(format_size(entry.file_size));
+                                    if let Some(modified) = entry.modified {
+                                        ui.label(
+                                            chrono::DateTime::<chrono::Local>::from(modified)
+                                                .format("%Y-%m-%d %H:%M")
+                                                .to_string(),
+                                        );
+                                    }
+                                    if i == &group[0] {
+                                        ui.weak("Largest");
+                                    } else {
+                                        ui.weak(format!(
+                                            "Distance {}",
+                                            distance(first.hash, entry.hash)
+                                        ));
+                                    }
+                                    if ui.button(format!("{TRASH} Trash")).clicked() {
+                                        confirm_trash = Some(entry.path.clone());
+                                    }
+                                });
+                            }
+                        });
+                        ui.separator();
+                    }
+                });
+        });
+
+    if let Some(p) = confirm_trash {
+        ctx.data_mut(|w| w.insert_temp(pending_id, p));
+        modal.open();
+    }
+    if let Some(p) = ctx.data(|r| r.get_temp::<PathBuf>(pending_id)) {
+        modal.show(
+            format!(
+                "Are you sure you want to move {} to the trash?",
+                p.file_name()
+                    .map(|s| s.to_string_lossy())
+                    .unwrap_or_default()
+            ),
+            |_| trash = Some(p.clone()),
+        );
+    }
+
+    if let Some(p) = trash {
+        ctx.data_mut(|w| w.remove_temp::<PathBuf>(pending_id));
+        if state.current_path.as_ref() == Some(&p) {
+            delete_file(state);
+        } else {
+            trash_file(&p, state);
+            state.scrubber.remove(&p);
+        }
+        state.duplicates.remove(&p);
+    }
+    if let Some(p) = load {
+        _ = state.load_channel.0.send(p);
+    }
+    state.duplicates.open = open;
+}
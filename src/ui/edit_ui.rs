@@ -1,7 +1,11 @@
 use super::*;
 use crate::appstate::OculanteState;
+use crate::brushes::{find_brush, Brush, BrushSettings};
+use crate::paint::{render_layer, render_strokes, PaintLayer, PaintTool};
+use crate::presets::EditPreset;
+use crate::sidecar;
 use crate::utils::*;
-use image::{ColorType, GenericImageView, RgbaImage};
+use image::GenericImageView;
 #[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
 use notan::egui::*;
 
@@ -55,9 +59,7 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
         ImgOpItem::new(ImageOperation::Filter3x3([
             0, -100, 0, -100, 500, -100, 0, -100, 0,
         ])),
-        ImgOpItem::new(ImageOperation::ColorConverter(
-            crate::image_editing::ColorTypeExt::Rgba8,
-        )),
+        ImgOpItem::new(ImageOperation::ColorConverter(ColorTypeExt::Rgba8)),
         // Mathematical
         ImgOpItem::new(ImageOperation::MMult),
         ImgOpItem::new(ImageOperation::MDiv),
@@ -70,9 +72,30 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
             mono: false,
         }),
         ImgOpItem::new(ImageOperation::ChromaticAberration(15)),
+        ImgOpItem::new(ImageOperation::Vignette {
+            amount: -50,
+            midpoint: 50,
+            roundness: 0,
+            feather: 50,
+        }),
+        ImgOpItem::new(ImageOperation::Grain {
+            amount: 25,
+            size: 25,
+            seed: 0,
+        }),
+        ImgOpItem::new(ImageOperation::SplitToning {
+            shadows: [40, 90, 160],
+            highlights: [230, 170, 80],
+            balance: 0,
+        }),
         // Geometry and Transformations
         ImgOpItem::new(ImageOperation::Flip(false)),
         ImgOpItem::new(ImageOperation::Rotate(90)),
+        ImgOpItem::new(ImageOperation::RotateFree {
+            angle: 0,
+            filter: RotateFilter::Bilinear,
+            crop: true,
+        }),
         ImgOpItem::new(ImageOperation::Resize {
             dimensions: state.image_geometry.dimensions,
             aspect: true,
@@ -91,6 +114,35 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
             ],
             original_size: state.image_geometry.dimensions,
         }),
+        ImgOpItem::new(ImageOperation::Pad {
+            sides: [20, 20, 20, 20],
+            percent: false,
+            color: [255, 255, 255, 255],
+        }),
+        ImgOpItem::new(ImageOperation::ExtendAspect {
+            ratio: (1, 1),
+            anchor: Anchor::Center,
+            color: [255, 255, 255, 255],
+        }),
+        // Overlays
+        ImgOpItem::new(ImageOperation::Text {
+            text: "DRAFT".into(),
+            font: String::new(),
+            size: 10,
+            color: [255, 255, 255],
+            opacity: 128,
+            anchor: Anchor::Center,
+            margin: 2,
+            rotation: 0,
+        }),
+        ImgOpItem::new(ImageOperation::Watermark {
+            path: String::new(),
+            size: 20,
+            opacity: 255,
+            anchor: Anchor::BottomRight,
+            margin: 2,
+            rotation: 0,
+        }),
     ];
 
     egui::SidePanel::right("editing")
@@ -156,7 +208,7 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
             ui.vertical_centered_justified(|ui| {
                 if state.edit_state.painting {
 
-                    if ctx.input(|i|i.pointer.secondary_down()) {
+                    if ctx.input(|i|i.pointer.secondary_down()) && !region_modifier(ctx) {
                         if let Some(stroke) = state.edit_state.paint_strokes.last_mut() {
                             if let Some(p) = get_pixel_checked(&state.edit_state.result_pixel_op, state.cursor_relative.x as u32, state.cursor_relative.y as u32) {
                                 stroke.color = [
@@ -200,11 +252,30 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                             ui.label("Brush");
                             ui.end_row();
 
-                            stroke_ui(stroke, &state.edit_state.brushes, ui, gfx);
+                            let brush = stroke.brush.clone();
+                            stroke_ui(stroke, state.brushes.brushes(), ui, gfx);
+                            // a newly picked brush starts with its stored settings
+                            if stroke.brush != brush {
+                                if let Some(brush) = find_brush(state.brushes.brushes(), &stroke.brush) {
+                                    brush.settings.apply(stroke);
+                                }
+                            }
                         }
                     }
                 });
 
+                if let Some(stroke) = state.edit_state.paint_strokes.last_mut() {
+                    if stroke.is_empty() {
+                        stroke_tool_ui(stroke, ui);
+                    }
+                }
+
+                if paint_layers_ui(state, ui) {
+                    pixels_changed = true;
+                }
+
+                brushes_ui(state, ui, gfx);
+
                 if state
                     .edit_state
                     .paint_strokes
@@ -253,7 +324,7 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
 
                                             let r = stroke_ui(
                                                 stroke,
-                                                &state.edit_state.brushes,
+                                                state.brushes.brushes(),
                                                 ui,
                                                 gfx,
                                             );
@@ -312,7 +383,7 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                             state.cursor_relative.x / state.image_geometry.dimensions.0 as f32,
                             (state.cursor_relative.y / state.image_geometry.dimensions.1 as f32),
                         );
-                        current_stroke.points.push(uv);
+                        current_stroke.add_point(uv);
                         pixels_changed = true;
                     } else if !current_stroke.is_empty() {
                         // clone last stroke to inherit settings
@@ -326,6 +397,14 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                 }
             }
 
+            if presets_ui(state, ui) {
+                image_changed = true;
+            }
+
+            if history_ui(state, ui) {
+                image_changed = true;
+            }
+
             ui.horizontal(|ui|{
                 if ui.add(egui::Button::new("Original").min_size(vec2(ui.available_width()/2., 0.))).clicked() {
                     if let Some(img) = &state.current_image {
@@ -350,6 +429,8 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                     if let Some(img) = &mut state.current_image {
                         *img = state.edit_state.result_pixel_op.clone();
                         state.edit_state = Default::default();
+                        // The edits are part of the image now and can't be undone
+                        state.edit_history = Default::default();
                         // state.dimensions = img.dimensions();
                         pixels_changed = true;
                         image_changed = true;
@@ -400,7 +481,8 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                         let image_to_save = state.edit_state.result_pixel_op.clone();
                         let msg_sender = state.message_channel.0.clone();
                         let err_sender = state.message_channel.0.clone();
-                        let image_info = state.image_info.clone();
+                        let image_info = state.image_metadata.clone();
+                        let privacy = state.volatile_settings.privacy.clone();
 
                         std::thread::spawn(move || {
                             let file_dialog_result = rfd::FileDialog::new()
@@ -419,8 +501,8 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                                                     debug!("Extended image info present");
 
                                                     // before doing anything, make sure we have raw exif data
-                                                    if info.raw_exif.is_some() {
-                                                        if let Err(e) = fix_exif(&file_path, info.raw_exif.clone()) {
+                                                    if info.raw_exif.is_some() || info.raw_icc.is_some() {
+                                                        if let Err(e) = fix_exif(&file_path, info, &privacy) {
                                                             error!("{e}");
                                                         } else {
                                                             info!("Saved EXIF.")
@@ -455,12 +537,13 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                         let keys = &state.volatile_settings.encoding_options.iter().map(|e|e.ext()).collect::<Vec<_>>();
                         let key_slice = keys.iter().map(|k|k.as_str()).collect::<Vec<_>>();
                         let encoders = state.volatile_settings.encoding_options.clone();
+                        let privacy = state.volatile_settings.privacy.clone();
                         filebrowser::browse_modal(
                             true,
                             key_slice.as_slice(),
                             &mut state.volatile_settings,
                             |p| {
-                                _ = save_with_encoding(&state.edit_state.result_pixel_op, p, &state.image_metadata, &encoders);
+                                _ = save_with_encoding(&state.edit_state.result_pixel_op, p, &state.image_metadata, &encoders, &privacy);
                             },
                             ctx,
                         );
@@ -471,7 +554,7 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                     let text = if p.exists() { "Overwrite" } else { "Save"};
 
                     let modal = show_modal(ui.ctx(), "Overwrite?", |_|{
-                        _ = save_with_encoding(&state.edit_state.result_pixel_op, p, &state.image_metadata, &state.volatile_settings.encoding_options).map(|_| state.send_message_info("Saved")).map_err(|e| state.send_message_err(&format!("Error: {e}")));
+                        _ = save_with_encoding(&state.edit_state.result_pixel_op, p, &state.image_metadata, &state.volatile_settings.encoding_options, &state.volatile_settings.privacy).map(|_| state.send_message_info("Saved")).map_err(|e| state.send_message_err(&format!("Error: {e}")));
                     }, "overwrite");
 
 
@@ -479,19 +562,19 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                         if p.exists() {
                             modal.open();
                         } else {
-                            _ = save_with_encoding(&state.edit_state.result_pixel_op, p, &state.image_metadata, &state.volatile_settings.encoding_options).map(|_| state.send_message_info("Saved")).map_err(|e| state.send_message_err(&format!("Error: {e}")));
+                            _ = save_with_encoding(&state.edit_state.result_pixel_op, p, &state.image_metadata, &state.volatile_settings.encoding_options, &state.volatile_settings.privacy).map(|_| state.send_message_info("Saved")).map_err(|e| state.send_message_err(&format!("Error: {e}")));
                         }
                     }
 
                     if ui.button("Save edits").on_hover_text("Saves an .oculante metafile in the same directory as the image. This file will contain all edits and will be restored automatically if you open the image again. This leaves the original image unmodified and allows you to continue editing later.").clicked() {
-                        if let Ok(f) = std::fs::File::create(p.with_extension("oculante")) {
-                            _ = serde_json::to_writer_pretty(&f, &state.edit_state);
+                        if let Err(e) = sidecar::save(&sidecar::edits_path(p), &state.edit_state, Some(p.as_path())) {
+                            state.send_message_err(&format!("Error: {e}"));
                         }
                     }
                     if ui.button("Save directory edits").on_hover_text("Saves an .oculante metafile in the same directory as all applicable images. This file will contain all edits and will be restored automatically if you open the image(s) again. This leaves the original image(s) unmodified and allows you to continue editing later.").clicked() {
                         if let Some(parent) = p.parent() {
-                            if let Ok(f) = std::fs::File::create(parent.join(".oculante")) {
-                                _ = serde_json::to_writer_pretty(&f, &state.edit_state);
+                            if let Err(e) = sidecar::save(&parent.join(".oculante"), &state.edit_state, None) {
+                                state.send_message_err(&format!("Error: {e}"));
                             }
                         }
                     }
@@ -499,23 +582,6 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
             });
         });
 
-        if state.edit_state.result_image_op.color() != ColorType::Rgba8 {
-            let op_present = state.edit_state.image_op_stack.first().map(|op| matches!(op.operation, ImageOperation::ColorConverter(_))).unwrap_or_default();
-            if !op_present {
-                state.edit_state.image_op_stack.insert(0, ImgOpItem::new(ImageOperation::ColorConverter(ColorTypeExt::Rgba8)));
-                image_changed = true;
-                pixels_changed = true;
-                state.send_message_info("Color conversion operator added.");
-            }
-        }
-
-        if let Some(img) = &state.current_image {
-            if img.color() != ColorType::Rgba8 {
-                ui.add_space(10.);
-                ui.small(format!("{INFO} Your image is not 8 bit RGBA. For full editing support a conversion operator was added."));
-            }
-        }
-
         #[cfg(debug_assertions)]
         {
             ui.colored_label(Color32::LIGHT_BLUE, "Debug info");
@@ -529,73 +595,7 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
 
             // If expensive operations happened (modifying image geometry), process them here
             let message: Option<String> = None;
-            if image_changed {
-                if let Some(img) = &mut state.current_image {
-                    let stamp = Instant::now();
-                    // start with a fresh copy of the unmodified image
-                    // FIXME This needs to go, and we need to implement operators for DynamicImage
-                    state.edit_state.result_image_op = img.clone();
-                    for operation in &state.edit_state.image_op_stack {
-                        if !operation.active {
-                            continue;
-                        }
-                        if let Err(e) = operation.operation.process_image(&mut state.edit_state.result_image_op) {
-                            error!("{e}");
-                            state.send_message_warn(&format!("{e}"));
-                        }
-                    }
-                    debug!(
-                        "Image changed. Finished evaluating in {}s",
-                        stamp.elapsed().as_secs_f32()
-                    );
-
-                    // tag strokes as uncommitted as they need to be rendered again
-                    for stroke in &mut state.edit_state.paint_strokes {
-                        stroke.committed = false;
-                    }
-                }
-                pixels_changed = true;
-            }
-
-            if pixels_changed {
-                // init result as a clean copy of image operation result
-                let stamp = Instant::now();
-
-                // start from the result of the image operations
-                state.edit_state.result_pixel_op = state.edit_state.result_image_op.clone();
-
-                // only process pixel stack if it is empty so we don't run through pixels without need
-                if !state.edit_state.pixel_op_stack.is_empty() {
-                    let ops = &state.edit_state.pixel_op_stack.iter().filter(|op|op.active).map(|op| op.operation.clone()).collect();
-                    if let Err(e) = process_pixels(&mut state.edit_state.result_pixel_op, ops) {
-                        state.send_message_warn(&format!("{e}"));
-                    }
-                }
-
-                    debug!(
-                    "Finished Pixel op stack in {} s",
-                    stamp.elapsed().as_secs_f32()
-                );
-
-                // draw paint lines
-                for stroke in &state.edit_state.paint_strokes {
-                    if !stroke.committed {
-                        if let Some(compatible_buffer) = state.edit_state.result_pixel_op.as_mut_rgba8() {
-                            stroke.render(
-                                compatible_buffer,
-                                &state.edit_state.brushes,
-                            );
-                        }
-
-                    }
-                }
-
-                state.send_frame(crate::utils::Frame::UpdateTexture);
-                debug!(
-                    "Done updating tex after pixel; ops in {} s",
-                    stamp.elapsed().as_secs_f32()
-                );
-            }
+            process_edits(state, image_changed, pixels_changed);
 
             // render uncommitted strokes if destructive to speed up painting
             if state.edit_state.painting {
@@ -614,12 +614,12 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
                     for (i, stroke) in state.edit_state.paint_strokes.iter_mut().enumerate() {
                         if i < stroke_count - 1 && !stroke.committed && !stroke.is_empty() {
 
-                            if let Some(compatible_buffer) = state.edit_state.result_pixel_op.as_mut_rgba8() {
-                                stroke.render(
-                                    compatible_buffer,
-                                    &state.edit_state.brushes,
-                                );
-                            }
+                            render_strokes(
+                                &mut state.edit_state.result_pixel_op,
+                                &[&*stroke],
+                                &state.edit_state.paint_layers,
+                                state.brushes.brushes(),
+                            );
 
 
                             stroke.committed = true;
@@ -633,11 +633,212 @@ pub fn edit_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, gfx: &mu
 
 
         });
+
+    // Changes made while a pointer button is held, like dragging a slider or painting, become one history entry
+    state.edit_history.record(&state.edit_state, ctx.input(|i| i.pointer.any_down()));
+}
+
+/// Tool and brush settings for the next stroke
+fn stroke_tool_ui(stroke: &mut PaintStroke, ui: &mut Ui) {
+    egui::Grid::new("paint_tool").num_columns(2).show(ui, |ui| {
+        ui.label("Tool");
+        egui::ComboBox::from_id_salt("paint_tool")
+            .selected_text(stroke.tool.to_string())
+            .show_ui(ui, |ui| {
+                for tool in PaintTool::iter() {
+                    ui.selectable_value(&mut stroke.tool, tool, tool.to_string());
+                }
+            });
+        ui.end_row();
+
+        ui.label("Opacity");
+        ui.styled_slider(&mut stroke.opacity, 0.0..=1.0);
+        ui.end_row();
+
+        ui.label("Hardness");
+        ui.styled_slider(&mut stroke.hardness, 0.0..=1.0);
+        ui.end_row();
+
+        ui.label("Spacing");
+        ui.styled_slider(&mut stroke.spacing, 0.05..=2.0)
+            .on_hover_text("Distance between brush dabs, relative to the brush size");
+        ui.end_row();
+
+        ui.label("Pressure");
+        ui.styled_slider(&mut stroke.pressure, 0.0..=2.0)
+            .on_hover_text("Make the stroke thinner where the pointer moves fast");
+        ui.end_row();
+
+        ui.label("Rotation jitter");
+        ui.styled_slider(&mut stroke.rotation_jitter, 0.0..=1.0)
+            .on_hover_text("Rotate each dab randomly, up to a full turn");
+        ui.end_row();
+
+        ui.label("Scatter");
+        ui.styled_slider(&mut stroke.scatter, 0.0..=2.0)
+            .on_hover_text("Move each dab randomly, relative to the brush size");
+        ui.end_row();
+    });
+}
+
+/// Annotation layers: pick the one to paint on, show, hide, rename, export and delete them.
+/// Returns true if the image needs to be painted again.
+fn paint_layers_ui(state: &mut OculanteState, ui: &mut Ui) -> bool {
+    let mut changed = false;
+    let current_layer = state.edit_state.paint_strokes.last().map(|stroke| stroke.layer).unwrap_or_default();
+    let layer_count = state.edit_state.paint_layers.len();
+    let mut select: Option<usize> = None;
+    let mut delete: Option<usize> = None;
+    let mut export: Option<usize> = None;
+
+    ui.styled_collapsing("Layers", |ui| {
+        egui::Grid::new("paint_layers").num_columns(5).show(ui, |ui| {
+            for (i, layer) in state.edit_state.paint_layers.iter_mut().enumerate() {
+                if ui.radio(current_layer == i, "").on_hover_text("Paint on this layer").clicked() {
+                    select = Some(i);
+                }
+                let icon = if layer.visible { EYE } else { EYEOFF };
+                if ui.button(icon).on_hover_text("Show or hide this layer").clicked() {
+                    layer.visible = !layer.visible;
+                    changed = true;
+                }
+                ui.add(egui::TextEdit::singleline(&mut layer.name).desired_width(100.));
+                if ui.button(FLOPPY_DISK).on_hover_text("Export this layer as a transparent image").clicked() {
+                    export = Some(i);
+                }
+                if ui.add_enabled(layer_count > 1, egui::Button::new(TRASH)).on_hover_text("Delete this layer and its strokes").clicked() {
+                    delete = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if ui.button(format!("{PLUS} Add layer")).clicked() {
+            state.edit_state.paint_layers.push(PaintLayer::new(&format!("Layer {}", layer_count + 1)));
+            select = Some(layer_count);
+        }
+
+        #[cfg(not(feature = "file_open"))]
+        {
+            let export_id = Id::new("PAINT_LAYER_EXPORT");
+            if let Some(layer) = export {
+                ui.ctx().data_mut(|w| w.insert_temp(export_id, layer));
+                ui.ctx().memory_mut(|w| w.open_popup(export_id));
+                export = None;
+            }
+            if ui.ctx().memory(|w| w.is_popup_open(export_id)) {
+                let mut selected: Option<std::path::PathBuf> = None;
+                filebrowser::browse_modal(true, &["png"], &mut state.volatile_settings, |p| selected = Some(p.clone()), ui.ctx());
+                if let (Some(path), Some(layer)) = (selected, ui.ctx().data(|r| r.get_temp::<usize>(export_id))) {
+                    export_paint_layer(state, layer, &path);
+                }
+            }
+        }
+    });
+
+    #[cfg(feature = "file_open")]
+    if let Some(layer) = export {
+        if let Some(path) = rfd::FileDialog::new().add_filter("PNG", &["png"]).save_file() {
+            export_paint_layer(state, layer, &path);
+        }
+    }
+
+    if let Some(layer) = select {
+        if let Some(stroke) = state.edit_state.paint_strokes.last_mut() {
+            stroke.layer = layer;
+        }
+    }
+
+    if let Some(layer) = delete {
+        state.edit_state.paint_layers.remove(layer);
+        state.edit_state.paint_strokes.retain(|stroke| stroke.layer != layer);
+        for stroke in &mut state.edit_state.paint_strokes {
+            if stroke.layer > layer {
+                stroke.layer -= 1;
+            }
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// The brush library: import stamps, store the settings of the current brush, delete imported brushes
+fn brushes_ui(state: &mut OculanteState, ui: &mut Ui, gfx: &mut Graphics) {
+    let Some(stroke) = state.edit_state.paint_strokes.last().filter(|stroke| stroke.is_empty()) else {
+        return;
+    };
+    let Some(brush) = find_brush(state.brushes.brushes(), &stroke.brush).cloned() else {
+        return;
+    };
+    let settings = BrushSettings::from_stroke(stroke);
+    let mut import: Option<std::path::PathBuf> = None;
+    let mut save_defaults = false;
+    let mut delete = false;
+
+    ui.styled_collapsing("Brushes", |ui| {
+        ui.horizontal(|ui| {
+            if let Some(notan_texture) = brush.image.to_texture_premult(gfx) {
+                let texture_id = gfx.egui_register_texture(&notan_texture);
+                ui.add(egui::Image::new(texture_id).fit_to_exact_size(egui::Vec2::splat(64.)));
+            }
+            ui.vertical(|ui| {
+                ui.label(&brush.name);
+                if ui
+                    .add_enabled(brush.settings != settings, egui::Button::new(format!("{FLOPPY_DISK} Save as brush defaults")))
+                    .on_hover_text("Start new strokes with this brush with the current spacing, rotation jitter and scatter")
+                    .clicked()
+                {
+                    save_defaults = true;
+                }
+                if !brush.builtin && ui.button(format!("{TRASH} Delete brush")).clicked() {
+                    delete = true;
+                }
+            });
+        });
+
+        #[cfg(feature = "file_open")]
+        if ui.button(format!("{PLUS} Import brush")).on_hover_text("Add a PNG stamp. Images without transparency are read as grayscale, where dark areas paint.").clicked() {
+            import = rfd::FileDialog::new().add_filter("Images", &["png", "jpg", "jpeg", "bmp", "tif", "tiff", "webp"]).pick_file();
+        }
+        #[cfg(not(feature = "file_open"))]
+        {
+            let import_id = Id::new("BRUSH_IMPORT");
+            if ui.button(format!("{PLUS} Import brush")).on_hover_text("Add a PNG stamp. Images without transparency are read as grayscale, where dark areas paint.").clicked() {
+                ui.ctx().memory_mut(|w| w.open_popup(import_id));
+            }
+            if ui.ctx().memory(|w| w.is_popup_open(import_id)) {
+                filebrowser::browse_modal(false, &["png", "jpg", "jpeg", "bmp", "tif", "tiff", "webp"], &mut state.volatile_settings, |p| import = Some(p.clone()), ui.ctx());
+            }
+        }
+    });
+
+    let result = if save_defaults {
+        state.brushes.set_settings(&brush.name, settings).map(|_| "Saved brush defaults")
+    } else if delete {
+        state.brushes.remove(&brush.name).map(|_| "Brush deleted")
+    } else if let Some(path) = import {
+        state.brushes.import(&path).map(|_| "Brush imported")
+    } else {
+        return;
+    };
+    match result {
+        Ok(msg) => state.send_message_info(msg),
+        Err(e) => state.send_message_err(&format!("{e}")),
+    }
+}
+
+/// Save the strokes of a layer on a transparent image the size of the edited image
+fn export_paint_layer(state: &OculanteState, layer: usize, path: &Path) {
+    let strokes = state.edit_state.paint_strokes.iter().filter(|stroke| stroke.layer == layer).collect::<Vec<_>>();
+    let size = state.edit_state.result_pixel_op.dimensions();
+    match render_layer(size, &strokes, state.brushes.brushes()).save(path.with_extension("png")) {
+        Ok(_) => state.send_message_info("Layer exported"),
+        Err(e) => state.send_message_err(&format!("{e}")),
+    }
 }
 
 fn stroke_ui(
     stroke: &mut PaintStroke,
-    brushes: &[RgbaImage],
+    brushes: &[Brush],
     ui: &mut Ui,
     gfx: &mut Graphics,
 ) -> Response {
@@ -684,7 +885,8 @@ fn stroke_ui(
     }
 
     ui.horizontal(|ui| {
-        if let Some(notan_texture) = brushes[stroke.brush_index].to_texture_premult(gfx) {
+        let current = find_brush(brushes, &stroke.brush);
+        if let Some(notan_texture) = current.and_then(|b| b.image.to_texture_premult(gfx)) {
             let texture_id = gfx.egui_register_texture(&notan_texture);
             ui.add(
                 egui::Image::new(texture_id)
@@ -693,11 +895,11 @@ fn stroke_ui(
         }
 
         let r = egui::ComboBox::from_id_salt(format!("s {:?}", stroke.points))
-            .selected_text(format!("Brush {}", stroke.brush_index))
+            .selected_text(current.map(|b| b.name.clone()).unwrap_or_default())
             .show_ui(ui, |ui| {
-                for (b_i, b) in brushes.iter().enumerate() {
+                for b in brushes {
                     ui.horizontal(|ui| {
-                        if let Some(notan_texture) = b.to_texture_premult(gfx) {
+                        if let Some(notan_texture) = b.image.to_texture_premult(gfx) {
                             let texture_id = gfx.egui_register_texture(&notan_texture);
                             ui.add(
                                 egui::Image::new(texture_id)
@@ -706,9 +908,10 @@ fn stroke_ui(
                         }
 
                         if ui
-                            .selectable_value(&mut stroke.brush_index, b_i, format!("Brush {b_i}"))
+                            .selectable_label(current.is_some_and(|c| c.name == b.name), &b.name)
                             .clicked()
                         {
+                            stroke.brush = b.name.clone();
                             combined_response.mark_changed();
                         }
                     });
@@ -819,6 +1022,9 @@ fn modifier_stack_ui(
                         {
                             *image_changed = true;
                         }
+                        if operation.blend_ui(ui, geo, mouse_grab).changed() {
+                            *image_changed = true;
+                        }
                     });
 
                     ui.style_mut().spacing.icon_spacing = 0.;
@@ -843,6 +1049,228 @@ fn modifier_stack_ui(
     }
 }
 
+/// Lists all recorded edit states, so earlier ones can be restored
+fn history_ui(state: &mut OculanteState, ui: &mut Ui) -> bool {
+    let mut changed = false;
+    ui.styled_collapsing("History", |ui| {
+        ui.horizontal(|ui| {
+            if ui.add_enabled(state.edit_history.can_undo(), egui::Button::new("↩ Undo")).on_hover_text(lookup(&state.persistent_settings.shortcuts, &crate::shortcuts::InputEvent::Undo)).clicked() {
+                changed = state.edit_history.undo(&mut state.edit_state);
+            }
+            if ui.add_enabled(state.edit_history.can_redo(), egui::Button::new("↪ Redo")).on_hover_text(lookup(&state.persistent_settings.shortcuts, &crate::shortcuts::InputEvent::Redo)).clicked() {
+                changed = state.edit_history.redo(&mut state.edit_state);
+            }
+        });
+        let mut jump_to: Option<usize> = None;
+        egui::ScrollArea::vertical().id_salt("history").max_height(150.).show(ui, |ui| {
+            ui.vertical_centered_justified(|ui| {
+                for (i, entry) in state.edit_history.entries().iter().enumerate() {
+                    let current = i == state.edit_history.position();
+                    let mut text = RichText::new(&entry.label);
+                    // States that can be redone
+                    if i > state.edit_history.position() {
+                        text = text.weak();
+                    }
+                    if ui.selectable_label(current, text).clicked() && !current {
+                        jump_to = Some(i);
+                    }
+                }
+            });
+        });
+        if let Some(index) = jump_to {
+            changed = state.edit_history.jump(index, &mut state.edit_state);
+        }
+    });
+    changed
+}
+
+/// Run the edit stacks again and show the result. Geometry changes run both stacks,
+/// pixel changes only the pixel stack.
+fn process_edits(state: &mut OculanteState, image_changed: bool, mut pixels_changed: bool) {
+    if image_changed {
+        if let Some(img) = &mut state.current_image {
+            let stamp = Instant::now();
+            // start with a fresh copy of the unmodified image
+            state.edit_state.result_image_op = img.clone();
+            for operation in &state.edit_state.image_op_stack {
+                if !operation.active {
+                    continue;
+                }
+                if let Err(e) = operation.process_image(&mut state.edit_state.result_image_op, state.brushes.brushes()) {
+                    error!("{e}");
+                    state.send_message_warn(&format!("{e}"));
+                }
+            }
+            debug!(
+                "Image changed. Finished evaluating in {}s",
+                stamp.elapsed().as_secs_f32()
+            );
+
+            // tag strokes as uncommitted as they need to be rendered again
+            for stroke in &mut state.edit_state.paint_strokes {
+                stroke.committed = false;
+            }
+        }
+        pixels_changed = true;
+    }
+
+    if pixels_changed {
+        // init result as a clean copy of image operation result
+        let stamp = Instant::now();
+
+        // start from the result of the image operations
+        state.edit_state.result_pixel_op = state.edit_state.result_image_op.clone();
+
+        // only process pixel stack if it is empty so we don't run through pixels without need
+        if !state.edit_state.pixel_op_stack.is_empty() {
+            if let Err(e) = process_pixel_stack(&mut state.edit_state.result_pixel_op, &state.edit_state.pixel_op_stack, state.brushes.brushes()) {
+                state.send_message_warn(&format!("{e}"));
+            }
+        }
+
+        debug!(
+            "Finished Pixel op stack in {} s",
+            stamp.elapsed().as_secs_f32()
+        );
+
+        // draw paint lines
+        let strokes = state.edit_state.paint_strokes.iter().filter(|stroke| !stroke.committed).collect::<Vec<_>>();
+        render_strokes(
+            &mut state.edit_state.result_pixel_op,
+            &strokes,
+            &state.edit_state.paint_layers,
+            state.brushes.brushes(),
+        );
+
+        state.send_frame(crate::utils::Frame::UpdateTexture);
+        debug!(
+            "Done updating tex after pixel; ops in {} s",
+            stamp.elapsed().as_secs_f32()
+        );
+    }
+}
+
+/// Apply a preset outside of edit mode, as one step of the edit history
+pub fn apply_preset(state: &mut OculanteState, preset: &EditPreset, append: bool) {
+    // the history starts with the edits before the preset
+    state.edit_history.record(&state.edit_state, false);
+    preset.apply(&mut state.edit_state, append);
+    process_edits(state, true, true);
+    state.edit_history.record(&state.edit_state, false);
+    state.send_message_info(&format!("Applied preset {}", preset.name));
+}
+
+/// Apply, save, import and export edit presets. Returns true if the edits changed.
+fn presets_ui(state: &mut OculanteState, ui: &mut Ui) -> bool {
+    let mut changed = false;
+    ui.styled_collapsing("Presets", |ui| {
+        let mut apply: Option<(EditPreset, bool)> = None;
+        let mut remove: Option<EditPreset> = None;
+        egui::Grid::new("presets").num_columns(4).show(ui, |ui| {
+            for preset in state.presets.presets() {
+                ui.label(&preset.name);
+                if ui.button("Apply").on_hover_text("Replace all edits with this preset").clicked() {
+                    apply = Some((preset.clone(), false));
+                }
+                if ui.button(PLUS).on_hover_text("Add this preset to the current edits").clicked() {
+                    apply = Some((preset.clone(), true));
+                }
+                if !preset.builtin && ui.button(TRASH).on_hover_text("Delete preset").clicked() {
+                    remove = Some(preset.clone());
+                }
+                ui.end_row();
+            }
+        });
+        if let Some((preset, append)) = apply {
+            preset.apply(&mut state.edit_state, append);
+            changed = true;
+        }
+        if let Some(preset) = remove {
+            if let Err(e) = state.presets.remove(&preset) {
+                state.send_message_err(&format!("{e}"));
+            }
+        }
+
+        ui.separator();
+        let name_id = Id::new("preset_name");
+        let mut name = ui.data(|r| r.get_temp::<String>(name_id)).unwrap_or_default();
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut name).hint_text("Preset name").desired_width(120.));
+            if ui.button(format!("{FLOPPY_DISK} Save")).on_hover_text("Save the current edits as a preset").clicked() {
+                match state.presets.add(EditPreset::from_edit_state(&name, &state.edit_state)) {
+                    Ok(_) => {
+                        state.send_message_info(&format!("Saved preset {name}"));
+                        name.clear();
+                    }
+                    Err(e) => state.send_message_err(&format!("{e}")),
+                }
+            }
+        });
+        ui.data_mut(|w| w.insert_temp(name_id, name));
+
+        ui.horizontal(|ui| {
+            #[cfg(feature = "file_open")]
+            {
+                if ui.button("Import").on_hover_text("Add a preset file to the library").clicked() {
+                    if let Some(file) = rfd::FileDialog::new().add_filter("Preset", &["json"]).pick_file() {
+                        import_preset(state, &file);
+                    }
+                }
+                if ui.button("Export").on_hover_text("Save the current edits as a preset file").clicked() {
+                    if let Some(file) = rfd::FileDialog::new().add_filter("Preset", &["json"]).save_file() {
+                        export_preset(state, &file);
+                    }
+                }
+            }
+
+            #[cfg(not(feature = "file_open"))]
+            {
+                if ui.button("Import").on_hover_text("Add a preset file to the library").clicked() {
+                    ui.ctx().memory_mut(|w| w.open_popup(Id::new("PRESET_IMPORT")));
+                }
+                if ui.button("Export").on_hover_text("Save the current edits as a preset file").clicked() {
+                    ui.ctx().memory_mut(|w| w.open_popup(Id::new("PRESET_EXPORT")));
+                }
+            }
+        });
+
+        #[cfg(not(feature = "file_open"))]
+        {
+            let mut selected: Option<(std::path::PathBuf, bool)> = None;
+            if ui.ctx().memory(|w| w.is_popup_open(Id::new("PRESET_IMPORT"))) {
+                filebrowser::browse_modal(false, &["json"], &mut state.volatile_settings, |p| selected = Some((p.clone(), false)), ui.ctx());
+            }
+            if ui.ctx().memory(|w| w.is_popup_open(Id::new("PRESET_EXPORT"))) {
+                filebrowser::browse_modal(true, &["json"], &mut state.volatile_settings, |p| selected = Some((p.clone(), true)), ui.ctx());
+            }
+            match selected {
+                Some((path, false)) => import_preset(state, &path),
+                Some((path, true)) => export_preset(state, &path),
+                None => (),
+            }
+        }
+    });
+    changed
+}
+
+fn import_preset(state: &mut OculanteState, path: &Path) {
+    match state.presets.import(path) {
+        Ok(_) => state.send_message_info("Preset imported"),
+        Err(e) => state.send_message_err(&format!("{e}")),
+    }
+}
+
+fn export_preset(state: &OculanteState, path: &Path) {
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match EditPreset::from_edit_state(&name, &state.edit_state).save(&path.with_extension("json")) {
+        Ok(_) => state.send_message_info("Preset exported"),
+        Err(e) => state.send_message_err(&format!("{e}")),
+    }
+}
+
 /// A ui for lossless JPEG editing
 #[cfg(feature = "turbo")]
 fn jpg_lossless_ui(state: &mut OculanteState, ui: &mut Ui) {
@@ -1,11 +1,18 @@
 use crate::appstate::OculanteState;
 use crate::comparelist::CompareItem;
+use crate::dicom::{decode_frame, DicomTag, WindowPreset, Windowing};
 #[cfg(feature = "file_open")]
 use crate::filebrowser::browse_for_image_path;
+use crate::geotag;
+use crate::histogram::{raw_pixel, HistogramChannel, ImageStats, StatsWorker, DEFAULT_BINS};
 use crate::icons::*;
+use crate::metadata::{GpsPosition, Metadata, PrivacyProfile};
+use crate::scopes::{Density, ScopeKind, Scopes};
 use crate::utils::*;
-use egui_plot::{Line, Plot, PlotPoints};
+use egui_plot::{HLine, Line, Plot, PlotImage, PlotPoint, PlotPoints, Points};
 use image::ColorType;
+use log::error;
+use std::sync::Arc;
 
 #[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
 use notan::{
@@ -252,6 +259,8 @@ pub fn info_ui(ctx: &Context, state: &mut OculanteState, _gfx: &mut Graphics) ->
                 });
 
                 palette_ui(ui, state);
+                region_ui(ui, state);
+                inspector_ui(ui, state);
 
                 if state.persistent_settings.experimental_features {
                     measure_ui(ui, state);
@@ -264,6 +273,8 @@ pub fn info_ui(ctx: &Context, state: &mut OculanteState, _gfx: &mut Graphics) ->
                 });
             }
 
+            rating_ui(ui, state);
+            cull_ui(ui, state);
             advanced_ui(ui, state);
 
         });
@@ -311,57 +322,103 @@ fn advanced_ui(ui: &mut Ui, state: &mut OculanteState) {
                 });
             });
         }
+    }
 
-        if let Some(dicom) = &info.dicom {
-            ui.styled_collapsing("DICOM", |ui| {
-                dark_panel(ui, |ui| {
-                    for (key, val) in &dicom.dicom_data {
-                        ui.scope(|ui| {
-                            ui.style_mut().override_font_id =
-                                Some(FontId::new(14., FontFamily::Name("bold".into())));
-                            ui.colored_label(
-                                if ui.style().visuals.dark_mode {
-                                    Color32::from_gray(200)
-                                } else {
-                                    Color32::from_gray(20)
-                                },
-                                key,
-                            );
-                        });
-                        ui.label(val);
-                        ui.separator();
-                    }
-                });
-            });
+    location_ui(ui, state);
+    metadata_ui(ui, state);
+    privacy_ui(ui, state);
+
+    dicom_ui(ui, state);
+
+    histogram_ui(ui, state);
+    scopes_ui(ui, state);
+}
+
+/// Which channels the histogram shows, and how
+#[derive(Clone)]
+struct HistogramView {
+    channels: Vec<HistogramChannel>,
+    log_scale: bool,
+    bins: usize,
+    /// Only count the pixels in the selected region
+    selection: bool,
+}
+
+impl Default for HistogramView {
+    fn default() -> Self {
+        Self {
+            channels: vec![
+                HistogramChannel::Red,
+                HistogramChannel::Green,
+                HistogramChannel::Blue,
+            ],
+            log_scale: false,
+            bins: DEFAULT_BINS,
+            selection: false,
         }
+    }
+}
+
+fn channel_color(channel: HistogramChannel) -> Color32 {
+    match channel {
+        HistogramChannel::Red => Color32::RED,
+        HistogramChannel::Green => Color32::GREEN,
+        HistogramChannel::Blue => Color32::BLUE,
+        HistogramChannel::Luminance => Color32::from_gray(200),
+        HistogramChannel::Alpha => Color32::from_gray(110),
+    }
+}
+
+/// The last rectangle measured, as `[x, y, width, height]`
+fn measured_rect(state: &OculanteState) -> Option<[u32; 4]> {
+    let points = state
+        .edit_state
+        .image_op_stack
+        .iter()
+        .rev()
+        .filter(|op| op.active)
+        .filter_map(|op| match &op.operation {
+            ImageOperation::Measure { shapes } => Some(shapes),
+            _ => None,
+        })
+        .flat_map(|shapes| shapes.iter().rev())
+        .find_map(|shape| match shape {
+            MeasureShape::Rect { points, .. } if !shape.is_placeholder() => Some(points),
+            _ => None,
+        })?;
+    let (a, b) = (points.first()?, points.get(1)?);
+    Some([
+        a.0.min(b.0),
+        a.1.min(b.1),
+        a.0.abs_diff(b.0) + 1,
+        a.1.abs_diff(b.1) + 1,
+    ])
+}
 
-        let red_vals = Line::new(
-            info.red_histogram
-                .iter()
-                .map(|(k, v)| [*k as f64, *v as f64])
-                .collect::<PlotPoints>(),
-        )
-        .fill(0.)
-        .color(Color32::RED);
-
-        let green_vals = Line::new(
-            info.green_histogram
-                .iter()
-                .map(|(k, v)| [*k as f64, *v as f64])
-                .collect::<PlotPoints>(),
-        )
-        .fill(0.)
-        .color(Color32::GREEN);
-
-        let blue_vals = Line::new(
-            info.blue_histogram
-                .iter()
-                .map(|(k, v)| [*k as f64, *v as f64])
-                .collect::<PlotPoints>(),
-        )
-        .fill(0.)
-        .color(Color32::BLUE);
+/// Histograms of the chosen channels, and statistics of all channels
+/// for the whole image or the measured rectangle
+fn histogram_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let (Some(info), Some(img)) = (&state.image_metadata, &state.current_image) else {
+        return;
+    };
+    let view_id = Id::new("histogram_view");
+    let mut view = ui
+        .data(|r| r.get_temp::<HistogramView>(view_id))
+        .unwrap_or_default();
+    let selection = state.region.or_else(|| measured_rect(state));
+    let region = selection.filter(|_| view.selection);
+    // 8 bit images always get a bin per value
+    let adjustable = info.stats.float || info.stats.range.1 > 256.;
+    let bins = if adjustable { view.bins } else { DEFAULT_BINS };
 
+    // The whole image at the default bins comes with the extended info
+    let stats = if region.is_none() && bins == DEFAULT_BINS {
+        Some(&info.stats)
+    } else {
+        worker_stats(ui, &mut state.histogram_stats, img, info, region, bins)
+    };
+
+    if let Some(stats) = stats {
         Plot::new("histogram")
             .allow_zoom(false)
             .allow_drag(false)
@@ -369,9 +426,1044 @@ fn advanced_ui(ui: &mut Ui, state: &mut OculanteState) {
             .show_grid(false)
             .width(PANEL_WIDTH - PANEL_WIDGET_OFFSET)
             .show(ui, |plot_ui| {
-                plot_ui.line(red_vals);
-                plot_ui.line(green_vals);
-                plot_ui.line(blue_vals);
+                for channel in HistogramChannel::iter().filter(|c| view.channels.contains(c)) {
+                    let Some(channel_stats) = stats.channel(channel) else {
+                        continue;
+                    };
+                    let points = channel_stats
+                        .histogram
+                        .iter()
+                        .enumerate()
+                        .map(|(bin, count)| {
+                            let count = *count as f64;
+                            [
+                                stats.bin_value(bin),
+                                if view.log_scale { count.ln_1p() } else { count },
+                            ]
+                        })
+                        .collect::<PlotPoints>();
+                    plot_ui.line(Line::new(points).fill(0.).color(channel_color(channel)));
+                }
             });
+    } else {
+        ui.label("Computing histogram...");
+    }
+
+    ui.horizontal_wrapped(|ui| {
+        for channel in HistogramChannel::iter() {
+            let mut shown = view.channels.contains(&channel);
+            if ui
+                .styled_checkbox(&mut shown, channel.to_string())
+                .changed()
+            {
+                if shown {
+                    view.channels.push(channel);
+                } else {
+                    view.channels.retain(|c| *c != channel);
+                }
+            }
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.styled_checkbox(&mut view.log_scale, "Log scale");
+        if selection.is_some() {
+            ui.styled_checkbox(&mut view.selection, "Selection")
+                .on_hover_text("Only count pixels in the selected region or measured rectangle");
+        }
+    });
+    if adjustable {
+        ui.horizontal(|ui| {
+            ui.label("Bins");
+            ui.style_mut().spacing.slider_width = ui.available_width() - 16.;
+            ui.styled_slider(&mut view.bins, 16..=4096);
+        });
+    }
+
+    if let Some(stats) = stats {
+        if region.is_some() {
+            ui.label(format!("{} pixels selected", stats.pixels));
+        }
+        stats_grid(ui, "histogram_stats", stats);
+    }
+
+    ui.data_mut(|w| w.insert_temp(view_id, view));
+}
+
+/// Statistics other than those of the whole image, from the panel's worker. The previous
+/// statistics are shown until the new ones are done.
+fn worker_stats<'a>(
+    ui: &Ui,
+    worker: &'a mut StatsWorker,
+    img: &DynamicImage,
+    info: &ExtendedImageInfo,
+    region: Option<[u32; 4]>,
+    bins: usize,
+) -> Option<&'a ImageStats> {
+    let image_key = Id::new((&info.name, info.num_pixels, info.num_colors)).value();
+    let (stats, done) = worker.stats(img, image_key, region, bins);
+    if !done {
+        ui.ctx().request_repaint_after(Duration::from_millis(100));
+    }
+    stats
+}
+
+/// Min, max, mean, median and standard deviation of every channel
+fn stats_grid(ui: &mut Ui, id_salt: &str, stats: &ImageStats) {
+    egui::Grid::new(id_salt).num_columns(6).show(ui, |ui| {
+        for header in ["", "Min", "Max", "Mean", "Median", "Std dev"] {
+            ui.label(header);
+        }
+        ui.end_row();
+        for channel in HistogramChannel::iter() {
+            let Some(c) = stats.channel(channel) else {
+                continue;
+            };
+            ui.label(channel.to_string());
+            // Integer images show whole values, except for averages
+            for (value, average) in [
+                (c.min, false),
+                (c.max, false),
+                (c.mean, true),
+                (c.median, false),
+                (c.std_dev, true),
+            ] {
+                let decimals = match (stats.float, average) {
+                    (true, _) => 4,
+                    (false, true) => 1,
+                    (false, false) => 0,
+                };
+                ui.label_right(format!("{value:.decimals$}"));
+            }
+            ui.end_row();
+        }
+    });
+}
+
+/// Densities side by side, brighter where more pixels fall. Brightness follows the log
+/// of the count so sparse traces stay visible.
+fn density_image(densities: &[(&Density, Color32)]) -> ColorImage {
+    let (width, height) = densities
+        .first()
+        .map(|(d, _)| (d.width, d.height))
+        .unwrap_or_default();
+    let mut image = ColorImage::new([width * densities.len(), height], Color32::TRANSPARENT);
+    for (i, (density, color)) in densities.iter().enumerate() {
+        let max = (density.max() as f32).ln_1p().max(1.);
+        for (cell, count) in density.counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let (x, y) = (cell % width + i * width, cell / width);
+            image[(x, y)] = color.linear_multiply((*count as f32).ln_1p() / max);
+        }
+    }
+    image
+}
+
+/// Waveform, RGB parade or vectorscope of the displayed image, including edits
+fn scopes_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let kind_id = Id::new("scope_kind");
+    let requested_id = Id::new("scopes_requested");
+    let texture_id = Id::new("scope_texture");
+    let mut kind = ui
+        .data(|r| r.get_temp::<ScopeKind>(kind_id))
+        .unwrap_or_default();
+
+    ui.horizontal(|ui| {
+        for k in ScopeKind::iter() {
+            ui.selectable_value(&mut kind, k, k.to_string());
+        }
+    });
+    ui.data_mut(|w| w.insert_temp(kind_id, kind));
+
+    // Scopes are only computed while the info panel is open, so ask for them once it opens
+    let Some(scopes) = state.scopes.clone() else {
+        if !ui
+            .data(|r| r.get_temp::<bool>(requested_id))
+            .unwrap_or_default()
+        {
+            if state.edit_state.result_pixel_op.width() > 0 {
+                state
+                    .scopes_worker
+                    .request(&state.edit_state.result_pixel_op);
+            } else if let Some(img) = &state.current_image {
+                state.scopes_worker.request(img);
+            }
+            ui.data_mut(|w| w.insert_temp(requested_id, true));
+        }
+        ui.label("Computing scopes...");
+        return;
+    };
+    ui.data_mut(|w| w.remove_temp::<bool>(requested_id));
+
+    let cached = ui.data(|r| r.get_temp::<(ScopeKind, Arc<Scopes>, TextureHandle)>(texture_id));
+    let texture = match cached {
+        Some((k, s, texture)) if k == kind && Arc::ptr_eq(&s, &scopes) => texture,
+        _ => {
+            let gray = Color32::from_gray(230);
+            let image = match kind {
+                ScopeKind::Waveform => density_image(&[(&scopes.waveform, gray)]),
+                ScopeKind::Parade => density_image(&[
+                    (&scopes.parade[0], Color32::RED),
+                    (&scopes.parade[1], Color32::GREEN),
+                    (&scopes.parade[2], Color32::from_rgb(60, 110, 255)),
+                ]),
+                ScopeKind::Vectorscope => density_image(&[(&scopes.vectorscope, gray)]),
+            };
+            let texture = ui
+                .ctx()
+                .load_texture("scope", image, TextureOptions::LINEAR);
+            ui.data_mut(|w| w.insert_temp(texture_id, (kind, scopes.clone(), texture.clone())));
+            texture
+        }
+    };
+
+    let width = PANEL_WIDTH - PANEL_WIDGET_OFFSET;
+    let graticule = Color32::from_white_alpha(40);
+    let plot = Plot::new("scopes")
+        .allow_zoom(false)
+        .allow_drag(false)
+        .allow_scroll(false)
+        .allow_boxed_zoom(false)
+        .show_axes(false)
+        .show_grid(false)
+        .show_x(false)
+        .show_y(false)
+        .width(width);
+
+    match kind {
+        ScopeKind::Waveform | ScopeKind::Parade => {
+            // One unit per channel wide, 0 to 100 percent high
+            let channels = if kind == ScopeKind::Parade { 3. } else { 1. };
+            plot.height(width * 0.6)
+                .include_x(0.)
+                .include_x(channels)
+                .include_y(0.)
+                .include_y(100.)
+                .show(ui, |plot_ui| {
+                    plot_ui.image(PlotImage::new(
+                        &texture,
+                        PlotPoint::new(channels / 2., 50.),
+                        vec2(channels as f32, 100.),
+                    ));
+                    for level in [0., 25., 50., 75., 100.] {
+                        plot_ui.hline(HLine::new(level).color(graticule));
+                    }
+                });
+        }
+        ScopeKind::Vectorscope => {
+            // Where 75% color bars land, and the skin tone line
+            let targets = [
+                (1., 0., 0., Color32::RED),
+                (1., 1., 0., Color32::YELLOW),
+                (0., 1., 0., Color32::GREEN),
+                (0., 1., 1., Color32::LIGHT_BLUE),
+                (0., 0., 1., Color32::BLUE),
+                (1., 0., 1., Color32::from_rgb(255, 0, 255)),
+            ]
+            .map(|(r, g, b, color): (f64, f64, f64, Color32)| {
+                let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+                let point = [0.75 * (b - luma) / 1.8556, 0.75 * (r - luma) / 1.5748];
+                Points::new(vec![point]).radius(3.).color(color)
+            });
+            let skin = 123f64.to_radians();
+            plot.height(width)
+                .data_aspect(1.)
+                .include_x(-0.5)
+                .include_x(0.5)
+                .include_y(-0.5)
+                .include_y(0.5)
+                .show(ui, |plot_ui| {
+                    plot_ui.image(PlotImage::new(
+                        &texture,
+                        PlotPoint::new(0., 0.),
+                        vec2(1., 1.),
+                    ));
+                    plot_ui.line(
+                        Line::new(PlotPoints::new(vec![[-0.5, 0.], [0.5, 0.]])).color(graticule),
+                    );
+                    plot_ui.line(
+                        Line::new(PlotPoints::new(vec![[0., -0.5], [0., 0.5]])).color(graticule),
+                    );
+                    plot_ui.line(
+                        Line::new(PlotPoints::new(vec![
+                            [0., 0.],
+                            [0.5 * skin.cos(), 0.5 * skin.sin()],
+                        ]))
+                        .color(Color32::from_rgb(230, 170, 120)),
+                    );
+                    for target in targets {
+                        plot_ui.points(target);
+                    }
+                });
+        }
+    }
+}
+
+/// Select a region by dragging with shift and the right mouse button and show its statistics
+fn region_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let selecting_id = Id::new("region_selecting");
+    let anchor_id = Id::new("region_anchor");
+    let mut selecting = ui
+        .data(|r| r.get_temp::<bool>(selecting_id))
+        .unwrap_or_default();
+    let cursor = (
+        state.cursor_relative.x as u32,
+        state.cursor_relative.y as u32,
+    );
+    let active = selecting && region_modifier(ui.ctx()) && !state.pointer_over_ui;
+    let dragging = active && ui.input(|r| r.pointer.secondary_down());
+
+    if active {
+        if ui.input(|r| r.pointer.secondary_pressed()) {
+            ui.data_mut(|w| w.insert_temp(anchor_id, cursor));
+        }
+        if dragging {
+            if let Some(anchor) = ui.data(|r| r.get_temp::<(u32, u32)>(anchor_id)) {
+                state.region = Some([
+                    anchor.0.min(cursor.0),
+                    anchor.1.min(cursor.1),
+                    anchor.0.abs_diff(cursor.0) + 1,
+                    anchor.1.abs_diff(cursor.1) + 1,
+                ]);
+            }
+        }
+    }
+
+    if let Some([x, y, width, height]) = state.region {
+        let geo = &state.image_geometry;
+        let to_screen = |x: u32, y: u32| {
+            Pos2::new(
+                geo.scale * x as f32 + geo.offset.x,
+                geo.scale * y as f32 + geo.offset.y,
+            )
+        };
+        ui.ctx()
+            .layer_painter(LayerId::new(Order::Foreground, selecting_id))
+            .rect_stroke(
+                Rect::from_min_max(to_screen(x, y), to_screen(x + width, y + height)),
+                0.,
+                Stroke::new(1.5, Color32::YELLOW),
+                StrokeKind::Outside,
+            );
+    }
+
+    ui.styled_collapsing("Region", |ui| {
+        ui.vertical_centered_justified(|ui| {
+            dark_panel(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.styled_checkbox(&mut selecting, "Select").on_hover_text(
+                        "Hold shift and drag with the right mouse button on the image",
+                    );
+                    if state.region.is_some() && ui.button(format!("{X} Clear")).clicked() {
+                        state.region = None;
+                    }
+                });
+                let (Some(region), Some(info), Some(img)) =
+                    (state.region, &state.image_metadata, &state.current_image)
+                else {
+                    ui.label("No region selected");
+                    return;
+                };
+                ui.label(format!(
+                    "{}x{} at {},{}",
+                    region[2], region[3], region[0], region[1]
+                ));
+                // Don't recompute while the region changes
+                if dragging {
+                    return;
+                }
+                let Some(stats) = worker_stats(
+                    ui,
+                    &mut state.region_stats,
+                    img,
+                    info,
+                    Some(region),
+                    DEFAULT_BINS,
+                ) else {
+                    ui.label("Computing statistics...");
+                    return;
+                };
+                ui.label(format!("{} pixels", stats.pixels));
+                stats_grid(ui, "region_stats", stats);
+            });
+        });
+    });
+
+    ui.data_mut(|w| w.insert_temp(selecting_id, selecting));
+}
+
+/// Sample names of a color type
+fn sample_names(color: ColorType) -> &'static [&'static str] {
+    match color.channel_count() {
+        1 => &["L"],
+        2 => &["L", "A"],
+        3 => &["R", "G", "B"],
+        _ => &["R", "G", "B", "A"],
+    }
+}
+
+/// The stored values of one channel around the cursor
+fn inspector_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let Some(img) = &state.current_image else {
+        return;
+    };
+    // prefer edit result if present
+    let img = if state.edit_state.result_pixel_op.width() > 0 {
+        &state.edit_state.result_pixel_op
+    } else {
+        img
+    };
+    let id = Id::new("pixel_inspector");
+    let (mut size, mut sample) = ui
+        .data(|r| r.get_temp::<(u32, usize)>(id))
+        .unwrap_or((5, 0));
+    let names = sample_names(img.color());
+    sample = sample.min(names.len() - 1);
+    let float = img.as_flat_samples_f32().is_some();
+
+    ui.styled_collapsing("Pixel inspector", |ui| {
+        ui.vertical_centered_justified(|ui| {
+            dark_panel(ui, |ui| {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("inspector_size")
+                        .selected_text(format!("{size}x{size}"))
+                        .width(60.)
+                        .show_ui(ui, |ui| {
+                            for s in [3, 5, 7] {
+                                ui.selectable_value(&mut size, s, format!("{s}x{s}"));
+                            }
+                        });
+                    egui::ComboBox::from_id_salt("inspector_sample")
+                        .selected_text(names[sample])
+                        .width(60.)
+                        .show_ui(ui, |ui| {
+                            for (i, name) in names.iter().enumerate() {
+                                ui.selectable_value(&mut sample, i, *name);
+                            }
+                        });
+                });
+
+                let (cx, cy) = (
+                    state.cursor_relative.x as i64,
+                    state.cursor_relative.y as i64,
+                );
+                let radius = size as i64 / 2;
+                egui::Grid::new("inspector")
+                    .num_columns(size as usize)
+                    .spacing(vec2(4., 2.))
+                    .show(ui, |ui| {
+                        for y in cy - radius..=cy + radius {
+                            for x in cx - radius..=cx + radius {
+                                let values = u32::try_from(x)
+                                    .ok()
+                                    .zip(u32::try_from(y).ok())
+                                    .and_then(|(x, y)| raw_pixel(img, x, y));
+                                let Some(values) = values else {
+                                    ui.label("-");
+                                    continue;
+                                };
+                                let value = values[sample];
+                                let mut text = RichText::new(if float {
+                                    format!("{value:.3}")
+                                } else {
+                                    format!("{value}")
+                                })
+                                .monospace()
+                                .size(10.);
+                                if (x, y) == (cx, cy) {
+                                    text = text.color(ui.style().visuals.selection.bg_fill);
+                                }
+                                // The full pixel at full precision
+                                let all = names
+                                    .iter()
+                                    .zip(&values)
+                                    .map(|(name, v)| format!("{name} {v}"))
+                                    .collect::<Vec<_>>()
+                                    .join("  ");
+                                ui.label(text).on_hover_text(format!("{x},{y}: {all}"));
+                            }
+                            ui.end_row();
+                        }
+                    });
+            });
+        });
+    });
+
+    ui.data_mut(|w| w.insert_temp(id, (size, sample)));
+}
+
+/// The frame and window shown for a DICOM file
+#[derive(Clone, Default)]
+struct DicomView {
+    path: PathBuf,
+    frame: u32,
+    /// Kept when moving through a series
+    windowing: Windowing,
+    /// The frame and window on screen
+    shown: (u32, Windowing),
+    search: String,
+}
+
+fn windowing_name(windowing: Windowing, presets: &[WindowPreset]) -> String {
+    match windowing {
+        Windowing::File => "File".into(),
+        Windowing::Custom { center, width } => presets
+            .iter()
+            .find(|p| p.center == center && p.width == width)
+            .map(|p| p.name.clone())
+            .unwrap_or_else(|| "Custom".into()),
+        Windowing::MinMax => "Min-max".into(),
+        Windowing::Raw => "Raw values".into(),
+    }
+}
+
+fn dicom_tags_ui(ui: &mut Ui, tags: &[DicomTag], query: &str, id: Id) {
+    for (i, tag) in tags.iter().enumerate().filter(|(_, t)| t.matches(query)) {
+        if tag.items.is_empty() {
+            ui.horizontal_wrapped(|ui| {
+                ui.label(RichText::new(&tag.tag).monospace().weak());
+                ui.label(RichText::new(&tag.name).strong())
+                    .on_hover_text(&tag.vr);
+                ui.label(&tag.value);
+            });
+            continue;
+        }
+        // A matching sequence shows all of its items, otherwise only matching ones
+        let nested_query = if tag.items.iter().flatten().any(|t| t.matches(query)) {
+            query
+        } else {
+            ""
+        };
+        egui::CollapsingHeader::new(format!("{} {}", tag.tag, tag.name))
+            .id_salt(id.with(i))
+            .open((!query.is_empty()).then_some(true))
+            .show(ui, |ui| {
+                for (n, item) in tag.items.iter().enumerate() {
+                    ui.label(RichText::new(format!("Item {}", n + 1)).weak());
+                    dicom_tags_ui(ui, item, nested_query, id.with(i).with(n));
+                }
+            });
+    }
+}
+
+fn dicom_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let Some(path) = state.current_path.clone() else {
+        return;
+    };
+    // The info of the previous file stays around until the new one is loaded
+    let Some(dicom) = state
+        .image_metadata
+        .as_ref()
+        .filter(|info| state.is_loaded && Path::new(&info.name) == path)
+        .and_then(|info| info.dicom.as_ref())
+    else {
+        return;
+    };
+    let view_id = Id::new("dicom_view");
+    let mut view = ui
+        .data(|r| r.get_temp::<DicomView>(view_id))
+        .unwrap_or_default();
+    if view.path != path {
+        // A freshly loaded file shows its first frame with the window from the file
+        view.path = path.clone();
+        view.frame = 0;
+        view.shown = (0, Windowing::File);
+    }
+    // Wait for sliders to be released before decoding again
+    let mut settled = true;
+    let mut load = None;
+
+    ui.styled_collapsing("DICOM", |ui| {
+        ui.vertical_centered_justified(|ui| {
+            dark_panel(ui, |ui| {
+                let (mut center, mut width) = match view.windowing {
+                    Windowing::Custom { center, width } => (center, width),
+                    _ => dicom
+                        .windows
+                        .first()
+                        .map(|w| (w.center, w.width))
+                        .unwrap_or((0., 0.)),
+                };
+                ui.horizontal(|ui| {
+                    ui.label("Window");
+                    egui::ComboBox::from_id_salt("dicom_windowing")
+                        .selected_text(windowing_name(view.windowing, &dicom.windows))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut view.windowing, Windowing::File, "File");
+                            for preset in &dicom.windows {
+                                ui.selectable_value(
+                                    &mut view.windowing,
+                                    Windowing::Custom {
+                                        center: preset.center,
+                                        width: preset.width,
+                                    },
+                                    &preset.name,
+                                );
+                            }
+                            ui.selectable_value(&mut view.windowing, Windowing::MinMax, "Min-max")
+                                .on_hover_text("Stretch the lowest to the highest value");
+                            ui.selectable_value(&mut view.windowing, Windowing::Raw, "Raw values")
+                                .on_hover_text("Keep the stored values, without any window");
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Center");
+                    let c = ui.add(egui::DragValue::new(&mut center).speed(1.));
+                    ui.label("Width");
+                    let w = ui.add(
+                        egui::DragValue::new(&mut width)
+                            .speed(1.)
+                            .range(1.0..=f64::MAX),
+                    );
+                    if c.changed() || w.changed() {
+                        view.windowing = Windowing::Custom { center, width };
+                    }
+                    settled &= !c.dragged() && !w.dragged();
+                });
+
+                if dicom.frames > 1 {
+                    ui.horizontal(|ui| {
+                        ui.label("Frame");
+                        let mut frame = view.frame + 1;
+                        let r = ui.styled_slider(&mut frame, 1..=dicom.frames);
+                        view.frame = frame - 1;
+                        settled &= !r.dragged();
+                    });
+                }
+
+                if let Some(position) = dicom.series.iter().position(|p| p == &path) {
+                    if dicom.series.len() > 1 {
+                        ui.horizontal(|ui| {
+                            ui.label("Series");
+                            if ui
+                                .add_enabled(position > 0, egui::Button::new("Previous"))
+                                .clicked()
+                            {
+                                load = dicom.series.get(position - 1).cloned();
+                            }
+                            ui.label(format!("{} of {}", position + 1, dicom.series.len()));
+                            if ui
+                                .add_enabled(
+                                    position + 1 < dicom.series.len(),
+                                    egui::Button::new("Next"),
+                                )
+                                .clicked()
+                            {
+                                load = dicom.series.get(position + 1).cloned();
+                            }
+                        });
+                    }
+                }
+            });
+
+            ui.add(egui::TextEdit::singleline(&mut view.search).hint_text("Search tags"));
+            let query = view.search.to_lowercase();
+            egui::ScrollArea::vertical()
+                .id_salt("dicom_tags")
+                .max_height(300.)
+                .show(ui, |ui| {
+                    ui.with_layout(egui::Layout::top_down(Align::LEFT), |ui| {
+                        dicom_tags_ui(ui, &dicom.tags, &query, Id::new("dicom_tags"));
+                    });
+                });
+        });
+    });
+
+    if settled && (view.frame, view.windowing) != view.shown {
+        view.shown = (view.frame, view.windowing);
+        let (frame, windowing) = view.shown;
+        let sender = state.texture_channel.0.clone();
+        let messages = state.message_channel.0.clone();
+        let path = path.clone();
+        std::thread::spawn(move || match decode_frame(&path, frame, windowing) {
+            Ok(img) => {
+                _ = sender.send(Frame::EditResult(img));
+            }
+            Err(e) => {
+                _ = messages.send(crate::appstate::Message::err(&format!(
+                    "Can't decode frame {}: {e}",
+                    frame + 1
+                )))
+            }
+        });
+    }
+    ui.data_mut(|w| w.insert_temp(view_id, view));
+
+    if let Some(next) = load {
+        if let Some(index) = state.scrubber.entries.iter().position(|p| p == &next) {
+            state.scrubber.set(index);
+        }
+        load_image_from_path(&next, state);
+    }
+}
+
+/// Show where the image was taken, and export the locations of all images in the folder
+fn location_ui(ui: &mut Ui, state: &mut OculanteState) {
+    if state.current_path.is_none() {
+        return;
+    }
+    let geotag = state
+        .image_metadata
+        .as_ref()
+        .and_then(|info| info.geotag.clone());
+    let mut export: Option<PathBuf> = None;
+
+    ui.styled_collapsing("Location", |ui| {
+        if let Some(tag) = &geotag {
+            egui::Grid::new("location").num_columns(2).show(ui, |ui| {
+                ui.label("Coordinates");
+                ui.label_right(tag.coordinates());
+                ui.end_row();
+                if let Some(altitude) = tag.position.altitude {
+                    ui.label("Altitude");
+                    ui.label_right(format!("{altitude:.1} m"));
+                    ui.end_row();
+                }
+                if let Some(heading) = tag.heading {
+                    ui.label("Heading");
+                    ui.label_right(format!("{heading:.1}°"));
+                    ui.end_row();
+                }
+                if let Some(time) = &tag.time {
+                    ui.label("Time");
+                    ui.label_right(time);
+                    ui.end_row();
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button(format!("{COPY} Copy")).clicked() {
+                    ui.ctx().copy_text(tag.coordinates());
+                }
+                if ui
+                    .button(format!("{LOCATION_PIN} Open map"))
+                    .on_hover_text(tag.map_url())
+                    .clicked()
+                {
+                    _ = webbrowser::open(&tag.map_url());
+                }
+            });
+        } else {
+            ui.label("This image has no location.");
+        }
+
+        let hover = "Save the locations of all images in this folder as GPX or GeoJSON";
+        #[cfg(feature = "file_open")]
+        if ui
+            .button(format!("{FLOPPY_DISK} Export folder locations"))
+            .on_hover_text(hover)
+            .clicked()
+        {
+            export = rfd::FileDialog::new()
+                .add_filter("GPX", &["gpx"])
+                .add_filter("GeoJSON", &["geojson"])
+                .save_file();
+        }
+        #[cfg(not(feature = "file_open"))]
+        {
+            let export_id = Id::new("LOCATIONS_EXPORT");
+            if ui
+                .button(format!("{FLOPPY_DISK} Export folder locations"))
+                .on_hover_text(hover)
+                .clicked()
+            {
+                ui.ctx().memory_mut(|w| w.open_popup(export_id));
+            }
+            if ui.ctx().memory(|w| w.is_popup_open(export_id)) {
+                filebrowser::browse_modal(
+                    true,
+                    &["gpx", "geojson"],
+                    &mut state.volatile_settings,
+                    |p| export = Some(p.clone()),
+                    ui.ctx(),
+                );
+            }
+        }
+    });
+
+    if let Some(path) = export {
+        let files = state.scrubber.entries.clone();
+        let sender = state.message_channel.0.clone();
+        std::thread::spawn(move || {
+            let tags = geotag::read_all(&files);
+            let message = match geotag::export(&path, &tags) {
+                Ok(_) => crate::appstate::Message::info(&format!(
+                    "Exported {} of {} images with a location",
+                    tags.len(),
+                    files.len()
+                )),
+                Err(e) => crate::appstate::Message::err(&format!("Can't export locations: {e}")),
+            };
+            _ = sender.send(message);
+        });
+    }
+}
+
+/// Human-readable EXIF orientations
+const ORIENTATIONS: [&str; 8] = [
+    "Normal",
+    "Mirrored horizontally",
+    "Rotated 180°",
+    "Mirrored vertically",
+    "Mirrored, rotated 90° CCW",
+    "Rotated 90° CW",
+    "Mirrored, rotated 90° CW",
+    "Rotated 90° CCW",
+];
+
+/// Edit title, description, copyright, keywords, orientation, date and location,
+/// and write them back to the file
+fn metadata_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let (Some(path), Some(original)) = (
+        state.current_path.clone(),
+        state
+            .image_metadata
+            .as_ref()
+            .and_then(|info| info.metadata.clone()),
+    ) else {
+        return;
+    };
+    // The metadata edits started from, the edited metadata, and keywords as they are typed.
+    // Edits are dropped when the file changes.
+    let id = Id::new("metadata_edit").with(&path);
+    let (mut metadata, mut keywords) = ui
+        .data(|r| r.get_temp::<(Metadata, Metadata, String)>(id))
+        .filter(|(start, _, _)| *start == original)
+        .map(|(_, metadata, keywords)| (metadata, keywords))
+        .unwrap_or_else(|| (original.clone(), original.keywords.join(", ")));
+    let mut save = false;
+
+    ui.styled_collapsing("Edit metadata", |ui| {
+        egui::Grid::new("metadata_edit")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Title");
+                ui.text_edit_singleline(&mut metadata.title);
+                ui.end_row();
+
+                ui.label("Description");
+                ui.text_edit_multiline(&mut metadata.description);
+                ui.end_row();
+
+                ui.label("Copyright");
+                ui.text_edit_singleline(&mut metadata.copyright);
+                ui.end_row();
+
+                ui.label("Keywords");
+                if ui
+                    .add(TextEdit::singleline(&mut keywords).hint_text("Comma separated"))
+                    .changed()
+                {
+                    metadata.keywords = keywords
+                        .split(',')
+                        .map(|k| k.trim().to_string())
+                        .filter(|k| !k.is_empty())
+                        .collect();
+                }
+                ui.end_row();
+
+                ui.label("Orientation");
+                let orientation_name = |o: Option<u16>| {
+                    o.and_then(|o| ORIENTATIONS.get((o as usize).checked_sub(1)?))
+                        .copied()
+                        .unwrap_or("Not set")
+                };
+                egui::ComboBox::from_id_salt("metadata_orientation")
+                    .selected_text(orientation_name(metadata.orientation))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut metadata.orientation, None, "Not set");
+                        for o in 1..=ORIENTATIONS.len() as u16 {
+                            ui.selectable_value(
+                                &mut metadata.orientation,
+                                Some(o),
+                                orientation_name(Some(o)),
+                            );
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Date taken");
+                ui.add(
+                    TextEdit::singleline(&mut metadata.date_time_original)
+                        .hint_text("YYYY:MM:DD HH:MM:SS"),
+                );
+                ui.end_row();
+
+                let mut has_gps = metadata.gps.is_some();
+                if ui.styled_checkbox(&mut has_gps, "Location").changed() {
+                    metadata.gps = has_gps.then(|| original.gps.unwrap_or_default());
+                }
+                ui.end_row();
+                if let Some(gps) = &mut metadata.gps {
+                    gps_ui(ui, gps);
+                }
+            });
+
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(metadata != original, egui::Button::new("Save"))
+                .on_hover_text("Write metadata to the file. Image data is kept as it is.")
+                .clicked()
+            {
+                save = true;
+            }
+            if ui
+                .add_enabled(metadata != original, egui::Button::new("Revert"))
+                .clicked()
+            {
+                keywords = original.keywords.join(", ");
+                metadata = original.clone();
+            }
+            if ui.button("Clear all").clicked() {
+                keywords.clear();
+                metadata = Metadata::default();
+            }
+        });
+    });
+
+    if !save {
+        ui.data_mut(|w| w.insert_temp(id, (original, metadata, keywords)));
+        return;
+    }
+    match metadata.write(&path) {
+        Ok(_) => {
+            state.send_message_info("Metadata saved");
+            ui.data_mut(|w| w.remove::<(Metadata, Metadata, String)>(id));
+            state.player.cache.clear();
+            if metadata.orientation != original.orientation {
+                // orientation is applied when loading
+                state.player.load(&path);
+            } else {
+                send_extended_info(
+                    &state.current_image,
+                    &state.current_path,
+                    &state.extended_info_channel,
+                );
+            }
+        }
+        Err(e) => {
+            ui.data_mut(|w| w.insert_temp(id, (original, metadata, keywords)));
+            state.send_message_err(&format!("Can't save metadata: {e}"));
+        }
+    }
+}
+
+/// Strip metadata from files in place before publishing them
+fn privacy_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let Some(path) = state.current_path.clone() else {
+        return;
+    };
+    ui.styled_collapsing("Privacy", |ui| {
+        state.volatile_settings.privacy.ui(ui);
+        let privacy = state.volatile_settings.privacy.clone();
+        // nothing would be removed
+        let keeps_all = privacy.profile == PrivacyProfile::KeepAll;
+        let keeps_all_text = "\"Keep all\" leaves the metadata as it is";
+        let strippable = |p: &Path| {
+            p.extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .is_some_and(|e| ["jpg", "jpeg", "png", "webp"].contains(&e.as_str()))
+        };
+        let files = state
+            .scrubber
+            .entries
+            .iter()
+            .filter(|p| strippable(p))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let sender = state.message_channel.0.clone();
+        let count = files.len();
+        let modal = show_modal(
+            ui.ctx(),
+            format!(
+                "Strip metadata from {count} images in this folder? The files are changed in place."
+            ),
+            |_| {
+                std::thread::spawn(move || {
+                    let stripped = files
+                        .iter()
+                        .filter(|p| {
+                            privacy
+                                .strip_file(p)
+                                .map_err(|e| error!("Can't strip {}: {e}", p.display()))
+                                .is_ok()
+                        })
+                        .count();
+                    _ = sender.send(crate::appstate::Message::info(&format!(
+                        "Stripped metadata from {stripped} of {count} images"
+                    )));
+                });
+            },
+            "strip_folder",
+        );
+
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(
+                    !keeps_all && strippable(&path),
+                    egui::Button::new("Strip this image"),
+                )
+                .on_hover_text("Remove metadata from the file, without re-encoding it")
+                .on_disabled_hover_text(if keeps_all {
+                    keeps_all_text
+                } else {
+                    "Only JPEG, PNG and WebP files can be stripped"
+                })
+                .clicked()
+            {
+                match state.volatile_settings.privacy.strip_file(&path) {
+                    Ok(_) => {
+                        state.send_message_info("Metadata stripped");
+                        send_extended_info(
+                            &state.current_image,
+                            &state.current_path,
+                            &state.extended_info_channel,
+                        );
+                    }
+                    Err(e) => state.send_message_err(&format!("Can't strip metadata: {e}")),
+                }
+            }
+            if ui
+                .add_enabled(!keeps_all && count > 0, egui::Button::new("Strip folder"))
+                .on_disabled_hover_text(if keeps_all {
+                    keeps_all_text
+                } else {
+                    "There are no JPEG, PNG or WebP files in this folder"
+                })
+                .clicked()
+            {
+                modal.open();
+            }
+        });
+    });
+}
+
+fn gps_ui(ui: &mut Ui, gps: &mut GpsPosition) {
+    ui.label("Latitude");
+    ui.add(
+        egui::DragValue::new(&mut gps.latitude)
+            .range(-90.0..=90.0)
+            .speed(0.0001)
+            .max_decimals(6),
+    );
+    ui.end_row();
+
+    ui.label("Longitude");
+    ui.add(
+        egui::DragValue::new(&mut gps.longitude)
+            .range(-180.0..=180.0)
+            .speed(0.0001)
+            .max_decimals(6),
+    );
+    ui.end_row();
+
+    let mut has_altitude = gps.altitude.is_some();
+    if ui.styled_checkbox(&mut has_altitude, "Altitude").changed() {
+        gps.altitude = has_altitude.then_some(0.);
+    }
+    if let Some(altitude) = &mut gps.altitude {
+        ui.add(egui::DragValue::new(altitude).speed(0.1).suffix(" m"));
     }
+    ui.end_row();
 }
@@ -5,21 +5,29 @@ pub const BUTTON_HEIGHT_SMALL: f32 = 24.;
 pub const PANEL_WIDTH: f32 = 260.0;
 const PANEL_WIDGET_OFFSET: f32 = 0.0;
 
+mod cull_ui;
+pub use cull_ui::cull_ui;
 mod info_ui;
 pub use info_ui::info_ui;
 mod palette_ui;
 pub use palette_ui::palette_ui;
+mod rating_ui;
+pub use rating_ui::rating_ui;
 mod settings_ui;
 pub use settings_ui::settings_ui;
 mod top_bar;
 pub use top_bar::*;
 mod edit_ui;
-pub use edit_ui::edit_ui;
+pub use edit_ui::{apply_preset, edit_ui};
+mod duplicates_ui;
+pub use duplicates_ui::duplicates_ui;
 mod theme;
 pub use theme::*;
 mod thumbnail_rendering;
 pub use thumbnail_rendering::*;
 
+use crate::annotations::{Annotations, SvgImage};
+use crate::calibration::{Calibration, LengthUnit, CALIBRATION_ID};
 #[cfg(feature = "file_open")]
 use crate::filebrowser::browse_for_image_path;
 use crate::icons::*;
@@ -34,7 +42,13 @@ use notan::{
     egui::{self, *},
     prelude::{App, Graphics},
 };
-use std::{collections::BTreeSet, f32, ops::RangeInclusive, path::Path, time::Instant};
+use std::{
+    collections::BTreeSet,
+    f32,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Instant,
+};
 use strum::IntoEnumIterator;
 use text::{LayoutJob, TextWrapping};
 
@@ -45,9 +59,10 @@ use crate::{
     appstate::{ImageGeometry, OculanteState},
     file_encoder::FileEncoder,
     image_editing::{
-        process_pixels, Channel, ColorTypeExt, GradientStop, ImageOperation, ImgOpItem,
-        MeasureShape, ScaleFilter,
+        process_pixel_stack, Anchor, Channel, ColorTypeExt, GradientStop, ImageOperation, ImgOpItem,
+        MeasureShape, MeasureTool, RotateFilter, ScaleFilter,
     },
+    metadata::PrivacySettings,
     paint::PaintStroke,
     settings::{set_system_theme, ColorTheme, PersistentSettings, VolatileSettings},
     shortcuts::{key_pressed, keypresses_as_string, lookup},
@@ -588,7 +603,20 @@ fn measure_ui(ui: &mut Ui, state: &mut OculanteState) {
                         }));
                 }
 
-                if ui.ctx().input(|r| r.pointer.secondary_pressed()) {
+                let tool = measure_tool_ui(ui, state);
+                let calibrating = calibration_ui(ui, state, cursor_relative);
+                let measuring = !calibrating && !region_modifier(ui.ctx());
+                let placing_rect = measuring && tool == MeasureTool::Rectangle;
+                if measuring && tool != MeasureTool::Rectangle {
+                    place_measure_shape(
+                        ui,
+                        state,
+                        tool,
+                        (cursor_relative.x as u32, cursor_relative.y as u32),
+                    );
+                }
+
+                if placing_rect && ui.ctx().input(|r| r.pointer.secondary_pressed()) {
                     for op in &mut state.edit_state.image_op_stack {
                         if !op.active {
                             continue;
@@ -604,7 +632,7 @@ fn measure_ui(ui: &mut Ui, state: &mut OculanteState) {
                     }
                 }
 
-                if ui.ctx().input(|r| r.pointer.secondary_down()) {
+                if placing_rect && ui.ctx().input(|r| r.pointer.secondary_down()) {
                     for op in &mut state.edit_state.image_op_stack {
                         if !op.active {
                             continue;
@@ -619,7 +647,7 @@ fn measure_ui(ui: &mut Ui, state: &mut OculanteState) {
                         }
                     }
                 }
-                if ui.ctx().input(|r| r.pointer.secondary_released()) {
+                if placing_rect && ui.ctx().input(|r| r.pointer.secondary_released()) {
                     for op in &mut state.edit_state.image_op_stack {
                         if !op.active {
                             continue;
@@ -635,19 +663,381 @@ fn measure_ui(ui: &mut Ui, state: &mut OculanteState) {
                     }
                 }
             });
+            annotations_ui(ui, state);
         });
 
         for op in &mut state.edit_state.image_op_stack {
             op.operation.ui(
                 ui,
                 &state.image_geometry,
-                &mut state.mouse_grab,
+                &mut state.edit_state.block_panning,
                 &mut state.volatile_settings,
             );
         }
     });
 }
 
+/// Holding shift while dragging with the right mouse button selects a region for statistics,
+/// so the measure, calibration and paint tools leave the right mouse button alone meanwhile
+pub fn region_modifier(ctx: &Context) -> bool {
+    ctx.input(|i| i.modifiers.shift)
+}
+
+/// Pick what to measure and clear measurements. Returns the current tool.
+fn measure_tool_ui(ui: &mut Ui, state: &mut OculanteState) -> MeasureTool {
+    let tool_id = Id::new("MEASURE_TOOL");
+    let mut tool = ui
+        .data(|r| r.get_temp::<MeasureTool>(tool_id))
+        .unwrap_or_default();
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_salt("measure_tool")
+            .selected_text(tool.to_string())
+            .show_ui(ui, |ui| {
+                for t in MeasureTool::iter() {
+                    ui.selectable_value(&mut tool, t, t.to_string());
+                }
+            });
+        if ui
+            .button(format!("{TRASH} Clear"))
+            .on_hover_text("Remove all measurements")
+            .clicked()
+        {
+            for op in &mut state.edit_state.image_op_stack {
+                if let ImageOperation::Measure { shapes } = &mut op.operation {
+                    *shapes = vec![MeasureShape::new_rect(vec![(0, 0), (0, 0)])];
+                }
+            }
+        }
+    });
+    ui.label(if tool.is_dragged() {
+        "Drag with the right mouse button to measure"
+    } else {
+        "Right click to add points, double click or press Enter to finish"
+    });
+    ui.data_mut(|w| w.insert_temp(tool_id, tool));
+    tool
+}
+
+/// Place shapes of the current measure tool with the right mouse button. Dragged shapes
+/// follow the pointer until released, clicked ones get a point per click.
+fn place_measure_shape(ui: &Ui, state: &mut OculanteState, tool: MeasureTool, cursor: (u32, u32)) {
+    let placing_id = Id::new("MEASURE_PLACING");
+    let Some(shapes) = state
+        .edit_state
+        .image_op_stack
+        .iter_mut()
+        .filter(|op| op.active)
+        .find_map(|op| match &mut op.operation {
+            ImageOperation::Measure { shapes } => Some(shapes),
+            _ => None,
+        })
+    else {
+        return;
+    };
+    let mut placing = ui
+        .data(|r| r.get_temp::<usize>(placing_id))
+        .filter(|i| *i < shapes.len());
+    let over_ui = ui.ctx().is_pointer_over_area();
+    let (pressed, down, double_click, enter, escape) = ui.input(|i| {
+        (
+            i.pointer.secondary_pressed() && !over_ui,
+            i.pointer.secondary_down(),
+            i.pointer.button_double_clicked(PointerButton::Secondary),
+            i.key_pressed(Key::Enter),
+            i.key_pressed(Key::Escape),
+        )
+    });
+
+    match placing {
+        None if pressed => {
+            shapes.push(tool.shape(vec![cursor, cursor]));
+            placing = Some(shapes.len() - 1);
+        }
+        None => {}
+        Some(index) if tool.is_dragged() => {
+            if let Some(last) = shapes[index].points_mut().last_mut() {
+                *last = cursor;
+            }
+            if !down {
+                placing = None;
+            }
+        }
+        Some(index) => {
+            let points = shapes[index].points_mut();
+            // the last point follows the pointer until the next click
+            if let Some(last) = points.last_mut() {
+                *last = cursor;
+            }
+            if escape {
+                shapes.remove(index);
+                placing = None;
+            } else if double_click || enter {
+                points.pop();
+                // both clicks of a double click added the same point
+                points.dedup();
+                let min_points = match tool {
+                    MeasureTool::Polygon | MeasureTool::Angle => 3,
+                    _ => 2,
+                };
+                if points.len() < min_points {
+                    shapes.remove(index);
+                }
+                placing = None;
+            } else if pressed {
+                if tool == MeasureTool::Angle && points.len() == 3 {
+                    placing = None;
+                } else {
+                    points.push(cursor);
+                }
+            }
+        }
+    }
+
+    ui.data_mut(|w| match placing {
+        Some(index) => w.insert_temp(placing_id, index),
+        None => w.remove_temp::<usize>(placing_id),
+    });
+}
+
+/// Set the real-world size of pixels, either detected from the image or by drawing
+/// a line of known length. Returns true while that line is being drawn.
+fn calibration_ui(ui: &mut Ui, state: &mut OculanteState, cursor: Vector2<f32>) -> bool {
+    let drawing_id = Id::new("CALIBRATION_DRAWING");
+    let line_id = Id::new("CALIBRATION_LINE");
+    let length_id = Id::new("CALIBRATION_LENGTH");
+    let mut drawing = ui.data(|r| r.get_temp::<bool>(drawing_id)).unwrap_or_default();
+    let mut line = ui
+        .data(|r| r.get_temp::<[(f64, f64); 2]>(line_id))
+        .unwrap_or_default();
+    let mut length = ui.data(|r| r.get_temp::<f64>(length_id)).unwrap_or(10.);
+
+    let detected = state.image_metadata.as_ref().and_then(|info| {
+        info.dicom
+            .as_ref()
+            .and_then(Calibration::from_dicom)
+            .or_else(|| Calibration::from_exif(&info.exif))
+    });
+    let mut calibration = state.edit_state.calibration.or(detected);
+
+    egui::Grid::new("calibration").num_columns(2).show(ui, |ui| {
+        ui.label("Unit");
+        let mut current = calibration.unwrap_or_default();
+        ui.add_enabled_ui(calibration.is_some(), |ui| {
+            egui::ComboBox::from_id_salt("calibration_unit")
+                .selected_text(current.unit.to_string())
+                .show_ui(ui, |ui| {
+                    for unit in LengthUnit::iter() {
+                        if ui
+                            .selectable_value(&mut current.unit, unit, unit.to_string())
+                            .clicked()
+                        {
+                            state.edit_state.calibration = Some(current);
+                        }
+                    }
+                });
+        })
+        .response
+        .on_disabled_hover_text("Calibrate first to measure in real-world units");
+        ui.end_row();
+
+        ui.label("Scale");
+        match calibration {
+            Some(c) => ui.label(format!(
+                "1 px = {:.4} x {:.4} mm ({})",
+                c.pixel_size.0, c.pixel_size.1, c.source
+            )),
+            None => ui.label("Not calibrated"),
+        };
+        ui.end_row();
+
+        ui.label("Known length");
+        ui.horizontal(|ui| {
+            let unit = match current.unit {
+                LengthUnit::Pixel => LengthUnit::Millimeter,
+                unit => unit,
+            };
+            if ui
+                .add(
+                    egui::DragValue::new(&mut length)
+                        .range(0.001..=f64::MAX)
+                        .speed(0.1)
+                        .suffix(format!(" {unit}")),
+                )
+                .changed()
+            {
+                ui.data_mut(|w| w.insert_temp(length_id, length));
+            }
+            let pixels = (line[0].0 - line[1].0).hypot(line[0].1 - line[1].1);
+            if ui
+                .add_enabled(pixels > 0., egui::Button::new("Set scale"))
+                .on_hover_text("Use the calibration line as this length")
+                .clicked()
+            {
+                calibration = Calibration::from_line(line[0], line[1], length, unit);
+                state.edit_state.calibration = calibration;
+                drawing = false;
+            }
+        });
+        ui.end_row();
+    });
+
+    ui.horizontal(|ui| {
+        if ui
+            .selectable_label(drawing, "Draw calibration line")
+            .on_hover_text("Drag with the right mouse button along something of known length")
+            .clicked()
+        {
+            drawing = !drawing;
+        }
+        if ui
+            .add_enabled(
+                state.edit_state.calibration.is_some(),
+                egui::Button::new("Reset"),
+            )
+            .on_hover_text("Use the scale stored in the image, or pixels")
+            .clicked()
+        {
+            state.edit_state.calibration = None;
+            calibration = detected;
+        }
+    });
+
+    if drawing && !region_modifier(ui.ctx()) {
+        let cursor = (cursor.x as f64, cursor.y as f64);
+        if ui.input(|r| r.pointer.secondary_pressed()) {
+            line = [cursor, cursor];
+        } else if ui.input(|r| r.pointer.secondary_down()) {
+            line[1] = cursor;
+        }
+        let geo = &state.image_geometry;
+        let to_screen = |p: (f64, f64)| {
+            Pos2::new(
+                geo.scale * p.0 as f32 + geo.offset.x,
+                geo.scale * p.1 as f32 + geo.offset.y,
+            )
+        };
+        ui.ctx()
+            .layer_painter(LayerId::new(Order::Foreground, drawing_id))
+            .line_segment(
+                [to_screen(line[0]), to_screen(line[1])],
+                Stroke::new(2., Color32::YELLOW),
+            );
+    }
+
+    ui.data_mut(|w| {
+        w.insert_temp(drawing_id, drawing);
+        w.insert_temp(line_id, line);
+        w.insert_temp(Id::new(CALIBRATION_ID), calibration.unwrap_or_default());
+    });
+    drawing
+}
+
+/// Export measurements and paint strokes as SVG or JSON, and import them back
+fn annotations_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let embed_id = Id::new("ANNOTATIONS_EMBED");
+    let mut embed = ui.data(|r| r.get_temp::<bool>(embed_id)).unwrap_or_default();
+    let mut export: Option<PathBuf> = None;
+    let mut import: Option<PathBuf> = None;
+
+    ui.horizontal(|ui| {
+        #[cfg(feature = "file_open")]
+        {
+            if ui
+                .button(format!("{FLOPPY_DISK} Export annotations"))
+                .on_hover_text("Save measurements and paint strokes as an SVG overlay or as JSON")
+                .clicked()
+            {
+                export = rfd::FileDialog::new()
+                    .add_filter("SVG", &["svg"])
+                    .add_filter("JSON", &["json"])
+                    .save_file();
+            }
+            if ui.button(format!("{PLUS} Import annotations")).clicked() {
+                import = rfd::FileDialog::new()
+                    .add_filter("Annotations", &["svg", "json"])
+                    .pick_file();
+            }
+        }
+        #[cfg(not(feature = "file_open"))]
+        {
+            let export_id = Id::new("ANNOTATIONS_EXPORT");
+            let import_id = Id::new("ANNOTATIONS_IMPORT");
+            if ui
+                .button(format!("{FLOPPY_DISK} Export annotations"))
+                .on_hover_text("Save measurements and paint strokes as an SVG overlay or as JSON")
+                .clicked()
+            {
+                ui.ctx().memory_mut(|w| w.open_popup(export_id));
+            }
+            if ui.button(format!("{PLUS} Import annotations")).clicked() {
+                ui.ctx().memory_mut(|w| w.open_popup(import_id));
+            }
+            if ui.ctx().memory(|w| w.is_popup_open(export_id)) {
+                filebrowser::browse_modal(
+                    true,
+                    &["svg", "json"],
+                    &mut state.volatile_settings,
+                    |p| export = Some(p.clone()),
+                    ui.ctx(),
+                );
+            }
+            if ui.ctx().memory(|w| w.is_popup_open(import_id)) {
+                filebrowser::browse_modal(
+                    false,
+                    &["svg", "json"],
+                    &mut state.volatile_settings,
+                    |p| import = Some(p.clone()),
+                    ui.ctx(),
+                );
+            }
+        }
+    });
+    if ui
+        .styled_checkbox(&mut embed, "Embed image in SVG")
+        .on_hover_text("Otherwise the SVG links to the image file")
+        .changed()
+    {
+        ui.data_mut(|w| w.insert_temp(embed_id, embed));
+    }
+
+    let size = state.image_geometry.dimensions;
+    if let Some(path) = export {
+        let annotations =
+            Annotations::from_edit_state(&state.edit_state, size, state.current_path.as_deref());
+        let result = if path.extension().is_some_and(|ext| ext == "json") {
+            annotations.save_json(&path)
+        } else {
+            let mode = if embed {
+                SvgImage::Embedded
+            } else {
+                SvgImage::Linked
+            };
+            annotations.save_svg(
+                &path.with_extension("svg"),
+                state.current_path.as_deref(),
+                mode,
+            )
+        };
+        match result {
+            Ok(_) => state.send_message_info("Annotations exported"),
+            Err(e) => state.send_message_err(&format!("{e}")),
+        }
+    }
+    if let Some(path) = import {
+        match Annotations::load(&path) {
+            Ok(annotations) => {
+                annotations.apply(&mut state.edit_state, size);
+                state.send_message_info(&format!(
+                    "Imported {} measurements and {} strokes",
+                    annotations.shapes.len(),
+                    annotations.strokes.len()
+                ));
+            }
+            Err(e) => state.send_message_err(&format!("{e}")),
+        }
+    }
+}
+
 // TODO redo as impl UI
 pub fn tooltip(r: Response, tooltip: &str, hotkey: &str, _ui: &mut Ui) -> Response {
     r.on_hover_ui(|ui| {
@@ -868,6 +1258,7 @@ fn save_with_encoding(
     path: &Path,
     image_info: &Option<ExtendedImageInfo>,
     encoders: &Vec<FileEncoder>,
+    privacy: &PrivacySettings,
 ) -> anyhow::Result<()> {
     let encoding_options = FileEncoder::matching_variant(path, encoders);
     encoding_options.save(image, path)?;
@@ -876,8 +1267,8 @@ fn save_with_encoding(
     if let Some(info) = &image_info {
         debug!("Extended image info present");
         // before doing anything, make sure we have raw exif data
-        if info.raw_exif.is_some() {
-            fix_exif(path, info.raw_exif.clone())?;
+        if info.raw_exif.is_some() || info.raw_icc.is_some() {
+            fix_exif(path, info, privacy)?;
         } else {
             debug!("No raw exif");
         }
@@ -0,0 +1,161 @@
+use super::*;
+use crate::appstate::OculanteState;
+use crate::rating::{ColorLabel, Flag, FlagFilter};
+use crate::utils::*;
+#[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
+use notan::egui::*;
+
+fn label_color(label: ColorLabel) -> Color32 {
+    match label {
+        ColorLabel::Red => Color32::from_rgb(220, 60, 60),
+        ColorLabel::Yellow => Color32::from_rgb(230, 200, 50),
+        ColorLabel::Green => Color32::from_rgb(70, 180, 80),
+        ColorLabel::Blue => Color32::from_rgb(60, 120, 220),
+        ColorLabel::Purple => Color32::from_rgb(150, 80, 200),
+    }
+}
+
+/// A clickable color swatch, outlined when selected
+fn swatch(ui: &mut Ui, label: ColorLabel, selected: bool) -> Response {
+    let (rect, response) = ui.allocate_exact_size(vec2(18., 18.), Sense::click());
+    ui.painter().rect_filled(rect, 4., label_color(label));
+    if selected {
+        ui.painter().rect_stroke(
+            rect.expand(2.),
+            4.,
+            Stroke::new(2., ui.style().visuals.strong_text_color()),
+            StrokeKind::Outside,
+        );
+    }
+    response.on_hover_text(label.to_string())
+}
+
+pub fn rating_ui(ui: &mut Ui, state: &mut OculanteState) {
+    let Some(path) = state.current_path.clone() else {
+        return;
+    };
+    let rating = state
+        .ratings
+        .get(&path, state.persistent_settings.rating_storage)
+        .clone();
+
+    ui.styled_collapsing("Rating", |ui| {
+        ui.vertical_centered_justified(|ui| {
+            dark_panel(ui, |ui| {
+                ui.horizontal(|ui| {
+                    for stars in 1..=5 {
+                        let star = if stars <= rating.stars { "★" } else { "☆" };
+                        if ui
+                            .add(egui::Button::new(RichText::new(star).size(18.)).frame(false))
+                            .on_hover_text(format!("{stars} stars"))
+                            .clicked()
+                        {
+                            // clicking the current rating clears it
+                            let stars = if stars == rating.stars { 0 } else { stars };
+                            update_rating(state, |r| r.stars = stars);
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    for label in ColorLabel::iter() {
+                        let selected = rating.label == Some(label);
+                        if swatch(ui, label, selected).clicked() {
+                            update_rating(state, |r| r.label = (!selected).then_some(label));
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    for flag in Flag::iter() {
+                        let selected = rating.flag == Some(flag);
+                        if ui.selectable_label(selected, flag.to_string()).clicked() {
+                            update_rating(state, |r| r.flag = (!selected).then_some(flag));
+                        }
+                    }
+                });
+
+                ui.horizontal_wrapped(|ui| {
+                    for tag in &rating.tags {
+                        if ui
+                            .button(format!("#{tag} {X}"))
+                            .on_hover_text("Remove tag")
+                            .clicked()
+                        {
+                            let tag = tag.clone();
+                            update_rating(state, |r| {
+                                r.tags.remove(&tag);
+                            });
+                        }
+                    }
+                });
+                let id = Id::new("rating_tag");
+                let mut new_tag = ui.data(|r| r.get_temp::<String>(id)).unwrap_or_default();
+                let response = ui.add(TextEdit::singleline(&mut new_tag).hint_text("Add tag"));
+                if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                    let tag = new_tag.trim().to_string();
+                    if !tag.is_empty() {
+                        update_rating(state, |r| {
+                            r.tags.insert(tag);
+                        });
+                    }
+                    new_tag.clear();
+                }
+                ui.data_mut(|w| w.insert_temp(id, new_tag));
+            });
+        });
+    });
+
+    ui.styled_collapsing("Filter", |ui| {
+        ui.vertical_centered_justified(|ui| {
+            dark_panel(ui, |ui| {
+                let filter = &mut state.scrubber.filter;
+                let before = filter.clone();
+                ui.horizontal(|ui| {
+                    ui.label("Minimum stars");
+                    ui.styled_slider(&mut filter.min_stars, 0..=5);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Label");
+                    egui::ComboBox::from_id_salt("filter_label")
+                        .selected_text(filter.label.map(|l| l.to_string()).unwrap_or("Any".into()))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut filter.label, None, "Any");
+                            for label in ColorLabel::iter() {
+                                ui.selectable_value(
+                                    &mut filter.label,
+                                    Some(label),
+                                    label.to_string(),
+                                );
+                            }
+                        });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Flag");
+                    egui::ComboBox::from_id_salt("filter_flag")
+                        .selected_text(filter.flag.to_string())
+                        .show_ui(ui, |ui| {
+                            for flag in FlagFilter::iter() {
+                                ui.selectable_value(&mut filter.flag, flag, flag.to_string());
+                            }
+                        });
+                });
+                ui.add(TextEdit::singleline(&mut filter.tag).hint_text("Tag"));
+                if *filter != before {
+                    apply_rating_filter(state);
+                }
+                if state.scrubber.filter.is_active() {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "{} of {} images",
+                            state.scrubber.len(),
+                            state.scrubber.unfiltered_len()
+                        ));
+                        if ui.button("Clear").clicked() {
+                            state.scrubber.filter = Default::default();
+                            apply_rating_filter(state);
+                        }
+                    });
+                }
+            });
+        });
+    });
+}
@@ -3,6 +3,7 @@ use std::sync::{Arc, Mutex};
 
 use super::*;
 use crate::appstate::OculanteState;
+use crate::rating::RatingStorage;
 use crate::thumbnails::get_disk_cache_path;
 use crate::{settings, utils::*};
 #[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
@@ -155,6 +156,21 @@ pub fn settings_ui(app: &mut App, ctx: &Context, state: &mut OculanteState, _gfx
                                         ui.styled_checkbox(&mut state.persistent_settings.keep_edits, "");
                                     }, ui);
 
+                                    configuration_item_ui("Rating storage", "Keep star ratings, color labels, flags and tags in XMP sidecar files next to the images, or in one local index for folders that can't be written to.", |ui| {
+                                        let before = state.persistent_settings.rating_storage;
+                                        egui::ComboBox::from_id_salt("Rating storage")
+                                        .selected_text(before.to_string())
+                                        .show_ui(ui, |ui| {
+                                            for storage in RatingStorage::iter() {
+                                                ui.selectable_value(&mut state.persistent_settings.rating_storage, storage, storage.to_string());
+                                            }
+                                        });
+                                        if state.persistent_settings.rating_storage != before {
+                                            state.ratings.clear();
+                                            apply_rating_filter(state);
+                                        }
+                                    }, ui);
+
                                     configuration_item_ui("Redraw every frame", "Turns off optimisations and redraws everything each frame. This will consume more CPU but gives you instant feedback if new images come in or if modifications are made. A restart is required to take effect.", |ui| {
                                         if ui.styled_checkbox(&mut state.persistent_settings.force_redraw, "").changed(){
                                             app.window().set_lazy_loop(!state.persistent_settings.force_redraw);
@@ -108,7 +108,7 @@ pub fn apply_theme(state: &mut OculanteState, ctx: &Context) {
 }
 
 /// Attempt to load a system font by any of the given `family_names`, returning the first match.
-fn load_font_family(family_names: &[&str]) -> Option<Vec<u8>> {
+pub fn load_font_family(family_names: &[&str]) -> Option<Vec<u8>> {
     let system_source = SystemSource::new();
     for &name in family_names {
         let font_handle = system_source
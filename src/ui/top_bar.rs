@@ -357,6 +357,43 @@ pub fn draw_hamburger_menu(ui: &mut Ui, state: &mut OculanteState, app: &mut App
                 ui.close_menu();
             }
 
+            if ui
+                .styled_button(format!("{COPY} Find duplicates"))
+                .on_hover_text("Find similar images in the current folder")
+                .clicked()
+            {
+                state.duplicates.open = !state.duplicates.open;
+                ui.close_menu();
+            }
+
+            if state.current_image.is_some() {
+                ui.styled_menu_button(format!("{OPTIONS} Presets"), |ui| {
+                    let mut apply = None;
+                    for preset in state.presets.presets() {
+                        ui.horizontal(|ui| {
+                            if ui
+                                .styled_button(&preset.name)
+                                .on_hover_text("Replace all edits with this preset")
+                                .clicked()
+                            {
+                                apply = Some((preset.clone(), false));
+                            }
+                            if ui
+                                .styled_button(PLUS)
+                                .on_hover_text("Add this preset to the current edits")
+                                .clicked()
+                            {
+                                apply = Some((preset.clone(), true));
+                            }
+                        });
+                    }
+                    if let Some((preset, append)) = apply {
+                        apply_preset(state, &preset, append);
+                        ui.close_menu();
+                    }
+                });
+            }
+
             if ui.styled_button(format!("{GEAR} Preferences")).clicked() {
                 state.settings_enabled = !state.settings_enabled;
                 ui.close_menu();
@@ -1,7 +1,7 @@
 use arboard::Clipboard;
 
-use img_parts::{Bytes, DynImage, ImageEXIF};
-use log::{debug, error, info};
+use img_parts::{Bytes, DynImage, ImageEXIF, ImageICC};
+use log::{debug, error};
 use nalgebra::{clamp, Vector2};
 use notan::graphics::Texture;
 use notan::prelude::{App, Graphics};
@@ -26,7 +26,14 @@ use strum_macros::EnumIter;
 
 use crate::appstate::{ImageGeometry, Message, OculanteState};
 use crate::cache::Cache;
+use crate::calibration::parse_pixel_spacing;
+use crate::cull::CullAction;
+use crate::dicom::{self, DicomTag, WindowPreset};
+use crate::geotag::GeoTag;
+use crate::histogram::{ImageStats, DEFAULT_BINS};
 use crate::image_loader::{open_image, rotate_dynimage};
+use crate::metadata::{Metadata, PrivacySettings};
+use crate::rating::Rating;
 use crate::settings::DecoderSettings;
 use crate::shortcuts::{lookup, InputEvent, Shortcuts};
 
@@ -96,8 +103,15 @@ fn is_pixel_fully_transparent(p: &Rgba<u8>) -> bool {
 
 #[derive(Debug, Clone, Default)]
 pub struct DicomData {
+    /// Width and height of a pixel in millimeters, or zero if unknown
     pub physical_size: (f32, f32),
-    pub dicom_data: HashMap<String, String>,
+    /// Every data element in the file
+    pub tags: Vec<DicomTag>,
+    /// Window center and width pairs stored in the file
+    pub windows: Vec<WindowPreset>,
+    pub frames: u32,
+    /// The files of the same series, ordered by instance number
+    pub series: Vec<PathBuf>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -105,12 +119,16 @@ pub struct ExtendedImageInfo {
     pub num_pixels: usize,
     pub num_transparent_pixels: usize,
     pub num_colors: usize,
-    pub red_histogram: Vec<(i32, u64)>,
-    pub green_histogram: Vec<(i32, u64)>,
-    pub blue_histogram: Vec<(i32, u64)>,
+    /// Histograms and statistics of the whole image, in its own bit depth
+    pub stats: ImageStats,
     pub exif: HashMap<String, String>,
     pub dicom: Option<DicomData>,
     pub raw_exif: Option<Bytes>,
+    /// The ICC profile, carried over when saving like `raw_exif`
+    pub raw_icc: Option<Bytes>,
+    /// Editable metadata, for formats it can be written back to
+    pub metadata: Option<Metadata>,
+    pub geotag: Option<GeoTag>,
     pub name: String,
 }
 
@@ -125,8 +143,10 @@ impl ExtendedImageInfo {
 
         // Store original EXIF to write in in case of save event
         if let Some(d) = DynImage::from_bytes(input.clone().into())? {
-            self.raw_exif = d.exif()
+            self.raw_exif = d.exif();
+            self.raw_icc = d.icc_profile();
         }
+        self.metadata = Metadata::from_bytes(input.clone().into()).ok();
 
         // User-friendly Exif in key/value form
         let mut c = Cursor::new(input);
@@ -138,6 +158,7 @@ impl ExtendedImageInfo {
         if self.raw_exif.is_none() {
             self.raw_exif = Some(exif.buf().to_vec().into());
         }
+        self.geotag = GeoTag::from_exif(&exif);
         for f in exif.fields() {
             self.exif.insert(
                 f.tag.to_string(),
@@ -149,51 +170,39 @@ impl ExtendedImageInfo {
 
     pub fn with_dicom(&mut self, image_path: &Path) -> Result<()> {
         self.name = image_path.to_string_lossy().to_string();
-        if image_path.extension() != Some(OsStr::new("dcm"))
-            || image_path.extension() != Some(OsStr::new("ima"))
-        {
-            let obj = dicom_object::open_file(image_path)?;
-            let mut dicom_data = HashMap::new();
-
-            // WIP: Find out interesting items to display
-            for name in &[
-                "StudyDate",
-                "ModalitiesInStudy",
-                "Modality",
-                "SourceType",
-                "ImageType",
-                "Manufacturer",
-                "InstitutionName",
-                "PrivateDataElement",
-                "PrivateDataElementName",
-                "OperatorsName",
-                "ManufacturerModelName",
-                "PatientName",
-                "PatientBirthDate",
-                "PatientAge",
-                "PixelSpacing",
-            ] {
-                if let Ok(e) = obj.element_by_name(name) {
-                    if let Ok(s) = e.to_str() {
-                        info!("{name}: {s}");
-                        dicom_data.insert(name.to_string(), s.to_string());
-                    }
-                }
-            }
-            self.dicom = Some(DicomData {
-                physical_size: (0.0, 0.0),
-                dicom_data,
-            })
+        let is_dicom = image_path
+            .extension()
+            .map(|e| e.to_ascii_lowercase())
+            .is_some_and(|e| e == "dcm" || e == "ima");
+        if !is_dicom {
+            return Ok(());
         }
 
+        let obj = dicom_object::open_file(image_path)?;
+        let physical_size = obj
+            .element_by_name("PixelSpacing")
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .and_then(|s| parse_pixel_spacing(&s))
+            .unwrap_or_default();
+        self.dicom = Some(DicomData {
+            physical_size,
+            tags: dicom::read_tags(&obj),
+            windows: dicom::window_presets(&obj),
+            frames: dicom::frame_count(&obj),
+            series: dicom::series(image_path).unwrap_or_else(|e| {
+                debug!("Can't find DICOM series: {e}");
+                vec![image_path.to_path_buf()]
+            }),
+        });
         Ok(())
     }
 
-    pub fn from_image(img: &RgbaImage) -> Self {
-        let mut hist_r: [u64; 256] = [0; 256];
-        let mut hist_g: [u64; 256] = [0; 256];
-        let mut hist_b: [u64; 256] = [0; 256];
+    pub fn with_stats(&mut self, img: &DynamicImage) {
+        self.stats = ImageStats::compute(img, None, DEFAULT_BINS);
+    }
 
+    pub fn from_image(img: &RgbaImage) -> Self {
         let num_pixels = img.width() as usize * img.height() as usize;
         let mut num_transparent_pixels = 0;
 
@@ -208,10 +217,6 @@ impl ExtendedImageInfo {
                 num_transparent_pixels += 1;
             }
 
-            hist_r[p.0[0] as usize] += 1;
-            hist_g[p.0[1] as usize] += 1;
-            hist_b[p.0[2] as usize] += 1;
-
             //Store every existing color combination in a bit
             //Therefore we use a 24 bit index, splitted into a main and a sub index.
             let pos = u32::from_le_bytes([p.0[0], p.0[1], p.0[2], 0]);
@@ -225,32 +230,15 @@ impl ExtendedImageInfo {
             full_colors += intensity.count_ones();
         }
 
-        let green_histogram: Vec<(i32, u64)> = hist_g
-            .iter()
-            .enumerate()
-            .map(|(k, v)| (k as i32, *v))
-            .collect();
-
-        let red_histogram: Vec<(i32, u64)> = hist_r
-            .iter()
-            .enumerate()
-            .map(|(k, v)| (k as i32, *v))
-            .collect();
-
-        let blue_histogram: Vec<(i32, u64)> = hist_b
-            .iter()
-            .enumerate()
-            .map(|(k, v)| (k as i32, *v))
-            .collect();
-
         Self {
             num_pixels,
             num_transparent_pixels,
             num_colors: full_colors as usize,
-            blue_histogram,
-            green_histogram,
-            red_histogram,
+            stats: Default::default(),
             raw_exif: Default::default(),
+            raw_icc: Default::default(),
+            metadata: Default::default(),
+            geotag: Default::default(),
             name: Default::default(),
             exif: Default::default(),
             dicom: Default::default(),
@@ -563,26 +551,123 @@ pub fn zoomratio(i: f32, s: f32) -> f32 {
 }
 
 pub fn delete_file(state: &mut OculanteState) {
-    if let Some(p) = &state.current_path {
-        #[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
-        {
-            _ = trash::delete(p);
+    if let Some(p) = state.current_path.clone() {
+        trash_file(&p, state);
+    }
+    clear_image(state);
+}
+
+/// Move a file to the trash, where supported, and forget it
+pub fn trash_file(p: &Path, state: &mut OculanteState) {
+    #[cfg(not(any(target_os = "netbsd", target_os = "freebsd")))]
+    {
+        _ = trash::delete(p);
+    }
+    #[cfg(any(target_os = "netbsd", target_os = "freebsd"))]
+    {
+        _ = std::fs::remove_file(p)
+    }
+
+    state.send_message_info(&format!(
+        "Deleted {}",
+        p.file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default()
+    ));
+    // remove from cache so we don't suceed to load it agaim
+    state.player.cache.data.remove(p);
+}
+
+/// Move or copy the current file to one of the bookmarked folders and go on to the next image
+pub fn cull_file(state: &mut OculanteState, action: CullAction, target: usize) {
+    let Some(path) = state.current_path.clone() else {
+        return;
+    };
+    let Some(folder) = state
+        .volatile_settings
+        .cull_targets
+        .get(target)
+        .cloned()
+        .flatten()
+    else {
+        state.send_message_warn(&format!(
+            "Choose a folder for {} in the culling panel",
+            target + 1
+        ));
+        return;
+    };
+    match state.culling.apply(action, &path, &folder) {
+        Ok(operation) => {
+            let (msg, destination) = (operation.to_string(), operation.destination.clone());
+            state.send_message_info(&msg);
+            match action {
+                CullAction::Move => {
+                    move_rating(state, &path, &destination);
+                    state.player.cache.data.remove(&path);
+                    clear_image(state);
+                }
+                CullAction::Copy => next_image(state),
+            }
         }
-        #[cfg(any(target_os = "netbsd", target_os = "freebsd"))]
-        {
-            _ = std::fs::remove_file(p)
+        Err(e) => state.send_message_err(&format!("Can't sort file: {e}")),
+    }
+}
+
+/// Revert the last move or copy. A moved file is shown again.
+pub fn undo_cull(state: &mut OculanteState) {
+    match state.culling.undo() {
+        Ok(Some(operation)) => {
+            state.send_message_info(&format!("Undid: {operation}"));
+            if operation.action == CullAction::Move {
+                move_rating(state, &operation.destination, &operation.source);
+                state.player.cache.data.remove(&operation.destination);
+                if state.scrubber.has_folder_changed(&operation.source) {
+                    _ = state.load_channel.0.send(operation.source);
+                } else {
+                    state.scrubber.restore(&operation.source);
+                    load_image_from_path(&operation.source, state);
+                }
+            }
         }
+        Ok(None) => state.send_message_info("Nothing to undo"),
+        Err(e) => state.send_message_err(&format!("Can't undo: {e}")),
+    }
+}
 
-        state.send_message_info(&format!(
-            "Deleted {}",
-            p.file_name()
-                .map(|f| f.to_string_lossy().to_string())
-                .unwrap_or_default()
-        ));
-        // remove from cache so we don't suceed to load it agaim
-        state.player.cache.data.remove(p);
+/// Let the rating of a culled file follow it
+fn move_rating(state: &mut OculanteState, from: &Path, to: &Path) {
+    if let Err(e) = state
+        .ratings
+        .rename(from, to, state.persistent_settings.rating_storage)
+    {
+        state.send_message_err(&format!("Can't move rating: {e}"));
+    }
+}
+
+/// Change the rating of the current image and save it
+pub fn update_rating(state: &mut OculanteState, edit: impl FnOnce(&mut Rating)) {
+    let Some(path) = state.current_path.clone() else {
+        return;
+    };
+    match state
+        .ratings
+        .update(&path, state.persistent_settings.rating_storage, edit)
+    {
+        Ok(rating) => state.send_message_info(&rating.to_string()),
+        Err(e) => state.send_message_err(&format!("Can't save rating: {e}")),
     }
-    clear_image(state);
+}
+
+/// Hide images the scrubber's rating filter does not match
+pub fn apply_rating_filter(state: &mut OculanteState) {
+    let storage = state.persistent_settings.rating_storage;
+    let filter = state.scrubber.filter.clone();
+    let ratings = &mut state.ratings;
+    state
+        .scrubber
+        .apply_filter(state.current_path.as_deref(), |p| {
+            filter.matches(ratings.get(p, storage))
+        });
 }
 
 /// Display RGBA values nicely
@@ -717,11 +802,12 @@ pub fn send_extended_info(
     channel: &(Sender<ExtendedImageInfo>, Receiver<ExtendedImageInfo>),
 ) {
     if let Some(img) = current_image {
-        let copied_img = img.to_rgba8();
+        let copied_img = img.clone();
         let sender = channel.0.clone();
         let current_path = current_path.clone();
         thread::spawn(move || {
-            let mut e_info = ExtendedImageInfo::from_image(&copied_img);
+            let mut e_info = ExtendedImageInfo::from_image(&copied_img.to_rgba8());
+            e_info.with_stats(&copied_img);
             if let Some(p) = current_path {
                 _ = e_info.with_exif(&p);
                 _ = e_info.with_dicom(&p);
@@ -732,6 +818,32 @@ pub fn send_extended_info(
     }
 }
 
+/// Update the pixel counts and statistics of `info` for new pixels of the same file in the
+/// background, keeping the tags that were read before
+pub fn send_pixel_info(
+    current_image: &Option<DynamicImage>,
+    info: &ExtendedImageInfo,
+    channel: &(Sender<ExtendedImageInfo>, Receiver<ExtendedImageInfo>),
+) {
+    if let Some(img) = current_image {
+        let copied_img = img.clone();
+        let sender = channel.0.clone();
+        let info = info.clone();
+        thread::spawn(move || {
+            let pixels = ExtendedImageInfo::from_image(&copied_img.to_rgba8());
+            let mut e_info = ExtendedImageInfo {
+                num_pixels: pixels.num_pixels,
+                num_transparent_pixels: pixels.num_transparent_pixels,
+                num_colors: pixels.num_colors,
+                ..info
+            };
+            e_info.with_stats(&copied_img);
+            debug!("Sending pixel info");
+            _ = sender.send(e_info);
+        });
+    }
+}
+
 pub trait ImageExt {
     fn size_vec(&self) -> Vector2<f32> {
         unimplemented!()
@@ -962,12 +1074,19 @@ pub fn toggle_zen_mode(state: &mut OculanteState, app: &mut App) {
     set_title(app, state);
 }
 
-/// Fix missing exif by re-applying exif to saved files
-pub fn fix_exif(p: &Path, exif: Option<Bytes>) -> Result<()> {
+/// Fix missing exif by re-applying exif and the ICC profile to saved files,
+/// keeping only what the privacy settings allow
+pub fn fix_exif(p: &Path, info: &ExtendedImageInfo, privacy: &PrivacySettings) -> Result<()> {
     use std::fs::{self, File};
     let input = fs::read(p)?;
     let mut dynimage = DynImage::from_bytes(input.into())?.context("Unsupported EXIF format")?;
-    dynimage.set_exif(exif);
+    dynimage.set_exif(privacy.filter_exif(info.raw_exif.clone())?);
+    // saved images are RGB, other profiles (e.g. CMYK) no longer describe them
+    dynimage.set_icc_profile(
+        info.raw_icc
+            .clone()
+            .filter(|icc| icc.get(16..20) == Some(b"RGB ".as_slice())),
+    );
     let output = File::create(p)?;
     dynimage.encoder().write_to(output)?;
     Ok(())